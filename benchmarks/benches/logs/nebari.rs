@@ -135,7 +135,8 @@ impl<B: NebariBenchmark> SimpleBench for ReadLogs<B> {
         config: &Self::Config,
         config_group_state: &<Self::Config as BenchConfig>::GroupState,
     ) -> Result<Self, anyhow::Error> {
-        let context = Context::default().with_cache(ChunkCache::new(2000, 160_384));
+        let context = Context::default()
+            .with_cache(ChunkCache::with_byte_capacity(64 * 1024 * 1024, 160_384));
         let file_path = group_state.path().join("tree");
         let tree = TreeFile::<B::Root, StdFile>::read(&file_path, State::default(), &context, None)
             .unwrap();
@@ -223,7 +224,8 @@ impl<B: NebariBenchmark> SimpleBench for ScanLogs<B> {
         config: &Self::Config,
         config_group_state: &<Self::Config as BenchConfig>::GroupState,
     ) -> Result<Self, anyhow::Error> {
-        let context = Context::default().with_cache(ChunkCache::new(2000, 160_384));
+        let context = Context::default()
+            .with_cache(ChunkCache::with_byte_capacity(64 * 1024 * 1024, 160_384));
         let file_path = group_state.path().join("tree");
         let tree = TreeFile::<B::Root, StdFile>::read(&file_path, State::default(), &context, None)
             .unwrap();
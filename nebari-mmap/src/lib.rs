@@ -0,0 +1,574 @@
+#![doc = include_str!("../README.md")]
+#![deny(unsafe_code)]
+#![warn(
+    clippy::cargo,
+    missing_docs,
+    clippy::nursery,
+    clippy::pedantic,
+    future_incompatible,
+    rust_2018_idioms,
+)]
+#![allow(
+    clippy::missing_errors_doc,
+    clippy::option_if_let_else,
+    clippy::module_name_repetitions,
+)]
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use memmap2::Mmap;
+use nebari::{
+    io::{
+        File as _, FileManager, FileOp, IntoPathId, ManagedFile, ManagedFileOpener, OpenableFile,
+        OperableFile, PathId, PathIds,
+    },
+    ArcBytes, Error, ErrorKind,
+};
+use parking_lot::{Mutex, RwLock};
+
+/// The memory map shared by every handle opened with the same file id.
+#[derive(Debug)]
+struct Mapping {
+    file: File,
+    map: RwLock<Option<Arc<Mmap>>>,
+}
+
+impl Mapping {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            map: RwLock::default(),
+        })
+    }
+
+    /// Returns a map containing at least `end` bytes, mapping the file again
+    /// if the current map is too short. Returns `None` if the file is shorter
+    /// than `end`.
+    fn map_through(&self, end: u64) -> io::Result<Option<Arc<Mmap>>> {
+        let current = self.map.read().clone();
+        if let Some(map) = current {
+            if map.len() as u64 >= end {
+                return Ok(Some(map));
+            }
+        }
+
+        let mut current = self.map.write();
+        // Another thread may have mapped the file while the lock was released.
+        if let Some(map) = &*current {
+            if map.len() as u64 >= end {
+                return Ok(Some(map.clone()));
+            }
+        }
+        if self.file.metadata()?.len() < end {
+            return Ok(None);
+        }
+        // SAFETY: Nebari only appends to files and replaces them by renaming,
+        // so the mapped bytes are never modified or truncated while mapped.
+        #[allow(unsafe_code)]
+        let map = Arc::new(unsafe { Mmap::map(&self.file)? });
+        *current = Some(map.clone());
+        drop(current);
+        Ok(Some(map))
+    }
+}
+
+/// An open file whose reads are served from a memory map.
+#[derive(Debug)]
+pub struct MmapFile {
+    mapping: Arc<Mapping>,
+    id: PathId,
+    position: u64,
+    /// The file used for writing, if this file was opened for appending.
+    writer: Option<File>,
+}
+
+impl MmapFile {
+    fn eof() -> io::Error {
+        io::Error::from(io::ErrorKind::UnexpectedEof)
+    }
+}
+
+impl ManagedFile for MmapFile {
+    type Manager = MmapFileManager;
+}
+
+impl nebari::io::File for MmapFile {
+    fn id(&self) -> &PathId {
+        &self.id
+    }
+
+    fn length(&self) -> Result<u64, Error> {
+        let metadata = self.mapping.file.metadata()?;
+        Ok(metadata.len())
+    }
+
+    fn synchronize(&mut self) -> Result<(), Error> {
+        match &self.writer {
+            Some(writer) => writer.sync_data().map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+
+    fn close(mut self) -> Result<(), Error> {
+        self.synchronize()
+    }
+
+    fn read_bytes_at(
+        &mut self,
+        position: u64,
+        length: usize,
+    ) -> Option<Result<ArcBytes<'static>, Error>> {
+        let end = position + length as u64;
+        Some(match self.mapping.map_through(end) {
+            Ok(Some(map)) => {
+                let start = usize::try_from(position).unwrap_or(usize::MAX);
+                Ok(ArcBytes::from(map[start..start + length].to_vec()))
+            }
+            Ok(None) => Err(Error::from(Self::eof())),
+            Err(err) => Err(Error::from(err)),
+        })
+    }
+}
+
+impl Seek for MmapFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(relative) => {
+                let length = self.mapping.file.metadata()?.len();
+                offset_position(length, relative)
+            }
+            SeekFrom::Current(relative) => offset_position(self.position, relative),
+        };
+        self.position = new_position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, ErrorKind::InvalidSeek))?;
+        Ok(self.position)
+    }
+}
+
+const fn offset_position(position: u64, relative: i64) -> Option<u64> {
+    if relative.is_negative() {
+        position.checked_sub(relative.unsigned_abs())
+    } else {
+        position.checked_add(relative.unsigned_abs())
+    }
+}
+
+impl Write for MmapFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let writer = self.writer.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, ErrorKind::NotOpenedForAppend)
+        })?;
+        let bytes_written = writer.write(buf)?;
+        self.position = writer.stream_position()?;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Read for MmapFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.mapping.file.metadata()?.len();
+        let end = length.min(self.position + buf.len() as u64);
+        if end <= self.position {
+            return Ok(0);
+        }
+        let map = self.mapping.map_through(end)?.ok_or_else(Self::eof)?;
+        let start = usize::try_from(self.position).unwrap_or(usize::MAX);
+        let bytes_read = usize::try_from(end - self.position).unwrap_or(usize::MAX);
+        buf[..bytes_read].copy_from_slice(&map[start..start + bytes_read]);
+        self.position = end;
+        Ok(bytes_read)
+    }
+}
+
+/// A [`ManagedFileOpener`] implementation that produces [`MmapFile`]s.
+pub struct MmapFileOpener;
+
+impl ManagedFileOpener<MmapFile> for MmapFileOpener {
+    fn open_for_read(&self, path: impl IntoPathId + Send) -> Result<MmapFile, Error> {
+        let path = path.into_path_id();
+        Ok(MmapFile {
+            mapping: Arc::new(Mapping::open(path.path())?),
+            id: path,
+            position: 0,
+            writer: None,
+        })
+    }
+
+    fn open_for_append(&self, path: impl IntoPathId + Send) -> Result<MmapFile, Error> {
+        let path = path.into_path_id();
+        let writer = OpenOptions::new()
+            .append(true)
+            .read(true)
+            .create(true)
+            .open(path.path())?;
+        let position = writer.metadata()?.len();
+        Ok(MmapFile {
+            mapping: Arc::new(Mapping::open(path.path())?),
+            id: path,
+            position,
+            writer: Some(writer),
+        })
+    }
+}
+
+/// The [`FileManager`] for [`MmapFile`].
+///
+/// Every reader of a file shares a single memory map, which is mapped again
+/// when a read extends past its end. Writers are exclusive, and write to the
+/// end of the file.
+#[derive(Debug, Default, Clone)]
+pub struct MmapFileManager {
+    file_ids: PathIds,
+    mappings: Arc<Mutex<HashMap<u64, Arc<Mapping>>>>,
+    writer_files: Arc<Mutex<HashMap<u64, WriterSlot>>>,
+}
+
+/// Holds the single writable file for a path. The file is sent back into the
+/// channel when the handle using it is dropped.
+#[derive(Debug, Clone)]
+struct WriterSlot {
+    sender: flume::Sender<MmapFile>,
+    receiver: flume::Receiver<MmapFile>,
+}
+
+impl MmapFileManager {
+    fn mapping(&self, file_id: &PathId) -> Result<Arc<Mapping>, Error> {
+        let mut mappings = self.mappings.lock();
+        let mapping = if let Some(mapping) = mappings.get(&file_id.id().unwrap()) {
+            mapping.clone()
+        } else {
+            let mapping = Arc::new(Mapping::open(file_id.path())?);
+            mappings.insert(file_id.id().unwrap(), mapping.clone());
+            mapping
+        };
+        drop(mappings);
+        Ok(mapping)
+    }
+
+    fn forget_handles(&self, path_id: &PathId) {
+        let mut writer_files = self.writer_files.lock();
+        let mut mappings = self.mappings.lock();
+        writer_files.remove(&path_id.id().unwrap());
+        mappings.remove(&path_id.id().unwrap());
+        drop((writer_files, mappings));
+    }
+}
+
+impl FileManager for MmapFileManager {
+    type File = MmapFile;
+    type FileHandle = OpenMmapFile;
+
+    fn resolve_path(&self, path: impl AsRef<Path>, create_if_not_found: bool) -> Option<PathId> {
+        self.file_ids
+            .file_id_for_path(path.as_ref(), create_if_not_found)
+    }
+
+    fn read(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        let path = path.into_path_id();
+        let file_id = self.file_ids.file_id_for_path(path, true).unwrap();
+        Ok(OpenMmapFile {
+            file: Some(MmapFile {
+                mapping: self.mapping(&file_id)?,
+                id: file_id,
+                position: 0,
+                writer: None,
+            }),
+            writer_slot: None,
+        })
+    }
+
+    fn append(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        let path = path.into_path_id();
+        let file_id = self.file_ids.file_id_for_path(path, true).unwrap();
+        let slot = {
+            let mut writer_files = self.writer_files.lock();
+            if let Some(slot) = writer_files.get(&file_id.id().unwrap()) {
+                slot.clone()
+            } else {
+                let writer = OpenOptions::new()
+                    .append(true)
+                    .read(true)
+                    .create(true)
+                    .open(file_id.path())?;
+                let position = writer.metadata()?.len();
+                let file = MmapFile {
+                    mapping: self.mapping(&file_id)?,
+                    id: file_id.clone(),
+                    position,
+                    writer: Some(writer),
+                };
+                let (sender, receiver) = flume::bounded(1);
+                sender.send(file).unwrap();
+                let slot = WriterSlot { sender, receiver };
+                writer_files.insert(file_id.id().unwrap(), slot.clone());
+                slot
+            }
+        };
+
+        // Because the slot holds both sides of the channel, this will only
+        // return once the current writer's handle has been dropped.
+        let file = slot.receiver.recv().map_err(|_| {
+            Error::from(ErrorKind::Internal(
+                nebari::InternalError::InternalCommunication,
+            ))
+        })?;
+        Ok(OpenMmapFile {
+            file: Some(file),
+            writer_slot: Some(slot.sender),
+        })
+    }
+
+    fn file_length(&self, path: impl IntoPathId) -> Result<u64, Error> {
+        path.path()
+            .metadata()
+            .map_err(Error::from)
+            .map(|metadata| metadata.len())
+    }
+
+    fn exists(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        if let Some(path_id) = self.resolve_path(path.path(), false) {
+            if self
+                .mappings
+                .lock()
+                .contains_key(&path_id.id().unwrap())
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(path.path().exists())
+    }
+
+    fn close_handles<F: FnOnce(PathId)>(&self, path: impl IntoPathId, publish_callback: F) {
+        if let Some(result) = self.file_ids.recreate_file_id_for_path(path) {
+            self.forget_handles(&result.previous_id);
+            publish_callback(result.new_id);
+        }
+    }
+
+    fn delete(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        let in_path = path.into_path_id();
+        if let Some(file_id) = self.file_ids.remove_file_id_for_path(&in_path) {
+            self.forget_handles(&file_id);
+        }
+
+        if in_path.path().exists() {
+            std::fs::remove_file(in_path.path())?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn delete_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        for path_id in self.file_ids.remove_file_ids_for_path_prefix(path) {
+            self.forget_handles(&path_id);
+        }
+
+        if path.exists() {
+            std::fs::remove_dir_all(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        std::fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        Ok(paths)
+    }
+
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), Error> {
+        // The file's current map may extend past its new end, so it is
+        // forgotten before the file is truncated.
+        if let Some(path_id) = self.resolve_path(path.path(), false) {
+            self.mappings.lock().remove(&path_id.id().unwrap());
+        }
+        let file = OpenOptions::new().write(true).open(path.path())?;
+        if file.metadata()?.len() > length {
+            file.set_len(length)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl ManagedFileOpener<MmapFile> for MmapFileManager {
+    fn open_for_read(&self, path: impl IntoPathId + Send) -> Result<MmapFile, Error> {
+        MmapFileOpener.open_for_read(path)
+    }
+
+    fn open_for_append(&self, path: impl IntoPathId + Send) -> Result<MmapFile, Error> {
+        MmapFileOpener.open_for_append(path)
+    }
+}
+
+/// An open [`MmapFile`] that belongs to an [`MmapFileManager`].
+#[derive(Debug)]
+pub struct OpenMmapFile {
+    file: Option<MmapFile>,
+    writer_slot: Option<flume::Sender<MmapFile>>,
+}
+
+impl OpenableFile<MmapFile> for OpenMmapFile {
+    fn id(&self) -> &PathId {
+        self.file.as_ref().unwrap().id()
+    }
+
+    fn replace_with<C: FnOnce(PathId)>(
+        self,
+        replacement: MmapFile,
+        manager: &MmapFileManager,
+        publish_callback: C,
+    ) -> Result<Self, Error> {
+        let current_path = PathBuf::from(self.id().path());
+        self.close()?;
+        let path = replacement.id.clone();
+        replacement.close()?;
+
+        std::fs::rename(path.path(), &current_path)?;
+        manager.close_handles(&current_path, publish_callback);
+        manager.append(current_path)
+    }
+
+    fn close(self) -> Result<(), Error> {
+        drop(self);
+        Ok(())
+    }
+}
+
+impl OperableFile<MmapFile> for OpenMmapFile {
+    fn execute<Output, Op: FileOp<Output>>(&mut self, operator: Op) -> Output {
+        operator.execute(self.file.as_mut().unwrap())
+    }
+}
+
+impl Drop for OpenMmapFile {
+    fn drop(&mut self) {
+        if let Some(writer_slot) = self.writer_slot.take() {
+            // If the slot is full or the manager has forgotten this file, the
+            // file is simply closed.
+            drop(writer_slot.try_send(self.file.take().unwrap()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nebari::{
+        tree::{Root, Unversioned, Versioned},
+        ArcBytes, Config,
+    };
+
+    use super::*;
+
+    fn reads_grow_with_file<R: Root<Value = ArcBytes<'static>>>()
+    where
+        R::Reducer: Default,
+    {
+        let directory = tempfile::tempdir().unwrap();
+        let roots = Config::new(directory.path())
+            .file_manager(MmapFileManager::default())
+            .open()
+            .unwrap();
+        let tree = roots.tree(R::tree("tree")).unwrap();
+
+        // Each write extends the file past the current mapping, which must be
+        // mapped again for the following reads.
+        for id in 0_u32..500 {
+            tree.set(id.to_be_bytes(), id.to_le_bytes()).unwrap();
+            assert_eq!(tree.get(&id.to_be_bytes()).unwrap().unwrap(), id.to_le_bytes());
+        }
+        assert_eq!(tree.get_range(&(..)).unwrap().len(), 500);
+
+        // Compaction replaces the file, which must be mapped again.
+        tree.compact().unwrap();
+        for id in 0_u32..500 {
+            assert_eq!(tree.get(&id.to_be_bytes()).unwrap().unwrap(), id.to_le_bytes());
+        }
+        tree.set(b"after", b"compaction").unwrap();
+        drop((tree, roots));
+
+        let roots = Config::new(directory.path())
+            .file_manager(MmapFileManager::default())
+            .open()
+            .unwrap();
+        let tree = roots.tree(R::tree("tree")).unwrap();
+        assert_eq!(tree.count(), 501);
+        assert_eq!(tree.get(b"after").unwrap().unwrap(), b"compaction");
+    }
+
+    #[test]
+    fn versioned_reads_grow_with_file() {
+        reads_grow_with_file::<Versioned>();
+    }
+
+    #[test]
+    fn unversioned_reads_grow_with_file() {
+        reads_grow_with_file::<Unversioned>();
+    }
+
+    #[test]
+    fn chunk_reads() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+        let manager = MmapFileManager::default();
+        let mut writer = manager.append(&path).unwrap();
+        let contents = (0..=u8::MAX).cycle().take(10_000).collect::<Vec<_>>();
+        let mut reader = manager.read(&path).unwrap();
+        writer.execute(WriteAll(&contents[..5_000]));
+        assert_eq!(
+            reader.execute(ReadBytesAt(4_000, 1_000)).unwrap(),
+            &contents[4_000..5_000]
+        );
+        // Reading past the end of the file fails without mapping it.
+        assert!(reader.execute(ReadBytesAt(4_000, 1_001)).is_err());
+
+        writer.execute(WriteAll(&contents[5_000..]));
+        assert_eq!(
+            reader.execute(ReadBytesAt(4_000, 6_000)).unwrap(),
+            &contents[4_000..]
+        );
+    }
+
+    struct WriteAll<'a>(&'a [u8]);
+
+    impl FileOp<()> for WriteAll<'_> {
+        fn execute(self, file: &mut dyn nebari::io::File) {
+            file.write_all(self.0).unwrap();
+        }
+    }
+
+    struct ReadBytesAt(u64, usize);
+
+    impl FileOp<Result<ArcBytes<'static>, Error>> for ReadBytesAt {
+        fn execute(self, file: &mut dyn nebari::io::File) -> Result<ArcBytes<'static>, Error> {
+            file.read_bytes_at(self.0, self.1).unwrap()
+        }
+    }
+}
@@ -3,6 +3,7 @@ use std::convert::Infallible;
 use byteorder::BigEndian;
 use nanorand::{Pcg64, Rng};
 use nebari::{
+    keys::EncodeKey,
     tree::{
         btree::{Indexer, Reducer},
         EmbeddedIndex, Root, ScanEvaluation, Serializable, VersionedTreeRoot,
@@ -20,7 +21,7 @@ fn main() -> Result<(), Error> {
     let mut rng = Pcg64::new();
     // This example counts the number of '0' characters and stores it in the index.
     for i in 0_u32..100 {
-        tree.set(i.to_be_bytes(), rng.generate::<u64>().to_string())?;
+        tree.set(i.encode_key(), rng.generate::<u64>().to_string())?;
     }
 
     // When scanning the tree, we can retrieve our embedded statistics during
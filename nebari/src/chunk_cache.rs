@@ -1,9 +1,20 @@
-use std::{any::Any, sync::Arc};
+use std::{any::Any, mem::size_of, sync::Arc};
 
 use lru::LruCache;
 use parking_lot::Mutex;
 
-use crate::ArcBytes;
+use crate::{ArcBytes, Metrics};
+
+/// The maximum number of shards a cache is divided into.
+const MAXIMUM_SHARDS: usize = 16;
+
+/// The percentage of each shard's capacity that can be used by chunks that
+/// have been read since being cached.
+const PROTECTED_PERCENT: usize = 80;
+
+/// The approximate number of bytes used to track each cached entry, in
+/// addition to the entry's data.
+const ENTRY_OVERHEAD: usize = size_of::<ChunkKey>() + size_of::<CachedChunk>() + 32;
 
 /// A configurable cache that operates at the "chunk" level.
 ///
@@ -11,6 +22,28 @@ use crate::ArcBytes;
 /// single chunk. These chunks may be stored encrypted on-disk, but the
 /// in-memory cache will be after decryption.
 ///
+/// The cache limits the total number of bytes it holds rather than the number
+/// of chunks. The bytes of a cached buffer are counted using its length. A
+/// chunk that has been decoded is counted using the length of the bytes it was
+/// decoded from.
+///
+/// To reduce contention, the cache is divided into shards that are each
+/// locked independently. Each chunk is assigned to a shard based on its file
+/// and position.
+///
+/// Each shard is a segmented LRU cache. Newly cached chunks are placed in a
+/// probationary segment, and are only moved into the protected segment once
+/// they are read again. When a shard exceeds its portion of the capacity,
+/// chunks are evicted from the probationary segment first. This prevents a
+/// single large scan from evicting frequently read chunks, such as the
+/// interior nodes of a tree. For scans that should not affect the cache at
+/// all, use [`ChunkCache::without_admission()`].
+///
+/// Interior B-Tree nodes can also be pinned using
+/// [`ChunkCache::pin_interior_nodes()`]. Pinned nodes are stored separately
+/// from the rest of the cache with their own capacity, and are never evicted
+/// to make room for other chunks.
+///
 /// To keep memory usage low, the maximum size for a cached value can be set. It
 /// is important that this value be large enough to fit most B-Tree nodes, and
 /// that size will depend on how big the tree grows.
@@ -18,7 +51,9 @@ use crate::ArcBytes;
 #[must_use]
 pub struct ChunkCache {
     max_block_length: usize,
-    cache: Arc<Mutex<LruCache<ChunkKey, CacheEntry>>>,
+    admit: bool,
+    shards: Arc<[Mutex<Shard>]>,
+    metrics: Option<Arc<dyn Metrics>>,
 }
 
 pub trait AnySendSync: Any + Send + Sync {
@@ -45,16 +80,224 @@ pub struct ChunkKey {
     file_id: u64,
 }
 
+impl ChunkKey {
+    /// Returns the index of the shard this key belongs to.
+    #[allow(clippy::cast_possible_truncation)]
+    const fn shard(&self, shard_count: usize) -> usize {
+        // Fibonacci hashing spreads sequential positions across shards.
+        let hash = (self.file_id ^ self.position.rotate_left(32))
+            .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            >> 32;
+        hash as usize % shard_count
+    }
+}
+
+#[derive(Debug)]
+struct CachedChunk {
+    entry: CacheEntry,
+    /// The number of bytes this chunk counts against the cache's capacity.
+    size: usize,
+}
+
+#[derive(Debug)]
+struct Shard {
+    /// Chunks that have not been read since being cached.
+    probation: LruCache<ChunkKey, CachedChunk>,
+    /// Chunks that have been read at least once since being cached.
+    protected: LruCache<ChunkKey, CachedChunk>,
+    /// Decoded interior nodes, which are not counted against `capacity`.
+    pinned: LruCache<ChunkKey, CachedChunk>,
+    capacity: usize,
+    protected_capacity: usize,
+    pinned_capacity: usize,
+    size: usize,
+    protected_size: usize,
+    pinned_size: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Self {
+        Self {
+            probation: LruCache::unbounded(),
+            protected: LruCache::unbounded(),
+            pinned: LruCache::unbounded(),
+            capacity,
+            protected_capacity: capacity / 100 * PROTECTED_PERCENT,
+            pinned_capacity: 0,
+            size: 0,
+            protected_size: 0,
+            pinned_size: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.probation.len() + self.protected.len()
+    }
+
+    fn peek(&self, key: &ChunkKey) -> Option<&CachedChunk> {
+        self.pinned
+            .peek(key)
+            .or_else(|| self.protected.peek(key))
+            .or_else(|| self.probation.peek(key))
+    }
+
+    fn peek_mut(&mut self, key: &ChunkKey) -> Option<&mut CachedChunk> {
+        if self.pinned.contains(key) {
+            self.pinned.peek_mut(key)
+        } else if self.protected.contains(key) {
+            self.protected.peek_mut(key)
+        } else {
+            self.probation.peek_mut(key)
+        }
+    }
+
+    /// Looks up `key`. If `promote` is true, the chunk is marked as recently
+    /// used, and a chunk in the probationary segment is moved to the protected
+    /// segment.
+    fn get(&mut self, key: &ChunkKey, promote: bool) -> Option<CacheEntry> {
+        let entry = if !promote {
+            self.peek(key).map(|cached| cached.entry.clone())
+        } else if let Some(cached) = self.pinned.get(key) {
+            Some(cached.entry.clone())
+        } else if let Some(cached) = self.protected.get(key) {
+            Some(cached.entry.clone())
+        } else if let Some((key, cached)) = self.probation.pop_entry(key) {
+            let entry = cached.entry.clone();
+            self.protected_size += cached.size;
+            self.protected.put(key, cached);
+            self.demote_protected();
+            Some(entry)
+        } else {
+            None
+        };
+
+        if entry.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        entry
+    }
+
+    fn put(&mut self, key: ChunkKey, entry: CacheEntry, size: usize) {
+        let size = size + ENTRY_OVERHEAD;
+        let chunk = CachedChunk { entry, size };
+        if self.protected.contains(&key) {
+            // A replaced chunk stays in the segment it was already in.
+            if let Some(replaced) = self.protected.put(key, chunk) {
+                self.protected_size -= replaced.size;
+                self.size -= replaced.size;
+            }
+            self.protected_size += size;
+            self.demote_protected();
+        } else if let Some(replaced) = self.probation.put(key, chunk) {
+            self.size -= replaced.size;
+        }
+        self.size += size;
+        self.evict();
+    }
+
+    /// Removes the chunk stored for `key` from the probationary and protected
+    /// segments, returning the number of bytes it was counted as.
+    fn remove(&mut self, key: &ChunkKey) -> Option<usize> {
+        let removed = if let Some(removed) = self.protected.pop(key) {
+            self.protected_size -= removed.size;
+            removed
+        } else {
+            self.probation.pop(key)?
+        };
+        self.size -= removed.size;
+        Some(removed.size)
+    }
+
+    /// Pins `entry`. If the pinned chunks exceed their capacity, the least
+    /// recently used pinned chunks are discarded.
+    fn pin(&mut self, key: ChunkKey, entry: CacheEntry, size: usize) {
+        let size = size + ENTRY_OVERHEAD;
+        if let Some(replaced) = self.pinned.put(key, CachedChunk { entry, size }) {
+            self.pinned_size -= replaced.size;
+        }
+        self.pinned_size += size;
+        while self.pinned_size > self.pinned_capacity {
+            if let Some((_, evicted)) = self.pinned.pop_lru() {
+                self.pinned_size -= evicted.size;
+                self.evictions += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Moves the least recently used protected chunks into the probationary
+    /// segment until the protected segment is within its capacity.
+    fn demote_protected(&mut self) {
+        while self.protected_size > self.protected_capacity {
+            if let Some((key, demoted)) = self.protected.pop_lru() {
+                self.protected_size -= demoted.size;
+                self.probation.put(key, demoted);
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Evicts chunks until this shard is within its capacity, starting with
+    /// the probationary segment.
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let evicted = if let Some((_, evicted)) = self.probation.pop_lru() {
+                evicted
+            } else if let Some((_, evicted)) = self.protected.pop_lru() {
+                self.protected_size -= evicted.size;
+                evicted
+            } else {
+                break;
+            };
+            self.size -= evicted.size;
+            self.evictions += 1;
+        }
+    }
+}
+
 impl ChunkCache {
-    /// Create a new cache with a maximum number of entries (`capacity`) and
+    /// Create a new cache that has room for `capacity` chunks of
     /// `max_chunk_length`. Any chunks longer than `max_chunk_length` will not
     /// be cached. The maximum memory usage of this cache can be calculated as
-    /// `capacity * max_chunk_length`, although the actual memory usage will
-    /// likely be much smaller as many chunks are small.
+    /// `capacity * max_chunk_length`, plus a small amount of overhead per
+    /// chunk. Because the cache is limited by the size of its chunks, it holds
+    /// more than `capacity` chunks when chunks are shorter than
+    /// `max_chunk_length`.
+    ///
+    /// To limit the cache to a number of bytes, use
+    /// [`ChunkCache::with_byte_capacity()`].
     pub fn new(capacity: usize, max_chunk_length: usize) -> Self {
+        let max_entry_size = max_chunk_length + ENTRY_OVERHEAD;
+        Self::with_byte_capacity(capacity.saturating_mul(max_entry_size), max_chunk_length)
+    }
+
+    /// Create a new cache that holds up to `capacity` bytes of chunks. Any
+    /// chunks longer than `max_chunk_length` will not be cached.
+    ///
+    /// Each shard of the cache is able to hold at least one chunk of
+    /// `max_chunk_length`. If `capacity` is too small for this, the cache
+    /// holds more than `capacity` bytes.
+    pub fn with_byte_capacity(capacity: usize, max_chunk_length: usize) -> Self {
+        let max_entry_size = max_chunk_length + ENTRY_OVERHEAD;
+        let shard_count = (capacity / max_entry_size).clamp(1, MAXIMUM_SHARDS);
+        let shard_capacity = (capacity / shard_count).max(max_entry_size);
         Self {
             max_block_length: max_chunk_length,
-            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            admit: true,
+            shards: (0..shard_count)
+                .map(|_| Mutex::new(Shard::new(shard_capacity)))
+                .collect(),
+            metrics: None,
         }
     }
 
@@ -64,36 +307,204 @@ impl ChunkCache {
         self.max_block_length
     }
 
+    /// Returns a handle to this cache that never adds chunks to the cache.
+    /// Chunks already in the cache can still be read, but reading them through
+    /// the returned handle does not mark them as recently used.
+    ///
+    /// This is useful for reads that are not expected to be repeated, such as
+    /// scanning a large range of keys, which would otherwise evict chunks that
+    /// are read frequently.
+    pub fn without_admission(&self) -> Self {
+        Self {
+            max_block_length: self.max_block_length,
+            admit: false,
+            shards: self.shards.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Returns a handle to this cache that reports lookups to `metrics`.
+    pub(crate) fn with_metrics_handle(self, metrics: Option<Arc<dyn Metrics>>) -> Self {
+        Self { metrics, ..self }
+    }
+
+    /// Returns true if this handle adds chunks to the cache. Returns false if
+    /// this handle was created with [`Self::without_admission()`].
+    #[must_use]
+    pub const fn admits_chunks(&self) -> bool {
+        self.admit
+    }
+
+    /// Sets the number of bytes that can be used to store pinned interior
+    /// B-Tree nodes. These bytes are not counted against the cache's capacity.
+    ///
+    /// Each interior node read while this cache is in use will be kept decoded
+    /// in memory until the pinned nodes exceed `capacity`, after which the
+    /// least recently used pinned nodes are discarded. When `capacity` is large
+    /// enough to hold every interior node of the trees in use, looking up a key
+    /// only requires reading its leaf node.
+    pub fn pin_interior_nodes(self, capacity: usize) -> Self {
+        let shard_capacity = capacity / self.shards.len();
+        for shard in self.shards.iter() {
+            shard.lock().pinned_capacity = shard_capacity;
+        }
+        self
+    }
+
+    fn shard(&self, key: &ChunkKey) -> &Mutex<Shard> {
+        &self.shards[key.shard(self.shards.len())]
+    }
+
     /// Adds a new cached chunk for `file_path` at `position`.
     pub fn insert(&self, file_id: u64, position: u64, buffer: ArcBytes<'static>) {
-        if buffer.len() <= self.max_block_length {
-            let mut cache = self.cache.lock();
-            cache.put(ChunkKey { position, file_id }, CacheEntry::ArcBytes(buffer));
+        if self.admit && buffer.len() <= self.max_block_length {
+            let key = ChunkKey { position, file_id };
+            let size = buffer.len();
+            self.shard(&key)
+                .lock()
+                .put(key, CacheEntry::ArcBytes(buffer), size);
         }
     }
 
     /// Adds a new cached chunk for `file_path` at `position`.
+    ///
+    /// The decoded value's size is estimated to be the size of the chunk
+    /// currently cached at `position`. If this handle does not admit new
+    /// chunks, the value is only stored if a chunk is already cached at
+    /// `position`.
     pub fn replace_with_decoded<T: AnySendSync + 'static>(
         &self,
         file_id: u64,
         position: u64,
         value: T,
     ) {
-        let mut cache = self.cache.lock();
-        cache.put(
-            ChunkKey { position, file_id },
-            CacheEntry::Decoded(Arc::new(value)),
-        );
+        let key = ChunkKey { position, file_id };
+        let mut shard = self.shard(&key).lock();
+        if !self.admit {
+            if let Some(cached) = shard.peek_mut(&key) {
+                cached.entry = CacheEntry::Decoded(Arc::new(value));
+            }
+            return;
+        }
+        let size = shard
+            .peek(&key)
+            .map_or_else(|| size_of::<T>(), |cached| cached.size - ENTRY_OVERHEAD);
+        shard.put(key, CacheEntry::Decoded(Arc::new(value)), size);
+    }
+
+    /// Pins a decoded interior node for `file_path` at `position`. If this
+    /// cache has no capacity for pinned nodes, or this handle does not admit
+    /// new chunks, this behaves like [`Self::replace_with_decoded()`].
+    pub(crate) fn pin_decoded<T: AnySendSync + 'static>(
+        &self,
+        file_id: u64,
+        position: u64,
+        value: T,
+    ) {
+        let key = ChunkKey { position, file_id };
+        let mut shard = self.shard(&key).lock();
+        if !self.admit || shard.pinned_capacity == 0 {
+            drop(shard);
+            self.replace_with_decoded(file_id, position, value);
+            return;
+        }
+        let size = shard
+            .remove(&key)
+            .or_else(|| shard.pinned.peek(&key).map(|cached| cached.size))
+            .map_or_else(|| size_of::<T>(), |size| size - ENTRY_OVERHEAD);
+        shard.pin(key, CacheEntry::Decoded(Arc::new(value)), size);
+    }
+
+    /// Removes the node pinned for `file_path` at `position`, if one is
+    /// pinned.
+    pub(crate) fn unpin(&self, file_id: u64, position: u64) {
+        let key = ChunkKey { position, file_id };
+        let mut shard = self.shard(&key).lock();
+        if let Some(removed) = shard.pinned.pop(&key) {
+            shard.pinned_size -= removed.size;
+        }
     }
 
     /// Looks up a previously read chunk for `file_path` at `position`,
     #[must_use]
     pub fn get(&self, file_id: u64, position: u64) -> Option<CacheEntry> {
-        let mut cache = self.cache.lock();
-        cache.get(&ChunkKey { position, file_id }).cloned()
+        let key = ChunkKey { position, file_id };
+        let entry = self.shard(&key).lock().get(&key, self.admit);
+        if let Some(metrics) = &self.metrics {
+            metrics.cache_lookup(entry.is_some());
+        }
+        entry
+    }
+
+    /// Returns the file id and position of each chunk currently cached.
+    ///
+    /// Pinned nodes are listed first, followed by chunks that have been read
+    /// since being cached, followed by all other chunks. Within each group,
+    /// the most recently used chunks are listed first. This list can be used
+    /// to warm a new cache, such as by using
+    /// [`Roots::warm_cache()`](crate::Roots::warm_cache).
+    #[must_use]
+    pub fn cached_chunks(&self) -> Vec<(u64, u64)> {
+        fn keys(
+            entries: &LruCache<ChunkKey, CachedChunk>,
+        ) -> impl Iterator<Item = (u64, u64)> + '_ {
+            entries.iter().map(|(key, _)| (key.file_id, key.position))
+        }
+
+        let mut pinned = Vec::new();
+        let mut protected = Vec::new();
+        let mut probation = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock();
+            pinned.extend(keys(&shard.pinned));
+            protected.extend(keys(&shard.protected));
+            probation.extend(keys(&shard.probation));
+        }
+        pinned.append(&mut protected);
+        pinned.append(&mut probation);
+        pinned
+    }
+
+    /// Returns the current statistics of this cache.
+    #[must_use]
+    pub fn stats(&self) -> ChunkCacheStats {
+        let mut stats = ChunkCacheStats::default();
+        for shard in self.shards.iter() {
+            let shard = shard.lock();
+            stats.hits += shard.hits;
+            stats.misses += shard.misses;
+            stats.evictions += shard.evictions;
+            stats.entries += shard.len();
+            stats.size += shard.size;
+            stats.pinned_entries += shard.pinned.len();
+            stats.pinned_size += shard.pinned_size;
+        }
+        stats
     }
 }
 
+/// Statistics about a [`ChunkCache`].
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ChunkCacheStats {
+    /// The number of lookups that found a cached chunk.
+    pub hits: u64,
+    /// The number of lookups that did not find a cached chunk.
+    pub misses: u64,
+    /// The number of chunks removed to stay within the cache's capacity,
+    /// including pinned nodes discarded to stay within the pinned capacity.
+    pub evictions: u64,
+    /// The number of chunks currently cached.
+    pub entries: usize,
+    /// The approximate number of bytes currently counted against the cache's
+    /// capacity.
+    pub size: usize,
+    /// The number of interior nodes currently pinned.
+    pub pinned_entries: usize,
+    /// The approximate number of bytes currently used by pinned interior
+    /// nodes.
+    pub pinned_size: usize,
+}
+
 /// A cached chunk of data that has possibly been decoded already.
 #[derive(Clone)]
 pub enum CacheEntry {
@@ -103,3 +514,138 @@ pub enum CacheEntry {
     /// [`ChunkCache::replace_with_decoded()`].
     Decoded(Arc<dyn AnySendSync>),
 }
+
+impl std::fmt::Debug for CacheEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ArcBytes(bytes) => f.debug_tuple("ArcBytes").field(bytes).finish(),
+            Self::Decoded(_) => f.write_str("Decoded(..)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_capacity() {
+        let cache = ChunkCache::with_byte_capacity(4096, 1024);
+        cache.insert(1, 0, ArcBytes::from(vec![0; 1025]));
+        assert!(cache.get(1, 0).is_none());
+
+        for position in 0..64 {
+            cache.insert(1, position, ArcBytes::from(vec![0; 512]));
+        }
+        let stats = cache.stats();
+        assert!(stats.size <= 4096);
+        assert_eq!(stats.evictions, 64 - stats.entries as u64);
+        assert!(stats.entries > 0);
+
+        let hits = (0..64).filter(|&position| cache.get(1, position).is_some());
+        assert_eq!(hits.count(), stats.entries);
+        let stats = cache.stats();
+        assert_eq!(stats.hits, stats.entries as u64);
+        assert_eq!(stats.misses, 65 - stats.hits);
+    }
+
+    #[test]
+    fn chunk_capacity() {
+        let cache = ChunkCache::new(4, 1024);
+        for position in 0..64 {
+            cache.insert(1, position, ArcBytes::from(vec![0; 1024]));
+        }
+        let stats = cache.stats();
+        assert!(stats.entries > 0);
+        assert!(stats.entries <= 4);
+        assert!(stats.size <= 4 * (1024 + ENTRY_OVERHEAD));
+
+        // Shorter chunks take less of the cache's capacity.
+        let cache = ChunkCache::new(4, 1024);
+        for position in 0..64 {
+            cache.insert(1, position, ArcBytes::from(vec![0; 16]));
+        }
+        let stats = cache.stats();
+        assert!(stats.entries > 4);
+        assert!(stats.size <= 4 * (1024 + ENTRY_OVERHEAD));
+    }
+
+    #[test]
+    fn maximum_length_chunks() {
+        for capacity in [1024, 4096, 16 * 1024, 1 << 20] {
+            let cache = ChunkCache::with_byte_capacity(capacity, 1024);
+            for position in 0..64 {
+                cache.insert(1, position, ArcBytes::from(vec![0; 1024]));
+                assert!(
+                    cache.get(1, position).is_some(),
+                    "chunk evicted when inserted into a cache of {} bytes",
+                    capacity
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decoded_size_estimate() {
+        let cache = ChunkCache::with_byte_capacity(1 << 20, 1024);
+        cache.insert(1, 0, ArcBytes::from(vec![0; 1000]));
+        let before = cache.stats().size;
+        cache.replace_with_decoded(1, 0, 42_u64);
+        assert_eq!(cache.stats().size, before);
+        assert!(matches!(cache.get(1, 0), Some(CacheEntry::Decoded(_))));
+    }
+
+    #[test]
+    fn scan_resistance() {
+        let cache = ChunkCache::with_byte_capacity(64 * 1024, 64 * 1024);
+        for position in 0..8 {
+            cache.insert(1, position, ArcBytes::from(vec![0; 1024]));
+            assert!(cache.get(1, position).is_some());
+        }
+
+        // A scan of chunks that are only read once should not evict the
+        // chunks that have been read again.
+        for position in 0..1024 {
+            cache.insert(2, position, ArcBytes::from(vec![0; 1024]));
+        }
+        assert!(cache.stats().evictions > 0);
+        for position in 0..8 {
+            assert!(cache.get(1, position).is_some());
+        }
+    }
+
+    #[test]
+    fn pinned_nodes() {
+        let cache = ChunkCache::with_byte_capacity(4096, 1024).pin_interior_nodes(4096);
+        cache.insert(1, 0, ArcBytes::from(vec![0; 512]));
+        cache.pin_decoded(1, 0, 42_u64);
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 0);
+        assert_eq!(stats.pinned_entries, 1);
+
+        // Pinned nodes are not evicted to make room for other chunks.
+        for position in 1..64 {
+            cache.insert(1, position, ArcBytes::from(vec![0; 512]));
+        }
+        assert!(matches!(cache.get(1, 0), Some(CacheEntry::Decoded(_))));
+
+        cache.unpin(1, 0);
+        assert!(cache.get(1, 0).is_none());
+        assert_eq!(cache.stats().pinned_size, 0);
+    }
+
+    #[test]
+    fn without_admission() {
+        let cache = ChunkCache::with_byte_capacity(1 << 20, 1024);
+        cache.insert(1, 0, ArcBytes::from(vec![0; 16]));
+
+        let uncached = cache.without_admission();
+        assert!(!uncached.admits_chunks());
+        uncached.insert(1, 1, ArcBytes::from(vec![0; 16]));
+        uncached.replace_with_decoded(1, 2, 42_u64);
+        assert!(uncached.get(1, 0).is_some());
+        assert!(uncached.get(1, 1).is_none());
+        assert!(cache.get(1, 2).is_none());
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use crate::{io::FileManager, vault::AnyVault, ChunkCache, Vault};
+use crate::{io::FileManager, vault::AnyVault, ChunkCache, Metrics, Vault};
 
 /// A shared environment for database operations.
 #[derive(Default, Debug, Clone)]
@@ -12,6 +12,11 @@ pub struct Context<M: FileManager> {
     pub(crate) vault: Option<Arc<dyn AnyVault>>,
     /// The optional chunk cache to use.
     pub(crate) cache: Option<ChunkCache>,
+    /// The optional metrics receiver.
+    pub(crate) metrics: Option<Arc<dyn Metrics>>,
+    /// Whether data written without a vault can be read when a vault is in
+    /// use.
+    pub(crate) allow_unencrypted_data: bool,
 }
 
 impl<M: FileManager> Context<M> {
@@ -25,9 +30,30 @@ impl<M: FileManager> Context<M> {
         self.cache.as_ref()
     }
 
+    /// Returns the context's metrics receiver.
+    pub fn metrics(&self) -> Option<&dyn Metrics> {
+        self.metrics.as_deref()
+    }
+
     /// Replaces the cache currently set with `cache`.
     pub fn with_cache(mut self, cache: ChunkCache) -> Self {
-        self.cache = Some(cache);
+        self.cache = Some(cache.with_metrics_handle(self.metrics.clone()));
+        self
+    }
+
+    /// Replaces the metrics receiver currently set with `metrics`. Lookups in
+    /// the context's cache are also reported to `metrics`.
+    pub fn with_metrics<T: Metrics>(self, metrics: T) -> Self {
+        self.with_any_metrics(Arc::new(metrics))
+    }
+
+    /// Replaces the metrics receiver currently set with `metrics`. Lookups in
+    /// the context's cache are also reported to `metrics`.
+    pub fn with_any_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.cache = self
+            .cache
+            .map(|cache| cache.with_metrics_handle(Some(metrics.clone())));
+        self.metrics = Some(metrics);
         self
     }
 
@@ -41,4 +67,13 @@ impl<M: FileManager> Context<M> {
         self.vault = Some(vault);
         self
     }
+
+    /// Allows trees containing data that was written without a vault to be
+    /// opened while a vault is in use. Data written to these trees is
+    /// encrypted by the vault, and the unencrypted data is encrypted when the
+    /// tree is compacted.
+    pub const fn allow_unencrypted_data(mut self) -> Self {
+        self.allow_unencrypted_data = true;
+        self
+    }
 }
@@ -2,39 +2,138 @@ use std::{
     array::TryFromSliceError,
     convert::Infallible,
     fmt::{Debug, Display},
+    path::PathBuf,
 };
 
 use backtrace::Backtrace;
 use parking_lot::{Mutex, MutexGuard};
 use thiserror::Error;
 
-use crate::AbortError;
+use crate::{io::PathId, transaction::TransactionId, AbortError};
 
 /// An error from Nebari as well as an associated backtrace.
+///
+/// Errors may also describe which tree, file, chunk position, and transaction
+/// the error occurred with. This information is only present when it is known
+/// where the error was encountered.
 pub struct Error {
     /// The error that occurred.
     pub kind: ErrorKind,
 
-    backtrace: Mutex<Backtrace>,
+    context: Option<Box<ErrorContext>>,
+    backtrace: Option<Mutex<Backtrace>>,
+}
+
+#[derive(Default, Debug)]
+struct ErrorContext {
+    tree: Option<String>,
+    path: Option<PathId>,
+    position: Option<u64>,
+    transaction_id: Option<TransactionId>,
 }
 
 impl Error {
-    pub(crate) fn data_integrity(error: impl Into<Self>) -> Self {
+    fn new(kind: ErrorKind) -> Self {
+        let backtrace = if kind.is_expected() {
+            None
+        } else {
+            Some(Mutex::new(Backtrace::new_unresolved()))
+        };
         Self {
-            kind: ErrorKind::DataIntegrity(Box::new(error.into())),
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
+            kind,
+            context: None,
+            backtrace,
         }
     }
 
-    /// Returns the backtrace of where this error was created.
-    pub fn backtrace(&self) -> MutexGuard<'_, Backtrace> {
-        let mut backtrace = self.backtrace.lock();
-        backtrace.resolve();
-        backtrace
+    pub(crate) fn data_integrity(error: impl Into<Self>) -> Self {
+        Self::new(ErrorKind::DataIntegrity(Box::new(error.into())))
+    }
+
+    /// Returns the backtrace of where this error was created. Errors that are
+    /// [expected](ErrorKind::is_expected) do not capture a backtrace.
+    pub fn backtrace(&self) -> Option<MutexGuard<'_, Backtrace>> {
+        self.backtrace.as_ref().map(|backtrace| {
+            let mut backtrace = backtrace.lock();
+            backtrace.resolve();
+            backtrace
+        })
+    }
+
+    /// Returns the name of the tree the error occurred with, if known.
+    #[must_use]
+    pub fn tree(&self) -> Option<&str> {
+        self.context.as_ref()?.tree.as_deref()
+    }
+
+    /// Returns the file the error occurred with, if known.
+    #[must_use]
+    pub fn path(&self) -> Option<&PathId> {
+        self.context.as_ref()?.path.as_ref()
+    }
+
+    /// Returns the position of the chunk within [`Self::path()`] the error
+    /// occurred with, if known.
+    #[must_use]
+    pub fn position(&self) -> Option<u64> {
+        self.context.as_ref()?.position
+    }
+
+    /// Returns the id of the transaction the error occurred with, if known.
+    #[must_use]
+    pub fn transaction_id(&self) -> Option<TransactionId> {
+        self.context.as_ref()?.transaction_id
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext {
+        self.context.get_or_insert_with(Box::default)
+    }
+
+    /// Notes that this error occurred with the tree named `name`, unless a tree
+    /// has already been noted.
+    pub(crate) fn with_tree(mut self, name: &str) -> Self {
+        let context = self.context_mut();
+        if context.tree.is_none() {
+            context.tree = Some(name.to_string());
+        }
+        self
+    }
+
+    /// Notes that this error occurred with the file `path`, unless a file has
+    /// already been noted.
+    pub(crate) fn with_path(mut self, path: &PathId) -> Self {
+        let context = self.context_mut();
+        if context.path.is_none() {
+            context.path = Some(path.clone());
+        }
+        self
+    }
+
+    /// Notes that this error occurred with the chunk at `position`, unless a
+    /// position has already been noted.
+    pub(crate) fn with_position(mut self, position: u64) -> Self {
+        let context = self.context_mut();
+        if context.position.is_none() {
+            context.position = Some(position);
+        }
+        self
+    }
+
+    /// Notes that this error occurred with the transaction `transaction_id`,
+    /// unless a transaction has already been noted.
+    pub(crate) fn with_transaction_id(mut self, transaction_id: TransactionId) -> Self {
+        let context = self.context_mut();
+        if context.transaction_id.is_none() {
+            context.transaction_id = Some(transaction_id);
+        }
+        self
     }
 
     fn format_backtrace_frames(&self) -> Vec<String> {
-        let mut backtrace = self.backtrace.lock();
+        let mut backtrace = match &self.backtrace {
+            Some(backtrace) => backtrace.lock(),
+            None => return Vec::new(),
+        };
         backtrace.resolve();
         backtrace
             .frames()
@@ -88,8 +187,31 @@ impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Display::fmt(&self.kind, f)?;
 
+        if let Some(context) = &self.context {
+            let mut separator = " (";
+            if let Some(tree) = &context.tree {
+                write!(f, "{separator}tree: {tree}")?;
+                separator = ", ";
+            }
+            if let Some(path) = &context.path {
+                write!(f, "{separator}file: {}", path.path().display())?;
+                separator = ", ";
+            }
+            if let Some(position) = context.position {
+                write!(f, "{separator}position: {position}")?;
+                separator = ", ";
+            }
+            if let Some(transaction_id) = context.transaction_id {
+                write!(f, "{separator}transaction: {transaction_id}")?;
+                separator = ", ";
+            }
+            if separator == ", " {
+                f.write_str(")")?;
+            }
+        }
+
         #[cfg(debug_assertions)]
-        {
+        if self.backtrace.is_some() {
             f.write_str("\nstack backtrace:")?;
 
             for (index, frame) in self.format_backtrace_frames().into_iter().enumerate() {
@@ -106,6 +228,7 @@ impl Debug for Error {
         let frames = self.format_backtrace_frames();
         f.debug_struct("Error")
             .field("kind", &self.kind)
+            .field("context", &self.context)
             .field("backtrace", &&frames[..])
             .finish()
     }
@@ -113,10 +236,7 @@ impl Debug for Error {
 
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Self {
-        Self {
-            kind,
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
-        }
+        Self::new(kind)
     }
 }
 
@@ -128,55 +248,37 @@ impl From<AbortError<Infallible>> for Error {
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
-        Self {
-            kind: ErrorKind::from(err),
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
-        }
+        Self::new(ErrorKind::from(err))
     }
 }
 
 impl From<&'static str> for Error {
     fn from(message: &'static str) -> Self {
-        Self {
-            kind: ErrorKind::message(message),
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
-        }
+        Self::new(ErrorKind::message(message))
     }
 }
 
 impl From<flume::RecvError> for Error {
     fn from(_err: flume::RecvError) -> Self {
-        Self {
-            kind: ErrorKind::Internal(InternalError::InternalCommunication),
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
-        }
+        Self::new(ErrorKind::Internal(InternalError::InternalCommunication))
     }
 }
 
 impl<T> From<flume::SendError<T>> for Error {
     fn from(_err: flume::SendError<T>) -> Self {
-        Self {
-            kind: ErrorKind::Internal(InternalError::InternalCommunication),
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
-        }
+        Self::new(ErrorKind::Internal(InternalError::InternalCommunication))
     }
 }
 
 impl From<String> for Error {
     fn from(message: String) -> Self {
-        Self {
-            kind: ErrorKind::message(message),
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
-        }
+        Self::new(ErrorKind::message(message))
     }
 }
 
 impl From<TryFromSliceError> for Error {
     fn from(_: TryFromSliceError) -> Self {
-        Self {
-            kind: ErrorKind::Internal(InternalError::IncorrectByteLength),
-            backtrace: Mutex::new(Backtrace::new_unresolved()),
-        }
+        Self::new(ErrorKind::Internal(InternalError::IncorrectByteLength))
     }
 }
 
@@ -227,9 +329,135 @@ pub enum ErrorKind {
     /// An error ocurred in the vault.
     #[error("a vault error occurred: {0}")]
     Vault(Box<dyn SendSyncError>),
+    /// A tree or transaction log was opened with a different vault than the
+    /// one it was written with, or contains data written without a vault that
+    /// was not allowed to be read.
+    #[error("{path:?} cannot be opened with the configured vault: {reason}")]
+    VaultMismatch {
+        /// The path of the file.
+        path: PathBuf,
+        /// A description of the mismatch.
+        reason: String,
+    },
     /// An transaction was pushed to the log out of order.
     #[error("transaction pushed out of order")]
     TransactionPushedOutOfOrder,
+    /// A transaction was too large to be written to the transaction log.
+    #[error("transaction too large")]
+    TransactionTooLarge,
+    /// A directory could not be created because a file already exists at its
+    /// path.
+    #[error("{0:?} already exists, but is not a directory")]
+    NotADirectory(PathBuf),
+    /// The chunk's stored CRC did not match the CRC of its contents. The
+    /// position of the chunk is available through [`Error::position()`].
+    #[error("crc32 failure: stored {stored:08x}, computed {computed:08x}")]
+    ChecksumMismatch {
+        /// The CRC stored with the chunk.
+        stored: u32,
+        /// The CRC computed from the chunk's contents.
+        computed: u32,
+    },
+    /// An operation requiring a vault was performed on a database opened
+    /// without one.
+    #[error("the database was not opened with a vault")]
+    VaultNotConfigured,
+    /// A vault without a [key id](crate::Vault::key_id) was provided where one
+    /// is required.
+    #[error("the vault must have a key id")]
+    VaultKeyIdRequired,
+    /// A vault was provided whose [key id](crate::Vault::key_id) is already
+    /// used by another of the database's vaults.
+    #[error("key id {0} is already in use by this database")]
+    VaultKeyIdInUse(u32),
+    /// None of the database's vaults are able to decrypt data encrypted with
+    /// the payload's key.
+    #[error("no vault is available for the key this payload was encrypted with")]
+    VaultKeyNotFound,
+    /// A tree existing in the database has not been opened by the
+    /// [`Roots`](crate::Roots) performing an operation that must process every
+    /// tree. Processing a tree requires knowing its root type, which is only
+    /// known once the tree has been opened.
+    #[error("tree {0:?} must be opened before every tree can be processed")]
+    TreeNotOpened(String),
+    /// Encrypted data was stored using an envelope version that this version
+    /// of Nebari does not support.
+    #[error("unsupported encryption envelope version {0}")]
+    UnsupportedEnvelope(u8),
+    /// A key or value could not be encoded or decoded by a
+    /// [`TypedTree`](crate::typed::TypedTree) codec, or a key could not be
+    /// decoded using [`DecodeKey`](crate::keys::DecodeKey).
+    #[error("a codec error occurred: {0}")]
+    Codec(Box<dyn SendSyncError>),
+    /// A tree was opened with a different
+    /// [`KeyComparator`](crate::tree::KeyComparator) than the one it was
+    /// written with.
+    #[error("tree was written using key comparator {found:?}, but was opened with {expected:?}")]
+    ComparatorMismatch {
+        /// The name of the comparator the tree was opened with.
+        expected: String,
+        /// The name of the comparator the tree was written with.
+        found: String,
+    },
+    /// A merge was performed on a tree opened without a
+    /// [`MergeOperator`](crate::tree::MergeOperator).
+    #[error("the tree was not opened with a merge operator")]
+    MergeOperatorNotConfigured,
+    /// A secondary index was queried that wasn't declared using
+    /// [`TreeRoot::with_index()`](crate::tree::TreeRoot::with_index).
+    #[error("the tree has no secondary index named {0:?}")]
+    SecondaryIndexNotFound(String),
+    /// A transaction included a tree without declaring one of the secondary
+    /// indexes stored in the tree's file using
+    /// [`TreeRoot::with_index()`](crate::tree::TreeRoot::with_index).
+    #[error("the tree has a secondary index named {0:?} that was not declared")]
+    SecondaryIndexNotDeclared(String),
+    /// A multi-key operation did not provide exactly one value or operation
+    /// for each of its keys.
+    #[error("multi-key operation did not have one value or operation per key")]
+    KeyCountMismatch,
+    /// A tree file contained a root written by a different
+    /// [`Root`](crate::tree::Root) type than the one it was opened with.
+    #[error("tree contains a different root type")]
+    RootTypeMismatch,
+    /// An existing transaction log did not contain any entries.
+    #[error("no entries found in an existing transaction log")]
+    TransactionLogEmpty,
+    /// A file's path has no file name or parent directory, which is required
+    /// to create a file alongside it.
+    #[error("{0:?} has no file name or parent directory")]
+    InvalidFilePath(PathBuf),
+    /// A [`KeyComparator`](crate::tree::KeyComparator)'s name was longer than
+    /// 65,535 bytes, which is the longest name that can be stored in a tree's
+    /// header.
+    #[error("key comparator name too long: {0:?}")]
+    ComparatorNameTooLong(String),
+    /// A tree's header could not be parsed.
+    #[error("invalid tree header")]
+    InvalidTreeHeader,
+    /// A [`Modification`](crate::tree::Modification) performing an
+    /// [`Operation::RemoveRange`](crate::tree::Operation::RemoveRange) contained
+    /// keys.
+    #[error("range removal modifications must not contain keys")]
+    RemoveRangeWithKeys,
+    /// A background thread started by Nebari panicked. The string contains the
+    /// name of the thread's task.
+    #[error("{0} thread panicked")]
+    ThreadPanicked(String),
+    /// A file was sought or written to at a position that is negative or
+    /// does not fit in the file's address space.
+    #[error("invalid seek to a negative or overflowing position")]
+    InvalidSeek,
+    /// A file that was opened for reading was written to.
+    #[error("file was not opened for appending")]
+    NotOpenedForAppend,
+    /// A read requested more bytes than the file contains.
+    #[error("read requested more bytes than available")]
+    ReadPastEnd,
+    /// A [`FaultInjectingFileManager`](crate::io::fault::FaultInjectingFileManager)
+    /// failed an operation.
+    #[error("injected fault: {0:?}")]
+    InjectedFault(crate::io::fault::Fault),
 }
 
 pub trait SendSyncError: std::error::Error + Send + Sync + 'static {}
@@ -245,6 +473,53 @@ impl ErrorKind {
         Self::DataIntegrity(Box::new(error.into()))
     }
 
+    /// Returns true if this error can be expected to occur during normal
+    /// operation, such as a file not being found or a tree being compacted.
+    /// Expected errors do not capture a [backtrace](Error::backtrace).
+    #[must_use]
+    pub fn is_expected(&self) -> bool {
+        match self {
+            Self::InvalidTreeName
+            | Self::KeyTooLarge
+            | Self::ValueTooLarge
+            | Self::KeysNotOrdered
+            | Self::TreeCompacted
+            | Self::VaultMismatch { .. }
+            | Self::TransactionTooLarge
+            | Self::NotADirectory(_)
+            | Self::VaultNotConfigured
+            | Self::VaultKeyIdRequired
+            | Self::VaultKeyIdInUse(_)
+            | Self::VaultKeyNotFound
+            | Self::TreeNotOpened(_)
+            | Self::ComparatorMismatch { .. }
+            | Self::MergeOperatorNotConfigured
+            | Self::SecondaryIndexNotFound(_)
+            | Self::SecondaryIndexNotDeclared(_)
+            | Self::KeyCountMismatch
+            | Self::ComparatorNameTooLong(_)
+            | Self::RemoveRangeWithKeys => true,
+            Self::Io(_) => self.is_file_not_found(),
+            Self::Message(_)
+            | Self::DataIntegrity(_)
+            | Self::Internal(_)
+            | Self::Vault(_)
+            | Self::Codec(_)
+            | Self::UnsupportedEnvelope(_)
+            | Self::TransactionPushedOutOfOrder
+            | Self::ChecksumMismatch { .. }
+            | Self::RootTypeMismatch
+            | Self::TransactionLogEmpty
+            | Self::InvalidFilePath(_)
+            | Self::InvalidTreeHeader
+            | Self::ThreadPanicked(_)
+            | Self::InvalidSeek
+            | Self::NotOpenedForAppend
+            | Self::ReadPastEnd
+            | Self::InjectedFault(_) => false,
+        }
+    }
+
     /// Returns true if this error represents an
     /// [`std::io::ErrorKind::NotFound`].
     #[must_use]
@@ -292,4 +567,9 @@ pub enum InternalError {
     /// An unexpected byte length was encountered.
     #[error("an unexpected byte length was encountered")]
     IncorrectByteLength,
+    /// A modification containing an [`Operation::Merge`](crate::tree::Operation::Merge)
+    /// was applied without first being resolved using
+    /// [`Modification::resolve_merges()`](crate::tree::Modification::resolve_merges).
+    #[error("merges must be resolved before being applied")]
+    UnresolvedMerge,
 }
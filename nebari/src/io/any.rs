@@ -1,6 +1,6 @@
 use std::{
     io::{Read, Seek, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use crate::io::{
@@ -51,6 +51,17 @@ impl super::File for AnyFile {
             Self::Memory(file) => file.synchronize(),
         }
     }
+
+    fn read_bytes_at(
+        &mut self,
+        position: u64,
+        length: usize,
+    ) -> Option<Result<crate::ArcBytes<'static>, crate::Error>> {
+        match self {
+            Self::Std(file) => file.read_bytes_at(position, length),
+            Self::Memory(file) => file.read_bytes_at(position, length),
+        }
+    }
 }
 
 impl Write for AnyFile {
@@ -169,6 +180,27 @@ impl FileManager for AnyFileManager {
             Self::Memory(manager) => manager.file_length(path),
         }
     }
+
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), crate::Error> {
+        match self {
+            Self::Std(manager) => manager.create_directory(path),
+            Self::Memory(manager) => manager.create_directory(path),
+        }
+    }
+
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, crate::Error> {
+        match self {
+            Self::Std(manager) => manager.list(directory),
+            Self::Memory(manager) => manager.list(directory),
+        }
+    }
+
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), crate::Error> {
+        match self {
+            Self::Std(manager) => manager.truncate(path, length),
+            Self::Memory(manager) => manager.truncate(path, length),
+        }
+    }
 }
 
 impl ManagedFileOpener<AnyFile> for AnyFileManager {
@@ -0,0 +1,663 @@
+//! A [`FileManager`] that stores files in a [`BlobStore`].
+//!
+//! Blob stores, such as object storage services, do not support modifying
+//! stored data in place. Because Nebari's tree files and transaction log are
+//! only ever appended to, they can be stored as a series of immutable parts.
+//! [`BlobFile`] buffers written data in memory and uploads it as a single part
+//! each time the file is flushed, synchronized, or closed. Data that has not
+//! been uploaded when a handle is dropped is discarded, so handles must be
+//! closed using [`OpenableFile::close()`] to observe upload failures.
+//!
+//! [`LocalBlobStore`] stores blobs as files within a local directory, which is
+//! useful for testing.
+//!
+//! Because blob stores cannot rename blobs, replacing a file copies the
+//! replacement's contents in place of the file's contents using
+//! [`BlobStore::copy()`]. Handles that were opened before the replacement
+//! continue reading the previous contents, which are copied to a temporary
+//! `{key}.retired-{id}` blob that is deleted once the last of those handles is
+//! closed. Temporary blobs left behind by a crash are deleted the first time a
+//! [`BlobFileManager`] opens the file they were copied from.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs::OpenOptions,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+};
+
+use parking_lot::{Mutex, RwLock};
+
+use super::{FileManager, FileOp, ManagedFile, OpenableFile};
+use crate::{
+    error::Error,
+    io::{File as _, IntoPathId, ManagedFileOpener, OperableFile, PathId, PathIds},
+    ErrorKind,
+};
+
+/// The maximum number of bytes a [`BlobFile`] buffers before uploading them as
+/// a part, even if the file has not been flushed.
+const MAXIMUM_BUFFERED_BYTES: usize = 8 * 1024 * 1024;
+
+/// A store of binary blobs, identified by string keys.
+pub trait BlobStore: Debug + Send + Sync + 'static {
+    /// Stores `data` as the full contents of the blob `key`, replacing any
+    /// existing blob. The replacement must be atomic.
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Appends `data` as a new part at the end of the blob `key`, creating the
+    /// blob if it does not exist. Once this function returns, the part must be
+    /// durably stored.
+    fn append_part(&self, key: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Returns the bytes within `range` of the blob `key`. If the blob is
+    /// shorter than `range.end`, the available bytes are returned.
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>, Error>;
+
+    /// Stores a copy of the blob `from` as the full contents of the blob `to`,
+    /// replacing any existing blob. The replacement must be atomic. Object
+    /// storage services can typically copy blobs without downloading them.
+    fn copy(&self, from: &str, to: &str) -> Result<(), Error>;
+
+    /// Returns the length of the blob `key`, or None if the blob does not
+    /// exist.
+    fn length(&self, key: &str) -> Result<Option<u64>, Error>;
+
+    /// Returns the keys of all blobs whose key starts with `prefix`.
+    fn list(&self, prefix: &str) -> Result<Vec<String>, Error>;
+
+    /// Deletes the blob `key`. Returns true if the blob existed.
+    fn delete(&self, key: &str) -> Result<bool, Error>;
+}
+
+/// A [`BlobStore`] that stores each blob as a file within a local directory.
+///
+/// The default store has no directory, and stores each blob as a file at the
+/// path its key was derived from.
+#[derive(Debug, Clone, Default)]
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    /// Returns a store that keeps its blobs within `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn blob_path(&self, key: &str) -> PathBuf {
+        if self.root.as_os_str().is_empty() {
+            PathBuf::from(key)
+        } else {
+            self.root.join(key.trim_start_matches('/'))
+        }
+    }
+
+    /// Replaces the blob `key` with a file written by `write`. The file is
+    /// written to a temporary path and renamed to make the replacement atomic.
+    fn replace<Writer: FnOnce(&Path) -> io::Result<()>>(
+        &self,
+        key: &str,
+        write: Writer,
+    ) -> Result<(), Error> {
+        let path = self.blob_path(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut temporary_name = path.file_name().unwrap_or_default().to_os_string();
+        temporary_name.push(".uploading");
+        let temporary_path = path.with_file_name(temporary_name);
+        write(&temporary_path)?;
+        OpenOptions::new()
+            .write(true)
+            .open(&temporary_path)?
+            .sync_all()?;
+        std::fs::rename(&temporary_path, &path)?;
+        Ok(())
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        self.replace(key, |temporary_path| std::fs::write(temporary_path, data))
+    }
+
+    fn append_part(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        let path = self.blob_path(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Vec<u8>, Error> {
+        let mut file = std::fs::File::open(self.blob_path(key))?;
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut contents = Vec::new();
+        file.take(range.end.saturating_sub(range.start))
+            .read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        let from = self.blob_path(from);
+        self.replace(to, |temporary_path| {
+            std::fs::copy(&from, temporary_path).map(|_| ())
+        })
+    }
+
+    fn length(&self, key: &str) -> Result<Option<u64>, Error> {
+        match std::fs::metadata(self.blob_path(key)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let (directory, name_prefix) = prefix.rsplit_once('/').unwrap_or(("", prefix));
+        let directory_path = self.blob_path(directory);
+        if !directory_path.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut keys = Vec::new();
+        for entry in std::fs::read_dir(directory_path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if name.starts_with(name_prefix) {
+                    if directory.is_empty() && !prefix.starts_with('/') {
+                        keys.push(name.to_string());
+                    } else {
+                        keys.push(format!("{}/{}", directory, name));
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, Error> {
+        match std::fs::remove_file(self.blob_path(key)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+}
+
+fn blob_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn not_found() -> Error {
+    Error::from(ErrorKind::Io(io::Error::from(io::ErrorKind::NotFound)))
+}
+
+/// The blob shared by every handle opened with the same file id.
+#[derive(Debug)]
+struct Generation<S: BlobStore> {
+    store: Arc<S>,
+    key: Arc<str>,
+    /// Once the file has been replaced, the key of the copy of its previous
+    /// contents. The copy is deleted when the generation is dropped.
+    retired_key: RwLock<Option<Arc<str>>>,
+}
+
+impl<S: BlobStore> Generation<S> {
+    fn new(store: Arc<S>, key: String) -> Self {
+        Self {
+            store,
+            key: Arc::from(key),
+            retired_key: RwLock::default(),
+        }
+    }
+
+    /// Returns the key of the blob containing this generation's contents.
+    fn key(&self) -> Arc<str> {
+        self.retired_key
+            .read()
+            .clone()
+            .unwrap_or_else(|| self.key.clone())
+    }
+}
+
+impl<S: BlobStore> Drop for Generation<S> {
+    fn drop(&mut self) {
+        if let Some(retired_key) = self.retired_key.get_mut() {
+            drop(self.store.delete(retired_key));
+        }
+    }
+}
+
+/// A file stored in a [`BlobStore`].
+#[derive(Debug)]
+pub struct BlobFile<S: BlobStore> {
+    store: Arc<S>,
+    id: PathId,
+    generation: Arc<Generation<S>>,
+    position: u64,
+    stored_length: u64,
+    /// Data written but not yet uploaded, if this file was opened for
+    /// appending.
+    unstored: Option<Vec<u8>>,
+}
+
+impl<S: BlobStore> BlobFile<S> {
+    fn upload(&mut self) -> Result<(), Error> {
+        if let Some(unstored) = &mut self.unstored {
+            if !unstored.is_empty() {
+                self.store.append_part(&self.generation.key(), unstored)?;
+                self.stored_length += unstored.len() as u64;
+                unstored.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the current length of the file. Files opened for reading can
+    /// observe data appended by other handles.
+    fn current_length(&self) -> Result<u64, Error> {
+        if self.unstored.is_some() {
+            Ok(self.stored_length + self.unstored_length())
+        } else {
+            self.store
+                .length(&self.generation.key())?
+                .ok_or_else(not_found)
+        }
+    }
+
+    fn unstored_length(&self) -> u64 {
+        self.unstored
+            .as_ref()
+            .map_or(0, |unstored| unstored.len() as u64)
+    }
+}
+
+impl<S: BlobStore + Default> ManagedFile for BlobFile<S> {
+    type Manager = BlobFileManager<S>;
+}
+
+impl<S: BlobStore> super::File for BlobFile<S> {
+    fn id(&self) -> &PathId {
+        &self.id
+    }
+
+    fn length(&self) -> Result<u64, Error> {
+        self.current_length()
+    }
+
+    fn synchronize(&mut self) -> Result<(), Error> {
+        self.upload()
+    }
+
+    fn close(mut self) -> Result<(), Error> {
+        self.upload()
+    }
+}
+
+impl<S: BlobStore> Seek for BlobFile<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(relative) => offset_position(
+                self.current_length()
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?,
+                relative,
+            ),
+            SeekFrom::Current(relative) => offset_position(self.position, relative),
+        };
+        self.position = new_position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, ErrorKind::InvalidSeek))?;
+        Ok(self.position)
+    }
+}
+
+const fn offset_position(position: u64, relative: i64) -> Option<u64> {
+    if relative.is_negative() {
+        position.checked_sub(relative.unsigned_abs())
+    } else {
+        position.checked_add(relative.unsigned_abs())
+    }
+}
+
+impl<S: BlobStore> Read for BlobFile<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.unstored.is_none() && self.position >= self.stored_length {
+            self.stored_length = self
+                .current_length()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+        let bytes_read = if self.position < self.stored_length {
+            let end = self
+                .stored_length
+                .min(self.position.saturating_add(buf.len() as u64));
+            let bytes = self
+                .store
+                .get_range(&self.generation.key(), self.position..end)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            let bytes_read = bytes.len().min(buf.len());
+            buf[..bytes_read].copy_from_slice(&bytes[..bytes_read]);
+            bytes_read
+        } else {
+            let unstored = self.unstored.as_deref().unwrap_or_default();
+            let start = usize::try_from(self.position - self.stored_length)
+                .unwrap_or(usize::MAX)
+                .min(unstored.len());
+            let bytes_read = buf.len().min(unstored.len() - start);
+            buf[..bytes_read].copy_from_slice(&unstored[start..start + bytes_read]);
+            bytes_read
+        };
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<S: BlobStore> Write for BlobFile<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let unstored = self.unstored.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                ErrorKind::NotOpenedForAppend,
+            )
+        })?;
+        unstored.extend_from_slice(buf);
+        let should_upload = unstored.len() >= MAXIMUM_BUFFERED_BYTES;
+        self.position = self.stored_length + self.unstored_length();
+        if should_upload {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.upload()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
+/// The [`FileManager`] for [`BlobFile`].
+#[derive(Debug)]
+pub struct BlobFileManager<S: BlobStore> {
+    store: Arc<S>,
+    file_ids: PathIds,
+    /// The generations of open files, by file id. This is locked while files
+    /// are opened and replaced, ensuring a handle never reads a replacement's
+    /// contents using the file id of the file it replaced.
+    generations: Arc<Mutex<HashMap<u64, Weak<Generation<S>>>>>,
+    /// The keys of the blobs that have been opened, whose retired copies left
+    /// behind by a previous process have been deleted.
+    opened_keys: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<S: BlobStore> Clone for BlobFileManager<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            file_ids: self.file_ids.clone(),
+            generations: self.generations.clone(),
+            opened_keys: self.opened_keys.clone(),
+        }
+    }
+}
+
+impl<S: BlobStore + Default> Default for BlobFileManager<S> {
+    fn default() -> Self {
+        Self::new(S::default())
+    }
+}
+
+impl<S: BlobStore> BlobFileManager<S> {
+    /// Returns a new manager that stores its files in `store`.
+    pub fn new(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+            file_ids: PathIds::default(),
+            generations: Arc::default(),
+            opened_keys: Arc::default(),
+        }
+    }
+
+    /// Returns the underlying blob store.
+    #[must_use]
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    fn open_file(
+        &self,
+        id: PathId,
+        for_append: bool,
+        generations: &mut HashMap<u64, Weak<Generation<S>>>,
+    ) -> Result<BlobFile<S>, Error> {
+        let key = blob_key(id.path());
+        self.remove_retired_copies(&key)?;
+        let generation = match id.id() {
+            Some(file_id) => {
+                if let Some(generation) = generations.get(&file_id).and_then(Weak::upgrade) {
+                    generation
+                } else {
+                    generations.retain(|_, generation| generation.strong_count() > 0);
+                    let generation = Arc::new(Generation::new(self.store.clone(), key));
+                    generations.insert(file_id, Arc::downgrade(&generation));
+                    generation
+                }
+            }
+            None => Arc::new(Generation::new(self.store.clone(), key)),
+        };
+        let key = generation.key();
+        let (stored_length, unstored) = if for_append {
+            let length = if let Some(length) = self.store.length(&key)? {
+                length
+            } else {
+                self.store.put(&key, &[])?;
+                0
+            };
+            (length, Some(Vec::new()))
+        } else {
+            (self.store.length(&key)?.ok_or_else(not_found)?, None)
+        };
+        Ok(BlobFile {
+            store: self.store.clone(),
+            id,
+            generation,
+            position: if for_append { stored_length } else { 0 },
+            stored_length,
+            unstored,
+        })
+    }
+
+    /// Deletes the retired copies of `key` the first time `key` is opened.
+    /// Retired copies are only created for files that have been opened, so
+    /// any existing copies were left behind by a process that stopped before
+    /// deleting them.
+    fn remove_retired_copies(&self, key: &str) -> Result<(), Error> {
+        let mut opened_keys = self.opened_keys.lock();
+        if !opened_keys.contains(key) {
+            for retired_key in self.store.list(&retired_key_prefix(key))? {
+                self.store.delete(&retired_key)?;
+            }
+            opened_keys.insert(key.to_string());
+        }
+        drop(opened_keys);
+        Ok(())
+    }
+}
+
+fn retired_key_prefix(key: &str) -> String {
+    format!("{}.retired-", key)
+}
+
+impl<S: BlobStore + Default> FileManager for BlobFileManager<S> {
+    type File = BlobFile<S>;
+    type FileHandle = OpenBlobFile<S>;
+
+    fn resolve_path(&self, path: impl AsRef<Path>, create_if_not_found: bool) -> Option<PathId> {
+        self.file_ids
+            .file_id_for_path(path.as_ref(), create_if_not_found)
+    }
+
+    fn read(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        let mut generations = self.generations.lock();
+        let path = path.into_path_id();
+        let file_id = self.file_ids.file_id_for_path(path, true).unwrap();
+        self.open_file(file_id, false, &mut generations)
+            .map(|file| OpenBlobFile { file: Some(file) })
+    }
+
+    fn append(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        let mut generations = self.generations.lock();
+        let path = path.into_path_id();
+        let file_id = self.file_ids.file_id_for_path(path, true).unwrap();
+        self.open_file(file_id, true, &mut generations)
+            .map(|file| OpenBlobFile { file: Some(file) })
+    }
+
+    fn file_length(&self, path: impl IntoPathId) -> Result<u64, Error> {
+        self.store
+            .length(&blob_key(path.path()))?
+            .ok_or_else(not_found)
+    }
+
+    fn exists(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        Ok(self.store.length(&blob_key(path.path()))?.is_some())
+    }
+
+    fn close_handles<F: FnOnce(PathId)>(&self, path: impl IntoPathId, publish_callback: F) {
+        if let Some(result) = self.file_ids.recreate_file_id_for_path(path) {
+            publish_callback(result.new_id);
+        }
+    }
+
+    fn delete(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        let path = path.into_path_id();
+        self.file_ids.remove_file_id_for_path(&path);
+        self.store.delete(&blob_key(path.path()))
+    }
+
+    fn delete_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        self.file_ids.remove_file_ids_for_path_prefix(path);
+        for key in self.store.list(&blob_key(&path.join("")))? {
+            self.store.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        // Blob stores have no directories.
+        let path = path.as_ref();
+        if self.exists(path)? {
+            Err(Error::from(ErrorKind::NotADirectory(path.to_path_buf())))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error> {
+        let prefix = blob_key(&directory.as_ref().join(""));
+        Ok(self
+            .store
+            .list(&prefix)?
+            .into_iter()
+            .filter(|key| !key[prefix.len()..].contains('/'))
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), Error> {
+        let key = blob_key(path.path());
+        let current_length = self.store.length(&key)?.ok_or_else(not_found)?;
+        if current_length > length {
+            let contents = self.store.get_range(&key, 0..length)?;
+            self.store.put(&key, &contents)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: BlobStore + Default> ManagedFileOpener<BlobFile<S>> for BlobFileManager<S> {
+    fn open_for_read(&self, path: impl IntoPathId + Send) -> Result<BlobFile<S>, Error> {
+        let mut generations = self.generations.lock();
+        self.open_file(path.into_path_id(), false, &mut generations)
+    }
+
+    fn open_for_append(&self, path: impl IntoPathId + Send) -> Result<BlobFile<S>, Error> {
+        let mut generations = self.generations.lock();
+        self.open_file(path.into_path_id(), true, &mut generations)
+    }
+}
+
+/// An open [`BlobFile`] that belongs to a [`BlobFileManager`].
+///
+/// Buffered data is uploaded when the file is flushed, synchronized, or
+/// [closed](OpenableFile::close), and is discarded if this handle is dropped.
+#[derive(Debug)]
+pub struct OpenBlobFile<S: BlobStore> {
+    file: Option<BlobFile<S>>,
+}
+
+impl<S: BlobStore + Default> OpenableFile<BlobFile<S>> for OpenBlobFile<S> {
+    fn id(&self) -> &PathId {
+        self.file.as_ref().unwrap().id()
+    }
+
+    fn replace_with<C: FnOnce(PathId)>(
+        mut self,
+        mut replacement: BlobFile<S>,
+        manager: &BlobFileManager<S>,
+        publish_callback: C,
+    ) -> Result<Self, Error> {
+        let current = self.file.take().unwrap();
+        let current_id = current.id.clone();
+        let current_key = blob_key(current_id.path());
+        drop(current);
+        replacement.upload()?;
+        let replacement_key = replacement.generation.key();
+
+        let mut generations = manager.generations.lock();
+        if let Some((file_id, generation)) = current_id.id().and_then(|file_id| {
+            generations
+                .remove(&file_id)
+                .and_then(|generation| generation.upgrade())
+                .map(|generation| (file_id, generation))
+        }) {
+            // Other handles are still reading the current contents. Preserve
+            // them for those handles, like a file system would for a file that
+            // has been unlinked.
+            let retired_key = format!("{}{}", retired_key_prefix(&current_key), file_id);
+            manager.store.copy(&current_key, &retired_key)?;
+            *generation.retired_key.write() = Some(Arc::from(retired_key));
+        }
+        // Blob stores don't support renaming, so the replacement's contents
+        // are copied in place of the current file's contents.
+        manager.store.copy(&replacement_key, &current_key)?;
+        manager.store.delete(&replacement_key)?;
+        manager.file_ids.remove_file_id_for_path(&replacement.id);
+        drop(replacement);
+
+        manager.close_handles(&current_id, publish_callback);
+        drop(generations);
+        manager.append(current_id.path())
+    }
+
+    fn close(mut self) -> Result<(), Error> {
+        self.file.take().map_or(Ok(()), BlobFile::close)
+    }
+}
+
+impl<S: BlobStore + Default> OperableFile<BlobFile<S>> for OpenBlobFile<S> {
+    fn execute<Output, Op: FileOp<Output>>(&mut self, operator: Op) -> Output {
+        operator.execute(self.file.as_mut().unwrap())
+    }
+}
@@ -0,0 +1,602 @@
+//! A [`FileManager`] that wraps another manager and injects failures.
+//!
+//! [`FaultInjectingFileManager`] keeps all data written since a file was last
+//! synchronized in memory. Only once [`File::synchronize()`] succeeds is the
+//! data written to the wrapped manager's file. This allows simulating a power
+//! loss by discarding (or partially keeping) every file's unsynchronized data.
+//!
+//! Individual operations can also be made to fail by scheduling a [`Fault`]
+//! using [`FaultInjectingFileManager::inject_at()`]. Every write and
+//! synchronize operation performed through the manager is counted, and the
+//! fault is triggered when the count reaches the scheduled operation.
+//!
+//! After a power loss, the manager refuses all operations until
+//! [`FaultInjectingFileManager::restore_power()`] is called. Files opened before
+//! the power loss remain unusable, which allows a test to drop the database,
+//! restore power, and reopen it using the same manager to verify what
+//! survived.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+
+use super::{
+    File, FileManager, FileOp, IntoPathId, ManagedFile, ManagedFileOpener, OpenableFile,
+    OperableFile, PathId,
+};
+use crate::{error::Error, ErrorKind};
+
+/// A failure that can be injected into a [`FaultInjectingFileManager`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Fault {
+    /// The write operation fails without writing any data.
+    FailWrite,
+    /// The write operation writes the first `bytes` bytes of the buffer and
+    /// then fails.
+    TearWrite {
+        /// The number of bytes to write before failing.
+        bytes: usize,
+    },
+    /// The synchronize operation fails. No unsynchronized data is persisted.
+    FailSync,
+    /// The operation fails, and a power loss is simulated using
+    /// [`FaultInjectingFileManager::simulate_power_loss()`].
+    PowerLoss,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    operations: AtomicU64,
+    scheduled: Mutex<Vec<(u64, Fault)>>,
+    unsynced: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    generation: AtomicU64,
+    powered_off: AtomicBool,
+}
+
+impl FaultState {
+    fn next_operation(&self) -> Option<Fault> {
+        let operation = self.operations.fetch_add(1, Ordering::SeqCst);
+        let mut scheduled = self.scheduled.lock();
+        let index = scheduled
+            .iter()
+            .position(|(scheduled_at, _)| *scheduled_at == operation)?;
+        Some(scheduled.remove(index).1)
+    }
+
+    fn power_loss(&self) {
+        self.powered_off.store(true, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let mut unsynced = self.unsynced.lock();
+        unsynced.clear();
+    }
+
+    fn ensure_available(&self, generation: Option<u64>) -> io::Result<()> {
+        if self.powered_off.load(Ordering::SeqCst)
+            || generation.map_or(false, |generation| {
+                generation != self.generation.load(Ordering::SeqCst)
+            })
+        {
+            Err(injected_error(Fault::PowerLoss))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unsynced_length(&self, path: &Path) -> u64 {
+        let unsynced = self.unsynced.lock();
+        unsynced.get(path).map_or(0, |bytes| bytes.len() as u64)
+    }
+}
+
+fn injected_error(fault: Fault) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, ErrorKind::InjectedFault(fault))
+}
+
+/// The wrapped manager's file that a [`FaultInjectingFile`] reads from and
+/// persists synchronized data to.
+#[derive(Debug)]
+enum InnerFile<F: ManagedFile> {
+    /// A file opened using the wrapped manager's [`ManagedFileOpener`].
+    File(F),
+    /// A handle returned from the wrapped manager's [`FileManager::read()`] or
+    /// [`FileManager::append()`], which keeps the manager's pooling and
+    /// exclusive access in effect while the file is open.
+    Handle(<F::Manager as FileManager>::FileHandle),
+}
+
+impl<F: ManagedFile> InnerFile<F> {
+    fn id(&self) -> &PathId {
+        match self {
+            Self::File(file) => file.id(),
+            Self::Handle(handle) => handle.id(),
+        }
+    }
+
+    fn execute<Output, Operation: FnOnce(&mut dyn File) -> Output>(
+        &mut self,
+        operation: Operation,
+    ) -> Output {
+        match self {
+            Self::File(file) => operation(file),
+            Self::Handle(handle) => handle.execute(InnerOperation(operation)),
+        }
+    }
+
+    fn close(self) -> Result<(), Error> {
+        match self {
+            Self::File(file) => file.close(),
+            Self::Handle(handle) => handle.close(),
+        }
+    }
+}
+
+/// Executes a closure against the file of an [`InnerFile::Handle`].
+struct InnerOperation<Operation>(Operation);
+
+impl<Operation, Output> FileOp<Output> for InnerOperation<Operation>
+where
+    Operation: FnOnce(&mut dyn File) -> Output,
+{
+    fn execute(self, file: &mut dyn File) -> Output {
+        (self.0)(file)
+    }
+}
+
+/// A file managed by a [`FaultInjectingFileManager`].
+#[derive(Debug)]
+pub struct FaultInjectingFile<F: ManagedFile> {
+    id: PathId,
+    // `File::length()` only has shared access to the file.
+    inner: Mutex<InnerFile<F>>,
+    state: Arc<FaultState>,
+    generation: u64,
+    position: u64,
+    writable: bool,
+}
+
+impl<F: ManagedFile> FaultInjectingFile<F> {
+    fn synced_length(&self) -> io::Result<u64> {
+        self.inner
+            .lock()
+            .execute(|file| file.length())
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+
+    fn total_length(&self) -> io::Result<u64> {
+        // The unsynced data is locked while measuring the file to prevent
+        // observing data that is in the middle of being persisted.
+        let unsynced = self.state.unsynced.lock();
+        let unsynced_length = unsynced
+            .get(self.id.path())
+            .map_or(0, |bytes| bytes.len() as u64);
+        Ok(self.synced_length()? + unsynced_length)
+    }
+
+    fn persist_unsynced(&mut self) -> Result<(), Error> {
+        let inner = self.inner.get_mut();
+        let mut unsynced = self.state.unsynced.lock();
+        if let Some(bytes) = unsynced.remove(self.id.path()) {
+            inner.execute(|file| file.write_all(&bytes))?;
+        }
+        drop(unsynced);
+        inner.execute(File::synchronize)
+    }
+}
+
+impl<F: ManagedFile> ManagedFile for FaultInjectingFile<F> {
+    type Manager = FaultInjectingFileManager<F::Manager>;
+}
+
+impl<F: ManagedFile> File for FaultInjectingFile<F> {
+    fn id(&self) -> &PathId {
+        &self.id
+    }
+
+    fn length(&self) -> Result<u64, Error> {
+        self.state.ensure_available(Some(self.generation))?;
+        Ok(self.total_length()?)
+    }
+
+    fn synchronize(&mut self) -> Result<(), Error> {
+        self.state.ensure_available(Some(self.generation))?;
+        if !self.writable {
+            return self.inner.get_mut().execute(File::synchronize);
+        }
+        match self.state.next_operation() {
+            Some(Fault::FailSync) => Err(Error::from(injected_error(Fault::FailSync))),
+            Some(Fault::PowerLoss) => {
+                self.state.power_loss();
+                Err(Error::from(injected_error(Fault::PowerLoss)))
+            }
+            Some(Fault::FailWrite | Fault::TearWrite { .. }) | None => self.persist_unsynced(),
+        }
+    }
+
+    fn close(mut self) -> Result<(), Error> {
+        if self.writable {
+            self.synchronize()?;
+        }
+        self.inner.into_inner().close()
+    }
+}
+
+impl<F: ManagedFile> Seek for FaultInjectingFile<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.state.ensure_available(Some(self.generation))?;
+        let new_position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(relative) => offset_position(self.total_length()?, relative),
+            SeekFrom::Current(relative) => offset_position(self.position, relative),
+        };
+        self.position = new_position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, ErrorKind::InvalidSeek))?;
+        Ok(self.position)
+    }
+}
+
+const fn offset_position(position: u64, relative: i64) -> Option<u64> {
+    if relative.is_negative() {
+        position.checked_sub(relative.unsigned_abs())
+    } else {
+        position.checked_add(relative.unsigned_abs())
+    }
+}
+
+impl<F: ManagedFile> Read for FaultInjectingFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.state.ensure_available(Some(self.generation))?;
+        let unsynced = self.state.unsynced.lock();
+        let synced_length = self.synced_length()?;
+        let bytes_read = if self.position < synced_length {
+            let position = self.position;
+            let readable = buf
+                .len()
+                .min(usize::try_from(synced_length - position).unwrap_or(usize::MAX));
+            self.inner.get_mut().execute(|file| {
+                file.seek(SeekFrom::Start(position))?;
+                file.read(&mut buf[..readable])
+            })?
+        } else {
+            let bytes = unsynced.get(self.id.path()).map_or(&[][..], Vec::as_slice);
+            let start = usize::try_from(self.position - synced_length)
+                .unwrap_or(usize::MAX)
+                .min(bytes.len());
+            let readable = buf.len().min(bytes.len() - start);
+            buf[..readable].copy_from_slice(&bytes[start..start + readable]);
+            readable
+        };
+        drop(unsynced);
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<F: ManagedFile> Write for FaultInjectingFile<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.ensure_available(Some(self.generation))?;
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                ErrorKind::NotOpenedForAppend,
+            ));
+        }
+        let (bytes_to_write, result) = match self.state.next_operation() {
+            Some(Fault::FailWrite) => (0, Err(injected_error(Fault::FailWrite))),
+            Some(Fault::TearWrite { bytes }) => (
+                bytes.min(buf.len()),
+                Err(injected_error(Fault::TearWrite { bytes })),
+            ),
+            Some(Fault::PowerLoss) => {
+                self.state.power_loss();
+                (0, Err(injected_error(Fault::PowerLoss)))
+            }
+            Some(Fault::FailSync) | None => (buf.len(), Ok(buf.len())),
+        };
+
+        let mut unsynced = self.state.unsynced.lock();
+        let bytes = unsynced.entry(self.id.path().to_path_buf()).or_default();
+        bytes.extend_from_slice(&buf[..bytes_to_write]);
+        drop(unsynced);
+        self.position = self.total_length()?;
+
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.ensure_available(Some(self.generation))
+    }
+}
+
+/// A [`FileManager`] that wraps another manager and can simulate failures,
+/// including power loss. See the [module documentation](self) for more
+/// information.
+#[derive(Debug, Clone)]
+pub struct FaultInjectingFileManager<M: FileManager> {
+    inner: M,
+    state: Arc<FaultState>,
+}
+
+impl<M: FileManager> Default for FaultInjectingFileManager<M> {
+    fn default() -> Self {
+        Self::new(M::default())
+    }
+}
+
+impl<M: FileManager> FaultInjectingFileManager<M> {
+    /// Returns a new manager that stores synchronized data using `inner`.
+    #[must_use]
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            state: Arc::default(),
+        }
+    }
+
+    /// Returns the wrapped file manager.
+    #[must_use]
+    pub const fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Returns the number of write and synchronize operations performed
+    /// through this manager.
+    #[must_use]
+    pub fn operations(&self) -> u64 {
+        self.state.operations.load(Ordering::SeqCst)
+    }
+
+    /// Schedules `fault` to occur during the write or synchronize operation
+    /// numbered `operation`. Operations are numbered starting at 0 when the
+    /// manager is created. Use [`Self::operations()`] to schedule a fault
+    /// relative to the current operation.
+    pub fn inject_at(&self, operation: u64, fault: Fault) {
+        let mut scheduled = self.state.scheduled.lock();
+        scheduled.push((operation, fault));
+    }
+
+    /// Removes all scheduled faults that have not been triggered.
+    pub fn clear_faults(&self) {
+        let mut scheduled = self.state.scheduled.lock();
+        scheduled.clear();
+    }
+
+    /// Simulates a power loss by discarding all data that has not been
+    /// synchronized. All operations will fail until
+    /// [`Self::restore_power()`] is called.
+    pub fn simulate_power_loss(&self) {
+        self.state.power_loss();
+    }
+
+    /// Simulates a power loss where only part of the unsynchronized data
+    /// reached the disk. `retained` is invoked with each file's path and its
+    /// unsynchronized data, and returns how many of those bytes to keep. All
+    /// operations will fail until [`Self::restore_power()`] is called.
+    pub fn simulate_power_loss_with_torn_writes<Retained: FnMut(&Path, &[u8]) -> usize>(
+        &self,
+        mut retained: Retained,
+    ) -> Result<(), Error> {
+        let unsynced = {
+            let mut unsynced = self.state.unsynced.lock();
+            std::mem::take(&mut *unsynced)
+        };
+        self.state.power_loss();
+        for (path, bytes) in unsynced {
+            let bytes_to_keep = retained(&path, &bytes).min(bytes.len());
+            if bytes_to_keep > 0 {
+                let mut file = self.inner.open_for_append(path)?;
+                file.write_all(&bytes[..bytes_to_keep])?;
+                file.close()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if a power loss has been simulated and power has not been
+    /// restored.
+    #[must_use]
+    pub fn is_powered_off(&self) -> bool {
+        self.state.powered_off.load(Ordering::SeqCst)
+    }
+
+    /// Allows operations to be performed after a simulated power loss. Files
+    /// opened before the power loss remain unusable.
+    pub fn restore_power(&self) {
+        self.state.powered_off.store(false, Ordering::SeqCst);
+    }
+
+    fn wrap(&self, inner: InnerFile<M::File>, writable: bool) -> FaultInjectingFile<M::File> {
+        FaultInjectingFile {
+            id: inner.id().clone(),
+            inner: Mutex::new(inner),
+            state: self.state.clone(),
+            generation: self.state.generation.load(Ordering::SeqCst),
+            position: 0,
+            writable,
+        }
+    }
+
+    fn wrap_for_append(
+        &self,
+        inner: InnerFile<M::File>,
+    ) -> Result<FaultInjectingFile<M::File>, Error> {
+        let mut file = self.wrap(inner, true);
+        file.position = file.length()?;
+        Ok(file)
+    }
+
+    fn forget_unsynced(&self, path: &Path, include_children: bool) {
+        let mut unsynced = self.state.unsynced.lock();
+        if include_children {
+            unsynced.retain(|unsynced_path, _| !unsynced_path.starts_with(path));
+        } else {
+            unsynced.remove(path);
+        }
+    }
+}
+
+impl<M: FileManager> FileManager for FaultInjectingFileManager<M> {
+    type File = FaultInjectingFile<M::File>;
+    type FileHandle = OpenFaultInjectingFile<M::File>;
+
+    fn resolve_path(&self, path: impl AsRef<Path>, create_if_not_found: bool) -> Option<PathId> {
+        self.inner.resolve_path(path, create_if_not_found)
+    }
+
+    fn read(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        self.state.ensure_available(None)?;
+        let handle = self.inner.read(path)?;
+        Ok(OpenFaultInjectingFile {
+            file: self.wrap(InnerFile::Handle(handle), false),
+        })
+    }
+
+    fn append(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        self.state.ensure_available(None)?;
+        let handle = self.inner.append(path)?;
+        self.wrap_for_append(InnerFile::Handle(handle))
+            .map(|file| OpenFaultInjectingFile { file })
+    }
+
+    fn file_length(&self, path: impl IntoPathId) -> Result<u64, Error> {
+        self.state.ensure_available(None)?;
+        let unsynced_length = self.state.unsynced_length(path.path());
+        Ok(self.inner.file_length(path)? + unsynced_length)
+    }
+
+    fn exists(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        self.state.ensure_available(None)?;
+        self.inner.exists(path)
+    }
+
+    fn close_handles<F: FnOnce(PathId)>(&self, path: impl IntoPathId, publish_callback: F) {
+        self.inner.close_handles(path, publish_callback);
+    }
+
+    fn delete(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        self.state.ensure_available(None)?;
+        self.forget_unsynced(path.path(), false);
+        self.inner.delete(path)
+    }
+
+    fn delete_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.state.ensure_available(None)?;
+        self.forget_unsynced(path.as_ref(), true);
+        self.inner.delete_directory(path)
+    }
+
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.state.ensure_available(None)?;
+        self.inner.create_directory(path)
+    }
+
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error> {
+        self.state.ensure_available(None)?;
+        self.inner.list(directory)
+    }
+
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), Error> {
+        self.state.ensure_available(None)?;
+        // Truncation is persisted immediately, discarding any unsynchronized
+        // data beyond `length`.
+        let mut unsynced = self.state.unsynced.lock();
+        let synced_length = self.inner.file_length(path.path())?;
+        if length >= synced_length {
+            if let Some(bytes) = unsynced.get_mut(path.path()) {
+                bytes.truncate(usize::try_from(length - synced_length).unwrap_or(usize::MAX));
+            }
+            drop(unsynced);
+            Ok(())
+        } else {
+            unsynced.remove(path.path());
+            drop(unsynced);
+            self.inner.truncate(path, length)
+        }
+    }
+}
+
+impl<M: FileManager> ManagedFileOpener<FaultInjectingFile<M::File>>
+    for FaultInjectingFileManager<M>
+{
+    fn open_for_read(
+        &self,
+        path: impl IntoPathId + Send,
+    ) -> Result<FaultInjectingFile<M::File>, Error> {
+        self.state.ensure_available(None)?;
+        let file = self.inner.open_for_read(path)?;
+        Ok(self.wrap(InnerFile::File(file), false))
+    }
+
+    fn open_for_append(
+        &self,
+        path: impl IntoPathId + Send,
+    ) -> Result<FaultInjectingFile<M::File>, Error> {
+        self.state.ensure_available(None)?;
+        let file = self.inner.open_for_append(path)?;
+        self.wrap_for_append(InnerFile::File(file))
+    }
+}
+
+/// An open [`FaultInjectingFile`] that belongs to a
+/// [`FaultInjectingFileManager`].
+#[derive(Debug)]
+pub struct OpenFaultInjectingFile<F: ManagedFile> {
+    file: FaultInjectingFile<F>,
+}
+
+impl<F: ManagedFile> OpenableFile<FaultInjectingFile<F>> for OpenFaultInjectingFile<F> {
+    fn id(&self) -> &PathId {
+        &self.file.id
+    }
+
+    fn replace_with<C: FnOnce(PathId)>(
+        self,
+        mut replacement: FaultInjectingFile<F>,
+        manager: &FaultInjectingFileManager<F::Manager>,
+        publish_callback: C,
+    ) -> Result<Self, Error> {
+        manager
+            .state
+            .ensure_available(Some(replacement.generation))?;
+        replacement.persist_unsynced()?;
+        // Any data not yet synchronized to the current file is being replaced.
+        manager.forget_unsynced(self.file.id.path(), false);
+
+        let current = match self.file.inner.into_inner() {
+            InnerFile::Handle(handle) => handle,
+            InnerFile::File(file) => {
+                file.close()?;
+                manager.inner.append(&self.file.id)?
+            }
+        };
+        let replacement = match replacement.inner.into_inner() {
+            InnerFile::File(file) => file,
+            InnerFile::Handle(handle) => {
+                handle.close()?;
+                manager.inner.open_for_append(replacement.id)?
+            }
+        };
+        let replaced = current.replace_with(replacement, &manager.inner, publish_callback)?;
+        manager
+            .wrap_for_append(InnerFile::Handle(replaced))
+            .map(|file| Self { file })
+    }
+
+    fn close(self) -> Result<(), Error> {
+        drop(self);
+        Ok(())
+    }
+}
+
+impl<F: ManagedFile> OperableFile<FaultInjectingFile<F>> for OpenFaultInjectingFile<F> {
+    fn execute<Output, Op: FileOp<Output>>(&mut self, operator: Op) -> Output {
+        operator.execute(&mut self.file)
+    }
+}
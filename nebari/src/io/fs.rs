@@ -2,7 +2,7 @@ use std::{
     collections::{HashMap, VecDeque},
     fs::{File, OpenOptions},
     io::{Read, Seek, SeekFrom, Write},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
@@ -12,6 +12,7 @@ use super::{FileManager, FileOp, ManagedFile, OpenableFile};
 use crate::{
     error::Error,
     io::{File as _, IntoPathId, ManagedFileOpener, OperableFile, PathId, PathIds},
+    ErrorKind,
 };
 
 /// An open file that uses [`std::fs`].
@@ -60,7 +61,6 @@ impl ManagedFileOpener<StdFile> for StdFileOpener {
         let path = path.into_path_id();
         Ok(StdFile {
             file: OpenOptions::new()
-                .write(true)
                 .append(true)
                 .read(true)
                 .create(true)
@@ -265,6 +265,47 @@ impl FileManager for StdFileManager {
 
         Ok(())
     }
+
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        create_directory(path.as_ref())
+    }
+
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error> {
+        list_directory(directory.as_ref())
+    }
+
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), Error> {
+        truncate_file(path.path(), length)
+    }
+}
+
+pub(crate) fn create_directory(path: &Path) -> Result<(), Error> {
+    if !path.exists() {
+        std::fs::create_dir_all(path)?;
+    } else if !path.is_dir() {
+        return Err(Error::from(ErrorKind::NotADirectory(path.to_path_buf())));
+    }
+    Ok(())
+}
+
+pub(crate) fn truncate_file(path: &Path, length: u64) -> Result<(), Error> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    if file.metadata()?.len() > length {
+        file.set_len(length)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+pub(crate) fn list_directory(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths)
 }
 
 impl ManagedFileOpener<StdFile> for StdFileManager {
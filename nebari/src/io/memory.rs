@@ -9,14 +9,119 @@ use std::{
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 
-use super::{FileManager, FileOp, ManagedFile, OpenableFile};
+use super::{FileManager, FileOp, ManagedFile, OpenableFile, PositionedFile};
 use crate::{
     error::Error,
     io::{File, IntoPathId, ManagedFileOpener, OperableFile, PathId, PathIds},
-    ErrorKind,
+    ArcBytes, ErrorKind,
 };
 
-type FileBuffer = Arc<RwLock<Vec<u8>>>;
+type FileBuffer = Arc<RwLock<MemoryBuffer>>;
+
+/// The number of bytes in each segment of a [`MemoryBuffer`].
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// The contents of a [`MemoryFile`], stored in segments of [`SEGMENT_SIZE`]
+/// bytes. Reads contained within a single segment share the segment instead of
+/// copying it, and writes only copy a segment that a previous read still
+/// shares.
+#[derive(Default)]
+struct MemoryBuffer {
+    segments: Vec<ArcBytes<'static>>,
+    length: usize,
+}
+
+impl std::fmt::Debug for MemoryBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryBuffer")
+            .field("length", &self.length)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemoryBuffer {
+    const fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Copies the bytes starting at `position` into `buffer`, returning the
+    /// number of bytes copied.
+    fn read_at(&self, position: usize, buffer: &mut [u8]) -> usize {
+        let start = position.min(self.length);
+        let end = start.saturating_add(buffer.len()).min(self.length);
+        let mut position = start;
+        while position < end {
+            let offset = position % SEGMENT_SIZE;
+            let count = (SEGMENT_SIZE - offset).min(end - position);
+            let segment = &self.segments[position / SEGMENT_SIZE];
+            buffer[position - start..position - start + count]
+                .copy_from_slice(&segment[offset..offset + count]);
+            position += count;
+        }
+        end - start
+    }
+
+    /// Returns `length` bytes starting at `position`, or `None` if the buffer
+    /// ends before them.
+    fn bytes_at(&self, position: usize, length: usize) -> Option<ArcBytes<'static>> {
+        if position.checked_add(length)? > self.length {
+            return None;
+        }
+        let offset = position % SEGMENT_SIZE;
+        if length == 0 {
+            Some(ArcBytes::default())
+        } else if offset + length <= SEGMENT_SIZE {
+            Some(self.segments[position / SEGMENT_SIZE].slice(offset..offset + length))
+        } else {
+            let mut bytes = vec![0; length];
+            self.read_at(position, &mut bytes);
+            Some(ArcBytes::from(bytes))
+        }
+    }
+
+    /// Writes `bytes` at `position`, filling any gap after the current end of
+    /// the buffer with zeroes.
+    fn write_at(&mut self, position: usize, mut bytes: &[u8]) {
+        if position > self.length {
+            self.write_at(self.length, &vec![0; position - self.length]);
+        }
+        let end = position + bytes.len();
+        let mut position = position;
+        while !bytes.is_empty() {
+            let index = position / SEGMENT_SIZE;
+            let offset = position % SEGMENT_SIZE;
+            let count = (SEGMENT_SIZE - offset).min(bytes.len());
+            if index == self.segments.len() {
+                self.segments.push(ArcBytes::default());
+            }
+            // This only copies the segment if a read is still sharing it.
+            let mut segment = std::mem::take(&mut self.segments[index]).into_vec();
+            if segment.len() < offset + count {
+                segment.resize(offset + count, 0);
+            }
+            segment[offset..offset + count].copy_from_slice(&bytes[..count]);
+            self.segments[index] = ArcBytes::from(segment);
+            bytes = &bytes[count..];
+            position += count;
+        }
+        self.length = self.length.max(end);
+    }
+
+    /// Shortens the buffer to `length` bytes, if it is longer.
+    fn truncate(&mut self, length: usize) {
+        if length >= self.length {
+            return;
+        }
+        let segment_count = (length + SEGMENT_SIZE - 1) / SEGMENT_SIZE;
+        self.segments.truncate(segment_count);
+        if let Some(last) = self.segments.last_mut() {
+            let mut segment = std::mem::take(last).into_vec();
+            segment.truncate(length - (segment_count - 1) * SEGMENT_SIZE);
+            *last = ArcBytes::from(segment);
+        }
+        self.length = length;
+    }
+}
 
 /// A fake "file" represented by an in-memory buffer. This should only be used
 /// in testing, as this database format is not optimized for memory efficiency.
@@ -38,11 +143,11 @@ impl std::fmt::Debug for MemoryFile {
     }
 }
 
-type OpenBuffers = Arc<Mutex<HashMap<Arc<PathBuf>, Weak<RwLock<Vec<u8>>>>>>;
+type OpenBuffers = Arc<Mutex<HashMap<Arc<PathBuf>, Weak<RwLock<MemoryBuffer>>>>>;
 static OPEN_BUFFERS: Lazy<OpenBuffers> = Lazy::new(Arc::default);
 
 #[allow(clippy::needless_pass_by_value)]
-fn lookup_buffer(path: &PathId, create_if_not_found: bool) -> Option<Arc<RwLock<Vec<u8>>>> {
+fn lookup_buffer(path: &PathId, create_if_not_found: bool) -> Option<FileBuffer> {
     let mut open_buffers = OPEN_BUFFERS.lock();
     if let Some(existing_buffer) = open_buffers.get(&path.path).and_then(Weak::upgrade) {
         Some(existing_buffer)
@@ -77,6 +182,37 @@ impl super::File for MemoryFile {
     fn close(self) -> Result<(), Error> {
         Ok(())
     }
+
+    fn read_bytes_at(
+        &mut self,
+        position: u64,
+        length: usize,
+    ) -> Option<Result<ArcBytes<'static>, Error>> {
+        let file_buffer = self.buffer.read();
+        let start = usize::try_from(position).unwrap_or(usize::MAX);
+        Some(file_buffer.bytes_at(start, length).ok_or_else(|| {
+            Error::from(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                ErrorKind::ReadPastEnd,
+            ))
+        }))
+    }
+}
+
+impl PositionedFile for MemoryFile {
+    fn read_at(&self, buffer: &mut [u8], position: u64) -> io::Result<usize> {
+        let file_buffer = self.buffer.read();
+        let start = usize::try_from(position).unwrap_or(usize::MAX);
+        Ok(file_buffer.read_at(start, buffer))
+    }
+
+    fn write_at(&self, buffer: &[u8], position: u64) -> io::Result<usize> {
+        let mut file_buffer = self.buffer.write();
+        let start = usize::try_from(position)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, ErrorKind::InvalidSeek))?;
+        file_buffer.write_at(start, buffer);
+        Ok(buffer.len())
+    }
 }
 
 /// A [`ManagedFileOpener`] implementation that produces [`MemoryFile`]s.
@@ -140,11 +276,11 @@ impl std::io::Read for MemoryFile {
         if read_end > file_buffer.len() {
             return Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
-                ErrorKind::message("read requested more bytes than available"),
+                ErrorKind::ReadPastEnd,
             ));
         }
 
-        buffer.copy_from_slice(&file_buffer[self.position..read_end]);
+        file_buffer.read_at(self.position, buffer);
         self.position = read_end;
 
         Ok(buffer.len())
@@ -155,7 +291,8 @@ impl std::io::Write for MemoryFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let mut file_buffer = self.buffer.write();
 
-        file_buffer.extend_from_slice(buf);
+        let end = file_buffer.len();
+        file_buffer.write_at(end, buf);
         self.position += buf.len();
 
         Ok(buf.len())
@@ -232,15 +369,21 @@ impl FileManager for MemoryFileManager {
     }
 
     fn file_length(&self, path: impl IntoPathId) -> Result<u64, Error> {
-        let file = self.lookup_file(path, false)?.ok_or_else(|| {
-            ErrorKind::Io(io::Error::new(
-                io::ErrorKind::NotFound,
-                ErrorKind::message("not found"),
-            ))
-        })?;
+        let file = self
+            .lookup_file(path, false)?
+            .ok_or_else(|| ErrorKind::Io(io::Error::from(io::ErrorKind::NotFound)))?;
         file.length()
     }
 
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), Error> {
+        let file = self
+            .lookup_file(path, false)?
+            .ok_or_else(|| ErrorKind::Io(io::Error::from(io::ErrorKind::NotFound)))?;
+        let length = usize::try_from(length).unwrap_or(usize::MAX);
+        file.buffer.write().truncate(length);
+        Ok(())
+    }
+
     fn exists(&self, path: impl IntoPathId) -> Result<bool, Error> {
         Ok(self.lookup_file(path, false)?.is_some())
     }
@@ -271,6 +414,27 @@ impl FileManager for MemoryFileManager {
 
         Ok(())
     }
+
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        // Directories are implied by the paths of the files within them.
+        let path = path.as_ref();
+        if self.exists(path)? {
+            Err(Error::from(ErrorKind::NotADirectory(path.to_path_buf())))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error> {
+        let open_files = self.open_files.lock();
+        Ok(self
+            .file_ids
+            .file_ids_in_directory(directory.as_ref())
+            .into_iter()
+            .filter(|id| open_files.contains_key(&id.id().unwrap()))
+            .map(|id| id.path().to_path_buf())
+            .collect())
+    }
 }
 
 impl ManagedFileOpener<MemoryFile> for MemoryFileManager {
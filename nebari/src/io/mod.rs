@@ -19,14 +19,18 @@ use std::{
 
 use parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard};
 
-use crate::error::Error;
+use crate::{error::Error, ArcBytes};
 
 /// A wrapper type for any file type.
 pub mod any;
+pub mod blob;
+pub mod fault;
 /// Filesystem IO provided by `std::fs`.
 pub mod fs;
 /// A virtual memory-based filesystem.
 pub mod memory;
+#[cfg(unix)]
+pub mod pread;
 
 /// A file that is managed by a [`FileManager`].
 pub trait ManagedFile: File {
@@ -51,6 +55,78 @@ pub trait File: Debug + Send + Sync + Seek + Read + Write + 'static {
 
     /// Safely closes the file after flushing any pending operations to disk.
     fn close(self) -> Result<(), Error>;
+
+    /// Returns `length` bytes starting at `position`, if this file is able to
+    /// provide them directly from memory. This allows implementations that
+    /// keep their contents in memory, such as [`memory::MemoryFile`], to serve
+    /// chunk reads by sharing their buffers instead of seeking and copying
+    /// through [`Read`]. Exactly `length` bytes must be returned.
+    ///
+    /// Returns `None` by default, which causes callers to fall back to
+    /// [`Seek`] and [`Read`].
+    fn read_bytes_at(
+        &mut self,
+        position: u64,
+        length: usize,
+    ) -> Option<Result<ArcBytes<'static>, Error>> {
+        let _ = (position, length);
+        None
+    }
+}
+
+/// A [`File`] that can be read from and written to at explicit offsets,
+/// similar to `pread` and `pwrite`. Positioned operations take `&self` and do
+/// not use or update the file's cursor.
+pub trait PositionedFile: File {
+    /// Reads up to `buffer.len()` bytes starting at `position`. Returns the
+    /// number of bytes read.
+    fn read_at(&self, buffer: &mut [u8], position: u64) -> std::io::Result<usize>;
+
+    /// Writes up to `buffer.len()` bytes starting at `position`. Returns the
+    /// number of bytes written.
+    fn write_at(&self, buffer: &[u8], position: u64) -> std::io::Result<usize>;
+
+    /// Reads exactly `buffer.len()` bytes starting at `position`.
+    fn read_exact_at(&self, mut buffer: &mut [u8], mut position: u64) -> std::io::Result<()> {
+        while !buffer.is_empty() {
+            match self.read_at(buffer, position) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(bytes_read) => {
+                    buffer = &mut buffer[bytes_read..];
+                    position += bytes_read as u64;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes all of `buffer` starting at `position`.
+    fn write_all_at(&self, mut buffer: &[u8], mut position: u64) -> std::io::Result<()> {
+        while !buffer.is_empty() {
+            match self.write_at(buffer, position) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(bytes_written) => {
+                    buffer = &buffer[bytes_written..];
+                    position += bytes_written as u64;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
 }
 
 /// A type that can open managed files.
@@ -103,6 +179,18 @@ pub trait FileManager:
 
     /// Removes a directory and all of its contents.
     fn delete_directory(&self, path: impl AsRef<Path>) -> Result<(), Error>;
+
+    /// Creates a directory at `path`, including any missing parent
+    /// directories. Succeeds without changes if the directory already exists.
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), Error>;
+
+    /// Returns the paths of all files contained directly within `directory`.
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error>;
+
+    /// Shortens the file at `path` to `length` bytes, durably. Files that are
+    /// already `length` bytes or shorter are left unchanged. This is only used
+    /// while recovering a file that no handles have open.
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), Error>;
 }
 
 /// A file that can have operations performed on it.
@@ -351,7 +439,13 @@ pub struct PathIds {
 }
 
 impl PathIds {
-    fn file_id_for_path(&self, path: impl IntoPathId, insert_if_not_found: bool) -> Option<PathId> {
+    /// Returns the id of `path`. If `path` has no id and `insert_if_not_found`
+    /// is true, a new id is assigned.
+    pub fn file_id_for_path(
+        &self,
+        path: impl IntoPathId,
+        insert_if_not_found: bool,
+    ) -> Option<PathId> {
         let file_ids = self.file_ids.upgradable_read();
         let path = path.into_path_id();
         if let Some(id) = file_ids.get(&path) {
@@ -374,7 +468,8 @@ impl PathIds {
         }
     }
 
-    fn remove_file_id_for_path(&self, path: impl IntoPathId) -> Option<PathId> {
+    /// Removes the id of `path`, returning it if present.
+    pub fn remove_file_id_for_path(&self, path: impl IntoPathId) -> Option<PathId> {
         let mut file_ids = self.file_ids.write();
         if path.id().is_some() {
             file_ids.take(&path.into_path_id())
@@ -383,7 +478,10 @@ impl PathIds {
         }
     }
 
-    fn recreate_file_id_for_path(&self, path: impl IntoPathId) -> Option<RecreatedFile<'_>> {
+    /// Assigns a new id to `path`, which must already have an id. This is used
+    /// when a file's contents are replaced, so that handles to the previous
+    /// contents can be told apart from handles to the new contents.
+    pub fn recreate_file_id_for_path(&self, path: impl IntoPathId) -> Option<RecreatedFile<'_>> {
         let existing = path.into_path_id();
         let mut file_ids = self.file_ids.write();
         let new_id = self.file_id_counter.fetch_add(1, Ordering::SeqCst);
@@ -400,7 +498,19 @@ impl PathIds {
             })
     }
 
-    fn remove_file_ids_for_path_prefix(&self, path: &Path) -> Vec<PathId> {
+    fn file_ids_in_directory(&self, directory: &Path) -> Vec<PathId> {
+        let file_ids = self.file_ids.read();
+        file_ids
+            .iter()
+            .filter(|id| id.path().parent() == Some(directory))
+            .cloned()
+            .collect()
+    }
+
+    /// Removes the ids of all paths starting with `path`, returning the
+    /// removed ids.
+    #[allow(clippy::must_use_candidate)]
+    pub fn remove_file_ids_for_path_prefix(&self, path: &Path) -> Vec<PathId> {
         let mut file_ids = self.file_ids.write();
         let mut ids_to_remove = Vec::new();
         let mut paths_to_remove = Vec::new();
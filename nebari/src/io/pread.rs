@@ -0,0 +1,387 @@
+//! Filesystem IO using positioned reads and writes on shared file descriptors.
+//!
+//! [`StdFileManager`](super::fs::StdFileManager) keeps a pool of file
+//! descriptors per path, and each read seeks the descriptor before reading.
+//! [`PreadFileManager`] instead shares a single read-only descriptor per path
+//! across every reader. Each [`PreadFile`] tracks its own cursor and issues
+//! positioned reads, allowing any number of threads to read the same file in
+//! parallel without seeking or waiting on one another.
+//!
+//! Writers are still exclusive: only one append handle per path can exist at a
+//! time. Writes are always performed at the end of the file, matching the
+//! append-only behavior of the other managers.
+//!
+//! An `io_uring` backend is not provided, as submitting requests to the kernel
+//! requires `unsafe` code, which this crate forbids.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use super::{FileManager, FileOp, ManagedFile, OpenableFile, PositionedFile};
+use crate::{
+    error::Error,
+    io::{File as _, IntoPathId, ManagedFileOpener, OperableFile, PathId, PathIds},
+    ErrorKind,
+};
+
+/// An open file that reads and writes using positioned IO.
+#[derive(Debug)]
+pub struct PreadFile {
+    file: Arc<File>,
+    id: PathId,
+    position: u64,
+    /// The end of the file, if this file was opened for appending.
+    append_position: Option<u64>,
+}
+
+impl ManagedFile for PreadFile {
+    type Manager = PreadFileManager;
+}
+
+impl super::File for PreadFile {
+    fn id(&self) -> &PathId {
+        &self.id
+    }
+
+    fn length(&self) -> Result<u64, Error> {
+        let metadata = self.file.metadata()?;
+        Ok(metadata.len())
+    }
+
+    fn close(mut self) -> Result<(), Error> {
+        self.synchronize()
+    }
+
+    fn synchronize(&mut self) -> Result<(), Error> {
+        self.file.sync_data().map_err(Error::from)
+    }
+}
+
+impl PositionedFile for PreadFile {
+    fn read_at(&self, buffer: &mut [u8], position: u64) -> io::Result<usize> {
+        self.file.read_at(buffer, position)
+    }
+
+    fn write_at(&self, buffer: &[u8], position: u64) -> io::Result<usize> {
+        self.file.write_at(buffer, position)
+    }
+}
+
+/// A [`ManagedFileOpener`] implementation that produces [`PreadFile`]s.
+pub struct PreadFileOpener;
+
+impl ManagedFileOpener<PreadFile> for PreadFileOpener {
+    fn open_for_read(&self, path: impl IntoPathId + Send) -> Result<PreadFile, Error> {
+        let path = path.into_path_id();
+        Ok(PreadFile {
+            file: Arc::new(File::open(path.path())?),
+            id: path,
+            position: 0,
+            append_position: None,
+        })
+    }
+
+    fn open_for_append(&self, path: impl IntoPathId + Send) -> Result<PreadFile, Error> {
+        let path = path.into_path_id();
+        // The file isn't opened in append mode, as positioned writes to files
+        // opened with `O_APPEND` ignore the requested position on Linux.
+        let file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .open(path.path())?;
+        let length = file.metadata()?.len();
+        Ok(PreadFile {
+            file: Arc::new(file),
+            id: path,
+            position: length,
+            append_position: Some(length),
+        })
+    }
+}
+
+impl Seek for PreadFile {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self,)))]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(relative) => {
+                let length = self.file.metadata()?.len();
+                offset_position(length, relative)
+            }
+            SeekFrom::Current(relative) => offset_position(self.position, relative),
+        };
+        self.position = new_position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, ErrorKind::InvalidSeek))?;
+        Ok(self.position)
+    }
+}
+
+const fn offset_position(position: u64, relative: i64) -> Option<u64> {
+    if relative.is_negative() {
+        position.checked_sub(relative.unsigned_abs())
+    } else {
+        position.checked_add(relative.unsigned_abs())
+    }
+}
+
+impl Write for PreadFile {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, buf)))]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let append_position = self.append_position.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                ErrorKind::NotOpenedForAppend,
+            )
+        })?;
+        let bytes_written = self.file.write_at(buf, *append_position)?;
+        *append_position += bytes_written as u64;
+        self.position = *append_position;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Positioned writes are not buffered.
+        Ok(())
+    }
+}
+
+impl Read for PreadFile {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, buf)))]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.file.read_at(buf, self.position)?;
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+/// The [`FileManager`] for [`PreadFile`].
+#[derive(Debug, Default, Clone)]
+pub struct PreadFileManager {
+    file_ids: PathIds,
+    reader_files: Arc<Mutex<HashMap<u64, Arc<File>>>>,
+    writer_files: Arc<Mutex<HashMap<u64, WriterSlot>>>,
+}
+
+/// Holds the single writable file for a path. The file is sent back into the
+/// channel when the handle using it is dropped.
+#[derive(Debug, Clone)]
+struct WriterSlot {
+    sender: flume::Sender<PreadFile>,
+    receiver: flume::Receiver<PreadFile>,
+}
+
+impl PreadFileManager {
+    fn forget_handles(&self, path_id: &PathId) {
+        let mut writer_files = self.writer_files.lock();
+        let mut reader_files = self.reader_files.lock();
+        writer_files.remove(&path_id.id().unwrap());
+        reader_files.remove(&path_id.id().unwrap());
+    }
+}
+
+impl FileManager for PreadFileManager {
+    type File = PreadFile;
+    type FileHandle = OpenPreadFile;
+
+    fn resolve_path(&self, path: impl AsRef<Path>, create_if_not_found: bool) -> Option<PathId> {
+        self.file_ids
+            .file_id_for_path(path.as_ref(), create_if_not_found)
+    }
+
+    fn read(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        let path = path.into_path_id();
+        let file_id = self.file_ids.file_id_for_path(path, true).unwrap();
+
+        let mut reader_files = self.reader_files.lock();
+        let file = if let Some(file) = reader_files.get(&file_id.id().unwrap()) {
+            file.clone()
+        } else {
+            let file = Arc::new(File::open(file_id.path())?);
+            reader_files.insert(file_id.id().unwrap(), file.clone());
+            file
+        };
+
+        Ok(OpenPreadFile {
+            file: Some(PreadFile {
+                file,
+                id: file_id,
+                position: 0,
+                append_position: None,
+            }),
+            writer_slot: None,
+        })
+    }
+
+    fn append(&self, path: impl IntoPathId) -> Result<Self::FileHandle, Error> {
+        let path = path.into_path_id();
+        let file_id = self.file_ids.file_id_for_path(path, true).unwrap();
+        let slot = {
+            let mut writer_files = self.writer_files.lock();
+            if let Some(slot) = writer_files.get(&file_id.id().unwrap()) {
+                slot.clone()
+            } else {
+                let file = self.open_for_append(file_id.clone())?;
+                let (sender, receiver) = flume::bounded(1);
+                sender.send(file).unwrap();
+                let slot = WriterSlot { sender, receiver };
+                writer_files.insert(file_id.id().unwrap(), slot.clone());
+                slot
+            }
+        };
+
+        // Because the slot holds both sides of the channel, this will only
+        // return once the current writer's handle has been dropped.
+        let file = slot.receiver.recv().map_err(|_| {
+            Error::from(ErrorKind::Internal(
+                crate::error::InternalError::InternalCommunication,
+            ))
+        })?;
+        Ok(OpenPreadFile {
+            file: Some(file),
+            writer_slot: Some(slot.sender),
+        })
+    }
+
+    fn file_length(&self, path: impl IntoPathId) -> Result<u64, Error> {
+        path.path()
+            .metadata()
+            .map_err(Error::from)
+            .map(|metadata| metadata.len())
+    }
+
+    fn exists(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        if let Some(path_id) = self.resolve_path(path.path(), false) {
+            {
+                let writer_files = self.writer_files.lock();
+                if writer_files.contains_key(&path_id.id().unwrap()) {
+                    return Ok(true);
+                }
+            }
+            {
+                let reader_files = self.reader_files.lock();
+                if reader_files.contains_key(&path_id.id().unwrap()) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        // Not already open, just ask the filesystem
+        Ok(path.path().exists())
+    }
+
+    fn close_handles<F: FnOnce(PathId)>(&self, path: impl IntoPathId, publish_callback: F) {
+        if let Some(result) = self.file_ids.recreate_file_id_for_path(path) {
+            self.forget_handles(&result.previous_id);
+            publish_callback(result.new_id);
+        }
+    }
+
+    fn delete(&self, path: impl IntoPathId) -> Result<bool, Error> {
+        let in_path = path.into_path_id();
+        if let Some(file_id) = self.file_ids.remove_file_id_for_path(&in_path) {
+            self.forget_handles(&file_id);
+        }
+
+        if in_path.path().exists() {
+            std::fs::remove_file(in_path.path())?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn delete_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        for path_id in self.file_ids.remove_file_ids_for_path_prefix(path) {
+            self.forget_handles(&path_id);
+        }
+
+        if path.exists() {
+            std::fs::remove_dir_all(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn create_directory(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        super::fs::create_directory(path.as_ref())
+    }
+
+    fn list(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error> {
+        super::fs::list_directory(directory.as_ref())
+    }
+
+    fn truncate(&self, path: impl IntoPathId, length: u64) -> Result<(), Error> {
+        super::fs::truncate_file(path.path(), length)
+    }
+}
+
+impl ManagedFileOpener<PreadFile> for PreadFileManager {
+    fn open_for_read(&self, path: impl IntoPathId + Send) -> Result<PreadFile, Error> {
+        PreadFileOpener.open_for_read(path)
+    }
+
+    fn open_for_append(&self, path: impl IntoPathId + Send) -> Result<PreadFile, Error> {
+        PreadFileOpener.open_for_append(path)
+    }
+}
+
+/// An open [`PreadFile`] that belongs to a [`PreadFileManager`].
+#[derive(Debug)]
+pub struct OpenPreadFile {
+    file: Option<PreadFile>,
+    writer_slot: Option<flume::Sender<PreadFile>>,
+}
+
+impl OpenableFile<PreadFile> for OpenPreadFile {
+    fn id(&self) -> &PathId {
+        self.file.as_ref().unwrap().id()
+    }
+
+    fn replace_with<C: FnOnce(PathId)>(
+        self,
+        replacement: PreadFile,
+        manager: &PreadFileManager,
+        publish_callback: C,
+    ) -> Result<Self, Error> {
+        let current_path = self.file.as_ref().unwrap().id.path.clone();
+        self.close()?;
+        let path = replacement.id.clone();
+        replacement.close()?;
+
+        std::fs::rename(path.path(), current_path.path())?;
+        manager.close_handles(&current_path, publish_callback);
+        manager.append(current_path)
+    }
+
+    fn close(self) -> Result<(), Error> {
+        drop(self);
+        Ok(())
+    }
+}
+
+impl OperableFile<PreadFile> for OpenPreadFile {
+    fn execute<Output, Op: FileOp<Output>>(&mut self, operator: Op) -> Output {
+        operator.execute(self.file.as_mut().unwrap())
+    }
+}
+
+impl Drop for OpenPreadFile {
+    fn drop(&mut self) {
+        if let Some(writer_slot) = self.writer_slot.take() {
+            // If the slot is full or the manager has forgotten this file, the
+            // file is simply closed.
+            drop(writer_slot.try_send(self.file.take().unwrap()));
+        }
+    }
+}
@@ -0,0 +1,493 @@
+//! Order-preserving key encodings.
+//!
+//! Trees order keys by comparing their bytes. The encodings in this module
+//! produce bytes that compare in the same order as the values they were encoded
+//! from, allowing integers, floats, strings, and tuples of them to be used as
+//! keys:
+//!
+//! - Integers are encoded big-endian. Signed integers have their sign bit
+//!   flipped so that negative numbers sort before positive numbers.
+//! - Floats have their sign bit flipped when positive, and all bits flipped when
+//!   negative.
+//! - Strings and byte strings are terminated by `[0, 0]`, with each `0` within
+//!   the string escaped as `[0, 255]`. This allows a string to sort before
+//!   every string it is a prefix of.
+//! - Options are encoded as `0` for `None`, or `1` followed by the value.
+//! - Tuples are encoded as their elements in order.
+//!
+//! Every encoding is self-delimiting, so the encoding of a tuple's leading
+//! elements is a prefix of the encoding of the tuple. [`PrefixRange`] builds
+//! the range of keys sharing a prefix:
+//!
+//! ```rust
+//! use nebari::keys::{DecodeKey, EncodeKey, PrefixRange};
+//!
+//! let key = (String::from("users"), -1_i64).encode_key();
+//! let range = PrefixRange::for_key(&(String::from("users"),));
+//! assert!(range.contains(&key));
+//! assert_eq!(
+//!     <(String, i64)>::decode_key(&key).unwrap(),
+//!     (String::from("users"), -1)
+//! );
+//! ```
+
+use std::{borrow::Cow, convert::TryInto, ops::Bound};
+
+use crate::{ArcBytes, Error, ErrorKind};
+
+/// A type that can be encoded as an order-preserving key.
+pub trait EncodeKey {
+    /// Appends the encoded key to `output`.
+    fn encode_key_into(&self, output: &mut Vec<u8>);
+
+    /// Returns the encoded key.
+    fn encode_key(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        self.encode_key_into(&mut output);
+        output
+    }
+}
+
+/// A type that can be decoded from an order-preserving key.
+pub trait DecodeKey: Sized {
+    /// Decodes a key from the start of `input`, advancing `input` past the
+    /// decoded bytes.
+    fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError>;
+
+    /// Decodes a key from `bytes`, which must contain exactly one encoded key.
+    fn decode_key(bytes: &[u8]) -> Result<Self, DecodeKeyError> {
+        let mut input = bytes;
+        let key = Self::decode_key_from(&mut input)?;
+        if input.is_empty() {
+            Ok(key)
+        } else {
+            Err(DecodeKeyError::TrailingBytes)
+        }
+    }
+}
+
+/// An error decoding an order-preserving key.
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeKeyError {
+    /// The encoded key ended unexpectedly.
+    #[error("unexpected end of encoded key")]
+    UnexpectedEnd,
+    /// Bytes remained after decoding the key.
+    #[error("encoded key has trailing bytes")]
+    TrailingBytes,
+    /// The encoded key contained an invalid encoding of a type.
+    #[error("invalid encoding of {0}")]
+    Invalid(&'static str),
+}
+
+impl From<DecodeKeyError> for Error {
+    fn from(err: DecodeKeyError) -> Self {
+        Self::from(ErrorKind::Codec(Box::new(err)))
+    }
+}
+
+/// The range of keys that begin with a prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixRange {
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+}
+
+impl PrefixRange {
+    /// Returns the range of keys that begin with `prefix`.
+    pub fn new(prefix: impl Into<Vec<u8>>) -> Self {
+        let start = prefix.into();
+        let mut end = start.clone();
+        // The first key after the range is found by incrementing the last byte
+        // that can be incremented. If every byte is 255, the range is unbounded.
+        let end = loop {
+            match end.pop() {
+                Some(255) => {}
+                Some(last) => {
+                    end.push(last + 1);
+                    break Some(end);
+                }
+                None => break None,
+            }
+        };
+        Self { start, end }
+    }
+
+    /// Returns the range of keys that begin with the encoding of `prefix`. When
+    /// `prefix` is a tuple, the range contains every key whose leading tuple
+    /// elements are equal to `prefix`.
+    pub fn for_key<K: EncodeKey + ?Sized>(prefix: &K) -> Self {
+        Self::new(prefix.encode_key())
+    }
+
+    /// Returns the prefix of this range.
+    #[must_use]
+    pub fn prefix(&self) -> &[u8] {
+        &self.start
+    }
+
+    /// Returns true if `key` begins with this range's prefix.
+    #[must_use]
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key.starts_with(&self.start)
+    }
+
+    /// Returns the bounds of this range, which can be passed to functions such
+    /// as [`Tree::get_range()`](crate::Tree::get_range).
+    #[must_use]
+    pub fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (
+            Bound::Included(&self.start),
+            match &self.end {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            },
+        )
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], length: usize) -> Result<&'a [u8], DecodeKeyError> {
+    if input.len() < length {
+        return Err(DecodeKeyError::UnexpectedEnd);
+    }
+    let (bytes, remaining) = input.split_at(length);
+    *input = remaining;
+    Ok(bytes)
+}
+
+pub(crate) fn encode_escaped(bytes: &[u8], output: &mut Vec<u8>) {
+    for &byte in bytes {
+        output.push(byte);
+        if byte == 0 {
+            output.push(255);
+        }
+    }
+    output.extend_from_slice(&[0, 0]);
+}
+
+/// Decodes an escaped byte string. Strings without escaped bytes are borrowed
+/// from the input.
+pub(crate) fn decode_escaped<'a>(input: &mut &'a [u8]) -> Result<Cow<'a, [u8]>, DecodeKeyError> {
+    let mut owned: Option<Vec<u8>> = None;
+    let mut index = 0;
+    loop {
+        let zero = input[index..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(DecodeKeyError::UnexpectedEnd)?
+            + index;
+        match input.get(zero + 1) {
+            Some(0) => {
+                let bytes = match owned {
+                    Some(mut owned) => {
+                        owned.extend_from_slice(&input[index..zero]);
+                        Cow::Owned(owned)
+                    }
+                    None => Cow::Borrowed(&input[..zero]),
+                };
+                *input = &input[zero + 2..];
+                return Ok(bytes);
+            }
+            Some(255) => {
+                let owned = owned.get_or_insert_with(Vec::new);
+                owned.extend_from_slice(&input[index..=zero]);
+                index = zero + 2;
+            }
+            Some(_) => return Err(DecodeKeyError::Invalid("byte string")),
+            None => return Err(DecodeKeyError::UnexpectedEnd),
+        }
+    }
+}
+
+macro_rules! impl_unsigned {
+    ($($ty:ty),+) => {
+        $(
+            impl EncodeKey for $ty {
+                fn encode_key_into(&self, output: &mut Vec<u8>) {
+                    output.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+
+            impl DecodeKey for $ty {
+                fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+                    let bytes = take(input, std::mem::size_of::<$ty>())?;
+                    Ok(<$ty>::from_be_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )+
+    };
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128);
+
+macro_rules! impl_signed {
+    ($($ty:ty),+) => {
+        $(
+            impl EncodeKey for $ty {
+                fn encode_key_into(&self, output: &mut Vec<u8>) {
+                    output.extend_from_slice(&(*self ^ <$ty>::MIN).to_be_bytes());
+                }
+            }
+
+            impl DecodeKey for $ty {
+                fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+                    let bytes = take(input, std::mem::size_of::<$ty>())?;
+                    Ok(<$ty>::from_be_bytes(bytes.try_into().unwrap()) ^ <$ty>::MIN)
+                }
+            }
+        )+
+    };
+}
+
+impl_signed!(i8, i16, i32, i64, i128);
+
+macro_rules! impl_float {
+    ($($ty:ty => $bits:ty),+) => {
+        $(
+            impl EncodeKey for $ty {
+                fn encode_key_into(&self, output: &mut Vec<u8>) {
+                    const SIGN: $bits = 1 << (<$bits>::BITS - 1);
+                    let bits = self.to_bits();
+                    let bits = if bits & SIGN == 0 { bits | SIGN } else { !bits };
+                    bits.encode_key_into(output);
+                }
+            }
+
+            impl DecodeKey for $ty {
+                fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+                    const SIGN: $bits = 1 << (<$bits>::BITS - 1);
+                    let bits = <$bits>::decode_key_from(input)?;
+                    let bits = if bits & SIGN == 0 { !bits } else { bits ^ SIGN };
+                    Ok(<$ty>::from_bits(bits))
+                }
+            }
+        )+
+    };
+}
+
+impl_float!(f32 => u32, f64 => u64);
+
+impl EncodeKey for bool {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        output.push(u8::from(*self));
+    }
+}
+
+impl DecodeKey for bool {
+    fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+        match u8::decode_key_from(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeKeyError::Invalid("bool")),
+        }
+    }
+}
+
+impl EncodeKey for char {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        u32::from(*self).encode_key_into(output);
+    }
+}
+
+impl DecodeKey for char {
+    fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+        Self::from_u32(u32::decode_key_from(input)?).ok_or(DecodeKeyError::Invalid("char"))
+    }
+}
+
+impl EncodeKey for str {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        encode_escaped(self.as_bytes(), output);
+    }
+}
+
+impl EncodeKey for String {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        encode_escaped(self.as_bytes(), output);
+    }
+}
+
+impl DecodeKey for String {
+    fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+        Self::from_utf8(decode_escaped(input)?.into_owned())
+            .map_err(|_| DecodeKeyError::Invalid("string"))
+    }
+}
+
+impl EncodeKey for [u8] {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        encode_escaped(self, output);
+    }
+}
+
+impl EncodeKey for Vec<u8> {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        encode_escaped(self, output);
+    }
+}
+
+impl DecodeKey for Vec<u8> {
+    fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+        decode_escaped(input).map(Cow::into_owned)
+    }
+}
+
+impl EncodeKey for ArcBytes<'_> {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        encode_escaped(self, output);
+    }
+}
+
+impl DecodeKey for ArcBytes<'static> {
+    fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+        decode_escaped(input).map(|bytes| ArcBytes::from(bytes.into_owned()))
+    }
+}
+
+impl<T: EncodeKey + ?Sized> EncodeKey for &T {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        (**self).encode_key_into(output);
+    }
+}
+
+impl<T: EncodeKey> EncodeKey for Option<T> {
+    fn encode_key_into(&self, output: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                output.push(1);
+                value.encode_key_into(output);
+            }
+            None => output.push(0),
+        }
+    }
+}
+
+impl<T: DecodeKey> DecodeKey for Option<T> {
+    fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+        match u8::decode_key_from(input)? {
+            0 => Ok(None),
+            1 => T::decode_key_from(input).map(Some),
+            _ => Err(DecodeKeyError::Invalid("option")),
+        }
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident: $index:tt),+) => {
+        impl<$($name: EncodeKey),+> EncodeKey for ($($name,)+) {
+            fn encode_key_into(&self, output: &mut Vec<u8>) {
+                $(self.$index.encode_key_into(output);)+
+            }
+        }
+
+        impl<$($name: DecodeKey),+> DecodeKey for ($($name,)+) {
+            fn decode_key_from(input: &mut &[u8]) -> Result<Self, DecodeKeyError> {
+                Ok(($($name::decode_key_from(input)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A: 0);
+impl_tuple!(A: 0, B: 1);
+impl_tuple!(A: 0, B: 1, C: 2);
+impl_tuple!(A: 0, B: 1, C: 2, D: 3);
+impl_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+impl_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+impl_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        tree::{Root, Unversioned},
+        Config,
+    };
+
+    fn assert_ordered<T: EncodeKey + DecodeKey + PartialOrd + Debug>(values: &[T]) {
+        let encoded = values.iter().map(T::encode_key).collect::<Vec<_>>();
+        for (value, encoded) in values.iter().zip(&encoded) {
+            assert_eq!(&T::decode_key(encoded).unwrap(), value);
+        }
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        assert_ordered(&[0_u16, 1, 255, 256, u16::MAX]);
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_ordered(&[f32::NEG_INFINITY, -1.5, -0.0, 1e-9, 1.5, f32::INFINITY]);
+        assert_ordered(&[
+            String::new(),
+            String::from("\0"),
+            String::from("\0\0"),
+            String::from("a"),
+            String::from("a\0b"),
+            String::from("ab"),
+        ]);
+        assert_ordered(&[vec![], vec![0_u8], vec![0, 255], vec![1]]);
+        assert_ordered(&[None, Some(false), Some(true)]);
+        assert_ordered(&[
+            (String::from("a"), -1_i32, 'z'),
+            (String::from("a"), 0, 'a'),
+            (String::from("ab"), i32::MIN, 'a'),
+        ]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            u32::decode_key(&[0, 0]),
+            Err(DecodeKeyError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            u8::decode_key(&[0, 0]),
+            Err(DecodeKeyError::TrailingBytes)
+        ));
+        assert!(matches!(
+            Vec::<u8>::decode_key(&[1, 0, 1]),
+            Err(DecodeKeyError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn prefix_ranges() {
+        let range = PrefixRange::new(vec![1, 255]);
+        assert_eq!(range.bounds().1, Bound::Excluded(&[2_u8][..]));
+        let range = PrefixRange::new(vec![255, 255]);
+        assert_eq!(range.bounds().1, Bound::Unbounded);
+
+        let temp = tempdir().unwrap();
+        let roots = Config::default_for(temp.path()).open().unwrap();
+        let tree = roots.tree(Unversioned::tree("keys")).unwrap();
+        for user in ["a", "a\0", "ab", "b"] {
+            for id in [-1_i64, 0, 1] {
+                tree.set((user, id).encode_key(), b"".to_vec()).unwrap();
+            }
+        }
+
+        // Keys beginning with ("a",) must not include keys for "a\0" or "ab".
+        let range = PrefixRange::for_key(&("a",));
+        let keys = tree
+            .get_range(&range.bounds())
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| <(String, i64)>::decode_key(&key).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                (String::from("a"), -1),
+                (String::from("a"), 0),
+                (String::from("a"), 1),
+            ]
+        );
+    }
+}
@@ -19,25 +19,35 @@
 #[macro_use]
 pub mod io;
 mod error;
+pub mod keys;
 mod roots;
 pub mod transaction;
 pub mod tree;
+/// Typed trees with serde-based key and value codecs.
+#[cfg(feature = "serde")]
+pub mod typed;
 mod vault;
 
 mod chunk_cache;
 mod context;
+mod metrics;
 #[cfg(test)]
 mod test_util;
 
 pub use arc_bytes::ArcBytes;
 
 pub use self::{
-    chunk_cache::{CacheEntry, ChunkCache},
+    chunk_cache::{CacheEntry, ChunkCache, ChunkCacheStats},
     context::Context,
     error::{Error, ErrorKind, InternalError},
+    metrics::Metrics,
     roots::{
-        AbortError, CompareAndSwapError, Config, ExecutingTransaction, LockedTransactionTree,
-        Roots, ThreadPool, TransactionTree, Tree, UnlockedTransactionTree,
+        AbortError, CacheWarmer, ChunkLocation, CompareAndSwapError, Config, ExecutingTransaction,
+        ExpirationReaper, LockedTransactionTree, Roots, ThreadPool, TransactionTree, Tree,
+        UnlockedTransactionTree,
     },
     vault::{AnyVault, Vault},
 };
+
+#[cfg(feature = "encryption")]
+pub use self::vault::{ChaChaVault, ChaChaVaultError};
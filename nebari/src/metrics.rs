@@ -0,0 +1,41 @@
+use std::{fmt::Debug, path::Path, time::Duration};
+
+/// Receives measurements of operations performed by a database.
+///
+/// Every function has a default implementation that ignores the measurement,
+/// allowing implementors to only handle the measurements they are interested
+/// in. Functions are called on the thread performing the operation, often while
+/// locks are held, so implementations should avoid blocking. Forwarding each
+/// measurement to a metrics library's counters and histograms is the intended
+/// use.
+///
+/// Metrics are enabled using [`Config::metrics()`](crate::Config::metrics) or
+/// [`Context::with_metrics()`](crate::Context::with_metrics).
+pub trait Metrics: Debug + Send + Sync + 'static {
+    /// A transaction affecting `trees` trees was committed. `duration` is the
+    /// time spent writing the trees and the transaction log.
+    fn transaction_committed(&self, _trees: usize, _duration: Duration) {}
+
+    /// A batch of `transactions` transactions was written to the transaction
+    /// log in `duration`. `queued` is the number of batches that were waiting
+    /// to be written when this batch was started.
+    fn transaction_batch_written(&self, _transactions: usize, _queued: usize, _duration: Duration) {
+    }
+
+    /// A transaction waited `duration` to acquire the locks of the trees it
+    /// affects.
+    fn transaction_lock_waited(&self, _duration: Duration) {}
+
+    /// The file at `path` was synchronized to disk in `duration`.
+    fn file_synchronized(&self, _path: &Path, _duration: Duration) {}
+
+    /// `bytes` bytes were written to the tree file at `path`.
+    fn tree_bytes_written(&self, _path: &Path, _bytes: u64) {}
+
+    /// The tree file at `path` was compacted in `duration`.
+    fn tree_compacted(&self, _path: &Path, _duration: Duration) {}
+
+    /// A chunk was looked up in the [`ChunkCache`](crate::ChunkCache). `hit` is
+    /// true if the chunk was cached.
+    fn cache_lookup(&self, _hit: bool) {}
+}
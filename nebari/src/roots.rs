@@ -4,16 +4,16 @@ use std::{
     collections::HashMap,
     convert::Infallible,
     fmt::{Debug, Display},
-    fs,
-    ops::{Deref, DerefMut, RangeBounds},
+    ops::{Bound, Deref, DerefMut, RangeBounds},
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU16, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
-use flume::Sender;
+use flume::{RecvTimeoutError, Sender};
 use once_cell::sync::Lazy;
 use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 
@@ -23,15 +23,17 @@ use crate::{
     io::{fs::StdFileManager, FileManager, ManagedFile, PathId},
     transaction::{LogEntry, ManagedTransaction, TransactionId, TransactionManager},
     tree::{
-        self,
+        self, index_tree_prefix, is_index_tree,
         root::{AnyReducer, AnyTreeRoot},
         state::AnyTreeState,
-        EmbeddedIndex, KeySequence, Modification, ModificationResult, Operation, PersistenceMode,
-        ScanEvaluation, SequenceEntry, SequenceId, SequenceIndex, State, TransactableCompaction,
-        TreeEntry, TreeFile, TreeRoot, TreeValueIndex, VersionedTreeRoot,
+        BatchConflict, EarliestExpiration, EmbeddedIndex, Expiration, IndexChanges, KeyComparator,
+        KeySequence, MergeOperator, Modification, ModificationResult, Operation, PersistenceMode,
+        RangeEstimate, ScanEvaluation, SecondaryIndex, SecondaryIndexEntry, SequenceEntry,
+        SequenceId, SequenceIndex, State, TransactableCompaction, TreeEntry, TreeFile, TreeRoot,
+        TreeValueIndex, Unversioned, VersionedTreeRoot, WriteBatch,
     },
-    vault::AnyVault,
-    ArcBytes, ChunkCache, ErrorKind,
+    vault::{AnyVault, VaultKeyring},
+    ArcBytes, ChunkCache, ErrorKind, Metrics,
 };
 
 /// A multi-tree transactional B-Tree database.
@@ -43,37 +45,45 @@ pub struct Roots<File: ManagedFile> {
 #[derive(Debug)]
 struct Data<File: ManagedFile> {
     context: Context<File::Manager>,
+    keyring: Option<Arc<VaultKeyring>>,
     transactions: TransactionManager<File::Manager>,
     thread_pool: ThreadPool<File>,
     path: PathBuf,
     tree_states: Mutex<HashMap<String, Box<dyn AnyTreeState>>>,
+    tree_roots: Mutex<HashMap<String, OpenedRoot<File>>>,
     tree_paths: Mutex<HashMap<String, PathId>>,
 }
 
+/// The root a tree was first opened with, which allows the tree to be
+/// processed without knowing its root type.
+struct OpenedRoot<File: ManagedFile>(Box<dyn AnyTreeRoot<File> + Send + Sync>);
+
+impl<File: ManagedFile> Debug for OpenedRoot<File> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("OpenedRoot").field(&self.0.name()).finish()
+    }
+}
+
 impl<File: ManagedFile> Roots<File> {
     fn open<P: Into<PathBuf> + Send>(
         path: P,
         context: Context<File::Manager>,
+        keyring: Option<Arc<VaultKeyring>>,
         thread_pool: ThreadPool<File>,
     ) -> Result<Self, Error> {
         let path = path.into();
-        if !path.exists() {
-            fs::create_dir_all(&path)?;
-        } else if !path.is_dir() {
-            return Err(Error::from(format!(
-                "'{:?}' already exists, but is not a directory.",
-                path
-            )));
-        }
+        context.file_manager.create_directory(&path)?;
 
         let transactions = TransactionManager::spawn(&path, context.clone())?;
         Ok(Self {
             data: Arc::new(Data {
                 context,
+                keyring,
                 path,
                 transactions,
                 thread_pool,
                 tree_states: Mutex::default(),
+                tree_roots: Mutex::default(),
                 tree_paths: Mutex::default(),
             }),
         })
@@ -100,26 +110,66 @@ impl<File: ManagedFile> Roots<File> {
     ///
     /// ## Errors
     ///
-    /// - [`InvalidTreeName`](ErrorKind::InvalidTreeName): The name contained an
-    ///   invalid character. For a full list of valid characters, see the
+    /// - [`InvalidTreeName`](ErrorKind::InvalidTreeName): The name, or the name
+    ///   of one of the tree's secondary indexes, contained an invalid
+    ///   character. For a full list of valid characters, see the
     ///   documentation on [`InvalidTreeName`](ErrorKind::InvalidTreeName).
     pub fn tree<Root: tree::Root>(
         &self,
         root: TreeRoot<Root, File>,
     ) -> Result<Tree<Root, File>, Error> {
         check_name(&root.name)?;
+        let index_trees = root.secondary_index_trees();
+        for index_tree in &index_trees {
+            check_name(index_tree.name())?;
+        }
         let path = self.tree_path(&root.name);
         if !self.context().file_manager.exists(&path)? {
             self.context().file_manager.append(&path)?;
         }
         let state = self.tree_state(root.clone());
+        // Register the index trees so that they are known to be opened when
+        // every tree must be processed.
+        self.tree_states::<_, dyn AnyTreeRoot<File>>(&index_trees);
+        {
+            // A previous attempt to open this tree may have used a different
+            // comparator.
+            let mut active_state = state.lock();
+            if !active_state.initialized() {
+                active_state.root.set_comparator(root.comparator.clone());
+            }
+            if root.merge_operator.is_some() {
+                active_state
+                    .root
+                    .set_merge_operator(root.merge_operator.clone());
+            }
+        }
+        // Load the tree's header now so that an incompatible vault is reported
+        // when the tree is opened rather than when it is first used.
+        let context = root.vault.as_ref().map_or_else(
+            || Cow::Borrowed(self.context()),
+            |vault| Cow::Owned(self.context().clone().with_any_vault(vault.clone())),
+        );
+        TreeFile::<Root, File>::initialize_state(
+            &state,
+            &path,
+            &context,
+            Some(self.transactions()),
+        )?;
+        root.comparator
+            .check_matches(state.lock().root.comparator().as_ref())
+            .map_err(|err| err.with_tree(&root.name))?;
         Ok(Tree {
             roots: self.clone(),
             path,
             state,
             vault: root.vault,
             reducer: root.reducer,
+            comparator: root.comparator,
+            merge_operator: root.merge_operator,
+            indexes: root.indexes,
             name: root.name,
+            cache_reads: true,
         })
     }
 
@@ -138,23 +188,42 @@ impl<File: ManagedFile> Roots<File> {
         }
     }
 
-    /// Removes a tree. Returns true if a tree was deleted.
+    /// Removes a tree, along with the companion trees storing its secondary
+    /// indexes. Returns true if a tree was deleted.
     pub fn delete_tree(&self, name: impl Into<Cow<'static, str>>) -> Result<bool, Error> {
         let name = name.into();
+        let index_prefix = index_tree_prefix(&name);
+        for index_tree in self
+            .stored_tree_names()?
+            .into_iter()
+            .filter(|stored| stored.starts_with(&index_prefix))
+        {
+            self.delete_stored_tree(&index_tree)?;
+        }
+        self.delete_stored_tree(&name)
+    }
+
+    fn delete_stored_tree(&self, name: &str) -> Result<bool, Error> {
         let mut tree_states = self.data.tree_states.lock();
-        self.context()
-            .file_manager
-            .delete(self.tree_path(name.as_ref()))?;
-        Ok(tree_states.remove(name.as_ref()).is_some())
+        self.context().file_manager.delete(self.tree_path(name))?;
+        self.data.tree_roots.lock().remove(name);
+        Ok(tree_states.remove(name).is_some())
     }
 
     /// Returns a list of all the names of trees contained in this database.
+    /// The companion trees storing secondary indexes are not included.
     pub fn tree_names(&self) -> Result<Vec<String>, Error> {
+        let mut names = self.stored_tree_names()?;
+        names.retain(|name| !is_index_tree(name));
+        Ok(names)
+    }
+
+    /// Returns the names of every tree stored in this database, including the
+    /// companion trees storing secondary indexes.
+    fn stored_tree_names(&self) -> Result<Vec<String>, Error> {
         let mut names = Vec::new();
-        // TODO use the file manager
-        for entry in std::fs::read_dir(self.path())? {
-            let entry = entry?;
-            if let Some(name) = entry.file_name().to_str() {
+        for path in self.context().file_manager.list(self.path())? {
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
                 if let Some(without_extension) = name.strip_suffix(".nebari") {
                     names.push(without_extension.to_string());
                 }
@@ -180,17 +249,250 @@ impl<File: ManagedFile> Roots<File> {
         trees: &[R],
     ) -> Vec<Box<dyn AnyTreeState>> {
         let mut tree_states = self.data.tree_states.lock();
+        let mut tree_roots = self.data.tree_roots.lock();
         let mut output = Vec::with_capacity(trees.len());
         for tree in trees {
+            let tree = tree.borrow();
             let state = tree_states
-                .entry(tree.borrow().name().to_string())
-                .or_insert_with(|| tree.borrow().default_state())
+                .entry(tree.name().to_string())
+                .or_insert_with(|| tree.default_state())
                 .cloned();
+            tree_roots
+                .entry(tree.name().to_string())
+                .or_insert_with(|| OpenedRoot(tree.boxed_root()));
             output.push(state);
         }
+        drop(tree_roots);
+        drop(tree_states);
         output
     }
 
+    /// Re-encrypts this database using `vault`.
+    ///
+    /// Every tree in this database is compacted, which rewrites each of its
+    /// live chunks encrypted by `vault`. The transaction log is then rewritten
+    /// the same way. Once complete, the previous vault is no longer used.
+    /// Trees that use their own vault via [`TreeRoot::with_vault()`] are
+    /// compacted with that vault and are not re-encrypted.
+    ///
+    /// Because compacting a tree requires knowing its root type, each tree
+    /// stored in this database must have been opened by this instance, using
+    /// [`Self::tree()`] or [`Self::transaction()`], before this function is
+    /// called.
+    ///
+    /// If rotation is interrupted, the database may contain data encrypted by
+    /// either vault. Open it using `vault` and provide the previous vault using
+    /// [`Config::previous_vault()`], then call this function again with `vault`
+    /// to finish rotating.
+    ///
+    /// ## Errors
+    ///
+    /// - `vault` does not have a [key id](crate::Vault::key_id), or its key id
+    ///   is already used by a previous vault.
+    /// - This database was not opened with a vault.
+    /// - [`TreeNotOpened`](ErrorKind::TreeNotOpened): A tree stored in this
+    ///   database has not been opened.
+    pub fn rotate_vault<V: AnyVault>(&self, vault: V) -> Result<(), Error> {
+        let keyring = self.keyring()?;
+        let _rotation = keyring.lock_rotation();
+        // Verify every tree can be compacted before the keyring is changed.
+        self.opened_roots()?;
+        keyring.rotate_to(Arc::new(vault))?;
+        self.reencrypt(keyring)
+    }
+
+    /// Encrypts this database using the vault it was opened with.
+    ///
+    /// This is used to begin using a vault with a database that was written
+    /// without one. Open the database with the vault and
+    /// [`Config::allow_unencrypted_data()`], and call this function. The
+    /// database can be used normally while it is being encrypted.
+    ///
+    /// Every tree in this database is compacted, which rewrites each of its
+    /// live chunks encrypted by the vault. The transaction log is then
+    /// rewritten the same way. As with [`Self::rotate_vault()`], each tree
+    /// stored in this database must have been opened by this instance before
+    /// this function is called. Once complete, the database no longer contains
+    /// unencrypted data, and can be opened without
+    /// [`Config::allow_unencrypted_data()`].
+    ///
+    /// Each tree and the transaction log are replaced atomically. If
+    /// encrypting is interrupted, open the database the same way and call this
+    /// function again to finish.
+    ///
+    /// ## Errors
+    ///
+    /// - This database was not opened with a vault.
+    /// - [`TreeNotOpened`](ErrorKind::TreeNotOpened): A tree stored in this
+    ///   database has not been opened.
+    pub fn encrypt(&self) -> Result<(), Error> {
+        let keyring = self.keyring()?;
+        let _rotation = keyring.lock_rotation();
+        self.reencrypt(keyring)
+    }
+
+    fn keyring(&self) -> Result<&VaultKeyring, Error> {
+        self.data
+            .keyring
+            .as_deref()
+            .ok_or_else(|| Error::from(ErrorKind::VaultNotConfigured))
+    }
+
+    /// Returns the root each tree in this database was opened with.
+    fn opened_roots(&self) -> Result<Vec<Box<dyn AnyTreeRoot<File> + Send + Sync>>, Error> {
+        let tree_roots = self.data.tree_roots.lock();
+        let roots = self
+            .stored_tree_names()?
+            .into_iter()
+            .map(|name| match tree_roots.get(&name) {
+                Some(root) => Ok(root.0.boxed_root()),
+                None => Err(Error::from(ErrorKind::TreeNotOpened(name))),
+            })
+            .collect();
+        drop(tree_roots);
+        roots
+    }
+
+    /// Compacts every tree and rewrites the transaction log using the
+    /// keyring's current vault, then retires all other vaults.
+    fn reencrypt(&self, keyring: &VaultKeyring) -> Result<(), Error> {
+        // Trees created after the keyring was rotated are already written with
+        // its current vault, but are compacted regardless.
+        let trees = self.opened_roots()?;
+        let states = self.tree_states::<_, dyn AnyTreeRoot<File> + Send + Sync>(&trees);
+        for (tree, state) in trees.iter().zip(states) {
+            match tree.compact(
+                &self.tree_path(tree.name()),
+                state.as_ref(),
+                self.context(),
+                Some(self.transactions()),
+            ) {
+                Err(err) if !err.kind.is_file_not_found() => return Err(err),
+                _ => {}
+            }
+        }
+        self.transactions().rewrite_log()?;
+        keyring.retire_previous();
+        Ok(())
+    }
+
+    /// Returns the location of each chunk of this database's trees that is
+    /// currently in the [`ChunkCache`], ordered as described by
+    /// [`ChunkCache::cached_chunks()`].
+    ///
+    /// Unlike the file ids used by the cache, these locations remain valid
+    /// after the database is closed. This list can be saved before shutting
+    /// down and passed to [`Self::warm_cache()`] once the database is opened
+    /// again.
+    pub fn cached_chunks(&self) -> Result<Vec<ChunkLocation>, Error> {
+        let cache = match self.context().cache() {
+            Some(cache) => cache,
+            None => return Ok(Vec::new()),
+        };
+        let mut trees = HashMap::new();
+        for name in self.stored_tree_names()? {
+            let path = self.tree_path(&name);
+            if let Some(file_id) = self
+                .context()
+                .file_manager
+                .resolve_path(path.path(), false)
+                .and_then(|path| path.id())
+            {
+                trees.insert(file_id, name);
+            }
+        }
+        Ok(cache
+            .cached_chunks()
+            .into_iter()
+            .filter_map(|(file_id, position)| {
+                trees.get(&file_id).map(|tree| ChunkLocation {
+                    tree: tree.clone(),
+                    position,
+                })
+            })
+            .collect())
+    }
+
+    /// Warms this database's [`ChunkCache`] using background threads.
+    ///
+    /// For each tree in `trees`, the chunks in `chunks` that belong to the
+    /// tree are read into the cache, and then the tree's nodes within `levels`
+    /// of its root node are loaded. `chunks` is typically a list saved from
+    /// [`Self::cached_chunks()`] before the database was last closed. Chunks
+    /// for trees not in `trees` are ignored, and chunks that can no longer be
+    /// read are skipped.
+    ///
+    /// The returned [`CacheWarmer`] can be used to wait for the cache to be
+    /// warmed. Dropping it does not stop warming the cache. If this database
+    /// has no cache, no threads are started.
+    pub fn warm_cache<R, T>(
+        &self,
+        trees: Vec<R>,
+        chunks: &[ChunkLocation],
+        levels: usize,
+    ) -> Result<CacheWarmer, Error>
+    where
+        R: Borrow<T> + Send + 'static,
+        T: AnyTreeRoot<File> + ?Sized + 'static,
+    {
+        if self.context().cache().is_none() || trees.is_empty() {
+            return Ok(CacheWarmer {
+                threads: Vec::new(),
+            });
+        }
+
+        let mut positions = HashMap::<&str, Vec<u64>>::new();
+        for chunk in chunks {
+            positions
+                .entry(chunk.tree.as_str())
+                .or_default()
+                .push(chunk.position);
+        }
+
+        let thread_count = trees.len().min(*CPU_COUNT).max(1);
+        let mut batches = (0..thread_count).map(|_| Vec::new()).collect::<Vec<_>>();
+        for (index, tree) in trees.into_iter().enumerate() {
+            let positions = positions.remove(tree.borrow().name()).unwrap_or_default();
+            batches[index % thread_count].push((tree, positions));
+        }
+
+        let mut threads = Vec::with_capacity(thread_count);
+        for batch in batches {
+            let roots = self.clone();
+            threads.push(
+                std::thread::Builder::new()
+                    .name(String::from("roots-cachewarm"))
+                    .spawn(move || roots.warm_trees::<R, T>(batch, levels))?,
+            );
+        }
+        Ok(CacheWarmer { threads })
+    }
+
+    fn warm_trees<R: Borrow<T>, T: AnyTreeRoot<File> + ?Sized>(
+        &self,
+        trees: Vec<(R, Vec<u64>)>,
+        levels: usize,
+    ) -> Result<(), Error> {
+        for (tree, positions) in trees {
+            let tree = tree.borrow();
+            check_name(tree.name())?;
+            let path = self.tree_path(tree.name());
+            if !self.context().file_manager.exists(&path)? {
+                continue;
+            }
+            let state = self.tree_states::<_, T>(&[tree]).pop().unwrap();
+            tree.warm_cache(
+                &path,
+                state.as_ref(),
+                self.context(),
+                Some(self.transactions()),
+                &positions,
+                levels,
+            )?;
+        }
+        Ok(())
+    }
+
     /// Begins a transaction over `trees`. All trees will be exclusively
     /// accessible by the transaction. Dropping the executing transaction will
     /// roll the transaction back.
@@ -200,47 +502,98 @@ impl<File: ManagedFile> Roots<File> {
     /// - [`InvalidTreeName`](ErrorKind::InvalidTreeName): A tree name contained
     ///   an invalid character. For a full list of valid characters, see the
     ///   documentation on [`InvalidTreeName`](ErrorKind::InvalidTreeName).
+    ///
+    /// The companion trees of secondary indexes declared on `trees` are added
+    /// to the transaction after `trees`, unless they are already present.
     pub fn transaction<R: Borrow<T>, T: AnyTreeRoot<File> + ?Sized>(
         &self,
         trees: &[R],
     ) -> Result<ExecutingTransaction<File>, Error> {
-        for tree in trees {
-            check_name(tree.borrow().name()).map(|_| tree.borrow().name().as_bytes())?;
+        let mut index_trees = Vec::<Box<dyn AnyTreeRoot<File>>>::new();
+        for index_tree in trees
+            .iter()
+            .flat_map(|tree| tree.borrow().secondary_index_trees())
+        {
+            if !trees
+                .iter()
+                .map(|tree| tree.borrow().name())
+                .chain(index_trees.iter().map(|tree| tree.name()))
+                .any(|name| name == index_tree.name())
+            {
+                index_trees.push(index_tree);
+            }
+        }
+        let tree_names = trees
+            .iter()
+            .map(|tree| tree.borrow().name())
+            .chain(index_trees.iter().map(|tree| tree.name()))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        for name in &tree_names {
+            check_name(name)?;
         }
         let transaction = self
             .data
             .transactions
-            .new_transaction(trees.iter().map(|t| t.borrow().name().as_bytes()));
+            .new_transaction(tree_names.iter().map(String::as_bytes));
         let states = self.tree_states(trees);
-        let trees = trees
+        let index_states = self.tree_states::<_, dyn AnyTreeRoot<File>>(&index_trees);
+        let mut trees = trees
             .iter()
             .zip(states.into_iter())
             .map(|(tree, state)| {
-                tree.borrow()
-                    .begin_transaction(
-                        transaction.id,
-                        &self.tree_path(tree.borrow().name()),
-                        state.as_ref(),
-                        self.context(),
-                        Some(&self.data.transactions),
-                    )
-                    .map(UnlockedTransactionTree::new)
+                self.begin_tree_transaction(tree.borrow(), state.as_ref(), transaction.id)
             })
             .collect::<Result<Vec<_>, Error>>()?;
+        for (tree, state) in index_trees.iter().zip(index_states) {
+            trees.push(self.begin_tree_transaction(
+                tree.as_ref(),
+                state.as_ref(),
+                transaction.id,
+            )?);
+        }
         Ok(ExecutingTransaction {
             roots: self.clone(),
             transaction: Some(transaction),
             trees,
+            tree_names,
+        })
+    }
+
+    fn begin_tree_transaction<T: AnyTreeRoot<File> + ?Sized>(
+        &self,
+        tree: &T,
+        state: &dyn AnyTreeState,
+        transaction_id: TransactionId,
+    ) -> Result<UnlockedTransactionTree<File>, Error> {
+        tree.begin_transaction(
+            transaction_id,
+            &self.tree_path(tree.name()),
+            state,
+            self.context(),
+            Some(&self.data.transactions),
+        )
+        .map(UnlockedTransactionTree::new)
+        .map_err(|err| {
+            err.with_tree(tree.name())
+                .with_transaction_id(transaction_id)
         })
     }
 }
 
 fn check_name(name: &str) -> Result<(), Error> {
-    if name != "_transactions"
-        && name
-            .bytes()
+    fn is_valid(name: &str) -> bool {
+        name.bytes()
             .all(|c| matches!(c as char, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' | '_'))
-    {
+    }
+
+    // The companion trees storing secondary indexes are named using a
+    // separator that isn't valid within tree or index names.
+    let (name, index) = match name.split_once(tree::INDEX_TREE_SEPARATOR) {
+        Some((name, index)) => (name, Some(index)),
+        None => (name, None),
+    };
+    if name != "_transactions" && is_valid(name) && index.map_or(true, is_valid) {
         Ok(())
     } else {
         Err(Error::from(ErrorKind::InvalidTreeName))
@@ -255,12 +608,68 @@ impl<File: ManagedFile> Clone for Roots<File> {
     }
 }
 
+/// The location of a chunk within a [`Roots`] database.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ChunkLocation {
+    /// The name of the tree the chunk belongs to.
+    pub tree: String,
+    /// The position of the chunk within the tree's file.
+    pub position: u64,
+}
+
+/// A handle to the background threads started by [`Roots::warm_cache()`].
+#[derive(Debug)]
+#[must_use]
+pub struct CacheWarmer {
+    threads: Vec<std::thread::JoinHandle<Result<(), Error>>>,
+}
+
+impl CacheWarmer {
+    /// Waits for the cache to be warmed, returning the first error
+    /// encountered.
+    pub fn wait(self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for thread in self.threads {
+            let thread_result = thread
+                .join()
+                .map_err(|_| Error::from(ErrorKind::ThreadPanicked(String::from("cache warming"))))
+                .and_then(|result| result);
+            if result.is_ok() {
+                result = thread_result;
+            }
+        }
+        result
+    }
+}
+
+/// A handle to the background thread started by
+/// [`Tree::reap_expired_every()`]. Dropping the handle stops the thread.
+#[derive(Debug)]
+#[must_use]
+pub struct ExpirationReaper {
+    stop: Sender<()>,
+    thread: std::thread::JoinHandle<Result<(), Error>>,
+}
+
+impl ExpirationReaper {
+    /// Stops the reaper and waits for its thread to exit, returning the error
+    /// that stopped the reaper, if any.
+    pub fn stop(self) -> Result<(), Error> {
+        drop(self.stop);
+        self.thread
+            .join()
+            .map_err(|_| Error::from(ErrorKind::ThreadPanicked(String::from("expiration reaper"))))
+            .and_then(|result| result)
+    }
+}
+
 /// An executing transaction. While this exists, no other transactions can
 /// execute across the same trees as this transaction holds.
 #[must_use]
 pub struct ExecutingTransaction<File: ManagedFile> {
     roots: Roots<File>,
     trees: Vec<UnlockedTransactionTree<File>>,
+    tree_names: Vec<String>,
     transaction: Option<ManagedTransaction<File::Manager>>,
 }
 
@@ -337,13 +746,25 @@ impl<File: ManagedFile> ExecutingTransaction<File> {
     /// well as impervious to sudden failures such as a power outage.
     #[allow(clippy::missing_panics_doc)]
     pub fn commit(mut self) -> Result<(), Error> {
+        let start = Instant::now();
+        let transaction_id = self.transaction.as_ref().unwrap().id;
+        self.update_secondary_indexes()
+            .map_err(|err| err.with_transaction_id(transaction_id))?;
         let trees = std::mem::take(&mut self.trees);
+        let tree_count = trees.len();
         // Write the trees to disk
-        let trees = self.roots.data.thread_pool.commit_trees(trees)?;
+        let trees = self
+            .roots
+            .data
+            .thread_pool
+            .commit_trees(trees)
+            .map_err(|err| err.with_transaction_id(transaction_id))?;
 
         // Push the transaction to the log.
         let transaction = self.transaction.take().unwrap();
-        let tree_locks = transaction.commit()?;
+        let tree_locks = transaction
+            .commit()
+            .map_err(|err| err.with_transaction_id(transaction_id))?;
 
         // Publish the tree states, now that the transaction has been fully recorded
         for tree in trees {
@@ -353,6 +774,10 @@ impl<File: ManagedFile> ExecutingTransaction<File> {
         // Release the locks for the trees, allowing a new transaction to begin.
         drop(tree_locks);
 
+        if let Some(metrics) = self.roots.context().metrics() {
+            metrics.transaction_committed(tree_count, start.elapsed());
+        }
+
         Ok(())
     }
 
@@ -378,6 +803,27 @@ impl<File: ManagedFile> ExecutingTransaction<File> {
         self.trees.get(index)
     }
 
+    /// Applies the changes made to trees with secondary indexes to the
+    /// indexes' companion trees.
+    fn update_secondary_indexes(&self) -> Result<(), Error> {
+        for tree in &self.trees {
+            let changes = tree.0.lock().secondary_index_changes()?;
+            for changes in changes {
+                let position = self
+                    .tree_position(&changes.tree_name)
+                    .expect("index trees are added to the transaction");
+                changes.apply(&mut self.tree::<Unversioned>(position).unwrap())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tree_position(&self, name: &str) -> Option<usize> {
+        self.tree_names
+            .iter()
+            .position(|tree_name| tree_name == name)
+    }
+
     fn rollback_tree_states(&mut self) {
         for tree in self.trees.drain(..) {
             let tree = tree.0.lock();
@@ -401,6 +847,10 @@ pub struct TransactionTree<Root: tree::Root, File: ManagedFile> {
     pub(crate) transaction_id: TransactionId,
     /// The underlying tree file.
     pub tree: TreeFile<Root, File>,
+    pub(crate) indexes: Vec<SecondaryIndex<Root::Value>>,
+    /// The keys modified within this transaction, which are only tracked if
+    /// the tree has secondary indexes.
+    pub(crate) changed_keys: Vec<ArcBytes<'static>>,
 }
 
 pub trait AnyTransactionTree<File: ManagedFile>: Any + Send + Sync {
@@ -411,6 +861,10 @@ pub trait AnyTransactionTree<File: ManagedFile>: Any + Send + Sync {
 
     fn commit(&mut self) -> Result<(), Error>;
     fn rollback(&self);
+
+    /// Returns the changes to apply to the companion trees of this tree's
+    /// secondary indexes, and clears the list of modified keys.
+    fn secondary_index_changes(&mut self) -> Result<Vec<IndexChanges>, Error>;
 }
 
 impl<Root: tree::Root, File: ManagedFile> AnyTransactionTree<File> for TransactionTree<Root, File> {
@@ -433,6 +887,35 @@ impl<Root: tree::Root, File: ManagedFile> AnyTransactionTree<File> for Transacti
         let mut state = self.tree.state.lock();
         state.rollback(&self.tree.state);
     }
+
+    fn secondary_index_changes(&mut self) -> Result<Vec<IndexChanges>, Error> {
+        let mut keys = std::mem::take(&mut self.changed_keys);
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        keys.sort();
+        keys.dedup();
+
+        let mut changes = self
+            .indexes
+            .iter()
+            .map(|index| IndexChanges {
+                tree_name: index.tree_name.clone(),
+                keys: Vec::with_capacity(keys.len()),
+            })
+            .collect::<Vec<_>>();
+        for key in keys {
+            let value = self.tree.get(&key, true)?;
+            for (index, changes) in self.indexes.iter().zip(&mut changes) {
+                let index_keys = value
+                    .as_ref()
+                    .map(|value| index.index_keys(value))
+                    .unwrap_or_default();
+                changes.keys.push((key.clone(), index_keys));
+            }
+        }
+        Ok(changes)
+    }
 }
 
 impl<File: ManagedFile, Index> TransactionTree<VersionedTreeRoot<Index>, File>
@@ -521,6 +1004,7 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
         key: impl Into<ArcBytes<'static>>,
         value: impl Into<Root::Value>,
     ) -> Result<Root::Index, Error> {
+        let key = self.record_key(key);
         self.tree.set(
             PersistenceMode::Transactional(self.transaction_id),
             key,
@@ -528,12 +1012,52 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
         )
     }
 
+    /// Sets `key` to `value`, which expires once `ttl` has elapsed. See
+    /// [`TreeFile::set_with_ttl()`] for more information.
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<ArcBytes<'static>>,
+        value: impl Into<Root::Value>,
+        ttl: Duration,
+    ) -> Result<Root::Index, Error> {
+        let key = self.record_key(key);
+        self.tree.set_with_ttl(
+            PersistenceMode::Transactional(self.transaction_id),
+            key,
+            value,
+            ttl,
+        )
+    }
+
+    /// Merges `operand` into the value of `key` using the tree's
+    /// [`MergeOperator`]. Returns the updated index for this key, or `None` if
+    /// the merge removed the key.
+    pub fn merge(
+        &mut self,
+        key: impl Into<ArcBytes<'static>>,
+        operand: impl Into<Root::Value>,
+    ) -> Result<Option<Root::Index>, Error> {
+        let key = self.record_key(key);
+        self.tree.merge(
+            PersistenceMode::Transactional(self.transaction_id),
+            key,
+            operand,
+        )
+    }
+
     /// Executes a modification. Returns a list of all changed keys.
     pub fn modify<'a>(
         &mut self,
         keys: Vec<ArcBytes<'a>>,
         operation: Operation<'a, Root::Value, Root::Index>,
     ) -> Result<Vec<ModificationResult<Root::Index>>, Error> {
+        if !self.indexes.is_empty() {
+            if let Some((start, end)) = operation.removed_range() {
+                self.record_range(&(start, end))?;
+            }
+            self.changed_keys
+                .extend(keys.iter().map(|key| key.clone().into_owned()));
+        }
         self.tree.modify(Modification {
             keys,
             persistence_mode: PersistenceMode::Transactional(self.transaction_id),
@@ -541,6 +1065,22 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
         })
     }
 
+    /// Applies each operation in `batch` within a single modification. Returns
+    /// the compare-and-swap operations that were skipped. See
+    /// [`TreeFile::apply_batch()`] for more information.
+    pub fn apply_batch(
+        &mut self,
+        batch: WriteBatch<Root::Value>,
+    ) -> Result<Vec<BatchConflict<Root::Value>>, Error>
+    where
+        Root::Value: PartialEq + AsRef<[u8]> + From<Vec<u8>>,
+    {
+        if !self.indexes.is_empty() {
+            self.changed_keys.extend(batch.keys().cloned());
+        }
+        self.tree.apply_batch(batch, self.transaction_id)
+    }
+
     /// Sets `key` to `value`. Returns a tuple containing two elements:
     ///
     /// - The previously stored value, if a value was already present.
@@ -550,6 +1090,7 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
         key: impl Into<ArcBytes<'static>>,
         value: impl Into<Root::Value>,
     ) -> Result<(Option<Root::Value>, Root::Index), Error> {
+        let key = self.record_key(key);
         self.tree.replace(key, value, self.transaction_id)
     }
 
@@ -573,9 +1114,36 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
 
     /// Removes `key` and returns the existing value amd index, if present.
     pub fn remove(&mut self, key: &[u8]) -> Result<Option<TreeValueIndex<Root>>, Error> {
+        self.record_key(key);
         self.tree.remove(key, self.transaction_id)
     }
 
+    /// Removes all keys contained within `range`, returning the number of keys
+    /// removed. See [`TreeFile::remove_range()`] for more information.
+    pub fn remove_range<'keys, KeyRangeBounds>(
+        &mut self,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<u64, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + ?Sized,
+    {
+        if !self.indexes.is_empty() {
+            self.record_range(&(range.start_bound().cloned(), range.end_bound().cloned()))?;
+        }
+        self.tree.remove_range(range, self.transaction_id)
+    }
+
+    /// Removes all keys whose values have expired, returning the number of keys
+    /// removed. See [`TreeFile::remove_expired()`] for more information.
+    pub fn remove_expired(&mut self) -> Result<usize, Error> {
+        let removed = self.tree.remove_expired_keys(self.transaction_id)?;
+        let count = removed.len();
+        if !self.indexes.is_empty() {
+            self.changed_keys.extend(removed);
+        }
+        Ok(count)
+    }
+
     /// Compares the value of `key` against `old`. If the values match, key will
     /// be set to the new value if `new` is `Some` or removed if `new` is
     /// `None`.
@@ -586,13 +1154,31 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
         new: Option<Root::Value>,
     ) -> Result<(), CompareAndSwapError<Root::Value>>
     where
-        Old: PartialEq,
+        Old: PartialEq + ?Sized,
         Root::Value: AsRef<Old> + Clone,
     {
+        self.record_key(key);
         self.tree
             .compare_and_swap(key, old, new, self.transaction_id)
     }
 
+    /// Records `key` as modified if this tree has secondary indexes.
+    fn record_key<'a>(&mut self, key: impl Into<ArcBytes<'a>>) -> ArcBytes<'a> {
+        let key = key.into();
+        if !self.indexes.is_empty() {
+            self.changed_keys.push(key.clone().into_owned());
+        }
+        key
+    }
+
+    /// Records every key contained within `range` as modified.
+    fn record_range(&mut self, range: &(Bound<&[u8]>, Bound<&[u8]>)) -> Result<(), Error> {
+        let keys = self.tree.get_range_indexes(range, true)?;
+        self.changed_keys
+            .extend(keys.into_iter().map(|(key, _index)| key));
+        Ok(())
+    }
+
     /// Retrieves the values of `keys`. If any keys are not found, they will be
     /// omitted from the results. Keys are required to be pre-sorted.
     pub fn get_multiple<'keys, KeysIntoIter, KeysIter>(
@@ -720,6 +1306,40 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
         )
     }
 
+    /// Retrieves all of the values of keys that begin with `prefix`.
+    pub fn get_prefix(
+        &mut self,
+        prefix: &[u8],
+    ) -> Result<Vec<(ArcBytes<'static>, Root::Value)>, Error> {
+        self.tree.get_prefix(prefix, true)
+    }
+
+    /// Scans the tree for keys that begin with `prefix`. See
+    /// [`TreeFile::scan_prefix()`] for more information.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, key_evaluator, callback))
+    )]
+    pub fn scan_prefix<CallerError, KeyEvaluator, DataCallback>(
+        &mut self,
+        prefix: &[u8],
+        forwards: bool,
+        mut key_evaluator: KeyEvaluator,
+        mut callback: DataCallback,
+    ) -> Result<(), AbortError<CallerError>>
+    where
+        KeyEvaluator: FnMut(&ArcBytes<'static>, &Root::Index) -> ScanEvaluation,
+        DataCallback: FnMut(
+            ArcBytes<'static>,
+            &Root::Index,
+            Root::Value,
+        ) -> Result<(), AbortError<CallerError>>,
+        CallerError: Display + Debug,
+    {
+        self.tree
+            .scan_prefix(prefix, forwards, true, &mut key_evaluator, &mut callback)
+    }
+
     /// Returns the reduced index over the provided range. This is an
     /// aggregation function that builds atop the `scan()` operation which calls
     /// [`Reducer::reduce()`](crate::tree::Reducer::reduce) and
@@ -741,6 +1361,57 @@ impl<Root: tree::Root, File: ManagedFile> TransactionTree<Root, File> {
         self.tree.reduce(range, true)
     }
 
+    /// Returns the number of keys contained within `range`. See
+    /// [`TreeFile::count_range()`] for more information.
+    pub fn count_range<'keys, KeyRangeBounds>(
+        &mut self,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<u64, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+    {
+        self.tree.count_range(range, true)
+    }
+
+    /// Returns the number of keys ordered before `key`. See
+    /// [`TreeFile::rank()`] for more information.
+    pub fn rank(&mut self, key: &[u8]) -> Result<u64, Error> {
+        self.tree.rank(key, true)
+    }
+
+    /// Returns the key whose rank is `n`. See [`TreeFile::nth()`] for more
+    /// information.
+    pub fn nth(&mut self, n: u64) -> Result<Option<ArcBytes<'static>>, Error> {
+        self.tree.nth(n, true)
+    }
+
+    /// Returns the approximate number of keys and value bytes contained within
+    /// `range`. See [`TreeFile::estimate_range()`] for more information.
+    pub fn estimate_range<'keys, KeyRangeBounds>(
+        &mut self,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<RangeEstimate, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+    {
+        self.tree.estimate_range(range, true)
+    }
+
+    /// Returns up to `count` entries chosen uniformly at random from the keys
+    /// contained within `range`. See [`TreeFile::sample()`] for more
+    /// information.
+    #[cfg(feature = "sampling")]
+    pub fn sample<'keys, KeyRangeBounds>(
+        &mut self,
+        count: usize,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<Vec<(ArcBytes<'static>, Root::Value)>, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+    {
+        self.tree.sample(count, range, true)
+    }
+
     /// Returns the first key of the tree.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
     pub fn first_key(&mut self) -> Result<Option<ArcBytes<'static>>, Error> {
@@ -783,7 +1454,10 @@ pub enum CompareAndSwapError<Value: Debug> {
 pub struct Config<M: FileManager = StdFileManager> {
     path: PathBuf,
     vault: Option<Arc<dyn AnyVault>>,
+    previous_vaults: Vec<Arc<dyn AnyVault>>,
+    allow_unencrypted_data: bool,
     cache: Option<ChunkCache>,
+    metrics: Option<Arc<dyn Metrics>>,
     file_manager: Option<M>,
     thread_pool: Option<ThreadPool<M::File>>,
 }
@@ -793,7 +1467,10 @@ impl<M: FileManager> Clone for Config<M> {
         Self {
             path: self.path.clone(),
             vault: self.vault.clone(),
+            previous_vaults: self.previous_vaults.clone(),
+            allow_unencrypted_data: self.allow_unencrypted_data,
             cache: self.cache.clone(),
+            metrics: self.metrics.clone(),
             file_manager: self.file_manager.clone(),
             thread_pool: self.thread_pool.clone(),
         }
@@ -806,7 +1483,10 @@ impl Config<StdFileManager> {
         Self {
             path: path.as_ref().to_path_buf(),
             vault: None,
+            previous_vaults: Vec::new(),
+            allow_unencrypted_data: false,
             cache: None,
+            metrics: None,
             thread_pool: None,
             file_manager: None,
         }
@@ -817,7 +1497,10 @@ impl Config<StdFileManager> {
         Self {
             path: path.as_ref().to_path_buf(),
             vault: None,
-            cache: Some(ChunkCache::new(2000, 65536)),
+            previous_vaults: Vec::new(),
+            allow_unencrypted_data: false,
+            cache: Some(ChunkCache::with_byte_capacity(64 * 1024 * 1024, 65536)),
+            metrics: None,
             thread_pool: Some(ThreadPool::default()),
             file_manager: None,
         }
@@ -833,7 +1516,10 @@ impl Config<StdFileManager> {
         Config {
             path: self.path,
             vault: self.vault,
+            previous_vaults: self.previous_vaults,
+            allow_unencrypted_data: self.allow_unencrypted_data,
             cache: self.cache,
+            metrics: self.metrics,
             file_manager: Some(file_manager),
             thread_pool: None,
         }
@@ -847,12 +1533,46 @@ impl<M: FileManager> Config<M> {
         self
     }
 
+    /// Adds a vault that is only used to decrypt data encrypted by a previous
+    /// key. This is necessary to open a database whose
+    /// [key rotation](Roots::rotate_vault) was interrupted. Has no effect
+    /// unless [`Config::vault()`] is also set.
+    pub fn previous_vault<V: AnyVault>(mut self, vault: V) -> Self {
+        self.previous_vaults.push(Arc::new(vault));
+        self
+    }
+
+    /// Allows data that was written without a vault to be read. This is
+    /// necessary to open a database written without a vault so that it can be
+    /// [encrypted](Roots::encrypt). Has no effect unless a vault is used.
+    ///
+    /// Data written while this is set is always encrypted. Each tree records
+    /// whether it contains data written without a vault, and trees that do
+    /// cannot be opened with a vault unless this is set. Trees written by
+    /// versions that did not record this are assumed to have been written
+    /// without a vault when this is set, and are decrypted by the vault
+    /// otherwise.
+    ///
+    /// The transaction log records whether each entry was written with a
+    /// vault in the same way. Entries written by versions that did not record
+    /// this are treated the same way as trees.
+    pub const fn allow_unencrypted_data(mut self) -> Self {
+        self.allow_unencrypted_data = true;
+        self
+    }
+
     /// Sets the chunk cache to use for this database.
     pub fn cache(mut self, cache: ChunkCache) -> Self {
         self.cache = Some(cache);
         self
     }
 
+    /// Sets the receiver of this database's [`Metrics`].
+    pub fn metrics<T: Metrics>(mut self, metrics: T) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
     /// Uses the `thread_pool` provided instead of creating its own. This will
     /// allow a single thread pool to manage multiple [`Roots`] instances'
     /// transactions.
@@ -863,13 +1583,24 @@ impl<M: FileManager> Config<M> {
 
     /// Opens the database, or creates one if the target path doesn't exist.
     pub fn open(self) -> Result<Roots<M::File>, Error> {
+        let previous_vaults = self.previous_vaults;
+        let keyring = self
+            .vault
+            .map(|vault| Arc::new(VaultKeyring::new(vault, previous_vaults)));
+        let mut context = Context {
+            file_manager: self.file_manager.unwrap_or_default(),
+            vault: keyring.clone().map(|keyring| keyring as Arc<dyn AnyVault>),
+            cache: self.cache,
+            metrics: None,
+            allow_unencrypted_data: self.allow_unencrypted_data,
+        };
+        if let Some(metrics) = self.metrics {
+            context = context.with_any_metrics(metrics);
+        }
         Roots::open(
             self.path,
-            Context {
-                file_manager: self.file_manager.unwrap_or_default(),
-                vault: self.vault,
-                cache: self.cache,
-            },
+            context,
+            keyring,
             self.thread_pool.unwrap_or_default(),
         )
     }
@@ -881,8 +1612,12 @@ pub struct Tree<Root: tree::Root, File: ManagedFile> {
     path: PathId,
     state: State<Root>,
     reducer: Arc<dyn AnyReducer>,
+    comparator: Arc<dyn KeyComparator>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    indexes: Vec<SecondaryIndex<Root::Value>>,
     vault: Option<Arc<dyn AnyVault>>,
     name: Cow<'static, str>,
+    cache_reads: bool,
 }
 
 impl<Root: tree::Root, File: ManagedFile> Clone for Tree<Root, File> {
@@ -893,7 +1628,11 @@ impl<Root: tree::Root, File: ManagedFile> Clone for Tree<Root, File> {
             state: self.state.clone(),
             vault: self.vault.clone(),
             reducer: self.reducer.clone(),
+            comparator: self.comparator.clone(),
+            merge_operator: self.merge_operator.clone(),
+            indexes: self.indexes.clone(),
             name: self.name.clone(),
+            cache_reads: self.cache_reads,
         }
     }
 }
@@ -925,24 +1664,96 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         key: impl Into<ArcBytes<'static>>,
         value: impl Into<Root::Value>,
     ) -> Result<(), Error> {
-        let transaction = self.begin_transaction()?;
-        transaction.tree::<Root>(0).unwrap().set(key, value)?;
-        transaction.commit()
+        self.execute_in_transaction(|tree| tree.set(key, value).map(|_| ()))
     }
 
-    fn begin_transaction(&self) -> Result<ExecutingTransaction<File>, Error> {
-        let reducer = self
-            .reducer
-            .as_ref()
-            .as_any()
-            .downcast_ref::<Root::Reducer>()
-            .unwrap()
+    /// Sets `key` to `value`, which expires once `ttl` has elapsed. This is
+    /// executed within its own transaction. See [`TreeFile::set_with_ttl()`]
+    /// for more information.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn set_with_ttl(
+        &self,
+        key: impl Into<ArcBytes<'static>>,
+        value: impl Into<Root::Value>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.execute_in_transaction(|tree| tree.set_with_ttl(key, value, ttl).map(|_| ()))
+    }
+
+    /// Merges `operand` into the value of `key` using the tree's
+    /// [`MergeOperator`]. This is executed within its own transaction.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn merge(
+        &self,
+        key: impl Into<ArcBytes<'static>>,
+        operand: impl Into<Root::Value>,
+    ) -> Result<(), Error> {
+        self.execute_in_transaction(|tree| tree.merge(key, operand).map(|_| ()))
+    }
+
+    /// Executes `operation` on this tree within its own transaction, committing
+    /// the transaction if `operation` succeeds.
+    fn execute_in_transaction<
+        T,
+        F: FnOnce(&mut TransactionTree<Root, File>) -> Result<T, Error>,
+    >(
+        &self,
+        operation: F,
+    ) -> Result<T, Error> {
+        let result = self.begin_transaction().and_then(|transaction| {
+            let result = operation(&mut transaction.tree::<Root>(0).unwrap())?;
+            transaction.commit()?;
+            Ok(result)
+        });
+        result.map_err(|err| err.with_tree(&self.name))
+    }
+
+    fn begin_transaction(&self) -> Result<ExecutingTransaction<File>, Error> {
+        self.roots.transaction(&[self.root()])
+    }
+
+    /// Returns a [`TreeRoot`] that opens this tree the same way it was opened.
+    fn root(&self) -> TreeRoot<Root, File> {
+        let reducer = self
+            .reducer
+            .as_ref()
+            .as_any()
+            .downcast_ref::<Root::Reducer>()
+            .unwrap()
             .clone();
         let mut root = Root::tree_with_reducer(self.name.clone(), reducer);
-        if let Some(vault) = &self.vault {
-            root.vault = Some(vault.clone());
+        root.comparator = self.comparator.clone();
+        root.merge_operator.clone_from(&self.merge_operator);
+        root.indexes.clone_from(&self.indexes);
+        root.vault.clone_from(&self.vault);
+        root
+    }
+
+    /// Returns a handle to this tree whose reads do not add chunks to the
+    /// [`ChunkCache`]. Chunks that are already cached are still used.
+    ///
+    /// This is useful for large scans, such as exporting or reporting on a
+    /// large range of keys, that would otherwise evict frequently read chunks
+    /// from the cache.
+    #[must_use]
+    pub fn without_caching_reads(&self) -> Self {
+        let mut tree = self.clone();
+        tree.cache_reads = false;
+        tree
+    }
+
+    fn context(&self) -> Cow<'_, Context<File::Manager>> {
+        let mut context = self.vault.as_ref().map_or_else(
+            || Cow::Borrowed(self.roots.context()),
+            |vault| Cow::Owned(self.roots.context().clone().with_any_vault(vault.clone())),
+        );
+        if !self.cache_reads {
+            if let Some(cache) = context.cache() {
+                let cache = cache.without_admission();
+                context = Cow::Owned(context.into_owned().with_cache(cache));
+            }
         }
-        self.roots.transaction(&[root])
+        context
     }
 
     /// Returns a [`TreeFile`] for lower-level operations within the context of
@@ -954,10 +1765,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// implementing custom roots and wish to expose functionality through
     /// Roots.
     pub fn open_for_read(&self) -> Result<TreeFile<Root, File>, Error> {
-        let context = self.vault.as_ref().map_or_else(
-            || Cow::Borrowed(self.roots.context()),
-            |vault| Cow::Owned(self.roots.context().clone().with_any_vault(vault.clone())),
-        );
+        let context = self.context();
 
         TreeFile::<Root, File>::read(
             &self.path,
@@ -976,10 +1784,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// implementing custom roots and wish to expose functionality through
     /// Roots.
     pub fn open_for_write(&self) -> Result<TreeFile<Root, File>, Error> {
-        let context = self.vault.as_ref().map_or_else(
-            || Cow::Borrowed(self.roots.context()),
-            |vault| Cow::Owned(self.roots.context().clone().with_any_vault(vault.clone())),
-        );
+        let context = self.context();
 
         TreeFile::<Root, File>::write(
             &self.path,
@@ -992,7 +1797,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// Retrieves the current value of `key`, if present. Does not reflect any
     /// changes in pending transactions.
     pub fn get(&self, key: &[u8]) -> Result<Option<Root::Value>, Error> {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1006,7 +1811,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// Retrieves the current index of `key`, if present. Does not reflect any
     /// changes in pending transactions.
     pub fn get_index(&self, key: &[u8]) -> Result<Option<Root::Index>, Error> {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1020,7 +1825,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// Retrieves the current value and index of `key`, if present. Does not reflect any
     /// changes in pending transactions.
     pub fn get_with_index(&self, key: &[u8]) -> Result<Option<TreeValueIndex<Root>>, Error> {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1041,10 +1846,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         key: impl Into<ArcBytes<'static>>,
         value: impl Into<Root::Value>,
     ) -> Result<(Option<Root::Value>, Root::Index), Error> {
-        let transaction = self.begin_transaction()?;
-        let existing_value = transaction.tree::<Root>(0).unwrap().replace(key, value)?;
-        transaction.commit()?;
-        Ok(existing_value)
+        self.execute_in_transaction(|tree| tree.replace(key, value))
     }
 
     /// Executes a modification. Returns a list of all changed keys.
@@ -1054,23 +1856,87 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         keys: Vec<ArcBytes<'a>>,
         operation: Operation<'a, Root::Value, Root::Index>,
     ) -> Result<Vec<ModificationResult<Root::Index>>, Error> {
-        let transaction = self.begin_transaction()?;
-        let results = transaction
-            .tree::<Root>(0)
-            .unwrap()
-            .modify(keys, operation)?;
-        transaction.commit()?;
-        Ok(results)
+        self.execute_in_transaction(|tree| tree.modify(keys, operation))
+    }
+
+    /// Applies each operation in `batch` within a single modification and
+    /// commits it in its own transaction. Returns the compare-and-swap
+    /// operations that were skipped. See [`TreeFile::apply_batch()`] for more
+    /// information.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn apply_batch(
+        &self,
+        batch: WriteBatch<Root::Value>,
+    ) -> Result<Vec<BatchConflict<Root::Value>>, Error>
+    where
+        Root::Value: PartialEq + AsRef<[u8]> + From<Vec<u8>>,
+    {
+        self.execute_in_transaction(|tree| tree.apply_batch(batch))
     }
 
     /// Removes `key` and returns the existing value and index, if present. This
     /// is executed within its own transaction.
     #[allow(clippy::missing_panics_doc)]
     pub fn remove(&self, key: &[u8]) -> Result<Option<TreeValueIndex<Root>>, Error> {
-        let transaction = self.begin_transaction()?;
-        let existing_value = transaction.tree::<Root>(0).unwrap().remove(key)?;
-        transaction.commit()?;
-        Ok(existing_value)
+        self.execute_in_transaction(|tree| tree.remove(key))
+    }
+
+    /// Removes all keys contained within `range`, returning the number of keys
+    /// removed. This is executed within its own transaction. See
+    /// [`TreeFile::remove_range()`] for more information.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn remove_range<'keys, KeyRangeBounds>(
+        &self,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<u64, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + ?Sized,
+    {
+        self.execute_in_transaction(|tree| tree.remove_range(range))
+    }
+
+    /// Removes all keys whose values have expired, returning the number of keys
+    /// removed. A transaction is only executed if the tree contains expired
+    /// values. See [`TreeFile::remove_expired()`] for more information.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn remove_expired(&self) -> Result<usize, Error> {
+        let now = Expiration::now();
+        let has_expired = self
+            .reduce(&(..))?
+            .and_then(|stats| stats.earliest_expiration())
+            .map_or(false, |expiration| expiration.has_passed(now));
+        if has_expired {
+            self.execute_in_transaction(TransactionTree::remove_expired)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Starts a background thread that removes expired keys from this tree
+    /// every `interval`. The thread runs until the returned
+    /// [`ExpirationReaper`] is dropped or stopped, or until removing the
+    /// expired keys fails.
+    ///
+    /// Reads ignore expired values, but the order statistics do not:
+    /// [`Self::count_range()`], [`Self::rank()`], [`Self::nth()`], and
+    /// [`Self::estimate_range()`] include expired keys until they are removed,
+    /// and [`Self::sample()`] may choose them, returning fewer entries than
+    /// requested. How long expired keys are included is bounded by `interval`.
+    pub fn reap_expired_every(&self, interval: Duration) -> Result<ExpirationReaper, Error> {
+        let (stop_sender, stop_receiver) = flume::bounded(1);
+        let tree = self.clone();
+        let thread = std::thread::Builder::new()
+            .name(String::from("roots-reaper"))
+            .spawn(move || {
+                while stop_receiver.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                    tree.remove_expired()?;
+                }
+                Ok(())
+            })?;
+        Ok(ExpirationReaper {
+            stop: stop_sender,
+            thread,
+        })
     }
 
     /// Compares the value of `key` against `old`. If the values match, key will
@@ -1084,15 +1950,25 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         new: Option<Root::Value>,
     ) -> Result<(), CompareAndSwapError<Root::Value>>
     where
-        Old: PartialEq,
+        Old: PartialEq + ?Sized,
         Root::Value: AsRef<Old> + Clone,
     {
-        let transaction = self.begin_transaction()?;
+        let transaction = self
+            .begin_transaction()
+            .map_err(|err| err.with_tree(&self.name))?;
         transaction
             .tree::<Root>(0)
             .unwrap()
-            .compare_and_swap(key, old, new)?;
-        transaction.commit()?;
+            .compare_and_swap(key, old, new)
+            .map_err(|err| match err {
+                CompareAndSwapError::Error(err) => {
+                    CompareAndSwapError::Error(err.with_tree(&self.name))
+                }
+                conflict @ CompareAndSwapError::Conflict(_) => conflict,
+            })?;
+        transaction
+            .commit()
+            .map_err(|err| err.with_tree(&self.name))?;
         Ok(())
     }
 
@@ -1106,7 +1982,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     where
         Keys: Iterator<Item = &'keys [u8]> + ExactSizeIterator + Clone,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
@@ -1128,7 +2004,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         KeysIntoIter: IntoIterator<Item = &'keys [u8], IntoIter = KeysIter> + Clone,
         KeysIter: Iterator<Item = &'keys [u8]> + ExactSizeIterator,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
@@ -1151,7 +2027,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         KeysIntoIter: IntoIterator<Item = &'keys [u8], IntoIter = KeysIter> + Clone,
         KeysIter: Iterator<Item = &'keys [u8]> + ExactSizeIterator,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
@@ -1170,7 +2046,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     where
         KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + Clone + ?Sized,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
@@ -1189,7 +2065,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     where
         KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
@@ -1208,7 +2084,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     where
         KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
@@ -1262,7 +2138,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         ) -> Result<(), AbortError<CallerError>>,
         CallerError: Display + Debug,
     {
-        catch_compaction_and_retry_abortable(move || {
+        catch_compaction_and_retry_abortable(&self.name, move || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(()),
@@ -1280,6 +2156,164 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         })
     }
 
+    /// Retrieves all of the values of keys that begin with `prefix`.
+    pub fn get_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> Result<Vec<(ArcBytes<'static>, Root::Value)>, Error> {
+        catch_compaction_and_retry(&self.name, || {
+            let mut tree = match self.open_for_read() {
+                Ok(tree) => tree,
+                Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            };
+
+            tree.get_prefix(prefix, false)
+        })
+    }
+
+    /// Retrieves the keys and values stored under `index_key` in the
+    /// secondary index named `index`. See
+    /// [`TreeRoot::with_index()`] for more information.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ErrorKind::SecondaryIndexNotFound`] if this tree has no index
+    /// named `index`.
+    pub fn get_by_index(
+        &self,
+        index: &str,
+        index_key: &[u8],
+    ) -> Result<Vec<(ArcBytes<'static>, Root::Value)>, Error>
+    where
+        Root::Value: Clone,
+    {
+        Ok(self
+            .get_range_by_index(index, &(index_key..=index_key))?
+            .into_iter()
+            .map(|entry| (entry.key, entry.value))
+            .collect())
+    }
+
+    /// Retrieves the entries whose index keys in the secondary index named
+    /// `index` are contained within `range`. Entries are ordered by index key,
+    /// and a key is returned once for each of its index keys within `range`.
+    ///
+    /// The index and this tree are read separately, so each entry's value is
+    /// checked against the index before it is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ErrorKind::SecondaryIndexNotFound`] if this tree has no index
+    /// named `index`.
+    pub fn get_range_by_index<'keys, KeyRangeBounds>(
+        &self,
+        index: &str,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<Vec<SecondaryIndexEntry<Root::Value>>, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + ?Sized,
+        Root::Value: Clone,
+    {
+        let index = self
+            .indexes
+            .iter()
+            .find(|candidate| candidate.name == index)
+            .ok_or_else(|| {
+                Error::from(ErrorKind::SecondaryIndexNotFound(index.to_string()))
+                    .with_tree(&self.name)
+            })?;
+        let index_tree = self.roots.tree(index.tree::<File>(self.vault.clone()))?;
+        let entry_range = tree::entry_range(range);
+        let entries = index_tree
+            .get_range_indexes(&(&entry_range.start[..]..&entry_range.end[..]))?
+            .into_iter()
+            .map(|(entry, _)| tree::decode_entry_key(&entry))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut keys = entries
+            .iter()
+            .map(|(_, key)| key.clone())
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| self.comparator.compare(a, b));
+        keys.dedup();
+        let values = self
+            .get_multiple(keys.iter().map(|key| &key[..]))?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|(index_key, key)| {
+                let value = values.get(&key)?;
+                // The key may have been modified after the index was read.
+                index
+                    .index_keys(value)
+                    .contains(&index_key)
+                    .then(|| SecondaryIndexEntry {
+                        index_key,
+                        key,
+                        value: value.clone(),
+                    })
+            })
+            .collect())
+    }
+
+    /// Rebuilds this tree's secondary indexes from the keys currently stored
+    /// in this tree. This is executed within its own transaction, and is
+    /// required after declaring an index on a tree that already contains keys.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn rebuild_indexes(&self) -> Result<(), Error> {
+        let result = self.begin_transaction().and_then(|transaction| {
+            for index in &self.indexes {
+                let position = transaction.tree_position(&index.tree_name).unwrap();
+                transaction
+                    .tree::<Unversioned>(position)
+                    .unwrap()
+                    .remove_range(&(..))?;
+            }
+            transaction
+                .tree::<Root>(0)
+                .unwrap()
+                .record_range(&(Bound::Unbounded, Bound::Unbounded))?;
+            transaction.commit()
+        });
+        result.map_err(|err| err.with_tree(&self.name))
+    }
+
+    /// Scans the tree for keys that begin with `prefix`. See
+    /// [`TreeFile::scan_prefix()`] for more information.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, key_evaluator, callback))
+    )]
+    pub fn scan_prefix<CallerError, KeyEvaluator, DataCallback>(
+        &self,
+        prefix: &[u8],
+        forwards: bool,
+        mut key_evaluator: KeyEvaluator,
+        mut callback: DataCallback,
+    ) -> Result<(), AbortError<CallerError>>
+    where
+        KeyEvaluator: FnMut(&ArcBytes<'static>, &Root::Index) -> ScanEvaluation,
+        DataCallback: FnMut(
+            ArcBytes<'static>,
+            &Root::Index,
+            Root::Value,
+        ) -> Result<(), AbortError<CallerError>>,
+        CallerError: Display + Debug,
+    {
+        catch_compaction_and_retry_abortable(&self.name, move || {
+            let mut tree = match self.open_for_read() {
+                Ok(tree) => tree,
+                Err(err) if err.kind.is_file_not_found() => return Ok(()),
+                Err(err) => return Err(AbortError::from(err)),
+            };
+
+            tree.scan_prefix(prefix, forwards, false, &mut key_evaluator, &mut callback)
+        })
+    }
+
     /// Returns the reduced index over the provided range. This is an
     /// aggregation function that builds atop the `scan()` operation which calls
     /// [`Reducer::reduce()`](crate::tree::Reducer::reduce) and
@@ -1298,7 +2332,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     where
         KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + Clone + ?Sized,
     {
-        catch_compaction_and_retry(move || {
+        catch_compaction_and_retry(&self.name, move || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1309,10 +2343,93 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
         })
     }
 
+    /// Returns the number of keys contained within `range`. See
+    /// [`TreeFile::count_range()`] for more information.
+    pub fn count_range<'keys, KeyRangeBounds>(
+        &self,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<u64, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+    {
+        catch_compaction_and_retry(&self.name, || {
+            let mut tree = match self.open_for_read() {
+                Ok(tree) => tree,
+                Err(err) if err.kind.is_file_not_found() => return Ok(0),
+                Err(err) => return Err(err),
+            };
+
+            tree.count_range(range, false)
+        })
+    }
+
+    /// Returns the number of keys ordered before `key`. See
+    /// [`TreeFile::rank()`] for more information.
+    pub fn rank(&self, key: &[u8]) -> Result<u64, Error> {
+        self.count_range(&(..key))
+    }
+
+    /// Returns the key whose rank is `n`, where the first key of the tree has a
+    /// rank of 0. See [`TreeFile::nth()`] for more information.
+    pub fn nth(&self, n: u64) -> Result<Option<ArcBytes<'static>>, Error> {
+        catch_compaction_and_retry(&self.name, || {
+            let mut tree = match self.open_for_read() {
+                Ok(tree) => tree,
+                Err(err) if err.kind.is_file_not_found() => return Ok(None),
+                Err(err) => return Err(err),
+            };
+
+            tree.nth(n, false)
+        })
+    }
+
+    /// Returns the approximate number of keys and value bytes contained within
+    /// `range`. See [`TreeFile::estimate_range()`] for more information.
+    pub fn estimate_range<'keys, KeyRangeBounds>(
+        &self,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<RangeEstimate, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+    {
+        catch_compaction_and_retry(&self.name, || {
+            let mut tree = match self.open_for_read() {
+                Ok(tree) => tree,
+                Err(err) if err.kind.is_file_not_found() => return Ok(RangeEstimate::default()),
+                Err(err) => return Err(err),
+            };
+
+            tree.estimate_range(range, false)
+        })
+    }
+
+    /// Returns up to `count` entries chosen uniformly at random from the keys
+    /// contained within `range`, ordered by key. See [`TreeFile::sample()`]
+    /// for more information.
+    #[cfg(feature = "sampling")]
+    pub fn sample<'keys, KeyRangeBounds>(
+        &self,
+        count: usize,
+        range: &'keys KeyRangeBounds,
+    ) -> Result<Vec<(ArcBytes<'static>, Root::Value)>, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+    {
+        catch_compaction_and_retry(&self.name, || {
+            let mut tree = match self.open_for_read() {
+                Ok(tree) => tree,
+                Err(err) if err.kind.is_file_not_found() => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            };
+
+            tree.sample(count, range, false)
+        })
+    }
+
     /// Returns the first key of the tree.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
     pub fn first_key(&self) -> Result<Option<ArcBytes<'static>>, Error> {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1326,7 +2443,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// Returns the first key and value of the tree.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
     pub fn first(&self) -> Result<Option<(ArcBytes<'static>, Root::Value)>, Error> {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1340,7 +2457,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// Returns the last key of the tree.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
     pub fn last_key(&self) -> Result<Option<ArcBytes<'static>>, Error> {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1354,7 +2471,7 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
     /// Returns the last key and value of the tree.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
     pub fn last(&self) -> Result<Option<(ArcBytes<'static>, Root::Value)>, Error> {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = match self.open_for_read() {
                 Ok(tree) => tree,
                 Err(err) if err.kind.is_file_not_found() => return Ok(None),
@@ -1367,15 +2484,18 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
 
     /// Rewrites the database to remove data that is no longer current. Because
     /// Nebari uses an append-only format, this is helpful in reducing disk
-    /// usage.
+    /// usage. Expired keys are removed using
+    /// [`remove_expired()`](Self::remove_expired) before the tree is
+    /// rewritten.
     ///
     /// See [`TreeFile::compact()`](crate::tree::TreeFile::compact) for more
     /// information.
     pub fn compact(&self) -> Result<(), Error> {
+        self.remove_expired()?;
         let tree = match self.open_for_read() {
             Ok(tree) => tree,
             Err(err) if err.kind.is_file_not_found() => return Ok(()),
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.with_tree(&self.name)),
         };
         tree.compact(
             &self.roots.context().file_manager,
@@ -1383,7 +2503,8 @@ impl<Root: tree::Root, File: ManagedFile> Tree<Root, File> {
                 name: self.name.as_ref(),
                 manager: self.roots.transactions(),
             }),
-        )?;
+        )
+        .map_err(|err| err.with_tree(&self.name))?;
         Ok(())
     }
 }
@@ -1394,18 +2515,17 @@ impl<Root: tree::Root, File: ManagedFile> AnyTreeRoot<File> for Tree<Root, File>
     }
 
     fn default_state(&self) -> Box<dyn AnyTreeState> {
-        Box::new(State::<Root>::new(
-            None,
-            None,
-            Root::default_with(
-                self.reducer
-                    .as_ref()
-                    .as_any()
-                    .downcast_ref::<Root::Reducer>()
-                    .unwrap()
-                    .clone(),
-            ),
-        ))
+        let mut root = Root::default_with(
+            self.reducer
+                .as_ref()
+                .as_any()
+                .downcast_ref::<Root::Reducer>()
+                .unwrap()
+                .clone(),
+        );
+        root.set_comparator(self.comparator.clone());
+        root.set_merge_operator(self.merge_operator.clone());
+        Box::new(State::<Root>::new(None, None, root))
     }
 
     fn begin_transaction(
@@ -1416,25 +2536,44 @@ impl<Root: tree::Root, File: ManagedFile> AnyTreeRoot<File> for Tree<Root, File>
         context: &Context<File::Manager>,
         transactions: Option<&TransactionManager<File::Manager>>,
     ) -> Result<Box<dyn AnyTransactionTree<File>>, Error> {
-        let context = self.vault.as_ref().map_or_else(
-            || Cow::Borrowed(context),
-            |vault| Cow::Owned(context.clone().with_any_vault(vault.clone())),
-        );
-        let tree = TreeFile::write(
-            file_path,
-            state
-                .as_any()
-                .downcast_ref::<State<Root>>()
-                .unwrap()
-                .clone(),
-            &context,
-            transactions,
-        )?;
+        self.root()
+            .begin_transaction(transaction_id, file_path, state, context, transactions)
+    }
 
-        Ok(Box::new(TransactionTree {
-            transaction_id,
-            tree,
-        }))
+    fn compact(
+        &self,
+        file_path: &PathId,
+        state: &dyn AnyTreeState,
+        context: &Context<File::Manager>,
+        transactions: Option<&TransactionManager<File::Manager>>,
+    ) -> Result<(), Error> {
+        self.root().compact(file_path, state, context, transactions)
+    }
+
+    fn warm_cache(
+        &self,
+        file_path: &PathId,
+        state: &dyn AnyTreeState,
+        context: &Context<File::Manager>,
+        transactions: Option<&TransactionManager<File::Manager>>,
+        positions: &[u64],
+        levels: usize,
+    ) -> Result<(), Error> {
+        self.root()
+            .warm_cache(file_path, state, context, transactions, positions, levels)
+    }
+
+    fn secondary_index_trees(&self) -> Vec<Box<dyn AnyTreeRoot<File>>> {
+        self.indexes
+            .iter()
+            .map(|index| {
+                Box::new(index.tree::<File>(self.vault.clone())) as Box<dyn AnyTreeRoot<File>>
+            })
+            .collect()
+    }
+
+    fn boxed_root(&self) -> Box<dyn AnyTreeRoot<File> + Send + Sync> {
+        Box::new(self.root())
     }
 }
 
@@ -1470,7 +2609,7 @@ where
             FnMut(KeySequence<Index>, ArcBytes<'static>) -> Result<(), AbortError<CallerError>>,
         CallerError: Display + Debug,
     {
-        catch_compaction_and_retry_abortable(|| {
+        catch_compaction_and_retry_abortable(&self.name, || {
             let mut tree = TreeFile::<VersionedTreeRoot<Index>, File>::read(
                 &self.path,
                 self.state.clone(),
@@ -1500,7 +2639,7 @@ where
     where
         Sequences: Iterator<Item = SequenceId> + Clone,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = TreeFile::<VersionedTreeRoot<Index>, File>::read(
                 &self.path,
                 self.state.clone(),
@@ -1523,7 +2662,7 @@ where
     where
         Sequences: Iterator<Item = SequenceId> + Clone,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = TreeFile::<VersionedTreeRoot<Index>, File>::read(
                 &self.path,
                 self.state.clone(),
@@ -1547,7 +2686,7 @@ where
     where
         Sequences: Iterator<Item = SequenceId> + Clone,
     {
-        catch_compaction_and_retry(|| {
+        catch_compaction_and_retry(&self.name, || {
             let mut tree = TreeFile::<VersionedTreeRoot<Index>, File>::read(
                 &self.path,
                 self.state.clone(),
@@ -1679,9 +2818,10 @@ impl<File: ManagedFile> Clone for ThreadPool<File> {
     }
 }
 
+static CPU_COUNT: Lazy<usize> = Lazy::new(num_cpus::get);
+
 impl<File: ManagedFile> Default for ThreadPool<File> {
     fn default() -> Self {
-        static CPU_COUNT: Lazy<usize> = Lazy::new(num_cpus::get);
         Self::new(*CPU_COUNT)
     }
 }
@@ -1707,7 +2847,10 @@ where
     completion_sender: Sender<Result<Box<dyn AnyTransactionTree<File>>, Error>>,
 }
 
-fn catch_compaction_and_retry<R, F: Fn() -> Result<R, Error>>(func: F) -> Result<R, Error> {
+fn catch_compaction_and_retry<R, F: Fn() -> Result<R, Error>>(
+    tree: &str,
+    func: F,
+) -> Result<R, Error> {
     loop {
         match func() {
             Ok(result) => return Ok(result),
@@ -1716,7 +2859,7 @@ fn catch_compaction_and_retry<R, F: Fn() -> Result<R, Error>>(func: F) -> Result
                     continue;
                 }
 
-                return Err(error);
+                return Err(error.with_tree(tree));
             }
         }
     }
@@ -1727,6 +2870,7 @@ fn catch_compaction_and_retry_abortable<
     E: Display + Debug,
     F: FnMut() -> Result<R, AbortError<E>>,
 >(
+    tree: &str,
     mut func: F,
 ) -> Result<R, AbortError<E>> {
     loop {
@@ -1737,7 +2881,7 @@ fn catch_compaction_and_retry_abortable<
                     continue;
                 }
 
-                return Err(AbortError::Nebari(error));
+                return Err(AbortError::Nebari(error.with_tree(tree)));
             }
             Err(other) => return Err(other),
         }
@@ -1746,14 +2890,29 @@ fn catch_compaction_and_retry_abortable<
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        collections::HashSet,
+        io::{SeekFrom, Write},
+    };
+
     use byteorder::{BigEndian, ByteOrder};
     use tempfile::tempdir;
 
     use super::*;
     use crate::{
-        io::{any::AnyFileManager, fs::StdFileManager, memory::MemoryFileManager},
+        io::{
+            any::AnyFileManager,
+            blob::{BlobFileManager, BlobStore, LocalBlobStore},
+            fault::{Fault, FaultInjectingFileManager},
+            fs::{StdFile, StdFileManager},
+            memory::MemoryFileManager,
+            File, FileOp, ManagedFileOpener, OpenableFile, OperableFile,
+        },
         test_util::RotatorVault,
-        tree::{Root, Unversioned, ValueIndex, Versioned},
+        tree::{
+            AddU64, AsciiCaseInsensitive, Bytewise, Reverse, Root, Unversioned, ValueIndex,
+            Versioned,
+        },
     };
 
     fn basic_get_set<M: FileManager>(file_manager: M) {
@@ -1781,21 +2940,184 @@ mod tests {
     }
 
     #[test]
-    fn basic_transaction_isolation_test() {
+    #[cfg(unix)]
+    fn pread_basic_get_set() {
+        basic_get_set(crate::io::pread::PreadFileManager::default());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pread_parallel_readers() {
         let tempdir = tempdir().unwrap();
+        let roots = Config::new(tempdir.path())
+            .file_manager(crate::io::pread::PreadFileManager::default())
+            .open()
+            .unwrap();
+        let mut tree = roots.tree(Versioned::tree("test")).unwrap();
+        let keys = (0_u32..1_000)
+            .map(|id| ArcBytes::from(id.to_be_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        tree.modify(keys.clone(), Operation::Set(ArcBytes::from(b"value")))
+            .unwrap();
 
-        let roots = Config::<StdFileManager>::new(tempdir.path())
+        // Every reader shares the tree's file descriptor, reading from its own
+        // position while the others read.
+        let readers = (0..8)
+            .map(|reader| {
+                let tree = tree.clone();
+                let keys = keys.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        for key in keys.iter().skip(reader).step_by(8) {
+                            assert_eq!(tree.get(key).unwrap().unwrap(), b"value");
+                        }
+                        assert_eq!(tree.get_range(&(..)).unwrap().len(), 1_000);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    fn tree_names<M: FileManager>(file_manager: M) {
+        let tempdir = tempdir().unwrap();
+        let roots = Config::new(tempdir.path())
+            .file_manager(file_manager)
             .open()
             .unwrap();
-        let tree = roots.tree(Versioned::tree("test")).unwrap();
-        tree.set(b"test", b"value").unwrap();
+        assert!(roots.tree_names().unwrap().is_empty());
 
-        // Begin a transaction
-        let transaction = roots.transaction(&[Versioned::tree("test")]).unwrap();
+        roots.tree(Versioned::tree("first")).unwrap();
+        roots.tree(Unversioned::tree("second")).unwrap();
+        let mut names = roots.tree_names().unwrap();
+        names.sort();
+        assert_eq!(names, vec![String::from("first"), String::from("second")]);
 
-        // Replace the key with a new value.
-        transaction
-            .tree::<Versioned>(0)
+        roots.delete_tree("first").unwrap();
+        assert_eq!(roots.tree_names().unwrap(), vec![String::from("second")]);
+    }
+
+    #[test]
+    fn std_tree_names() {
+        tree_names(StdFileManager::default());
+    }
+
+    #[test]
+    fn memory_tree_names() {
+        tree_names(MemoryFileManager::default());
+    }
+
+    #[test]
+    fn blob_tree_names() {
+        let blobs = tempdir().unwrap();
+        tree_names(BlobFileManager::new(LocalBlobStore::new(blobs.path())));
+    }
+
+    #[test]
+    fn any_tree_names() {
+        tree_names(AnyFileManager::std());
+        tree_names(AnyFileManager::memory());
+    }
+
+    #[test]
+    fn blob_basic_get_set() {
+        let blobs = tempdir().unwrap();
+        basic_get_set(BlobFileManager::new(LocalBlobStore::new(blobs.path())));
+    }
+
+    #[test]
+    fn blob_replacement() {
+        struct WriteAll(&'static [u8]);
+
+        impl FileOp<Result<(), Error>> for WriteAll {
+            fn execute(self, file: &mut dyn File) -> Result<(), Error> {
+                file.write_all(self.0).map_err(Error::from)
+            }
+        }
+
+        fn read_all(file: &mut impl File) -> Vec<u8> {
+            let mut contents = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut contents).unwrap();
+            contents
+        }
+
+        let blobs = tempdir().unwrap();
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("file");
+        let key = path.to_string_lossy().into_owned();
+        let retired_copies = |manager: &BlobFileManager<LocalBlobStore>| {
+            manager
+                .store()
+                .list(&format!("{}.retired-", key))
+                .unwrap()
+                .len()
+        };
+
+        // A retired copy left behind by a crash is deleted once the file is
+        // opened.
+        let manager = BlobFileManager::new(LocalBlobStore::new(blobs.path()));
+        manager
+            .store()
+            .put(&format!("{}.retired-1", key), b"stale")
+            .unwrap();
+        let mut file = manager.append(&path).unwrap();
+        assert_eq!(retired_copies(&manager), 0);
+
+        // Buffered data is uploaded when the handle is closed, and discarded if
+        // the handle is dropped.
+        file.execute(WriteAll(b"original")).unwrap();
+        file.close().unwrap();
+        let mut file = manager.append(&path).unwrap();
+        file.execute(WriteAll(b" discarded")).unwrap();
+        drop(file);
+        assert_eq!(manager.file_length(&path).unwrap(), 8);
+
+        // Readers opened before the file is replaced keep reading its previous
+        // contents until they are closed.
+        let id = manager.resolve_path(&path, true).unwrap();
+        let mut reader = manager.open_for_read(id.clone()).unwrap();
+        let mut replacement = manager
+            .open_for_append(tempdir.path().join("replacement"))
+            .unwrap();
+        replacement.write_all(b"replacement").unwrap();
+        let file = manager
+            .append(id)
+            .unwrap()
+            .replace_with(replacement, &manager, |_| {})
+            .unwrap();
+        assert_eq!(read_all(&mut reader), b"original");
+        assert_eq!(retired_copies(&manager), 1);
+        let id = manager.resolve_path(&path, true).unwrap();
+        assert_eq!(
+            read_all(&mut manager.open_for_read(id).unwrap()),
+            b"replacement"
+        );
+        assert!(!manager.exists(tempdir.path().join("replacement")).unwrap());
+
+        drop(reader);
+        file.close().unwrap();
+        assert_eq!(retired_copies(&manager), 0);
+    }
+
+    #[test]
+    fn basic_transaction_isolation_test() {
+        let tempdir = tempdir().unwrap();
+
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .open()
+            .unwrap();
+        let tree = roots.tree(Versioned::tree("test")).unwrap();
+        tree.set(b"test", b"value").unwrap();
+
+        // Begin a transaction
+        let transaction = roots.transaction(&[Versioned::tree("test")]).unwrap();
+
+        // Replace the key with a new value.
+        transaction
+            .tree::<Versioned>(0)
             .unwrap()
             .set(b"test", b"updated value")
             .unwrap();
@@ -1880,6 +3202,30 @@ mod tests {
         compact_test::<Unversioned, _>(MemoryFileManager::default());
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn pread_compact_test_versioned() {
+        compact_test::<Versioned, _>(crate::io::pread::PreadFileManager::default());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pread_compact_test_unversioned() {
+        compact_test::<Unversioned, _>(crate::io::pread::PreadFileManager::default());
+    }
+
+    #[test]
+    fn blob_compact_test_versioned() {
+        let blobs = tempdir().unwrap();
+        compact_test::<Versioned, _>(BlobFileManager::new(LocalBlobStore::new(blobs.path())));
+    }
+
+    #[test]
+    fn blob_compact_test_unversioned() {
+        let blobs = tempdir().unwrap();
+        compact_test::<Unversioned, _>(BlobFileManager::new(LocalBlobStore::new(blobs.path())));
+    }
+
     #[test]
     fn any_compact_test_versioned() {
         compact_test::<Versioned, _>(AnyFileManager::std());
@@ -1892,6 +3238,117 @@ mod tests {
         compact_test::<Unversioned, _>(AnyFileManager::memory());
     }
 
+    fn commit_test_transaction<M: FileManager>(
+        roots: &Roots<M::File>,
+        id: u64,
+    ) -> Result<(), Error> {
+        let transaction = roots.transaction(&[Versioned::tree("test")])?;
+        transaction
+            .tree::<Versioned>(0)
+            .unwrap()
+            .set(id.to_be_bytes().to_vec(), id.to_be_bytes().to_vec())?;
+        transaction.commit()
+    }
+
+    #[test]
+    fn power_loss_preserves_committed_transactions() {
+        const TRANSACTIONS: u64 = 4;
+        // Simulate a power loss during every write and sync operation that
+        // writing the transactions performs.
+        for crash_point in 0.. {
+            let tempdir = tempdir().unwrap();
+            let manager = FaultInjectingFileManager::new(MemoryFileManager::default());
+            manager.inject_at(crash_point, Fault::PowerLoss);
+            let mut committed = 0;
+            if let Ok(roots) = Config::new(tempdir.path())
+                .file_manager(manager.clone())
+                .open()
+            {
+                while committed < TRANSACTIONS
+                    && commit_test_transaction::<FaultInjectingFileManager<MemoryFileManager>>(
+                        &roots, committed,
+                    )
+                    .is_ok()
+                {
+                    committed += 1;
+                }
+            }
+
+            if !manager.is_powered_off() {
+                assert_eq!(committed, TRANSACTIONS);
+                break;
+            }
+
+            manager.restore_power();
+            let roots = Config::new(tempdir.path())
+                .file_manager(manager.clone())
+                .open()
+                .unwrap();
+            let tree = roots.tree(Versioned::tree("test")).unwrap();
+            for id in 0..TRANSACTIONS {
+                let stored = tree.get(&id.to_be_bytes()).unwrap();
+                // The transaction being committed during the power loss may or
+                // may not have been persisted.
+                if id < committed {
+                    assert!(
+                        stored.is_some(),
+                        "transaction {} lost at {}",
+                        id,
+                        crash_point
+                    );
+                } else if id > committed {
+                    assert!(
+                        stored.is_none(),
+                        "transaction {} found at {}",
+                        id,
+                        crash_point
+                    );
+                }
+            }
+        }
+    }
+
+    fn torn_power_loss_preserves_committed_transactions<M: FileManager>(inner: M) {
+        let tempdir = tempdir().unwrap();
+        let manager = FaultInjectingFileManager::new(inner);
+        let roots = Config::new(tempdir.path())
+            .file_manager(manager.clone())
+            .open()
+            .unwrap();
+        commit_test_transaction::<FaultInjectingFileManager<M>>(&roots, 0).unwrap();
+
+        // Fail synchronizing the second transaction's data, and then keep only
+        // half of the unsynchronized data when the power is lost.
+        for operation in manager.operations()..manager.operations() + 16 {
+            manager.inject_at(operation, Fault::FailSync);
+        }
+        assert!(commit_test_transaction::<FaultInjectingFileManager<M>>(&roots, 1).is_err());
+        drop(roots);
+        manager
+            .simulate_power_loss_with_torn_writes(|_path, unsynced| unsynced.len() / 2)
+            .unwrap();
+        manager.clear_faults();
+        manager.restore_power();
+
+        let roots = Config::new(tempdir.path())
+            .file_manager(manager)
+            .open()
+            .unwrap();
+        let tree = roots.tree(Versioned::tree("test")).unwrap();
+        assert!(tree.get(&0_u64.to_be_bytes()).unwrap().is_some());
+        assert!(tree.get(&1_u64.to_be_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn std_torn_power_loss_preserves_committed_transactions() {
+        torn_power_loss_preserves_committed_transactions(StdFileManager::default());
+    }
+
+    #[test]
+    fn memory_torn_power_loss_preserves_committed_transactions() {
+        torn_power_loss_preserves_committed_transactions(MemoryFileManager::default());
+    }
+
     fn compact_test<R: Root<Value = ArcBytes<'static>>, M: FileManager>(file_manager: M)
     where
         R::Reducer: Default,
@@ -1951,6 +3408,9 @@ mod tests {
         assert!(check_name("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_-.").is_ok());
         assert!(check_name("=").is_err());
         assert!(check_name("_transactions").is_err());
+        assert!(check_name("users~email").is_ok());
+        assert!(check_name("users~email~v2").is_err());
+        assert!(check_name("_transactions~email").is_err());
     }
 
     #[test]
@@ -1980,9 +3440,14 @@ mod tests {
             let value = tree.get(b"test").unwrap();
             assert_eq!(value.as_deref(), Some(&b"value"[..]));
 
-            // Verify we can't read the other tree without the right vault
-            let bad_tree = roots.tree(Versioned::tree("test-otherkey")).unwrap();
-            assert!(bad_tree.get(b"test").is_err());
+            // Verify we can't open the other tree without the right vault
+            assert!(matches!(
+                roots.tree(Versioned::tree("test-otherkey")),
+                Err(Error {
+                    kind: ErrorKind::VaultMismatch { .. },
+                    ..
+                })
+            ));
 
             // And test retrieving the other key with the correct vault
             let tree = roots
@@ -1991,20 +3456,663 @@ mod tests {
             let value = tree.get(b"test").unwrap();
             assert_eq!(value.as_deref(), Some(&b"other"[..]));
         }
+        // The transaction log is encrypted, so it can't be read without the
+        // vault.
+        assert!(matches!(
+            Config::<StdFileManager>::new(tempdir.path()).open(),
+            Err(Error {
+                kind: ErrorKind::VaultNotConfigured,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn cache_with_vault() {
+        let tempdir = tempdir().unwrap();
+        let cache = ChunkCache::with_byte_capacity(1024 * 1024, 65536);
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .vault(RotatorVault::new(13))
+            .cache(cache.clone())
+            .open()
+            .unwrap();
+        let tree = roots.tree(Versioned::tree("test")).unwrap();
+        tree.set(b"test", b"value").unwrap();
+        // Chunks are cached decrypted as they are written.
+        assert_eq!(tree.get(b"test").unwrap().as_deref(), Some(&b"value"[..]));
+        assert!(cache.stats().hits > 0);
+    }
+
+    #[test]
+    fn reads_without_caching() {
+        let tempdir = tempdir().unwrap();
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .open()
+                .unwrap();
+            let tree = roots.tree(Unversioned::tree("test")).unwrap();
+            for i in 0_u32..100 {
+                tree.set(i.to_be_bytes().to_vec(), b"value").unwrap();
+            }
+        }
+
+        let cache = ChunkCache::with_byte_capacity(1024 * 1024, 65536);
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .cache(cache.clone())
+            .open()
+            .unwrap();
+        let tree = roots.tree(Unversioned::tree("test")).unwrap();
+        let uncached = tree.without_caching_reads();
+        assert_eq!(uncached.get_range(&(..)).unwrap().len(), 100);
+        assert_eq!(cache.stats().entries, 0);
+
+        // Chunks cached by other reads are still used.
+        assert_eq!(
+            tree.get(&42_u32.to_be_bytes()).unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        let hits = cache.stats().hits;
+        assert_eq!(
+            uncached.get(&42_u32.to_be_bytes()).unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        assert!(cache.stats().hits > hits);
+    }
+
+    #[test]
+    fn pinned_interior_nodes() {
+        let tempdir = tempdir().unwrap();
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .open()
+                .unwrap();
+            let mut tree = roots.tree(Unversioned::tree("test")).unwrap();
+            tree.modify(
+                (0_u32..1000)
+                    .map(|i| ArcBytes::from(i.to_be_bytes().to_vec()))
+                    .collect(),
+                Operation::Set(ArcBytes::from(b"value")),
+            )
+            .unwrap();
+        }
+
+        // The cache is only able to hold a couple of chunks that aren't pinned.
+        let cache = ChunkCache::with_byte_capacity(1024, 1024).pin_interior_nodes(1024 * 1024);
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .cache(cache.clone())
+            .open()
+            .unwrap();
+        let tree = roots.tree(Unversioned::tree("test")).unwrap();
+        for i in 0_u32..1000 {
+            assert!(tree.get(&i.to_be_bytes()).unwrap().is_some());
+        }
+        assert!(cache.stats().pinned_entries > 0);
+
+        // Each lookup should only need to read the leaf node and the value.
+        for i in 0_u32..1000 {
+            let misses = cache.stats().misses;
+            assert!(tree.get(&i.to_be_bytes()).unwrap().is_some());
+            assert!(cache.stats().misses - misses <= 2);
+        }
+    }
+
+    #[test]
+    fn warm_cache() {
+        let tempdir = tempdir().unwrap();
+        let mut chunks = {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .cache(ChunkCache::with_byte_capacity(1024 * 1024, 65536))
+                .open()
+                .unwrap();
+            let mut tree = roots.tree(Unversioned::tree("test")).unwrap();
+            tree.modify(
+                (0_u32..1000)
+                    .map(|i| ArcBytes::from(i.to_be_bytes().to_vec()))
+                    .collect(),
+                Operation::Set(ArcBytes::from(b"value")),
+            )
+            .unwrap();
+            roots.cached_chunks().unwrap()
+        };
+        assert!(!chunks.is_empty());
+        assert!(chunks.iter().all(|chunk| chunk.tree == "test"));
+
+        // Locations that no longer point to a chunk are skipped.
+        chunks.push(ChunkLocation {
+            tree: String::from("test"),
+            position: 3,
+        });
+        chunks.push(ChunkLocation {
+            tree: String::from("test"),
+            position: u64::MAX,
+        });
+
+        let cache = ChunkCache::with_byte_capacity(1024 * 1024, 65536);
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .cache(cache.clone())
+            .open()
+            .unwrap();
+        roots
+            .warm_cache(vec![Unversioned::tree("test")], &chunks, 1)
+            .unwrap()
+            .wait()
+            .unwrap();
+        let warmed = roots.cached_chunks().unwrap();
+        assert_eq!(warmed.len(), chunks.len() - 2);
+        assert!(chunks[..chunks.len() - 2]
+            .iter()
+            .all(|chunk| warmed.contains(chunk)));
+
+        let tree = roots.tree(Unversioned::tree("test")).unwrap();
+        let misses = cache.stats().misses;
+        for i in 0_u32..1000 {
+            assert!(tree.get(&i.to_be_bytes()).unwrap().is_some());
+        }
+        assert_eq!(cache.stats().misses, misses);
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct RecordingMetrics(Arc<Mutex<RecordedMetrics>>);
+
+    #[derive(Debug, Default)]
+    struct RecordedMetrics {
+        transactions_committed: usize,
+        batched_transactions: usize,
+        lock_waits: usize,
+        synchronized_files: HashSet<PathBuf>,
+        tree_bytes_written: HashMap<PathBuf, u64>,
+        compacted_trees: usize,
+        cache_hits: usize,
+        cache_misses: usize,
+    }
+
+    impl Metrics for RecordingMetrics {
+        fn transaction_committed(&self, _trees: usize, _duration: std::time::Duration) {
+            self.0.lock().transactions_committed += 1;
+        }
+
+        fn transaction_batch_written(
+            &self,
+            transactions: usize,
+            _queued: usize,
+            _duration: std::time::Duration,
+        ) {
+            self.0.lock().batched_transactions += transactions;
+        }
+
+        fn transaction_lock_waited(&self, _duration: std::time::Duration) {
+            self.0.lock().lock_waits += 1;
+        }
+
+        fn file_synchronized(&self, path: &Path, _duration: std::time::Duration) {
+            self.0
+                .lock()
+                .synchronized_files
+                .insert(path.file_name().unwrap().into());
+        }
+
+        fn tree_bytes_written(&self, path: &Path, bytes: u64) {
+            *self
+                .0
+                .lock()
+                .tree_bytes_written
+                .entry(path.file_name().unwrap().into())
+                .or_default() += bytes;
+        }
+
+        fn tree_compacted(&self, _path: &Path, _duration: std::time::Duration) {
+            self.0.lock().compacted_trees += 1;
+        }
+
+        fn cache_lookup(&self, hit: bool) {
+            let mut recorded = self.0.lock();
+            if hit {
+                recorded.cache_hits += 1;
+            } else {
+                recorded.cache_misses += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        let tempdir = tempdir().unwrap();
+        let metrics = RecordingMetrics::default();
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .cache(ChunkCache::with_byte_capacity(1024 * 1024, 65536))
+            .metrics(metrics.clone())
+            .open()
+            .unwrap();
+        let mut tree = roots.tree(Unversioned::tree("test")).unwrap();
+        tree.modify(
+            (0_u32..100)
+                .map(|i| ArcBytes::from(i.to_be_bytes().to_vec()))
+                .collect(),
+            Operation::Set(ArcBytes::from(b"value")),
+        )
+        .unwrap();
+        {
+            let recorded = metrics.0.lock();
+            assert_eq!(recorded.transactions_committed, 1);
+            assert_eq!(recorded.batched_transactions, 1);
+            assert_eq!(recorded.lock_waits, 1);
+        }
+
+        for i in 0_u32..100 {
+            assert!(tree.get(&i.to_be_bytes()).unwrap().is_some());
+        }
+        tree.compact().unwrap();
+
+        let recorded = metrics.0.lock();
+        assert!(recorded
+            .synchronized_files
+            .contains(Path::new("_transactions")));
+        assert!(recorded
+            .synchronized_files
+            .contains(Path::new("test.nebari")));
+        assert!(recorded.tree_bytes_written[Path::new("test.nebari")] > 0);
+        assert_eq!(recorded.compacted_trees, 1);
+        assert!(recorded.cache_hits > 0);
+    }
+
+    #[test]
+    fn error_context() {
+        let tempdir = tempdir().unwrap();
+        let file_path = tempdir.path().join("file");
+        std::fs::write(&file_path, b"not a directory").unwrap();
+        let err = Config::<StdFileManager>::new(&file_path)
+            .open()
+            .unwrap_err();
+        assert!(matches!(&err.kind, ErrorKind::NotADirectory(path) if path == &file_path));
+        assert!(err.backtrace().is_none());
+
+        let roots = Config::<StdFileManager>::new(tempdir.path().join("db"))
+            .open()
+            .unwrap();
+        let tree = roots.tree(Unversioned::tree("test")).unwrap();
+        tree.set(b"test", vec![0xAB; 64]).unwrap();
+
+        // Corrupt the value's chunk.
+        let tree_path = tempdir.path().join("db").join("test.nebari");
+        let mut contents = std::fs::read(&tree_path).unwrap();
+        let value_offset = contents
+            .windows(64)
+            .position(|window| window.iter().all(|&b| b == 0xAB))
+            .unwrap();
+        contents[value_offset] = 0;
+        std::fs::write(&tree_path, contents).unwrap();
+
+        let err = tree.compact().unwrap_err();
+        match &err.kind {
+            ErrorKind::DataIntegrity(inner) => {
+                assert!(matches!(inner.kind, ErrorKind::ChecksumMismatch { .. }));
+            }
+            other => unreachable!("unexpected error {:?}", other),
+        }
+        assert!(err.backtrace().is_some());
+        assert_eq!(err.tree(), Some("test"));
+        assert_eq!(err.path().unwrap().path(), tree_path);
+        assert_eq!(err.position(), Some(value_offset as u64 - 8));
+        assert!(err.to_string().contains("tree: test"));
+    }
+
+    #[test]
+    fn unencrypted_trees_open_with_vault() {
+        let tempdir = tempdir().unwrap();
+
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .open()
+                .unwrap();
+            let tree = roots.tree(Versioned::tree("plain")).unwrap();
+            tree.set(b"test", b"value").unwrap();
+            let tree = roots
+                .tree(Versioned::tree("encrypted").with_vault(RotatorVault::new(13).with_key_id(7)))
+                .unwrap();
+            tree.set(b"test", b"value").unwrap();
+        }
+
+        // A tree written without a vault can't be opened with a vault unless
+        // unencrypted data is allowed.
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .open()
+            .unwrap();
+        assert!(matches!(
+            roots.tree(Versioned::tree("plain").with_vault(RotatorVault::new(13))),
+            Err(Error {
+                kind: ErrorKind::VaultMismatch { .. },
+                ..
+            })
+        ));
+        drop(roots);
+
+        // When allowed, the unencrypted data remains readable, and new data is
+        // encrypted.
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .allow_unencrypted_data()
+            .open()
+            .unwrap();
+        let tree = roots
+            .tree(Versioned::tree("plain").with_vault(RotatorVault::new(13)))
+            .unwrap();
+        assert_eq!(tree.get(b"test").unwrap().as_deref(), Some(&b"value"[..]));
+        tree.set(b"other", b"encrypted").unwrap();
+        drop(tree);
+        drop(roots);
+        let contents = std::fs::read(tempdir.path().join("plain.nebari")).unwrap();
+        assert!(!contents
+            .windows(b"encrypted".len())
+            .any(|window| window == b"encrypted"));
+
+        // The tree now requires both the vault and allowing unencrypted data.
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .open()
+            .unwrap();
+        assert!(matches!(
+            roots.tree(Versioned::tree("plain")),
+            Err(Error {
+                kind: ErrorKind::VaultMismatch { .. },
+                ..
+            })
+        ));
+        assert!(matches!(
+            roots.tree(Versioned::tree("plain").with_vault(RotatorVault::new(13))),
+            Err(Error {
+                kind: ErrorKind::VaultMismatch { .. },
+                ..
+            })
+        ));
+        drop(roots);
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .allow_unencrypted_data()
+            .open()
+            .unwrap();
+        let tree = roots
+            .tree(Versioned::tree("plain").with_vault(RotatorVault::new(13)))
+            .unwrap();
+        assert_eq!(tree.get(b"test").unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(
+            tree.get(b"other").unwrap().as_deref(),
+            Some(&b"encrypted"[..])
+        );
+
+        // Compacting encrypts the unencrypted data, after which it no longer
+        // needs to be allowed.
+        tree.compact().unwrap();
+        drop(tree);
+        drop(roots);
+        let roots = Config::<StdFileManager>::new(tempdir.path())
+            .open()
+            .unwrap();
+        let tree = roots
+            .tree(Versioned::tree("plain").with_vault(RotatorVault::new(13)))
+            .unwrap();
+        assert_eq!(tree.get(b"test").unwrap().as_deref(), Some(&b"value"[..]));
+
+        // A tree written with a vault reports the key id it was written with.
+        match roots.tree(Versioned::tree("encrypted").with_vault(RotatorVault::new(42))) {
+            Err(Error {
+                kind: ErrorKind::VaultMismatch { reason, .. },
+                ..
+            }) => assert!(reason.contains("key id 7"), "{}", reason),
+            other => unreachable!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        let tree = roots
+            .tree(Versioned::tree("encrypted").with_vault(RotatorVault::new(13).with_key_id(7)))
+            .unwrap();
+        assert_eq!(tree.get(b"test").unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[test]
+    fn vault_rotation_tests() {
+        fn rotate(roots: &Roots<StdFile>, vault: RotatorVault) -> Result<(), Error> {
+            roots.tree(Versioned::tree("versioned"))?;
+            roots.tree(Unversioned::tree("unversioned"))?;
+            roots.rotate_vault(vault)
+        }
+
+        fn assert_readable(roots: &Roots<StdFile>) {
+            let versioned = roots.tree(Versioned::tree("versioned")).unwrap();
+            assert_eq!(
+                versioned.get(b"test").unwrap().as_deref(),
+                Some(&b"versioned"[..])
+            );
+            let unversioned = roots.tree(Unversioned::tree("unversioned")).unwrap();
+            assert_eq!(
+                unversioned.get(b"test").unwrap().as_deref(),
+                Some(&b"unversioned"[..])
+            );
+        }
+
+        let tempdir = tempdir().unwrap();
+
+        // Start with a vault that doesn't have a key id.
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(RotatorVault::new(13))
+                .open()
+                .unwrap();
+            let versioned = roots.tree(Versioned::tree("versioned")).unwrap();
+            versioned.set(b"test", b"versioned").unwrap();
+            let unversioned = roots.tree(Unversioned::tree("unversioned")).unwrap();
+            unversioned.set(b"test", b"unversioned").unwrap();
+
+            // New vaults must have a key id.
+            assert!(rotate(&roots, RotatorVault::new(42)).is_err());
+
+            rotate(&roots, RotatorVault::new(42).with_key_id(1)).unwrap();
+            assert_readable(&roots);
+        }
+        // The old vault can no longer read the transaction log.
+        assert!(Config::<StdFileManager>::new(tempdir.path())
+            .vault(RotatorVault::new(13))
+            .open()
+            .is_err());
+        // The new vault reads everything, including the transaction log.
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(RotatorVault::new(42).with_key_id(1))
+                .open()
+                .unwrap();
+            // Every tree must be opened before rotating, otherwise it would be
+            // left encrypted by the previous vault.
+            roots.tree(Versioned::tree("versioned")).unwrap();
+            assert!(matches!(
+                roots.rotate_vault(RotatorVault::new(7).with_key_id(2)),
+                Err(Error {
+                    kind: ErrorKind::TreeNotOpened(name),
+                    ..
+                }) if name == "unversioned"
+            ));
+            assert_readable(&roots);
+            rotate(&roots, RotatorVault::new(7).with_key_id(2)).unwrap();
+        }
+        // Opening with a previous vault allows finishing an interrupted
+        // rotation.
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(RotatorVault::new(99).with_key_id(3))
+                .previous_vault(RotatorVault::new(7).with_key_id(2))
+                .open()
+                .unwrap();
+            assert_readable(&roots);
+            // Key ids of vaults still in use can't be reused.
+            assert!(rotate(&roots, RotatorVault::new(8).with_key_id(2)).is_err());
+            rotate(&roots, RotatorVault::new(99).with_key_id(3)).unwrap();
+        }
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(RotatorVault::new(99).with_key_id(3))
+                .open()
+                .unwrap();
+            assert_readable(&roots);
+        }
+    }
+
+    #[test]
+    fn encrypt_unencrypted_database() {
+        fn encrypt(roots: &Roots<StdFile>) -> Result<(), Error> {
+            roots.tree(Versioned::tree("versioned"))?;
+            roots.tree(Unversioned::tree("unversioned"))?;
+            roots.encrypt()
+        }
+
+        fn assert_readable(roots: &Roots<StdFile>, key: &[u8]) {
+            let versioned = roots.tree(Versioned::tree("versioned")).unwrap();
+            assert_eq!(
+                versioned.get(key).unwrap().as_deref(),
+                Some(&b"plaintext-versioned"[..])
+            );
+            let unversioned = roots.tree(Unversioned::tree("unversioned")).unwrap();
+            assert_eq!(
+                unversioned.get(key).unwrap().as_deref(),
+                Some(&b"plaintext-unversioned"[..])
+            );
+        }
+
+        fn contains_plaintext(path: &Path) -> bool {
+            std::fs::read_dir(path).unwrap().any(|entry| {
+                let contents = std::fs::read(entry.unwrap().path()).unwrap();
+                contents
+                    .windows(b"plaintext".len())
+                    .any(|window| window == b"plaintext")
+            })
+        }
+
+        let tempdir = tempdir().unwrap();
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .open()
+                .unwrap();
+            let versioned = roots.tree(Versioned::tree("versioned")).unwrap();
+            versioned.set(b"before", b"plaintext-versioned").unwrap();
+            let unversioned = roots.tree(Unversioned::tree("unversioned")).unwrap();
+            unversioned
+                .set(b"before", b"plaintext-unversioned")
+                .unwrap();
+        }
+        // The unencrypted transaction log can't be read without allowing
+        // unencrypted data.
+        assert!(Config::<StdFileManager>::new(tempdir.path())
+            .vault(RotatorVault::new(13).with_key_id(1))
+            .open()
+            .is_err());
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(RotatorVault::new(13).with_key_id(1))
+                .allow_unencrypted_data()
+                .open()
+                .unwrap();
+            assert_readable(&roots, b"before");
+            // The database remains usable while it is being encrypted.
+            let versioned = roots.tree(Versioned::tree("versioned")).unwrap();
+            versioned.set(b"during", b"plaintext-versioned").unwrap();
+            let unversioned = roots.tree(Unversioned::tree("unversioned")).unwrap();
+            unversioned
+                .set(b"during", b"plaintext-unversioned")
+                .unwrap();
+
+            encrypt(&roots).unwrap();
+            assert_readable(&roots, b"before");
+            assert_readable(&roots, b"during");
+        }
+        assert!(!contains_plaintext(tempdir.path()));
+
+        // Encrypting again is harmless, which allows resuming an interrupted
+        // migration.
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(RotatorVault::new(13).with_key_id(1))
+                .allow_unencrypted_data()
+                .open()
+                .unwrap();
+            // Every tree must be opened before encrypting.
+            assert!(matches!(
+                roots.encrypt(),
+                Err(Error {
+                    kind: ErrorKind::TreeNotOpened(_),
+                    ..
+                })
+            ));
+            encrypt(&roots).unwrap();
+        }
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(RotatorVault::new(13).with_key_id(1))
+                .open()
+                .unwrap();
+            assert_readable(&roots, b"before");
+            assert_readable(&roots, b"during");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn tampered_log_entries_are_rejected() {
+        use crate::ChaChaVault;
+
+        let tempdir = tempdir().unwrap();
+        let key = ChaChaVault::generate_key();
         {
             let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(ChaChaVault::new(&key))
                 .open()
                 .unwrap();
-            // Try to access roots without the vault.
-            let bad_tree = roots.tree(Versioned::tree("test")).unwrap();
-            assert!(bad_tree.get(b"test").is_err());
+            let tree = roots.tree(Versioned::tree("test")).unwrap();
+            tree.set(b"test", b"value").unwrap();
+        }
+        // Flip a byte within the only entry's encrypted contents.
+        let log_path = tempdir.path().join("_transactions");
+        let mut log = std::fs::read(&log_path).unwrap();
+        log[40] ^= 1;
+        std::fs::write(&log_path, log).unwrap();
+
+        // Allowing unencrypted data doesn't cause entries that fail to decrypt
+        // to be treated as unencrypted.
+        assert!(matches!(
+            Config::<StdFileManager>::new(tempdir.path())
+                .vault(ChaChaVault::new(&key))
+                .allow_unencrypted_data()
+                .open(),
+            Err(Error {
+                kind: ErrorKind::Vault(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn chacha_vault_tests() {
+        use crate::ChaChaVault;
 
-            // Try to access roots with the vault specified. In this situation, the transaction log will be unreadable, causing itself to not consider any transactions valid.
-            let bad_tree = roots
-                .tree(Versioned::tree("test").with_vault(RotatorVault::new(13)))
+        let tempdir = tempdir().unwrap();
+        let key = ChaChaVault::generate_key();
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(ChaChaVault::new(&key))
+                .open()
+                .unwrap();
+            let tree = roots.tree(Versioned::tree("test")).unwrap();
+            tree.set(b"test", b"value").unwrap();
+            // Compaction moves every chunk, which must be re-encrypted for its
+            // new location.
+            tree.compact().unwrap();
+            tree.set(b"other", b"value").unwrap();
+        }
+        {
+            let roots = Config::<StdFileManager>::new(tempdir.path())
+                .vault(ChaChaVault::new(&key))
+                .open()
                 .unwrap();
-            assert_eq!(bad_tree.get(b"test").unwrap(), None);
+            let tree = roots.tree(Versioned::tree("test")).unwrap();
+            assert_eq!(tree.get(b"test").unwrap().as_deref(), Some(&b"value"[..]));
+            assert_eq!(tree.get(b"other").unwrap().as_deref(), Some(&b"value"[..]));
         }
+        assert!(Config::<StdFileManager>::new(tempdir.path())
+            .vault(ChaChaVault::new(&ChaChaVault::generate_key()))
+            .open()
+            .is_err());
     }
 
     #[test]
@@ -2052,4 +4160,559 @@ mod tests {
             transaction.commit().unwrap();
         }
     }
+
+    #[test]
+    fn key_comparators() {
+        let tempdir = tempdir().unwrap();
+        let config = Config::default_for(tempdir.path());
+        {
+            let roots = config.clone().open().unwrap();
+            let mut tree = roots
+                .tree(Versioned::tree("reverse").with_comparator(Reverse(Bytewise)))
+                .unwrap();
+            // Enough keys to require interior nodes.
+            let keys = (0_u32..1_000)
+                .rev()
+                .map(|key| ArcBytes::from(key.to_be_bytes()))
+                .collect::<Vec<_>>();
+            tree.modify(keys.clone(), Operation::Set(ArcBytes::from(b"value")))
+                .unwrap();
+            tree.set(1_000_u32.to_be_bytes(), b"value").unwrap();
+            assert_eq!(tree.first_key().unwrap().unwrap(), 1_000_u32.to_be_bytes());
+            assert_eq!(tree.last().unwrap().unwrap().0, 0_u32.to_be_bytes());
+            assert!(tree.get(&500_u32.to_be_bytes()).unwrap().is_some());
+            // Ranges are ordered by the comparator, so the start is the larger
+            // key.
+            let range = tree
+                .get_range(&(&20_u32.to_be_bytes()[..]..&10_u32.to_be_bytes()[..]))
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| u32::from_be_bytes(key.as_slice().try_into().unwrap()))
+                .collect::<Vec<_>>();
+            assert_eq!(range, (11..=20).rev().collect::<Vec<_>>());
+
+            // Keys passed to modify must be ordered by the comparator.
+            let mut keys = keys;
+            keys.reverse();
+            assert!(matches!(
+                tree.modify(keys, Operation::Remove),
+                Err(Error {
+                    kind: ErrorKind::KeysNotOrdered,
+                    ..
+                })
+            ));
+
+            // Opening the same tree with a different comparator fails, even
+            // though its state is already loaded.
+            assert!(matches!(
+                roots.tree(Versioned::tree("reverse")),
+                Err(Error {
+                    kind: ErrorKind::ComparatorMismatch { .. },
+                    ..
+                })
+            ));
+
+            let tree = roots
+                .tree(Unversioned::tree("insensitive").with_comparator(AsciiCaseInsensitive))
+                .unwrap();
+            tree.set(b"Key", b"first").unwrap();
+            tree.set(b"KEY", b"second").unwrap();
+            assert_eq!(tree.count(), 1);
+            assert_eq!(tree.get(b"key").unwrap().unwrap(), b"second");
+        }
+
+        // The comparator is stored in the tree's file.
+        let roots = config.open().unwrap();
+        assert!(matches!(
+            roots.tree(Versioned::tree("reverse")),
+            Err(Error {
+                kind: ErrorKind::ComparatorMismatch { .. },
+                ..
+            })
+        ));
+        assert!(matches!(
+            roots.tree(Unversioned::tree("insensitive").with_comparator(Reverse(Bytewise))),
+            Err(Error {
+                kind: ErrorKind::ComparatorMismatch { .. },
+                ..
+            })
+        ));
+        let tree = roots
+            .tree(Versioned::tree("reverse").with_comparator(Reverse(Bytewise)))
+            .unwrap();
+        assert_eq!(tree.count(), 1_001);
+        assert_eq!(tree.first_key().unwrap().unwrap(), 1_000_u32.to_be_bytes());
+    }
+
+    #[test]
+    fn write_batches() {
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let tree = roots
+            .tree(Versioned::tree("batch").with_comparator(Reverse(Bytewise)))
+            .unwrap();
+        tree.set(b"a", b"a").unwrap();
+
+        // Keys are sorted using the tree's comparator, and the last operation
+        // for a key wins.
+        let mut batch = WriteBatch::new();
+        for key in 0_u32..100 {
+            batch.set(key.to_be_bytes(), key.to_be_bytes());
+        }
+        batch.remove(b"a").set(0_u32.to_be_bytes(), b"zero");
+        assert!(tree.apply_batch(batch).unwrap().is_empty());
+        assert_eq!(tree.count(), 100);
+        assert!(tree.get(b"a").unwrap().is_none());
+        assert_eq!(tree.get(&0_u32.to_be_bytes()).unwrap().unwrap(), b"zero");
+        assert_eq!(tree.get_range(&(..)).unwrap()[0].0, 99_u32.to_be_bytes());
+
+        // Conflicts are reported without preventing the other operations.
+        let mut batch = WriteBatch::new();
+        batch
+            .compare_and_swap(b"a", Some(ArcBytes::from(b"a")), None)
+            .remove(1_u32.to_be_bytes());
+        let conflicts = tree.apply_batch(batch).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].key, b"a");
+        assert_eq!(conflicts[0].value, None);
+        assert_eq!(tree.count(), 99);
+    }
+
+    #[test]
+    fn merge_operators() {
+        let tempdir = tempdir().unwrap();
+        let config = Config::default_for(tempdir.path());
+        let one = 1_u64.to_be_bytes();
+        {
+            let roots = config.clone().open().unwrap();
+            let mut tree = roots
+                .tree(Versioned::tree("counters").with_merge_operator(AddU64))
+                .unwrap();
+            for _ in 0..10 {
+                tree.merge(b"total", one).unwrap();
+            }
+            let keys = (0_u32..100)
+                .map(|key| ArcBytes::from(key.to_be_bytes()))
+                .collect::<Vec<_>>();
+            tree.modify(keys.clone(), Operation::Merge(ArcBytes::from(one)))
+                .unwrap();
+            tree.modify(keys, Operation::Merge(ArcBytes::from(one)))
+                .unwrap();
+            assert_eq!(tree.get(b"total").unwrap().unwrap(), 10_u64.to_be_bytes());
+            assert_eq!(
+                tree.get(&42_u32.to_be_bytes()).unwrap().unwrap(),
+                2_u64.to_be_bytes()
+            );
+
+            // A rolled back transaction keeps the merge operator.
+            let transaction = roots.transaction(&[Versioned::tree("counters")]).unwrap();
+            transaction
+                .tree::<VersionedTreeRoot<()>>(0)
+                .unwrap()
+                .merge(b"total", one)
+                .unwrap();
+            drop(transaction);
+            let other_handle = roots.tree(Versioned::tree("counters")).unwrap();
+            other_handle.merge(b"total", one).unwrap();
+            assert_eq!(tree.get(b"total").unwrap().unwrap(), 11_u64.to_be_bytes());
+        }
+
+        // The merge operator isn't stored in the file.
+        let roots = config.open().unwrap();
+        let tree = roots.tree(Versioned::tree("counters")).unwrap();
+        assert!(matches!(
+            tree.merge(b"total", one),
+            Err(Error {
+                kind: ErrorKind::MergeOperatorNotConfigured,
+                ..
+            })
+        ));
+        let tree = roots
+            .tree(Versioned::tree("counters").with_merge_operator(AddU64))
+            .unwrap();
+        tree.merge(b"total", one).unwrap();
+        assert_eq!(tree.get(b"total").unwrap().unwrap(), 12_u64.to_be_bytes());
+    }
+
+    #[test]
+    fn expirations() {
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let tree = roots.tree(Unversioned::tree("sessions")).unwrap();
+        assert_eq!(tree.remove_expired().unwrap(), 0);
+
+        for session in 0_u32..100 {
+            tree.set_with_ttl(session.to_be_bytes(), b"expired", Duration::ZERO)
+                .unwrap();
+        }
+        tree.set_with_ttl(b"alive", b"alive", Duration::from_secs(60 * 60))
+            .unwrap();
+        assert!(tree.get(&42_u32.to_be_bytes()).unwrap().is_none());
+        assert_eq!(tree.get_range(&(..)).unwrap().len(), 1);
+        assert_eq!(tree.remove_expired().unwrap(), 100);
+        assert_eq!(tree.count(), 1);
+
+        let transaction = roots.transaction(&[Unversioned::tree("sessions")]).unwrap();
+        transaction
+            .tree::<Unversioned>(0)
+            .unwrap()
+            .set_with_ttl(b"expired", b"expired", Duration::ZERO)
+            .unwrap();
+        transaction.commit().unwrap();
+        tree.compact().unwrap();
+        assert_eq!(tree.count(), 1);
+
+        // The reaper removes keys as they expire.
+        let reaper = tree.reap_expired_every(Duration::from_millis(10)).unwrap();
+        tree.set_with_ttl(b"expiring", b"expiring", Duration::from_millis(50))
+            .unwrap();
+        let start = Instant::now();
+        while tree.count() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        reaper.stop().unwrap();
+        assert_eq!(tree.get(b"alive").unwrap().unwrap(), b"alive");
+    }
+
+    #[test]
+    fn order_statistics() {
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let mut tree = roots.tree(Unversioned::tree("pages")).unwrap();
+        assert_eq!(tree.count_range(&(..)).unwrap(), 0);
+        assert!(tree.nth(0).unwrap().is_none());
+
+        let keys = (0_u32..3_000)
+            .map(|id| ArcBytes::from(id.to_be_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        tree.modify(keys.clone(), Operation::Set(ArcBytes::from(b"page")))
+            .unwrap();
+        // The first key of the third page of 50 entries.
+        assert_eq!(tree.nth(100).unwrap().unwrap(), keys[100]);
+        assert_eq!(tree.rank(&keys[100]).unwrap(), 100);
+        assert_eq!(
+            tree.count_range(&(&keys[100][..]..&keys[150][..])).unwrap(),
+            50
+        );
+
+        let transaction = roots.transaction(&[Unversioned::tree("pages")]).unwrap();
+        {
+            let mut tree = transaction.tree::<Unversioned>(0).unwrap();
+            tree.remove(&keys[0]).unwrap();
+            assert_eq!(tree.nth(0).unwrap().unwrap(), keys[1]);
+            assert_eq!(tree.count_range(&(..)).unwrap(), 2_999);
+        }
+        transaction.commit().unwrap();
+        assert_eq!(tree.rank(&keys[100]).unwrap(), 99);
+    }
+
+    #[test]
+    fn range_estimates() {
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let mut tree = roots.tree(Unversioned::tree("events")).unwrap();
+        assert_eq!(
+            tree.estimate_range(&(..)).unwrap(),
+            RangeEstimate::default()
+        );
+
+        let keys = (0_u32..1_000)
+            .map(|id| ArcBytes::from(id.to_be_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        tree.modify(keys.clone(), Operation::Set(ArcBytes::from(b"event")))
+            .unwrap();
+        assert_eq!(
+            tree.estimate_range(&(..&keys[500][..])).unwrap(),
+            RangeEstimate {
+                keys: 500,
+                value_bytes: 500 * 5,
+            }
+        );
+
+        let transaction = roots.transaction(&[Unversioned::tree("events")]).unwrap();
+        {
+            let mut tree = transaction.tree::<Unversioned>(0).unwrap();
+            tree.remove(&keys[0]).unwrap();
+            assert_eq!(tree.estimate_range(&(..)).unwrap().keys, 999);
+        }
+        transaction.rollback();
+    }
+
+    #[test]
+    #[cfg(feature = "sampling")]
+    fn sampling() {
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let mut tree = roots.tree(Unversioned::tree("events")).unwrap();
+        assert!(tree.sample(10, &(..)).unwrap().is_empty());
+
+        let keys = (0_u32..1_000)
+            .map(|id| ArcBytes::from(id.to_be_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        tree.modify(keys.clone(), Operation::Set(ArcBytes::from(b"event")))
+            .unwrap();
+        let sample = tree.sample(10, &(&keys[100][..]..&keys[200][..])).unwrap();
+        assert_eq!(sample.len(), 10);
+        assert!(sample
+            .iter()
+            .all(|(key, _)| key >= &keys[100] && key < &keys[200]));
+
+        let transaction = roots.transaction(&[Unversioned::tree("events")]).unwrap();
+        {
+            let mut tree = transaction.tree::<Unversioned>(0).unwrap();
+            tree.remove(&keys[0]).unwrap();
+            assert_eq!(tree.sample(1_000, &(..)).unwrap().len(), 999);
+        }
+        transaction.rollback();
+    }
+
+    #[test]
+    fn secondary_indexes() {
+        fn by_city(value: &[u8]) -> Vec<ArcBytes<'static>> {
+            value
+                .split(|&b| b == b'/')
+                .next()
+                .map(|city| ArcBytes::from(city.to_vec()))
+                .into_iter()
+                .collect()
+        }
+        let keys = |entries: Vec<(ArcBytes<'static>, ArcBytes<'static>)>| {
+            entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+        };
+
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let tree = roots
+            .tree(Versioned::tree("users").with_index("city", by_city))
+            .unwrap();
+        tree.set(b"ada", b"london/ada").unwrap();
+        tree.set(b"bob", b"paris/bob").unwrap();
+        tree.set(b"cy", b"london/cy").unwrap();
+        tree.set_with_ttl(b"dee", b"london/dee", Duration::ZERO)
+            .unwrap();
+        assert_eq!(
+            keys(tree.get_by_index("city", b"london").unwrap()),
+            [&b"ada"[..], &b"cy"[..]]
+        );
+        assert!(tree.get_by_index("city", b"lon").unwrap().is_empty());
+
+        // Removing expired keys removes their index entries.
+        tree.set_with_ttl(b"dee", b"london/dee", Duration::from_millis(100))
+            .unwrap();
+        let index_tree = roots.tree(Unversioned::tree("users~city")).unwrap();
+        assert!(index_tree.get(b"\x01dee").unwrap().is_some());
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(tree.remove_expired().unwrap(), 1);
+        assert!(index_tree.get(b"\x01dee").unwrap().is_none());
+
+        // Moving a key removes it from its previous index key.
+        tree.set(b"ada", b"paris/ada").unwrap();
+        tree.remove(b"cy").unwrap();
+        assert!(tree.get_by_index("city", b"london").unwrap().is_empty());
+        let entries = tree.get_range_by_index("city", &(..)).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (&entry.index_key[..], &entry.key[..]))
+                .collect::<Vec<_>>(),
+            [(&b"paris"[..], &b"ada"[..]), (&b"paris"[..], &b"bob"[..])]
+        );
+
+        // Rolled back changes are not indexed.
+        let transaction = roots
+            .transaction(&[Versioned::tree("users").with_index("city", by_city)])
+            .unwrap();
+        transaction
+            .tree::<Versioned>(0)
+            .unwrap()
+            .set(b"eve", b"oslo/eve")
+            .unwrap();
+        transaction.rollback();
+        assert!(tree.get_by_index("city", b"oslo").unwrap().is_empty());
+
+        tree.set(b"eve", b"oslo/eve").unwrap();
+        tree.set(b"fay", b"rome/fay").unwrap();
+        assert_eq!(
+            tree.get_range_by_index("city", &(&b"oslo"[..]..&b"rome"[..]))
+                .unwrap()
+                .len(),
+            3
+        );
+        tree.remove_range(&(&b"a"[..]..&b"c"[..])).unwrap();
+        assert_eq!(
+            keys(tree.get_by_index("city", b"paris").unwrap()),
+            Vec::<ArcBytes<'_>>::new()
+        );
+
+        // Indexes declared on existing trees must be rebuilt.
+        let tree = roots
+            .tree(
+                Versioned::tree("users")
+                    .with_index("city", by_city)
+                    .with_index("name", |value| {
+                        value
+                            .split(|&b| b == b'/')
+                            .skip(1)
+                            .map(|name| ArcBytes::from(name.to_vec()))
+                            .collect()
+                    }),
+            )
+            .unwrap();
+        assert!(tree.get_by_index("name", b"fay").unwrap().is_empty());
+        tree.rebuild_indexes().unwrap();
+        assert_eq!(keys(tree.get_by_index("name", b"fay").unwrap()), [b"fay"]);
+        assert_eq!(keys(tree.get_by_index("city", b"oslo").unwrap()), [b"eve"]);
+
+        assert!(matches!(
+            tree.get_by_index("age", b"42"),
+            Err(Error {
+                kind: ErrorKind::SecondaryIndexNotFound(_),
+                ..
+            })
+        ));
+
+        // The declared indexes are stored in the tree's file, and the tree
+        // can't be modified without declaring each of them.
+        drop((tree, index_tree, roots));
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let tree = roots
+            .tree(Versioned::tree("users").with_index("city", by_city))
+            .unwrap();
+        assert_eq!(keys(tree.get_by_index("city", b"oslo").unwrap()), [b"eve"]);
+        assert!(matches!(
+            tree.set(b"gus", b"oslo/gus"),
+            Err(Error {
+                kind: ErrorKind::SecondaryIndexNotDeclared(name),
+                ..
+            }) if name == "name"
+        ));
+        assert!(tree.get(b"gus").unwrap().is_none());
+
+        // Index trees aren't listed, and are deleted along with their tree.
+        assert_eq!(roots.tree_names().unwrap(), [String::from("users")]);
+        assert!(roots.delete_tree("users").unwrap());
+        assert!(roots.stored_tree_names().unwrap().is_empty());
+    }
+
+    #[test]
+    fn secondary_index_tree_names() {
+        fn by_value(value: &[u8]) -> Vec<ArcBytes<'static>> {
+            vec![ArcBytes::from(value.to_vec())]
+        }
+
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let users = roots
+            .tree(Versioned::tree("users").with_index("email", by_value))
+            .unwrap();
+        users.set(b"ada", b"ada@example.com").unwrap();
+        let orders = roots
+            .tree(Versioned::tree("orders").with_index("customer", by_value))
+            .unwrap();
+        orders.set(b"1", b"ada").unwrap();
+        assert!(matches!(
+            roots.tree(Versioned::tree("users").with_index("e~mail", by_value)),
+            Err(Error {
+                kind: ErrorKind::InvalidTreeName,
+                ..
+            })
+        ));
+
+        // Trees whose names resemble a companion tree are ordinary trees.
+        let orders_v2 = roots.tree(Versioned::tree("orders.index.v2")).unwrap();
+        orders_v2.set(b"1", b"v2").unwrap();
+        let users_email = roots.tree(Versioned::tree("users.index.email")).unwrap();
+        users_email.set(b"ada", b"unrelated").unwrap();
+        assert_eq!(
+            users_email.get_range(&(..)).unwrap(),
+            vec![(ArcBytes::from(b"ada"), ArcBytes::from(b"unrelated"))]
+        );
+        assert_eq!(
+            users
+                .get_by_index("email", b"ada@example.com")
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let mut names = roots.tree_names().unwrap();
+        names.sort();
+        assert_eq!(
+            names,
+            ["orders", "orders.index.v2", "users", "users.index.email"]
+        );
+
+        drop(orders);
+        assert!(roots.delete_tree("orders").unwrap());
+        let mut names = roots.tree_names().unwrap();
+        names.sort();
+        assert_eq!(names, ["orders.index.v2", "users", "users.index.email"]);
+        assert!(!roots
+            .stored_tree_names()
+            .unwrap()
+            .contains(&String::from("orders~customer")));
+        assert_eq!(orders_v2.get(b"1").unwrap().unwrap(), b"v2");
+    }
+
+    fn prefixes<R: Root<Value = ArcBytes<'static>> + Default>(root: TreeRoot<R, StdFile>) {
+        let tempdir = tempdir().unwrap();
+        let roots = Config::default_for(tempdir.path()).open().unwrap();
+        let mut tree = roots.tree(root).unwrap();
+        for tenant in ["a", "b", "c"] {
+            // Enough keys per tenant to require interior nodes.
+            let keys = (0..500)
+                .map(|id| ArcBytes::from(format!("{}/{:04}", tenant, id).into_bytes()))
+                .collect::<Vec<_>>();
+            tree.modify(keys, Operation::Set(ArcBytes::from(tenant.as_bytes())))
+                .unwrap();
+        }
+
+        let tenant = tree.get_prefix(b"b/").unwrap();
+        assert_eq!(tenant.len(), 500);
+        assert!(tenant
+            .iter()
+            .all(|(key, value)| key.starts_with(b"b/") && value == b"b"));
+        assert_eq!(tree.get_prefix(b"b/00").unwrap().len(), 100);
+        assert!(tree.get_prefix(b"d/").unwrap().is_empty());
+
+        let mut scanned = Vec::new();
+        tree.scan_prefix::<Infallible, _, _>(
+            b"c/049",
+            false,
+            |_, _| ScanEvaluation::ReadData,
+            |key, _index, _value| {
+                scanned.push(key);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            scanned,
+            (490..500)
+                .rev()
+                .map(|id| ArcBytes::from(format!("c/{:04}", id).into_bytes()))
+                .collect::<Vec<_>>()
+        );
+
+        // Dropping a tenant leaves its neighbors intact.
+        assert_eq!(tree.remove_range(&(&b"b/"[..]..&b"c/"[..])).unwrap(), 500);
+        assert!(tree.get_prefix(b"b/").unwrap().is_empty());
+        assert_eq!(tree.get_prefix(b"a/").unwrap().len(), 500);
+        assert_eq!(tree.get_prefix(b"c/").unwrap().len(), 500);
+        assert_eq!(tree.count(), 1_000);
+        assert_eq!(tree.remove_range(&(&b"b/"[..]..&b"c/"[..])).unwrap(), 0);
+        assert_eq!(tree.remove_range(&(..)).unwrap(), 1_000);
+        assert_eq!(tree.count(), 0);
+    }
+
+    #[test]
+    fn prefixes_versioned() {
+        prefixes(Versioned::tree("prefixes"));
+    }
+
+    #[test]
+    fn prefixes_unversioned() {
+        prefixes(Unversioned::tree("prefixes"));
+    }
 }
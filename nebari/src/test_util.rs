@@ -47,11 +47,20 @@ impl Deref for TestDirectory {
 #[derive(Debug)]
 pub struct RotatorVault {
     rotation_amount: u8,
+    key_id: Option<u32>,
 }
 
 impl RotatorVault {
     pub const fn new(rotation_amount: u8) -> Self {
-        Self { rotation_amount }
+        Self {
+            rotation_amount,
+            key_id: None,
+        }
+    }
+
+    pub const fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = Some(key_id);
+        self
     }
 }
 
@@ -78,6 +87,10 @@ impl Vault for RotatorVault {
             .map(|c| c.wrapping_sub(self.rotation_amount))
             .collect())
     }
+
+    fn key_id(&self) -> Option<u32> {
+        self.key_id
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -1,11 +1,11 @@
 use std::{
     borrow::Cow,
     cmp::Ordering,
-    fs::OpenOptions,
     io::{SeekFrom, Write},
     ops::{Bound, RangeBounds},
     path::Path,
     sync::Arc,
+    time::Instant,
 };
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
@@ -13,17 +13,27 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use super::{State, TransactionHandle};
 use crate::{
     error::Error,
-    io::{File, FileManager, FileOp, ManagedFile, OpenableFile, OperableFile},
+    io::{File, FileManager, FileOp, ManagedFile, ManagedFileOpener, OpenableFile, OperableFile},
     transaction::TransactionId,
-    vault::AnyVault,
-    ArcBytes, Context, ErrorKind,
+    vault::{location_binding, seal, unseal, AnyVault},
+    ArcBytes, Context, ErrorKind, Metrics,
 };
 
 const PAGE_SIZE: usize = 1024;
+/// The first byte of a page that begins an entry written by older versions,
+/// which is encrypted by the vault if one was in use.
+const ENTRY: u8 = 1;
+/// The first byte of a page that begins an entry whose contents are a
+/// [sealed](crate::vault::seal) envelope.
+const SEALED_ENTRY: u8 = 2;
+/// The first byte of a page that begins an entry written without a vault.
+const UNENCRYPTED_ENTRY: u8 = 3;
 
 /// A transaction log that records changes for one or more trees.
 pub struct TransactionLog<File: ManagedFile> {
     vault: Option<Arc<dyn AnyVault>>,
+    allow_unencrypted_data: bool,
+    metrics: Option<Arc<dyn Metrics>>,
     state: State,
     log: <File::Manager as FileManager>::FileHandle,
 }
@@ -38,6 +48,8 @@ impl<File: ManagedFile> TransactionLog<File> {
         let log = context.file_manager.read(log_path)?;
         Ok(Self {
             vault: context.vault,
+            allow_unencrypted_data: context.allow_unencrypted_data,
+            metrics: context.metrics,
             state,
             log,
         })
@@ -52,6 +64,8 @@ impl<File: ManagedFile> TransactionLog<File> {
         let log = context.file_manager.append(log_path)?;
         Ok(Self {
             vault: context.vault,
+            allow_unencrypted_data: context.allow_unencrypted_data,
+            metrics: context.metrics,
             state,
             log,
         })
@@ -85,20 +99,15 @@ impl<File: ManagedFile> TransactionLog<File> {
                 "Transaction log has {} extra bytes. Truncating.",
                 excess_length
             );
-            let file = OpenOptions::new()
-                .append(true)
-                .write(true)
-                .open(state.path())?;
             log_length -= excess_length;
-            file.set_len(log_length)?;
-            file.sync_all()?;
+            context.file_manager.truncate(state.path(), log_length)?;
         }
 
         let mut file = context.file_manager.read(state.path())?;
         file.execute(StateInitializer {
             state,
             log_length,
-            vault: context.vault(),
+            decoder: EntryDecoder::new(context),
         })
     }
 
@@ -114,6 +123,7 @@ impl<File: ManagedFile> TransactionLog<File> {
         self.log.execute(LogWriter {
             state: self.state.clone(),
             vault: self.vault.clone(),
+            metrics: self.metrics.clone(),
             transactions: handles,
         })
     }
@@ -123,7 +133,10 @@ impl<File: ManagedFile> TransactionLog<File> {
         match self.log.execute(EntryFetcher {
             id,
             state: &self.state,
-            vault: self.vault.as_deref(),
+            decoder: EntryDecoder {
+                vault: self.vault.as_deref(),
+                allow_unencrypted_data: self.allow_unencrypted_data,
+            },
         })? {
             ScanResult::Found { entry, .. } => Ok(Some(entry)),
             ScanResult::NotFound { .. } => Ok(None),
@@ -141,7 +154,10 @@ impl<File: ManagedFile> TransactionLog<File> {
             ids,
             callback,
             state: &self.state,
-            vault: self.vault.as_deref(),
+            decoder: EntryDecoder {
+                vault: self.vault.as_deref(),
+                allow_unencrypted_data: self.allow_unencrypted_data,
+            },
         })
     }
 
@@ -150,6 +166,76 @@ impl<File: ManagedFile> TransactionLog<File> {
         self.log.close()
     }
 
+    /// Rewrites every entry of the transaction log into a new file, encrypting
+    /// each entry with the log's current vault, and atomically replaces the log
+    /// with the new file.
+    ///
+    /// No transactions may be pushed to the log while it is being rewritten.
+    pub fn rewrite(mut self, context: &Context<File::Manager>) -> Result<Self, Error> {
+        let current_path = self.state.path().to_path_buf();
+        let mut rewritten_name = current_path
+            .file_name()
+            .ok_or_else(|| ErrorKind::InvalidFilePath(current_path.clone()))?
+            .to_os_string();
+        rewritten_name.push(".rewriting");
+        let rewritten_path = current_path.with_file_name(rewritten_name);
+        context.file_manager.delete(&rewritten_path)?;
+
+        let mut rewritten = context.file_manager.open_for_append(&rewritten_path)?;
+        let mut rewritten_length = 0;
+        if !self.state.is_empty() {
+            let mut scratch = [0_u8; PAGE_SIZE];
+            let mut write_result = Ok(());
+            let vault = self.vault.as_deref();
+            let mut current = context.file_manager.read(&current_path)?;
+            current.execute(EntryScanner {
+                state: &self.state,
+                ids: ..,
+                decoder: EntryDecoder {
+                    vault: self.vault.as_deref(),
+                    allow_unencrypted_data: self.allow_unencrypted_data,
+                },
+                callback: |entry| {
+                    let written = entry
+                        .serialize()
+                        .and_then(|bytes| {
+                            encode_entry(bytes, vault, &current_path, rewritten_length)
+                        })
+                        .and_then(|(entry_type, bytes)| {
+                            write_entry(&mut rewritten, &mut scratch, entry_type, &bytes)
+                        });
+                    match written {
+                        Ok(length) => {
+                            rewritten_length += length;
+                            true
+                        }
+                        Err(err) => {
+                            write_result = Err(err);
+                            false
+                        }
+                    }
+                },
+            })?;
+            write_result?;
+        }
+        let start = Instant::now();
+        rewritten.synchronize()?;
+        if let Some(metrics) = context.metrics() {
+            metrics.file_synchronized(&rewritten_path, start.elapsed());
+        }
+
+        let mut log_position = self.state.lock_for_write();
+        self.log = self
+            .log
+            .replace_with(rewritten, &context.file_manager, |_| {})?;
+        log_position.file_offset = rewritten_length;
+        drop(log_position);
+        // Entries have moved, so any cached positions are no longer valid.
+        self.state.forget_transaction_positions();
+
+        Ok(self)
+    }
+
     /// Begins a new transaction, exclusively locking `trees`.
     pub fn new_transaction<
         'a,
@@ -171,7 +257,7 @@ impl<File: ManagedFile> TransactionLog<File> {
 struct StateInitializer<'a> {
     state: &'a State,
     log_length: u64,
-    vault: Option<&'a dyn AnyVault>,
+    decoder: EntryDecoder<'a>,
 }
 
 impl<'a> FileOp<Result<(), Error>> for StateInitializer<'a> {
@@ -183,12 +269,13 @@ impl<'a> FileOp<Result<(), Error>> for StateInitializer<'a> {
         scratch_buffer.resize(4, 0);
 
         let last_transaction =
-            match scan_for_transaction(log, &mut scratch_buffer, block_start, false, self.vault)? {
+            match scan_for_transaction(log, &mut scratch_buffer, block_start, false, self.decoder)?
+            {
                 ScanResult::Found { entry, .. } => entry,
                 ScanResult::NotFound { .. } => {
-                    return Err(Error::data_integrity(
-                        "No entries found in an existing transaction log",
-                    ))
+                    return Err(
+                        Error::data_integrity(ErrorKind::TransactionLogEmpty).with_path(log.id())
+                    )
                 }
             };
 
@@ -197,6 +284,60 @@ impl<'a> FileOp<Result<(), Error>> for StateInitializer<'a> {
     }
 }
 
+/// Decodes the entries read from a transaction log.
+#[derive(Clone, Copy)]
+#[allow(clippy::redundant_pub_crate)]
+pub(crate) struct EntryDecoder<'a> {
+    vault: Option<&'a dyn AnyVault>,
+    allow_unencrypted_data: bool,
+}
+
+impl<'a> EntryDecoder<'a> {
+    /// Returns a decoder using the vault in `context`.
+    pub(crate) fn new<Manager: FileManager>(context: &'a Context<Manager>) -> Self {
+        Self {
+            vault: context.vault(),
+            allow_unencrypted_data: context.allow_unencrypted_data,
+        }
+    }
+
+    /// Returns the serialized entry contained in `payload`, an entry of
+    /// `entry_type` read from `position` in the log at `path`.
+    ///
+    /// Entries written without a vault are only returned when there is no
+    /// vault, or when unencrypted data is allowed. Entries written by older
+    /// versions do not record whether they are encrypted, and are decrypted
+    /// by the vault unless unencrypted data is allowed.
+    fn decode<'p>(
+        self,
+        entry_type: u8,
+        payload: &'p [u8],
+        path: &Path,
+        position: u64,
+    ) -> Result<Cow<'p, [u8]>, Error> {
+        match (self.vault, entry_type) {
+            (Some(vault), SEALED_ENTRY) => Ok(Cow::Owned(unseal(
+                vault,
+                payload,
+                &location_binding(path, position),
+            )?)),
+            (Some(vault), ENTRY) if !self.allow_unencrypted_data => Ok(Cow::Owned(
+                vault.decrypt_with_associated_data(payload, &location_binding(path, position))?,
+            )),
+            (Some(_), _) if !self.allow_unencrypted_data => {
+                Err(Error::from(ErrorKind::VaultMismatch {
+                    path: path.to_path_buf(),
+                    reason: String::from(
+                        "the transaction log contains entries written without a vault, and unencrypted data is not allowed",
+                    ),
+                }))
+            }
+            (None, SEALED_ENTRY) => Err(Error::from(ErrorKind::VaultNotConfigured)),
+            _ => Ok(Cow::Borrowed(payload)),
+        }
+    }
+}
+
 pub enum ScanResult {
     Found {
         entry: LogEntry<'static>,
@@ -213,7 +354,7 @@ fn scan_for_transaction(
     scratch_buffer: &mut Vec<u8>,
     mut block_start: u64,
     scan_forward: bool,
-    vault: Option<&dyn AnyVault>,
+    decoder: EntryDecoder<'_>,
 ) -> Result<ScanResult, Error> {
     if scratch_buffer.len() < 4 {
         scratch_buffer.resize(4, 0);
@@ -243,7 +384,7 @@ fn scan_for_transaction(
                 }
                 continue;
             }
-            1 => {
+            entry_type @ (ENTRY | SEALED_ENTRY | UNENCRYPTED_ENTRY) => {
                 // The length is the next 3 bytes.
                 let length = (scratch_buffer[1] as usize) << 16
                     | (scratch_buffer[2] as usize) << 8
@@ -272,11 +413,12 @@ fn scan_for_transaction(
                     bytes_to_read -= page_length;
                 }
 
-                let payload = &scratch_buffer[0..length];
-                let decrypted = match &vault {
-                    Some(vault) => Cow::Owned(vault.decrypt(payload)?),
-                    None => Cow::Borrowed(payload),
-                };
+                let decrypted = decoder.decode(
+                    entry_type,
+                    &scratch_buffer[0..length],
+                    log.id().path(),
+                    block_start,
+                )?;
                 let entry = LogEntry::deserialize(&decrypted)
                     .map_err(Error::data_integrity)?
                     .into_owned();
@@ -295,13 +437,13 @@ fn scan_for_transaction(
 pub(crate) struct EntryFetcher<'a> {
     pub state: &'a State,
     pub id: TransactionId,
-    pub vault: Option<&'a dyn AnyVault>,
+    pub decoder: EntryDecoder<'a>,
 }
 
 impl<'a> FileOp<Result<ScanResult, Error>> for EntryFetcher<'a> {
     fn execute(self, log: &mut dyn File) -> Result<ScanResult, Error> {
         let mut scratch = Vec::with_capacity(PAGE_SIZE);
-        fetch_entry(log, &mut scratch, self.state, self.id, self.vault)
+        fetch_entry(log, &mut scratch, self.state, self.id, self.decoder)
     }
 }
 
@@ -310,7 +452,7 @@ fn fetch_entry(
     scratch_buffer: &mut Vec<u8>,
     state: &State,
     id: TransactionId,
-    vault: Option<&dyn AnyVault>,
+    decoder: EntryDecoder<'_>,
 ) -> Result<ScanResult, Error> {
     if !id.valid() {
         return Ok(ScanResult::NotFound {
@@ -346,7 +488,7 @@ fn fetch_entry(
         // load the transaction at this location
         #[allow(clippy::cast_possible_wrap)]
         let scan_forward = guessed_location >= upper_location;
-        match scan_for_transaction(log, scratch_buffer, guessed_location, scan_forward, vault)? {
+        match scan_for_transaction(log, scratch_buffer, guessed_location, scan_forward, decoder)? {
             ScanResult::Found {
                 entry,
                 position,
@@ -397,7 +539,7 @@ pub struct EntryScanner<
 > {
     pub state: &'a State,
     pub ids: Range,
-    pub vault: Option<&'a dyn AnyVault>,
+    pub decoder: EntryDecoder<'a>,
     pub callback: Callback,
 }
 
@@ -410,7 +552,7 @@ where
         let mut scratch = Vec::with_capacity(PAGE_SIZE);
         let (start_location, start_transaction, start_length) = match self.ids.start_bound() {
             Bound::Included(start_key) | Bound::Excluded(start_key) => {
-                match fetch_entry(log, &mut scratch, self.state, *start_key, self.vault)? {
+                match fetch_entry(log, &mut scratch, self.state, *start_key, self.decoder)? {
                     ScanResult::Found {
                         entry,
                         position,
@@ -434,7 +576,7 @@ where
             entry,
             position,
             length,
-        } = scan_for_transaction(log, &mut scratch, next_scan_start, true, self.vault)?
+        } = scan_for_transaction(log, &mut scratch, next_scan_start, true, self.decoder)?
         {
             if self.ids.contains(&entry.id) && !(self.callback)(entry) {
                 break;
@@ -455,6 +597,7 @@ struct LogWriter {
     state: State,
     transactions: Vec<LogEntry<'static>>,
     vault: Option<Arc<dyn AnyVault>>,
+    metrics: Option<Arc<dyn Metrics>>,
 }
 
 impl FileOp<Result<(), Error>> for LogWriter {
@@ -466,53 +609,26 @@ impl FileOp<Result<(), Error>> for LogWriter {
             if transaction.id > log_position.last_written_transaction {
                 log_position.last_written_transaction = transaction.id;
             } else {
-                return Err(Error::from(ErrorKind::TransactionPushedOutOfOrder));
+                return Err(Error::from(ErrorKind::TransactionPushedOutOfOrder)
+                    .with_transaction_id(transaction.id));
             }
             completed_transactions.push((transaction.id, Some(log_position.file_offset)));
-            let mut bytes = transaction.serialize()?;
-            if let Some(vault) = &self.vault {
-                bytes = vault.encrypt(&bytes)?;
-            }
-            // Write out the transaction in pages.
-            let total_length = bytes.len() + 3;
-            let mut offset = 0;
-            while offset < bytes.len() {
-                // Write the page header
-                let header_len = if offset == 0 {
-                    // The first page has the length of the payload as the next 3 bytes.
-                    let length = u32::try_from(bytes.len())
-                        .map_err(|_| Error::from("transaction too large"))?;
-                    if length & 0xFF00_0000 != 0 {
-                        return Err(Error::from("transaction too large"));
-                    }
-                    scratch[0] = 1;
-                    #[allow(clippy::cast_possible_truncation)]
-                    {
-                        scratch[1] = (length >> 16) as u8;
-                        scratch[2] = (length >> 8) as u8;
-                        scratch[3] = (length & 0xFF) as u8;
-                    }
-                    4
-                } else {
-                    // Set page_header to have a 0 byte for future pages written.
-                    scratch[0] = 0;
-                    1
-                };
-
-                // Write up to PAGE_SIZE - header_len bytes
-                let total_bytes_left = total_length - (offset + 3);
-                let bytes_to_write = total_bytes_left.min(PAGE_SIZE - header_len as usize);
-                scratch[header_len..bytes_to_write + header_len]
-                    .copy_from_slice(&bytes[offset..offset + bytes_to_write]);
-                log.write_all(&scratch)?;
-                offset += bytes_to_write;
-                log_position.file_offset += PAGE_SIZE as u64;
-            }
+            let (entry_type, bytes) = encode_entry(
+                transaction.serialize()?,
+                self.vault.as_deref(),
+                self.state.path(),
+                log_position.file_offset,
+            )?;
+            log_position.file_offset += write_entry(log, &mut scratch, entry_type, &bytes)?;
         }
 
         drop(log_position);
 
+        let start = Instant::now();
         log.synchronize()?;
+        if let Some(metrics) = &self.metrics {
+            metrics.file_synchronized(self.state.path(), start.elapsed());
+        }
 
         self.state
             .note_transaction_ids_completed(&completed_transactions);
@@ -521,6 +637,69 @@ impl FileOp<Result<(), Error>> for LogWriter {
     }
 }
 
+/// Seals a serialized entry if `vault` is provided, returning the type of the
+/// entry and the bytes to write for an entry written to `position` in the log
+/// at `path`.
+fn encode_entry(
+    bytes: Vec<u8>,
+    vault: Option<&dyn AnyVault>,
+    path: &Path,
+    position: u64,
+) -> Result<(u8, Vec<u8>), Error> {
+    match vault {
+        Some(vault) => Ok((
+            SEALED_ENTRY,
+            seal(vault, &bytes, &location_binding(path, position))?,
+        )),
+        None => Ok((UNENCRYPTED_ENTRY, bytes)),
+    }
+}
+
+/// Writes `bytes` as a single entry of `entry_type` in pages, returning the
+/// number of bytes written to `log`.
+fn write_entry(
+    log: &mut dyn File,
+    scratch: &mut [u8; PAGE_SIZE],
+    entry_type: u8,
+    bytes: &[u8],
+) -> Result<u64, Error> {
+    let total_length = bytes.len() + 3;
+    let mut offset = 0;
+    let mut bytes_written = 0;
+    while offset < bytes.len() {
+        // Write the page header
+        let header_len = if offset == 0 {
+            // The first page has the length of the payload as the next 3 bytes.
+            let length = u32::try_from(bytes.len()).map_err(|_| ErrorKind::TransactionTooLarge)?;
+            if length & 0xFF00_0000 != 0 {
+                return Err(Error::from(ErrorKind::TransactionTooLarge));
+            }
+            scratch[0] = entry_type;
+            #[allow(clippy::cast_possible_truncation)]
+            {
+                scratch[1] = (length >> 16) as u8;
+                scratch[2] = (length >> 8) as u8;
+                scratch[3] = (length & 0xFF) as u8;
+            }
+            4
+        } else {
+            // Set page_header to have a 0 byte for future pages written.
+            scratch[0] = 0;
+            1
+        };
+
+        // Write up to PAGE_SIZE - header_len bytes
+        let total_bytes_left = total_length - (offset + 3);
+        let bytes_to_write = total_bytes_left.min(PAGE_SIZE - header_len as usize);
+        scratch[header_len..bytes_to_write + header_len]
+            .copy_from_slice(&bytes[offset..offset + bytes_to_write]);
+        log.write_all(&scratch[..])?;
+        offset += bytes_to_write;
+        bytes_written += PAGE_SIZE as u64;
+    }
+    Ok(bytes_written)
+}
+
 /// An entry in a transaction log.
 #[derive(Eq, PartialEq, Debug)]
 pub struct LogEntry<'a> {
@@ -687,6 +866,7 @@ mod tests {
     use crate::{
         io::{
             any::AnyFileManager,
+            blob::{BlobFileManager, LocalBlobStore},
             fs::{StdFile, StdFileManager},
             memory::MemoryFileManager,
         },
@@ -735,6 +915,8 @@ mod tests {
             file_manager,
             vault,
             cache,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         std::fs::create_dir(&temp_dir).unwrap();
         let log_path = {
@@ -790,7 +972,9 @@ mod tests {
                 &Context {
                     file_manager: context.file_manager.clone(),
                     vault: Some(Arc::new(RotatorVault::new(13))),
-                    cache: None
+                    cache: None,
+                    metrics: None,
+                    allow_unencrypted_data: false,
                 }
             )
             .is_err());
@@ -851,6 +1035,71 @@ mod tests {
         assert_eq!(after_first.as_ref(), first_ten.get(1));
     }
 
+    /// Appends bytes to a file, without regard for its pages.
+    struct AppendBytes(&'static [u8]);
+
+    impl FileOp<Result<(), Error>> for AppendBytes {
+        fn execute(self, file: &mut dyn File) -> Result<(), Error> {
+            file.write_all(self.0)?;
+            file.synchronize()
+        }
+    }
+
+    fn torn_log_tests<Manager: FileManager>(file_manager: Manager) {
+        let temp_dir = tempdir().unwrap();
+        let context = Context {
+            file_manager,
+            vault: None,
+            cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
+        };
+        let log_path = temp_dir.path().join("_transactions");
+        let state = State::from_path(&log_path);
+        TransactionLog::<Manager::File>::initialize_state(&state, &context).unwrap();
+        let mut transactions =
+            TransactionLog::<Manager::File>::open(&log_path, state, context.clone()).unwrap();
+        for _ in 0..3 {
+            let tx = transactions.new_transaction([&b"hello"[..]]);
+            transactions.push(vec![tx.transaction]).unwrap();
+        }
+        transactions.close().unwrap();
+
+        // A crash while writing the next entry leaves a partial page behind,
+        // which is removed when the log is opened again.
+        let log_length = context.file_manager.file_length(&log_path).unwrap();
+        let mut file = context.file_manager.append(&log_path).unwrap();
+        file.execute(AppendBytes(b"partial page")).unwrap();
+        file.close().unwrap();
+
+        let state = State::from_path(&log_path);
+        TransactionLog::<Manager::File>::initialize_state(&state, &context).unwrap();
+        assert_eq!(
+            context.file_manager.file_length(&log_path).unwrap(),
+            log_length
+        );
+        let mut transactions =
+            TransactionLog::<Manager::File>::open(&log_path, state, context).unwrap();
+        assert_eq!(transactions.state().next_transaction_id(), TransactionId(4));
+        assert!(transactions.get(TransactionId(3)).unwrap().is_some());
+    }
+
+    #[test]
+    fn file_torn_log_tests() {
+        torn_log_tests(StdFileManager::default());
+    }
+
+    #[test]
+    fn memory_torn_log_tests() {
+        torn_log_tests(MemoryFileManager::default());
+    }
+
+    #[test]
+    fn blob_torn_log_tests() {
+        let blobs = tempdir().unwrap();
+        torn_log_tests(BlobFileManager::new(LocalBlobStore::new(blobs.path())));
+    }
+
     #[test]
     fn discontiguous_log_file_tests() {
         let temp_dir = tempdir().unwrap();
@@ -859,6 +1108,8 @@ mod tests {
             file_manager,
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let log_path = temp_dir.path().join("_transactions");
         let mut rng = Pcg64::new_seed(1);
@@ -937,6 +1188,8 @@ mod tests {
             file_manager,
             vault,
             cache,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let manager = TransactionManager::spawn(&temp_dir, context).unwrap();
         assert_eq!(manager.current_transaction_id(), None);
@@ -1030,6 +1283,8 @@ mod tests {
             file_manager,
             vault,
             cache,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let manager = TransactionManager::spawn(&temp_dir, context).unwrap();
         let mut rng = Pcg64::new_seed(1);
@@ -5,11 +5,15 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Instant,
 };
 
 use parking_lot::Mutex;
 
-use super::{log::EntryFetcher, LogEntry, State, TransactionLog};
+use super::{
+    log::{EntryDecoder, EntryFetcher},
+    LogEntry, State, TransactionLog,
+};
 use crate::{
     error::{Error, InternalError},
     io::{FileManager, OperableFile},
@@ -62,8 +66,13 @@ where
         &self,
         trees: I,
     ) -> ManagedTransaction<Manager> {
+        let start = Instant::now();
+        let transaction = self.state.new_transaction(trees);
+        if let Some(metrics) = self.context.metrics() {
+            metrics.transaction_lock_waited(start.elapsed());
+        }
         ManagedTransaction {
-            transaction: Some(self.state.new_transaction(trees)),
+            transaction: Some(transaction),
             manager: self.clone(),
         }
     }
@@ -85,6 +94,20 @@ where
         })
     }
 
+    /// Rewrites the transaction log, encrypting every entry with the current
+    /// vault. Transactions are not committed while the log is being rewritten.
+    pub(crate) fn rewrite_log(&self) -> Result<(), Error> {
+        let (completion_sender, completion_receiver) = flume::bounded(1);
+        self.transaction_sender
+            .send(ThreadCommand::Rewrite(completion_sender))
+            .map_err(|_| ErrorKind::Internal(InternalError::TransactionManagerStopped))?;
+        completion_receiver.recv().map_err(|_| {
+            Error::from(ErrorKind::Internal(
+                InternalError::TransactionManagerStopped,
+            ))
+        })?
+    }
+
     /// Scans the transaction log for entries with ids within `range`. Invokes
     /// `callback` for each entry found. The scan will always scan forwards
     /// starting with the lowest ID matching the range.
@@ -121,7 +144,7 @@ where
             let transaction = log.execute(EntryFetcher {
                 state: self.state(),
                 id: transaction_id,
-                vault: self.context.vault(),
+                decoder: EntryDecoder::new(&self.context),
             })?;
             match transaction {
                 ScanResult::Found { position, .. } => {
@@ -169,12 +192,14 @@ enum ThreadCommand {
         completion_sender: flume::Sender<TreeLocks>,
     },
     Drop(TransactionId),
+    Rewrite(flume::Sender<Result<(), Error>>),
 }
 
 struct ManagerThread<Manager: FileManager> {
     state: ThreadState,
     commands: flume::Receiver<ThreadCommand>,
-    log: TransactionLog<Manager::File>,
+    context: Context<Manager>,
+    log: Option<TransactionLog<Manager::File>>,
     pending_transaction_ids: IdSequence,
     last_processed_id: TransactionId,
     transaction_batch: Vec<LogEntry<'static>>,
@@ -199,8 +224,9 @@ impl<Manager: FileManager> ManagerThread<Manager> {
         let state = State::from_path(&log_path);
 
         let log = match TransactionLog::<Manager::File>::initialize_state(&state, &context)
-            .and_then(|_| TransactionLog::<Manager::File>::open(log_path, state.clone(), context))
-        {
+            .and_then(|_| {
+                TransactionLog::<Manager::File>::open(log_path, state.clone(), context.clone())
+            }) {
             Ok(log) => log,
             Err(err) => {
                 drop(state_sender.send(Err(err)));
@@ -213,9 +239,10 @@ impl<Manager: FileManager> ManagerThread<Manager> {
         Self {
             state: ThreadState::Fresh,
             commands: transactions,
+            context,
             last_processed_id: transaction_id,
             pending_transaction_ids: IdSequence::new(transaction_id),
-            log,
+            log: Some(log),
             transaction_batch: Vec::with_capacity(Self::BATCH),
             completion_senders: Vec::with_capacity(Self::BATCH),
         }
@@ -263,6 +290,9 @@ impl<Manager: FileManager> ManagerThread<Manager> {
                     ThreadCommand::Drop(id) => {
                         self.mark_transaction_handled(id);
                     }
+                    ThreadCommand::Rewrite(completion_sender) => {
+                        drop(completion_sender.send(self.rewrite_log()));
+                    }
                 }
                 true
             }
@@ -299,6 +329,9 @@ impl<Manager: FileManager> ManagerThread<Manager> {
                     ThreadCommand::Drop(id) => {
                         self.note_potentially_sequntial_id(id);
                     }
+                    ThreadCommand::Rewrite(completion_sender) => {
+                        drop(completion_sender.send(self.rewrite_log()));
+                    }
                 }
                 true
             }
@@ -346,6 +379,9 @@ impl<Manager: FileManager> ManagerThread<Manager> {
                     ThreadCommand::Drop(id) => {
                         self.mark_transaction_handled(id);
                     }
+                    ThreadCommand::Rewrite(completion_sender) => {
+                        drop(completion_sender.send(self.rewrite_log()));
+                    }
                 }
                 true
             }
@@ -353,13 +389,41 @@ impl<Manager: FileManager> ManagerThread<Manager> {
         }
     }
 
+    fn rewrite_log(&mut self) -> Result<(), Error> {
+        // Transactions that are still being batched haven't been written yet,
+        // so the log can be rewritten without waiting for them.
+        let log = self.log.take().unwrap();
+        let state = log.state().clone();
+        match log.rewrite(&self.context) {
+            Ok(log) => {
+                self.log = Some(log);
+                Ok(())
+            }
+            Err(err) => {
+                // The existing log is only replaced once the rewritten log is
+                // complete, so it can be reopened.
+                self.log = Some(TransactionLog::open(
+                    state.path(),
+                    state.clone(),
+                    self.context.clone(),
+                )?);
+                Err(err)
+            }
+        }
+    }
+
     fn commit_transaction_batch(&mut self) {
         let mut transaction_batch = Vec::with_capacity(Self::BATCH);
         std::mem::swap(&mut transaction_batch, &mut self.transaction_batch);
         transaction_batch.sort_unstable_by(|a, b| a.id.cmp(&b.id));
         self.last_processed_id = transaction_batch.last().unwrap().id;
         self.state = ThreadState::Fresh;
-        self.log.push(transaction_batch).unwrap();
+        let transactions = transaction_batch.len();
+        let start = Instant::now();
+        self.log.as_mut().unwrap().push(transaction_batch).unwrap();
+        if let Some(metrics) = self.context.metrics() {
+            metrics.transaction_batch_written(transactions, self.commands.len(), start.elapsed());
+        }
         for (completion_sender, tree_locks) in self.completion_senders.drain(..) {
             drop(completion_sender.send(tree_locks));
         }
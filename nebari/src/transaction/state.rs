@@ -181,6 +181,11 @@ impl State {
         }
     }
 
+    pub(crate) fn forget_transaction_positions(&self) {
+        let mut cache = self.state.known_completed_transactions.lock();
+        cache.clear();
+    }
+
     /// Returns an option representing whether the transaction id has
     /// information cached about it. The inner option contains the cache
     /// contents: either a valid position, or None if the transaction ID
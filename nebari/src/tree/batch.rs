@@ -0,0 +1,260 @@
+use std::{cell::RefCell, cmp::Ordering, sync::Arc};
+
+use super::{
+    btree::KeyOperation,
+    modify::{BatchOperation, Modification, Operation, PersistenceMode},
+    KeyComparator, MergeOperator,
+};
+use crate::{error::Error, ArcBytes, ErrorKind};
+
+/// A set of per-key operations that are applied to a tree in a single
+/// modification.
+///
+/// Unlike a [`Modification`], which performs one [`Operation`] on every key, a
+/// batch can set, remove, compare-and-swap, and merge different keys. The keys
+/// do not need to be added in order: they are sorted using the tree's
+/// [`KeyComparator`] when the batch is applied. If a key is operated on more
+/// than once, each operation replaces the operations before it, except for
+/// merges, which are applied to the result of the operations before them.
+#[derive(Debug, Clone)]
+pub struct WriteBatch<Value = ArcBytes<'static>> {
+    entries: Vec<(ArcBytes<'static>, BatchEntry<Value>)>,
+}
+
+#[derive(Debug, Clone)]
+enum BatchEntry<Value> {
+    Set(Value),
+    Remove,
+    CompareSwap {
+        old: Option<Value>,
+        new: Option<Value>,
+    },
+    Merge(Value),
+}
+
+impl<Value> Default for WriteBatch<Value> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<Value> WriteBatch<Value> {
+    /// Returns an empty batch.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `key` to `value`.
+    pub fn set(&mut self, key: impl Into<ArcBytes<'static>>, value: impl Into<Value>) -> &mut Self {
+        self.entries
+            .push((key.into(), BatchEntry::Set(value.into())));
+        self
+    }
+
+    /// Removes `key`.
+    pub fn remove(&mut self, key: impl Into<ArcBytes<'static>>) -> &mut Self {
+        self.entries.push((key.into(), BatchEntry::Remove));
+        self
+    }
+
+    /// Compares the value of `key` against `old`. If the values match, key will
+    /// be set to the new value if `new` is `Some` or removed if `new` is
+    /// `None`. If the values don't match, the key is left unchanged and a
+    /// [`BatchConflict`] is returned when the batch is applied.
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl Into<ArcBytes<'static>>,
+        old: Option<Value>,
+        new: Option<Value>,
+    ) -> &mut Self {
+        self.entries
+            .push((key.into(), BatchEntry::CompareSwap { old, new }));
+        self
+    }
+
+    /// Merges `operand` into the value of `key` using the tree's
+    /// [`MergeOperator`].
+    pub fn merge(
+        &mut self,
+        key: impl Into<ArcBytes<'static>>,
+        operand: impl Into<Value>,
+    ) -> &mut Self {
+        self.entries
+            .push((key.into(), BatchEntry::Merge(operand.into())));
+        self
+    }
+
+    /// Returns the number of operations in this batch.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if this batch contains no operations.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the keys operated on by this batch, in the order they were
+    /// added.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &ArcBytes<'static>> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    /// Converts this batch into a modification whose keys are ordered by
+    /// `comparator`. Compare-and-swap conflicts are pushed to `conflicts`.
+    pub(crate) fn into_modification<'a, Index>(
+        mut self,
+        comparator: &dyn KeyComparator,
+        merge_operator: Option<&Arc<dyn MergeOperator>>,
+        persistence_mode: PersistenceMode,
+        conflicts: &'a RefCell<Vec<BatchConflict<Value>>>,
+    ) -> Result<Modification<'a, Value, Index>, Error>
+    where
+        Value: PartialEq + AsRef<[u8]> + From<Vec<u8>> + 'a,
+    {
+        // Sorting is stable, so the operations for each key remain in the order
+        // they were added.
+        self.entries
+            .sort_by(|(a, _), (b, _)| comparator.compare(a, b));
+
+        let mut keys = Vec::with_capacity(self.entries.len());
+        let mut operations = Vec::with_capacity(self.entries.len());
+        let mut entries = self.entries.into_iter().peekable();
+        while let Some((mut key, entry)) = entries.next() {
+            let mut operation = KeyOperations::default();
+            operation.push(entry);
+            while let Some((next_key, _)) = entries.peek() {
+                if comparator.compare(&key, next_key) != Ordering::Equal {
+                    break;
+                }
+                let (next_key, entry) = entries.next().unwrap();
+                key = next_key;
+                operation.push(entry);
+            }
+
+            operations.push(operation.into_batch_operation(&key, merge_operator, conflicts)?);
+            keys.push(key);
+        }
+
+        Ok(Modification {
+            persistence_mode,
+            keys,
+            operation: Operation::Batch(operations),
+        })
+    }
+}
+
+/// The combined operations of a single key in a [`WriteBatch`].
+struct KeyOperations<Value> {
+    /// The last operation that wasn't a merge.
+    base: Option<BatchEntry<Value>>,
+    /// The merge operands added after `base`.
+    operands: Vec<Value>,
+}
+
+impl<Value> Default for KeyOperations<Value> {
+    fn default() -> Self {
+        Self {
+            base: None,
+            operands: Vec::new(),
+        }
+    }
+}
+
+impl<Value> KeyOperations<Value>
+where
+    Value: PartialEq + AsRef<[u8]> + From<Vec<u8>>,
+{
+    fn push(&mut self, entry: BatchEntry<Value>) {
+        if let BatchEntry::Merge(operand) = entry {
+            self.operands.push(operand);
+        } else {
+            self.base = Some(entry);
+            self.operands.clear();
+        }
+    }
+
+    fn into_batch_operation<'a, Index>(
+        self,
+        key: &[u8],
+        merge_operator: Option<&Arc<dyn MergeOperator>>,
+        conflicts: &'a RefCell<Vec<BatchConflict<Value>>>,
+    ) -> Result<BatchOperation<'a, Value, Index>, Error>
+    where
+        Value: 'a,
+    {
+        let Self { base, operands } = self;
+        let merge_operator = if operands.is_empty() {
+            None
+        } else {
+            Some(
+                merge_operator
+                    .ok_or(ErrorKind::MergeOperatorNotConfigured)?
+                    .clone(),
+            )
+        };
+        Ok(match (base, merge_operator) {
+            (Some(BatchEntry::Set(value)), None) => BatchOperation::Set(value),
+            (Some(BatchEntry::Remove), None) => BatchOperation::Remove,
+            // The value being merged into is known, so the merge can be
+            // performed now.
+            (Some(BatchEntry::Set(value)), Some(merge_operator)) => {
+                batch_operation(merge_operator.merged(key, Some(&value), &operands))
+            }
+            (Some(BatchEntry::Remove), Some(merge_operator)) => {
+                batch_operation(merge_operator.merged(key, None, &operands))
+            }
+            (Some(BatchEntry::CompareSwap { old, mut new }), merge_operator) => {
+                BatchOperation::CompareSwap(Box::new(move |key, _index, value| {
+                    if value == old {
+                        match &merge_operator {
+                            Some(merge_operator) => {
+                                merge_operator.merged(key, new.as_ref(), &operands)
+                            }
+                            None => new.take().map_or(KeyOperation::Remove, KeyOperation::Set),
+                        }
+                    } else {
+                        conflicts.borrow_mut().push(BatchConflict {
+                            key: key.to_owned(),
+                            value,
+                        });
+                        KeyOperation::Skip
+                    }
+                }))
+            }
+            (None, Some(merge_operator)) => {
+                BatchOperation::CompareSwap(Box::new(move |key, _index, value| {
+                    merge_operator.merged(key, value.as_ref(), &operands)
+                }))
+            }
+            (None | Some(BatchEntry::Merge(_)), _) => {
+                unreachable!("every key has at least one operation, and merges are operands")
+            }
+        })
+    }
+}
+
+fn batch_operation<'a, Value, Index>(
+    operation: KeyOperation<Value>,
+) -> BatchOperation<'a, Value, Index> {
+    match operation {
+        KeyOperation::Set(value) => BatchOperation::Set(value),
+        KeyOperation::Remove => BatchOperation::Remove,
+        KeyOperation::Skip => unreachable!("merges never skip keys"),
+    }
+}
+
+/// A compare-and-swap within a [`WriteBatch`] that was skipped because the
+/// stored value didn't match the expected value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchConflict<Value> {
+    /// The key of the compare-and-swap.
+    pub key: ArcBytes<'static>,
+    /// The value stored when the compare-and-swap was executed.
+    pub value: Option<Value>,
+}
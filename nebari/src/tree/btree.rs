@@ -1,9 +1,11 @@
 use std::{
+    cmp::Ordering,
     collections::HashMap,
     convert::Infallible,
     fmt::{Debug, Display},
     marker::PhantomData,
     ops::{Bound, RangeBounds},
+    sync::Arc,
 };
 
 use byteorder::{ReadBytesExt, WriteBytesExt};
@@ -11,15 +13,18 @@ use byteorder::{ReadBytesExt, WriteBytesExt};
 use super::{
     interior::Interior,
     key_entry::KeyEntry,
-    modify::{Modification, Operation},
+    modify::{BatchOperation, Modification, Operation},
     serialization::BinarySerialization,
     ChangeResult, KeyRange, PagedWriter,
 };
 use crate::{
     chunk_cache::CacheEntry,
-    error::Error,
+    error::{Error, InternalError},
     io::File,
-    tree::{key_entry::PositionIndex, read_chunk, versioned::Children, ScanEvaluation},
+    tree::{
+        key_entry::PositionIndex, read_chunk, versioned::Children, Bytewise, Expiration,
+        KeyComparator, ScanEvaluation,
+    },
     vault::AnyVault,
     AbortError, ArcBytes, ChunkCache, ErrorKind,
 };
@@ -148,6 +153,8 @@ where
     /// A [`Reducer`] that reduces many `Index`es or many `ReducedIndex`es into
     /// a single `ReducedIndex` value.
     pub reducer: IndexReducer,
+    /// The [`KeyComparator`] that orders the keys of the tree being modified.
+    pub comparator: Arc<dyn KeyComparator>,
     _phantom: PhantomData<(Value, Index, ReducedIndex)>,
 }
 
@@ -177,25 +184,72 @@ where
             indexer,
             loader,
             reducer,
+            comparator: Arc::new(Bytewise),
             _phantom: PhantomData,
         }
     }
+
+    /// Orders keys using `comparator` instead of [`Bytewise`].
+    #[must_use]
+    pub fn with_comparator(mut self, comparator: Arc<dyn KeyComparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+}
+
+/// Returns true if `range` starts at or before every key greater than
+/// `lower_key`.
+fn lower_key_after_start<'k, KeyRangeBounds: RangeBounds<&'k [u8]> + ?Sized>(
+    comparator: &dyn KeyComparator,
+    range: &KeyRangeBounds,
+    lower_key: Option<&ArcBytes<'_>>,
+) -> bool {
+    match (range.start_bound(), lower_key) {
+        (Bound::Unbounded, _) => true,
+        (Bound::Included(start) | Bound::Excluded(start), Some(lower_key)) => {
+            comparator.compare(start, lower_key) != Ordering::Greater
+        }
+        (_, None) => false,
+    }
+}
+
+/// Returns true if `range` may contain keys greater than `lower_key`.
+fn lower_key_before_end<'k, KeyRangeBounds: RangeBounds<&'k [u8]> + ?Sized>(
+    comparator: &dyn KeyComparator,
+    range: &KeyRangeBounds,
+    lower_key: Option<&ArcBytes<'_>>,
+) -> bool {
+    match (range.end_bound(), lower_key) {
+        (Bound::Included(end) | Bound::Excluded(end), Some(lower_key)) => {
+            comparator.compare(lower_key, end) == Ordering::Less
+        }
+        _ => true,
+    }
+}
+
+fn unresolved_merge() -> Error {
+    Error::from(ErrorKind::Internal(InternalError::UnresolvedMerge))
 }
 
 #[cfg(any(debug_assertions, feature = "paranoid"))]
 macro_rules! assert_children_order {
-    ($children:expr) => {
+    ($children:expr, $comparator:expr) => {
         assert_eq!(
             $children
                 .windows(2)
-                .find_map(|w| (w[0].key > w[1].key).then(|| (&w[0].key, &w[1].key))),
+                .find_map(
+                    |w| ($comparator.compare(&w[0].key, &w[1].key) == Ordering::Greater)
+                        .then(|| (&w[0].key, &w[1].key))
+                ),
             None
         );
     };
 }
 #[cfg(not(any(debug_assertions, feature = "paranoid")))]
 macro_rules! assert_children_order {
-    ($children:expr) => {};
+    ($children:expr, $comparator:expr) => {
+        let _ = $comparator;
+    };
 }
 
 impl<Index, ReducedIndex> BTreeEntry<Index, ReducedIndex>
@@ -243,6 +297,7 @@ where
                         children,
                         context.current_order,
                         context.minimum_children,
+                        context.comparator.as_ref(),
                     ))
                 } else {
                     Ok(ChangeResult::Unchanged)
@@ -256,6 +311,7 @@ where
                             children,
                             context.current_order,
                             context.minimum_children,
+                            context.comparator.as_ref(),
                         ))
                     }
                     other => Ok(other),
@@ -265,13 +321,151 @@ where
         }
     }
 
+    /// Removes all keys contained within `range` from this entry.
+    ///
+    /// Children of interior nodes whose keys are entirely contained within
+    /// `range` are removed without being loaded. Because an interior node only
+    /// records the maximum key of each child, `lower_key` is the key of this
+    /// entry's previous sibling, if any: every key in this entry is greater
+    /// than `lower_key`.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn remove_range<'k, KeyRangeBounds, IndexedType, Indexer, Loader, IndexReducer>(
+        &mut self,
+        range: &KeyRangeBounds,
+        lower_key: Option<&ArcBytes<'static>>,
+        context: &mut ModificationContext<
+            IndexedType,
+            Index,
+            ReducedIndex,
+            Indexer,
+            Loader,
+            IndexReducer,
+        >,
+        writer: &mut PagedWriter<'_>,
+    ) -> Result<ChangeResult, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'k [u8]> + ?Sized,
+        Indexer: FnMut(
+            &ArcBytes<'_>,
+            Option<&IndexedType>,
+            Option<&Index>,
+            &mut PagedWriter<'_>,
+        ) -> Result<KeyOperation<Index>, Error>,
+        IndexReducer: Reducer<Index, ReducedIndex>,
+        Loader: FnMut(&Index, &mut PagedWriter<'_>) -> Result<Option<IndexedType>, Error>,
+    {
+        let comparator = context.comparator.clone();
+        match &mut self.node {
+            BTreeNode::Leaf(children) => {
+                let original_count = children.len();
+                children.retain(|child| !comparator.range_contains(range, &child.key));
+                if children.len() == original_count {
+                    Ok(ChangeResult::Unchanged)
+                } else {
+                    self.dirty = true;
+                    Ok(Self::clean_up_leaf(
+                        children,
+                        context.current_order,
+                        context.minimum_children,
+                        comparator.as_ref(),
+                    ))
+                }
+            }
+            BTreeNode::Interior(children) => {
+                let mut any_changes = false;
+                loop {
+                    // Only the first and last children overlapping the range
+                    // can be partially contained, and they are adjacent once
+                    // the fully contained children between them are removed.
+                    let mut partially_contained = Vec::with_capacity(2);
+                    let mut lower_key = lower_key.cloned();
+                    let mut index = 0;
+                    while index < children.len() {
+                        let child_key = children[index].key.clone();
+                        let child_lower_key = lower_key.replace(child_key.clone());
+                        if !lower_key_before_end(
+                            comparator.as_ref(),
+                            range,
+                            child_lower_key.as_ref(),
+                        ) {
+                            break;
+                        } else if !comparator.is_after_start(range.start_bound(), &child_key) {
+                            index += 1;
+                        } else if lower_key_after_start(
+                            comparator.as_ref(),
+                            range,
+                            child_lower_key.as_ref(),
+                        ) && comparator.is_before_end(range.end_bound(), &child_key)
+                        {
+                            children.remove(index);
+                            any_changes = true;
+                        } else {
+                            partially_contained.push((index, child_lower_key));
+                            index += 1;
+                        }
+                    }
+
+                    let mut rescan = false;
+                    for (index, child_lower_key) in partially_contained.into_iter().rev() {
+                        let child = &mut children[index];
+                        child.position.load(
+                            writer.file,
+                            false,
+                            writer.vault,
+                            writer.cache,
+                            Some(context.current_order),
+                        )?;
+                        let result = child.position.get_mut().unwrap().remove_range(
+                            range,
+                            child_lower_key.as_ref(),
+                            context,
+                            writer,
+                        )?;
+                        // Absorbing into the previous child can move keys that
+                        // haven't been visited yet into a new child when the
+                        // previous child splits, so the children are scanned
+                        // again.
+                        rescan = result == ChangeResult::Absorb && index > 0;
+                        match Self::process_interior_change_result(
+                            result, index, children, context, writer,
+                        )? {
+                            (ChangeResult::Unchanged, _) => {}
+                            _ => any_changes = true,
+                        }
+                        if rescan {
+                            break;
+                        }
+                    }
+
+                    if !rescan {
+                        break;
+                    }
+                }
+
+                if any_changes {
+                    self.dirty = true;
+                    Ok(Self::clean_up_interior(
+                        children,
+                        context.current_order,
+                        context.minimum_children,
+                        comparator.as_ref(),
+                    ))
+                } else {
+                    Ok(ChangeResult::Unchanged)
+                }
+            }
+            BTreeNode::Uninitialized => unreachable!(),
+        }
+    }
+
     fn clean_up_leaf(
         children: &mut [KeyEntry<Index>],
         current_order: usize,
         minimum_children: usize,
+        comparator: &dyn KeyComparator,
     ) -> ChangeResult {
         let child_count = children.len();
-        assert_children_order!(children);
+        assert_children_order!(children, comparator);
 
         if child_count > current_order {
             ChangeResult::Split
@@ -288,9 +482,10 @@ where
         children: &mut [Interior<Index, ReducedIndex>],
         current_order: usize,
         minimum_children: usize,
+        comparator: &dyn KeyComparator,
     ) -> ChangeResult {
         let child_count = children.len();
-        assert_children_order!(children);
+        assert_children_order!(children, comparator);
 
         if child_count > current_order {
             ChangeResult::Split
@@ -333,27 +528,31 @@ where
         let max_len = children.len().max(context.current_order);
         while !modification.keys.is_empty() && children.len() <= max_len {
             let key = modification.keys.last().unwrap();
-            if max_key.map(|max_key| key > max_key).unwrap_or_default() {
+            if max_key
+                .map(|max_key| context.comparator.compare(key, max_key) == Ordering::Greater)
+                .unwrap_or_default()
+            {
                 break;
             }
 
-            let search_result = children[last_index..].binary_search_by(|child| child.key.cmp(key));
+            let search_result = children[last_index..]
+                .binary_search_by(|child| context.comparator.compare(&child.key, key));
             match search_result {
                 Ok(matching_index) => {
                     let key = modification.keys.pop().unwrap();
                     last_index += matching_index;
                     let index = match &mut modification.operation {
-                        Operation::Set(value) => (context.indexer)(
-                            &key,
-                            Some(value),
-                            Some(&children[last_index].index),
-                            writer,
-                        )?,
+                        Operation::Set(value) | Operation::SetWithExpiration(value, _) => {
+                            (context.indexer)(
+                                &key,
+                                Some(value),
+                                Some(&children[last_index].index),
+                                writer,
+                            )?
+                        }
                         Operation::SetEach(values) => (context.indexer)(
                             &key,
-                            Some(&values.pop().ok_or_else(|| {
-                                ErrorKind::message("need the same number of keys as values")
-                            })?),
+                            Some(&values.pop().ok_or_else(|| ErrorKind::KeyCountMismatch)?),
                             Some(&children[last_index].index),
                             writer,
                         )?,
@@ -363,6 +562,45 @@ where
                             Some(&children[last_index].index),
                             writer,
                         )?,
+                        Operation::RemoveRange(..) => {
+                            unreachable!("RemoveRange modifications have no keys")
+                        }
+                        Operation::Merge(_) => return Err(unresolved_merge()),
+                        Operation::Batch(operations) => {
+                            let current_index = &children[last_index].index;
+                            match operations
+                                .pop()
+                                .ok_or_else(|| ErrorKind::KeyCountMismatch)?
+                            {
+                                BatchOperation::Set(new_value) => (context.indexer)(
+                                    &key,
+                                    Some(&new_value),
+                                    Some(current_index),
+                                    writer,
+                                )?,
+                                BatchOperation::Remove => {
+                                    (context.indexer)(&key, None, Some(current_index), writer)?
+                                }
+                                BatchOperation::CompareSwap(mut callback) => {
+                                    let existing_value = (context.loader)(current_index, writer)?;
+                                    match callback(&key, Some(current_index), existing_value) {
+                                        KeyOperation::Skip => KeyOperation::Skip,
+                                        KeyOperation::Set(new_value) => (context.indexer)(
+                                            &key,
+                                            Some(&new_value),
+                                            Some(current_index),
+                                            writer,
+                                        )?,
+                                        KeyOperation::Remove => (context.indexer)(
+                                            &key,
+                                            None,
+                                            Some(current_index),
+                                            writer,
+                                        )?,
+                                    }
+                                }
+                            }
+                        }
                         Operation::CompareSwap(callback) => {
                             let current_index = &children[last_index].index;
                             let existing_value = (context.loader)(current_index, writer)?;
@@ -400,14 +638,16 @@ where
                     last_index += insert_at;
                     let key = modification.keys.pop().unwrap();
                     let operation = match &mut modification.operation {
-                        Operation::Set(new_value) => {
+                        Operation::Set(new_value) | Operation::SetWithExpiration(new_value, _) => {
                             (context.indexer)(&key, Some(new_value), None, writer)?
                         }
                         Operation::SetEach(new_values) => (context.indexer)(
                             &key,
-                            Some(&new_values.pop().ok_or_else(|| {
-                                ErrorKind::message("need the same number of keys as values")
-                            })?),
+                            Some(
+                                &new_values
+                                    .pop()
+                                    .ok_or_else(|| ErrorKind::KeyCountMismatch)?,
+                            ),
                             None,
                             writer,
                         )?,
@@ -415,6 +655,31 @@ where
                             // The key doesn't exist, so a remove is a no-op.
                             KeyOperation::Remove
                         }
+                        Operation::RemoveRange(..) => {
+                            unreachable!("RemoveRange modifications have no keys")
+                        }
+                        Operation::Merge(_) => return Err(unresolved_merge()),
+                        Operation::Batch(operations) => match operations
+                            .pop()
+                            .ok_or_else(|| ErrorKind::KeyCountMismatch)?
+                        {
+                            BatchOperation::Set(new_value) => {
+                                (context.indexer)(&key, Some(&new_value), None, writer)?
+                            }
+                            // The key doesn't exist, so a remove is a no-op.
+                            BatchOperation::Remove => KeyOperation::Remove,
+                            BatchOperation::CompareSwap(mut callback) => {
+                                match callback(&key, None, None) {
+                                    KeyOperation::Skip => KeyOperation::Skip,
+                                    KeyOperation::Set(new_value) => {
+                                        (context.indexer)(&key, Some(&new_value), None, writer)?
+                                    }
+                                    KeyOperation::Remove => {
+                                        (context.indexer)(&key, None, None, writer)?
+                                    }
+                                }
+                            }
+                        },
                         Operation::CompareSwap(callback) => match callback(&key, None, None) {
                             KeyOperation::Skip => KeyOperation::Skip,
                             KeyOperation::Set(new_value) => {
@@ -444,7 +709,7 @@ where
                     }
                 }
             }
-            assert_children_order!(children);
+            assert_children_order!(children, context.comparator);
         }
         Ok(any_changes)
     }
@@ -477,12 +742,14 @@ where
         let mut any_changes = false;
         while let Some(key) = modification.keys.last().cloned() {
             if last_index >= children.len()
-                || max_key.map(|max_key| &key > max_key).unwrap_or_default()
+                || max_key
+                    .map(|max_key| context.comparator.compare(&key, max_key) == Ordering::Greater)
+                    .unwrap_or_default()
             {
                 break;
             }
             let (containing_node_index, pushing_end) = children[last_index..]
-                .binary_search_by(|child| child.key.cmp(&key))
+                .binary_search_by(|child| context.comparator.compare(&child.key, &key))
                 .map_or_else(
                     |not_found| {
                         if not_found > 0 && not_found + last_index == children.len() {
@@ -511,7 +778,11 @@ where
                 // The new key is being added to the end of the node.
                 key
             } else if let Some(max_key) = max_key {
-                child.key.clone().min(max_key.clone())
+                if context.comparator.compare(&child.key, max_key) == Ordering::Greater {
+                    max_key.clone().into_owned()
+                } else {
+                    child.key.clone()
+                }
             } else {
                 child.key.clone()
             };
@@ -527,7 +798,7 @@ where
                 context,
                 writer,
             )?;
-            assert_children_order!(children);
+            assert_children_order!(children, context.comparator);
             match change_result {
                 ChangeResult::Unchanged => {}
                 ChangeResult::Split => unreachable!(),
@@ -652,6 +923,7 @@ where
                     insert_on_top,
                     context.current_order,
                     context.minimum_children,
+                    context.comparator.as_ref(),
                     writer,
                 )?,
                 sponge_index,
@@ -959,6 +1231,7 @@ where
         insert_at_top: bool,
         current_order: usize,
         minimum_children: usize,
+        comparator: &dyn KeyComparator,
         writer: &mut PagedWriter<'_>,
     ) -> Result<ChangeResult, Error> {
         self.dirty = true;
@@ -974,6 +1247,7 @@ where
                     existing_children,
                     current_order,
                     minimum_children,
+                    comparator,
                 ))
             }
             (BTreeNode::Interior(existing_children), Children::Leaves(leaves)) => {
@@ -996,6 +1270,7 @@ where
                     insert_at_top,
                     current_order,
                     minimum_children,
+                    comparator,
                     writer,
                 )
             }
@@ -1010,6 +1285,7 @@ where
                     existing_children,
                     current_order,
                     minimum_children,
+                    comparator,
                 ))
             }
             (BTreeNode::Leaf(_), Children::Interiors(_)) | (BTreeNode::Uninitialized, _) => {
@@ -1099,8 +1375,9 @@ where
     /// [`ScanArgs::data_callback`] will be invoked.
     #[cfg_attr(
         feature = "tracing",
-        tracing::instrument(skip(self, args, file, vault, cache))
+        tracing::instrument(skip(self, comparator, args, file, vault, cache))
     )]
+    #[allow(clippy::too_many_arguments)]
     pub fn scan<
         'k,
         'keys,
@@ -1112,6 +1389,7 @@ where
     >(
         &self,
         range: &'keys KeyRangeBounds,
+        comparator: &dyn KeyComparator,
         args: &mut ScanArgs<
             ArcBytes<'static>,
             Index,
@@ -1138,11 +1416,12 @@ where
     {
         match &self.node {
             BTreeNode::Leaf(children) => {
+                let now = Expiration::now();
                 for child in DirectionalSliceIterator::new(args.forwards, children) {
-                    if range.contains(&child.key.as_slice()) {
+                    if comparator.range_contains(range, &child.key) {
                         match (args.key_evaluator)(&child.key, &child.index) {
                             ScanEvaluation::ReadData => {
-                                if child.index.position() > 0 {
+                                if child.index.has_value(now) {
                                     let data = match read_chunk(
                                         child.index.position(),
                                         false,
@@ -1175,18 +1454,8 @@ where
 
                             // One the previous entry's key is less than the end
                             // bound, we can break out of the loop.
-                            match end_bound {
-                                Bound::Included(key) => {
-                                    if previous_entry.key > **key {
-                                        break;
-                                    }
-                                }
-                                Bound::Excluded(key) => {
-                                    if &previous_entry.key >= key {
-                                        break;
-                                    }
-                                }
-                                Bound::Unbounded => {}
+                            if !comparator.is_before_end(end_bound, &previous_entry.key) {
+                                break;
                             }
                         }
                     } else {
@@ -1195,18 +1464,8 @@ where
 
                     // Keys in this child could match as long as the start bound
                     // is less than the key for this child.
-                    match start_bound {
-                        Bound::Included(key) => {
-                            if child.key < **key {
-                                continue;
-                            }
-                        }
-                        Bound::Excluded(key) => {
-                            if &child.key <= key {
-                                continue;
-                            }
-                        }
-                        Bound::Unbounded => {}
+                    if !comparator.is_after_start(start_bound, &child.key) {
+                        continue;
                     }
 
                     let keep_scanning =
@@ -1218,7 +1477,15 @@ where
                                 cache,
                                 Some(children.len()),
                                 |entry, file| {
-                                    entry.scan(range, args, file, vault, cache, current_depth + 1)
+                                    entry.scan(
+                                        range,
+                                        comparator,
+                                        args,
+                                        file,
+                                        vault,
+                                        cache,
+                                        current_depth + 1,
+                                    )
                                 },
                             )?,
                             ScanEvaluation::Skip => true,
@@ -1241,9 +1508,11 @@ where
     ///
     /// The `key_reader` function is not invoked immediately in an effort to
     /// optimize the order of reads from the disk.
+    #[allow(clippy::too_many_arguments)]
     pub fn get_multiple<KeyEvaluator, KeyReader, Keys, Bytes>(
         &self,
         keys: &mut Keys,
+        comparator: &dyn KeyComparator,
         mut key_evaluator: KeyEvaluator,
         mut key_reader: KeyReader,
         file: &mut dyn File,
@@ -1257,12 +1526,13 @@ where
         Bytes: AsRef<[u8]>,
     {
         let mut positions_to_read = Vec::new();
+        let now = Expiration::now();
         self.get(
             &mut KeyRange::new(keys),
+            comparator,
             &mut |key, index| key_evaluator(key, index),
             &mut |key, index| {
-                // Deleted keys are stored with a 0 position.
-                if index.position() > 0 {
+                if index.has_value(now) {
                     positions_to_read.push((key, index.clone()));
                 }
                 Ok(())
@@ -1300,11 +1570,22 @@ where
     /// optimize the order of reads from the disk.
     #[cfg_attr(
         feature = "tracing",
-        tracing::instrument(skip(self, key_evaluator, keys, key_reader, file, vault, cache))
+        tracing::instrument(skip(
+            self,
+            comparator,
+            key_evaluator,
+            keys,
+            key_reader,
+            file,
+            vault,
+            cache
+        ))
     )]
+    #[allow(clippy::too_many_arguments)]
     fn get<KeyEvaluator, KeyReader, Keys, Bytes>(
         &self,
         keys: &mut KeyRange<Keys, Bytes>,
+        comparator: &dyn KeyComparator,
         key_evaluator: &mut KeyEvaluator,
         key_reader: &mut KeyReader,
         file: &mut dyn File,
@@ -1322,7 +1603,9 @@ where
                 let mut last_index = 0;
                 let mut took_one_key = false;
                 while let Some(key) = keys.current_key() {
-                    match children[last_index..].binary_search_by(|child| (&*child.key).cmp(key)) {
+                    match children[last_index..]
+                        .binary_search_by(|child| comparator.compare(&child.key, key))
+                    {
                         Ok(matching) => {
                             took_one_key = true;
                             keys.next();
@@ -1354,7 +1637,7 @@ where
                 let mut last_index = 0;
                 while let Some(key) = keys.current_key() {
                     let containing_node_index = children[last_index..]
-                        .binary_search_by(|child| (&*child.key).cmp(key))
+                        .binary_search_by(|child| comparator.compare(&child.key, key))
                         .unwrap_or_else(|not_found| not_found);
                     last_index += containing_node_index;
 
@@ -1369,7 +1652,15 @@ where
                             Some(children.len()),
                             |entry, file| {
                                 entry
-                                    .get(keys, key_evaluator, key_reader, file, vault, cache)
+                                    .get(
+                                        keys,
+                                        comparator,
+                                        key_evaluator,
+                                        key_reader,
+                                        file,
+                                        vault,
+                                        cache,
+                                    )
                                     .map_err(AbortError::Nebari)
                             },
                         )?;
@@ -1483,7 +1774,6 @@ impl<
         // The next byte determines the node type.
         match &mut self.node {
             BTreeNode::Leaf(leafs) => {
-                assert_children_order!(leafs);
                 writer.write_u8(1)?;
                 bytes_written += 1;
                 for leaf in leafs {
@@ -1491,7 +1781,6 @@ impl<
                 }
             }
             BTreeNode::Interior(interiors) => {
-                assert_children_order!(interiors);
                 writer.write_u8(0)?;
                 bytes_written += 1;
                 for interior in interiors {
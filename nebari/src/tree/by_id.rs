@@ -5,10 +5,49 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use super::{btree::Reducer, BinarySerialization, PagedWriter};
 use crate::{
     error::Error,
-    tree::{by_sequence::SequenceId, key_entry::PositionIndex},
+    tree::{
+        by_sequence::SequenceId, key_entry::PositionIndex, EarliestExpiration, Expiration,
+        KeyStatistics,
+    },
     ArcBytes,
 };
 
+/// Set on an index's serialized position, or on the serialized alive key count
+/// of [`ByIdStats`], when an [`Expiration`] is serialized after it. Neither
+/// value can grow large enough to set this bit on its own, which allows
+/// reading indexes written before expirations were supported.
+const EXPIRATION_FLAG: u64 = 1 << 63;
+
+fn write_flagged(
+    writer: &mut Vec<u8>,
+    value: u64,
+    expiration: Option<Expiration>,
+) -> Result<(), Error> {
+    if expiration.is_some() {
+        writer.write_u64::<BigEndian>(value | EXPIRATION_FLAG)?;
+    } else {
+        writer.write_u64::<BigEndian>(value)?;
+    }
+    Ok(())
+}
+
+fn write_expiration(writer: &mut Vec<u8>, expiration: Option<Expiration>) -> Result<usize, Error> {
+    if let Some(expiration) = expiration {
+        writer.write_u64::<BigEndian>(expiration.0)?;
+        Ok(8)
+    } else {
+        Ok(0)
+    }
+}
+
+fn read_expiration(reader: &mut ArcBytes<'_>, flagged: bool) -> Result<Option<Expiration>, Error> {
+    if flagged {
+        Ok(Some(Expiration(reader.read_u64::<BigEndian>()?)))
+    } else {
+        Ok(None)
+    }
+}
+
 /// The index stored within [`VersionedTreeRoot::by_id_root`](crate::tree::VersionedTreeRoot::by_id_root).
 #[derive(Clone, Debug)]
 pub struct VersionedByIdIndex<EmbeddedIndex: super::EmbeddedIndex<Value>, Value> {
@@ -18,6 +57,8 @@ pub struct VersionedByIdIndex<EmbeddedIndex: super::EmbeddedIndex<Value>, Value>
     pub value_length: u32,
     /// The position of the value on disk.
     pub position: u64,
+    /// The moment the value expires, if it expires.
+    pub expiration: Option<Expiration>,
     /// The embedded index.
     pub embedded: EmbeddedIndex,
 
@@ -39,10 +80,18 @@ where
             sequence_id,
             value_length,
             position,
+            expiration: None,
             embedded,
             _value: PhantomData,
         }
     }
+
+    /// Returns this index with `expiration` as the moment the value expires.
+    #[must_use]
+    pub fn with_expiration(mut self, expiration: Option<Expiration>) -> Self {
+        self.expiration = expiration;
+        self
+    }
 }
 
 impl<EmbeddedIndex, Value> BinarySerialization for VersionedByIdIndex<EmbeddedIndex, Value>
@@ -57,8 +106,9 @@ where
     ) -> Result<usize, Error> {
         writer.write_u64::<BigEndian>(self.sequence_id.0)?;
         writer.write_u32::<BigEndian>(self.value_length)?;
-        writer.write_u64::<BigEndian>(self.position)?;
-        Ok(20 + self.embedded.serialize_to(writer)?)
+        write_flagged(writer, self.position, self.expiration)?;
+        let expiration_bytes = write_expiration(writer, self.expiration)?;
+        Ok(20 + expiration_bytes + self.embedded.serialize_to(writer)?)
     }
 
     fn deserialize_from(
@@ -68,12 +118,14 @@ where
         let sequence_id = SequenceId(reader.read_u64::<BigEndian>()?);
         let value_length = reader.read_u32::<BigEndian>()?;
         let position = reader.read_u64::<BigEndian>()?;
+        let expiration = read_expiration(reader, position & EXPIRATION_FLAG != 0)?;
         Ok(Self::new(
             sequence_id,
             value_length,
-            position,
+            position & !EXPIRATION_FLAG,
             EmbeddedIndex::deserialize_from(reader)?,
-        ))
+        )
+        .with_expiration(expiration))
     }
 }
 
@@ -84,6 +136,10 @@ where
     fn position(&self) -> u64 {
         self.position
     }
+
+    fn expiration(&self) -> Option<Expiration> {
+        self.expiration
+    }
 }
 
 /// The index stored within [`UnversionedTreeRoot::by_id_root`](crate::tree::UnversionedTreeRoot::by_id_root).
@@ -93,6 +149,8 @@ pub struct UnversionedByIdIndex<EmbeddedIndex: super::EmbeddedIndex<Value>, Valu
     pub value_length: u32,
     /// The position of the value on disk.
     pub position: u64,
+    /// The moment the value expires, if it expires.
+    pub expiration: Option<Expiration>,
     /// The embedded index.
     pub embedded: EmbeddedIndex,
 
@@ -108,10 +166,18 @@ where
         Self {
             value_length,
             position,
+            expiration: None,
             embedded,
             _value: PhantomData,
         }
     }
+
+    /// Returns this index with `expiration` as the moment the value expires.
+    #[must_use]
+    pub fn with_expiration(mut self, expiration: Option<Expiration>) -> Self {
+        self.expiration = expiration;
+        self
+    }
 }
 
 impl<EmbeddedIndex, Value> BinarySerialization for UnversionedByIdIndex<EmbeddedIndex, Value>
@@ -125,8 +191,9 @@ where
         _paged_writer: &mut PagedWriter<'_>,
     ) -> Result<usize, Error> {
         writer.write_u32::<BigEndian>(self.value_length)?;
-        writer.write_u64::<BigEndian>(self.position)?;
-        Ok(12 + self.embedded.serialize_to(writer)?)
+        write_flagged(writer, self.position, self.expiration)?;
+        let expiration_bytes = write_expiration(writer, self.expiration)?;
+        Ok(12 + expiration_bytes + self.embedded.serialize_to(writer)?)
     }
 
     fn deserialize_from(
@@ -135,11 +202,13 @@ where
     ) -> Result<Self, Error> {
         let value_length = reader.read_u32::<BigEndian>()?;
         let position = reader.read_u64::<BigEndian>()?;
+        let expiration = read_expiration(reader, position & EXPIRATION_FLAG != 0)?;
         Ok(Self::new(
             value_length,
-            position,
+            position & !EXPIRATION_FLAG,
             EmbeddedIndex::deserialize_from(reader)?,
-        ))
+        )
+        .with_expiration(expiration))
     }
 }
 
@@ -150,17 +219,24 @@ where
     fn position(&self) -> u64 {
         self.position
     }
+
+    fn expiration(&self) -> Option<Expiration> {
+        self.expiration
+    }
 }
 
 /// The reduced index of both [`VersionedByIdIndex`] and [`UnversionedByIdIndex`]
 #[derive(Clone, Debug)]
 pub struct ByIdStats<EmbeddedStats> {
-    /// The number of keys that have values stored within them.
+    /// The number of keys that have values stored within them. Keys whose
+    /// values have expired are counted until they are removed.
     pub alive_keys: u64,
     /// The number of keys that no longer have values stored within them.
     pub deleted_keys: u64,
     /// The total number of bytes stored on disk associated with currently-alive values.
     pub total_indexed_bytes: u64,
+    /// The earliest expiration of the currently-alive values.
+    pub earliest_expiration: Option<Expiration>,
     /// The embedded statistics.
     pub embedded: EmbeddedStats,
 }
@@ -173,6 +249,22 @@ impl<EmbeddedStats> ByIdStats<EmbeddedStats> {
     }
 }
 
+impl<EmbeddedStats> EarliestExpiration for ByIdStats<EmbeddedStats> {
+    fn earliest_expiration(&self) -> Option<Expiration> {
+        self.earliest_expiration
+    }
+}
+
+impl<EmbeddedStats> KeyStatistics for ByIdStats<EmbeddedStats> {
+    fn alive_keys(&self) -> u64 {
+        self.alive_keys
+    }
+
+    fn alive_bytes(&self) -> u64 {
+        self.total_indexed_bytes
+    }
+}
+
 impl<EmbeddedStats> BinarySerialization for ByIdStats<EmbeddedStats>
 where
     EmbeddedStats: super::Serializable,
@@ -182,10 +274,11 @@ where
         writer: &mut Vec<u8>,
         _paged_writer: &mut PagedWriter<'_>,
     ) -> Result<usize, Error> {
-        writer.write_u64::<BigEndian>(self.alive_keys)?;
+        write_flagged(writer, self.alive_keys, self.earliest_expiration)?;
         writer.write_u64::<BigEndian>(self.deleted_keys)?;
         writer.write_u64::<BigEndian>(self.total_indexed_bytes)?;
-        Ok(24 + self.embedded.serialize_to(writer)?)
+        let expiration_bytes = write_expiration(writer, self.earliest_expiration)?;
+        Ok(24 + expiration_bytes + self.embedded.serialize_to(writer)?)
     }
 
     fn deserialize_from(
@@ -195,10 +288,12 @@ where
         let alive_keys = reader.read_u64::<BigEndian>()?;
         let deleted_keys = reader.read_u64::<BigEndian>()?;
         let total_indexed_bytes = reader.read_u64::<BigEndian>()?;
+        let earliest_expiration = read_expiration(reader, alive_keys & EXPIRATION_FLAG != 0)?;
         Ok(Self {
-            alive_keys,
+            alive_keys: alive_keys & !EXPIRATION_FLAG,
             deleted_keys,
             total_indexed_bytes,
+            earliest_expiration,
             embedded: EmbeddedStats::deserialize_from(reader)?,
         })
     }
@@ -321,10 +416,16 @@ impl<EmbeddedIndexer> ByIdIndexer<EmbeddedIndexer> {
                 },
             )
             .unwrap_or_default();
+        let earliest_expiration = values
+            .clone()
+            .filter(|index| index.position() > 0)
+            .filter_map(IdIndex::expiration)
+            .min();
         ByIdStats {
             alive_keys,
             deleted_keys,
             total_indexed_bytes,
+            earliest_expiration,
             embedded: self.0.reduce(values.map(IdIndex::embedded)),
         }
     }
@@ -346,6 +447,7 @@ impl<EmbeddedIndexer> ByIdIndexer<EmbeddedIndexer> {
             alive_keys: values.clone().map(|v| v.alive_keys).sum(),
             deleted_keys: values.clone().map(|v| v.deleted_keys).sum(),
             total_indexed_bytes: values.clone().map(|v| v.total_indexed_bytes).sum(),
+            earliest_expiration: values.clone().filter_map(|v| v.earliest_expiration).min(),
             // TODO change this to an iterator
             embedded: self.0.rereduce(values.map(|v| &v.embedded)),
         }
@@ -355,6 +457,7 @@ impl<EmbeddedIndexer> ByIdIndexer<EmbeddedIndexer> {
 pub trait IdIndex<EmbeddedIndex> {
     fn value_size(&self) -> u32;
     fn position(&self) -> u64;
+    fn expiration(&self) -> Option<Expiration>;
     fn embedded(&self) -> &EmbeddedIndex;
 }
 
@@ -370,6 +473,10 @@ where
         self.position
     }
 
+    fn expiration(&self) -> Option<Expiration> {
+        self.expiration
+    }
+
     fn embedded(&self) -> &EmbeddedIndex {
         &self.embedded
     }
@@ -387,6 +494,10 @@ where
         self.position
     }
 
+    fn expiration(&self) -> Option<Expiration> {
+        self.expiration
+    }
+
     fn embedded(&self) -> &EmbeddedIndex {
         &self.embedded
     }
@@ -0,0 +1,219 @@
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    fmt::Debug,
+    ops::{Bound, RangeBounds},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{error::Error, ArcBytes, ErrorKind};
+
+/// Determines the order of keys within a tree.
+///
+/// A comparator is configured using
+/// [`TreeRoot::with_comparator()`](crate::tree::TreeRoot::with_comparator),
+/// and is used for inserting, looking up, and scanning keys. Keys passed to
+/// operations that require ordered keys, such as
+/// [`Tree::modify()`](crate::Tree::modify), must be ordered using the tree's
+/// comparator.
+///
+/// The comparator's [`name()`](Self::name) is stored within the tree file. A
+/// tree cannot be opened with a comparator whose name differs from the one it
+/// was written with, as the keys stored in the tree would be in the wrong
+/// order.
+pub trait KeyComparator: Debug + Send + Sync + 'static {
+    /// Returns the unique name of this comparator. Two comparators with the
+    /// same name must order keys identically.
+    fn name(&self) -> Cow<'_, str>;
+
+    /// Compares `a` with `b`.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders keys by comparing their bytes. This is the default comparator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bytewise;
+
+impl KeyComparator for Bytewise {
+    fn name(&self) -> Cow<'_, str> {
+        Cow::Borrowed("bytewise")
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Orders keys by comparing their bytes, ignoring the case of ASCII letters.
+///
+/// Keys that only differ by the case of ASCII letters are considered the same
+/// key.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AsciiCaseInsensitive;
+
+impl KeyComparator for AsciiCaseInsensitive {
+    fn name(&self) -> Cow<'_, str> {
+        Cow::Borrowed("ascii-case-insensitive")
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.iter()
+            .map(u8::to_ascii_lowercase)
+            .cmp(b.iter().map(u8::to_ascii_lowercase))
+    }
+}
+
+/// Orders keys in the reverse order of another comparator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Reverse<C>(pub C);
+
+impl<C: KeyComparator> KeyComparator for Reverse<C> {
+    fn name(&self) -> Cow<'_, str> {
+        Cow::Owned(format!("reverse({})", self.0.name()))
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.0.compare(b, a)
+    }
+}
+
+impl dyn KeyComparator {
+    pub(crate) fn is_bytewise(&self) -> bool {
+        self.name() == Bytewise.name()
+    }
+
+    /// Returns true if `key` is contained within `range`.
+    pub(crate) fn range_contains<'k, R: RangeBounds<&'k [u8]> + ?Sized>(
+        &self,
+        range: &R,
+        key: &[u8],
+    ) -> bool {
+        self.is_after_start(range.start_bound(), key) && self.is_before_end(range.end_bound(), key)
+    }
+
+    /// Returns true if `key` is not before `start`.
+    pub(crate) fn is_after_start(&self, start: Bound<&&[u8]>, key: &[u8]) -> bool {
+        match start {
+            Bound::Included(start) => self.compare(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.compare(key, start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Returns true if `key` is not after `end`.
+    pub(crate) fn is_before_end(&self, end: Bound<&&[u8]>, key: &[u8]) -> bool {
+        match end {
+            Bound::Included(end) => self.compare(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.compare(key, end) == Ordering::Less,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Returns an error if `other` does not have the same name as this
+    /// comparator.
+    pub(crate) fn check_matches(&self, other: &dyn KeyComparator) -> Result<(), Error> {
+        self.check_name(other.name())
+    }
+
+    fn check_name(&self, found: Cow<'_, str>) -> Result<(), Error> {
+        let expected = self.name();
+        if expected == found {
+            Ok(())
+        } else {
+            Err(Error::from(ErrorKind::ComparatorMismatch {
+                expected: expected.into_owned(),
+                found: found.into_owned(),
+            }))
+        }
+    }
+
+    /// Writes the identity of this comparator after a serialized root. Unless
+    /// `more_follows` is true, nothing is written for [`Bytewise`], keeping
+    /// trees using the default comparator readable by versions that do not
+    /// support comparators.
+    pub(crate) fn serialize_to(
+        &self,
+        output: &mut Vec<u8>,
+        more_follows: bool,
+    ) -> Result<(), Error> {
+        if more_follows || !self.is_bytewise() {
+            let name = self.name();
+            let length = u16::try_from(name.len())
+                .map_err(|_| ErrorKind::ComparatorNameTooLong(name.to_string()))?;
+            output.write_u16::<BigEndian>(length)?;
+            output.extend_from_slice(name.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Reads the comparator identity written by
+    /// [`serialize_to()`](Self::serialize_to) from the bytes remaining after a
+    /// serialized root, and ensures it matches this comparator. Any bytes
+    /// following the identity are left in `bytes`.
+    pub(crate) fn check_serialized(&self, bytes: &mut ArcBytes<'_>) -> Result<(), Error> {
+        let found = if bytes.is_empty() {
+            Bytewise.name()
+        } else {
+            let length = usize::from(bytes.read_u16::<BigEndian>()?);
+            let name = bytes.read_bytes(length)?;
+            Cow::Owned(
+                String::from_utf8(name.to_vec())
+                    .map_err(|_| Error::data_integrity(ErrorKind::InvalidTreeHeader))?,
+            )
+        };
+        self.check_name(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_ordering() {
+        assert_eq!(Bytewise.compare(b"B", b"a"), Ordering::Less);
+        assert_eq!(AsciiCaseInsensitive.compare(b"B", b"a"), Ordering::Greater);
+        assert_eq!(AsciiCaseInsensitive.compare(b"Ab", b"aB"), Ordering::Equal);
+        assert_eq!(Reverse(Bytewise).compare(b"a", b"b"), Ordering::Greater);
+        assert_eq!(
+            Reverse(AsciiCaseInsensitive).name(),
+            "reverse(ascii-case-insensitive)"
+        );
+    }
+
+    #[test]
+    fn serialized_identity() {
+        let bytewise: &dyn KeyComparator = &Bytewise;
+        let reverse: &dyn KeyComparator = &Reverse(Bytewise);
+
+        let mut output = Vec::new();
+        bytewise.serialize_to(&mut output, false).unwrap();
+        assert!(output.is_empty());
+        bytewise
+            .check_serialized(&mut ArcBytes::from(output.as_slice()))
+            .unwrap();
+
+        reverse.serialize_to(&mut output, false).unwrap();
+        reverse
+            .check_serialized(&mut ArcBytes::from(output.as_slice()))
+            .unwrap();
+        let err = bytewise
+            .check_serialized(&mut ArcBytes::from(output.as_slice()))
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ComparatorMismatch { .. }));
+        let err = reverse
+            .check_serialized(&mut ArcBytes::default())
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ComparatorMismatch { .. }));
+
+        // When more data follows, the default comparator's identity is written
+        // and the following data is left unread.
+        output.clear();
+        bytewise.serialize_to(&mut output, true).unwrap();
+        output.push(42);
+        let mut bytes = ArcBytes::from(output.as_slice());
+        bytewise.check_serialized(&mut bytes).unwrap();
+        assert_eq!(bytes.as_slice(), &[42]);
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The moment a key's value expires, stored as the number of milliseconds
+/// since the Unix epoch.
+///
+/// Expired values are ignored when reading, and are removed by
+/// [`TreeFile::remove_expired()`](crate::tree::TreeFile::remove_expired).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Expiration(pub u64);
+
+impl Expiration {
+    /// Returns the current time as an expiration.
+    #[must_use]
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    /// Returns an expiration `ttl` from now.
+    #[must_use]
+    pub fn after(ttl: Duration) -> Self {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        Self(Self::now().0.saturating_add(ttl))
+    }
+
+    /// Returns true if this expiration is at or before `now`.
+    #[must_use]
+    pub fn has_passed(self, now: Self) -> bool {
+        self <= now
+    }
+}
+
+impl From<SystemTime> for Expiration {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self(u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX))
+    }
+}
+
+impl From<Expiration> for SystemTime {
+    fn from(expiration: Expiration) -> Self {
+        UNIX_EPOCH + Duration::from_millis(expiration.0)
+    }
+}
+
+/// A reduced index that tracks the earliest [`Expiration`] of the keys it
+/// contains, which allows skipping nodes that contain no expired keys.
+pub trait EarliestExpiration {
+    /// Returns the earliest expiration of the values contained in this
+    /// reduced index, if any values expire.
+    fn earliest_expiration(&self) -> Option<Expiration>;
+}
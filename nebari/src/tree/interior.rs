@@ -5,7 +5,10 @@ use std::{
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use super::{btree::BTreeEntry, read_chunk, BinarySerialization, PagedWriter};
+use super::{
+    btree::{BTreeEntry, BTreeNode},
+    read_chunk, BinarySerialization, PagedWriter,
+};
 use crate::{
     chunk_cache::CacheEntry,
     error::Error,
@@ -132,11 +135,12 @@ impl<
         match self {
             Pointer::OnDisk(position) => match read_chunk(*position, false, file, vault, cache)? {
                 CacheEntry::ArcBytes(mut buffer) => {
-                    let decoded = BTreeEntry::deserialize_from(&mut buffer, current_order)?;
+                    let decoded = BTreeEntry::deserialize_from(&mut buffer, current_order)
+                        .map_err(|err| err.with_path(file.id()).with_position(*position))?;
 
                     let result = callback(&decoded, file);
                     if let (Some(cache), Some(file_id)) = (cache, file.id().id()) {
-                        cache.replace_with_decoded(file_id, *position, Box::new(decoded));
+                        cache_decoded(cache, file_id, *position, Box::new(decoded));
                     }
                     result
                 }
@@ -154,6 +158,24 @@ impl<
     }
 }
 
+/// Stores a decoded node in `cache`. Interior nodes are pinned, if the cache
+/// has capacity for pinned nodes.
+fn cache_decoded<Index, ReducedIndex>(
+    cache: &ChunkCache,
+    file_id: u64,
+    position: u64,
+    entry: Box<BTreeEntry<Index, ReducedIndex>>,
+) where
+    Index: Send + Sync + 'static,
+    ReducedIndex: Send + Sync + 'static,
+{
+    if matches!(entry.node, BTreeNode::Interior(_)) {
+        cache.pin_decoded(file_id, position, entry);
+    } else {
+        cache.replace_with_decoded(file_id, position, entry);
+    }
+}
+
 impl<
         Index: Clone + PositionIndex + BinarySerialization + Debug + 'static,
         ReducedIndex: Clone + BinarySerialization + Debug + 'static,
@@ -257,7 +279,12 @@ impl<
                     if let (Some(cache), Some(file_id)) =
                         (paged_writer.cache, paged_writer.id().id())
                     {
-                        cache.replace_with_decoded(file_id, position, entry);
+                        // The previous version of this node is no longer
+                        // needed once this change is published.
+                        if let Some(previous_location) = previous_location {
+                            cache.unpin(file_id, previous_location);
+                        }
+                        cache_decoded(cache, file_id, position, entry);
                     }
                     position
                 }
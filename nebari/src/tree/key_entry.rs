@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use super::{serialization::BinarySerialization, PagedWriter};
+use super::{serialization::BinarySerialization, Expiration, PagedWriter};
 use crate::{error::Error, io::File, vault::AnyVault, ArcBytes, ErrorKind};
 
 /// An entry for a key. Stores a single index value for a single key.
@@ -18,6 +18,21 @@ pub struct KeyEntry<Index> {
 pub trait PositionIndex {
     /// The position on-disk of the stored value.
     fn position(&self) -> u64;
+
+    /// The moment the stored value expires, if it expires.
+    fn expiration(&self) -> Option<Expiration> {
+        None
+    }
+
+    /// Returns true if this index refers to a value that has not been removed
+    /// and has not expired as of `now`.
+    fn has_value(&self, now: Expiration) -> bool {
+        // Removed keys are stored with a 0 position.
+        self.position() > 0
+            && !self
+                .expiration()
+                .map_or(false, |expiration| expiration.has_passed(now))
+    }
 }
 
 impl<Index: PositionIndex + BinarySerialization> KeyEntry<Index> {
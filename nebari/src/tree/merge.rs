@@ -0,0 +1,165 @@
+use std::{convert::TryFrom, fmt::Debug, sync::Arc};
+
+use super::{
+    btree::KeyOperation,
+    modify::{BatchOperation, Modification, Operation},
+};
+use crate::{error::Error, ArcBytes, ErrorKind};
+
+/// Combines a key's stored value with merge operands.
+///
+/// A merge operator is registered using
+/// [`TreeRoot::with_merge_operator()`](crate::tree::TreeRoot::with_merge_operator),
+/// and is executed for each key modified using [`Operation::Merge`]. Merges
+/// are applied while the tree is being modified, which allows read-modify-write
+/// updates such as incrementing counters or appending to values without the
+/// caller reading the existing value first.
+///
+/// Merges are resolved eagerly: the existing value of each merged key is read
+/// while the modification is applied, and the operator's result is stored in
+/// place of the operands. Operands are never written to the tree, so reads and
+/// compaction do not invoke the operator, and the merged values remain
+/// readable by trees opened without it. Multiple operands merged into the same
+/// key by a [`WriteBatch`](crate::tree::WriteBatch) are passed to a single
+/// call of [`merge()`](Self::merge).
+///
+/// Because merges are resolved eagerly, merging does not reduce the cost of
+/// an update: each merged key's existing value is still read while the tree is
+/// locked, as it would be by [`Operation::CompareSwap`]. Merging only saves the
+/// caller from reading the value separately and retrying when it changes.
+///
+/// Merge operators are not stored in the tree's file, so the operator must be
+/// registered each time the tree is opened.
+pub trait MergeOperator: Debug + Send + Sync + 'static {
+    /// Returns the value of `key` after applying each of `operands`, in order,
+    /// to the `existing` value. Returning `None` removes the key.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>>;
+}
+
+/// Appends each operand to the end of the existing value.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        let existing = existing.unwrap_or_default();
+        let mut value = Vec::with_capacity(
+            existing.len() + operands.iter().map(|operand| operand.len()).sum::<usize>(),
+        );
+        value.extend_from_slice(existing);
+        for operand in operands {
+            value.extend_from_slice(operand);
+        }
+        Some(value)
+    }
+}
+
+/// Adds each operand to the existing value, treating both as big-endian
+/// encoded `u64`s. Addition wraps on overflow, which allows decrementing by
+/// adding the two's complement of the amount.
+///
+/// A missing value, or a value or operand that isn't 8 bytes long, is treated
+/// as 0.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AddU64;
+
+impl AddU64 {
+    fn decode(bytes: &[u8]) -> u64 {
+        <[u8; 8]>::try_from(bytes).map_or(0, u64::from_be_bytes)
+    }
+}
+
+impl MergeOperator for AddU64 {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        let total = operands
+            .iter()
+            .fold(existing.map_or(0, Self::decode), |total, operand| {
+                total.wrapping_add(Self::decode(operand))
+            });
+        Some(total.to_be_bytes().to_vec())
+    }
+}
+
+impl dyn MergeOperator {
+    /// Returns the result of merging `operands` into `existing`.
+    pub(crate) fn merged<Value>(
+        &self,
+        key: &[u8],
+        existing: Option<&Value>,
+        operands: &[Value],
+    ) -> KeyOperation<Value>
+    where
+        Value: AsRef<[u8]> + From<Vec<u8>>,
+    {
+        let operands = operands.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        self.merge(key, existing.map(AsRef::as_ref), &operands)
+            .map_or(KeyOperation::Remove, |value| {
+                KeyOperation::Set(Value::from(value))
+            })
+    }
+}
+
+impl<Index> Modification<'_, ArcBytes<'static>, Index> {
+    /// Replaces an [`Operation::Merge`] with compare-and-swaps that merge the
+    /// operand into each key's value using `operator` as the modification is
+    /// applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ErrorKind::MergeOperatorNotConfigured`] if this modification
+    /// contains a merge and `operator` is `None`.
+    pub fn resolve_merges(
+        &mut self,
+        operator: Option<&Arc<dyn MergeOperator>>,
+    ) -> Result<(), Error> {
+        if let Operation::Merge(operand) = &self.operation {
+            let operator = operator.ok_or(ErrorKind::MergeOperatorNotConfigured)?;
+            let operations = self
+                .keys
+                .iter()
+                .map(|_| {
+                    let operator = operator.clone();
+                    let operand = operand.clone();
+                    BatchOperation::CompareSwap(Box::new(move |key, _index, existing| {
+                        operator.merged(key, existing.as_ref(), std::slice::from_ref(&operand))
+                    }))
+                })
+                .collect();
+            self.operation = Operation::Batch(operations);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_operators() {
+        let append: &dyn MergeOperator = &Append;
+        assert_eq!(
+            append.merge(b"key", Some(b"a"), &[b"b", b"cd"]).unwrap(),
+            b"abcd"
+        );
+        assert_eq!(append.merge(b"key", None, &[b"b"]).unwrap(), b"b");
+
+        let add: &dyn MergeOperator = &AddU64;
+        let one = 1_u64.to_be_bytes();
+        let minus_two = 2_u64.wrapping_neg().to_be_bytes();
+        assert_eq!(
+            add.merge(b"key", None, &[&one, &one, &one]).unwrap(),
+            3_u64.to_be_bytes()
+        );
+        assert_eq!(
+            add.merge(b"key", Some(&5_u64.to_be_bytes()), &[&minus_two])
+                .unwrap(),
+            3_u64.to_be_bytes()
+        );
+        assert_eq!(
+            add.merge(b"key", Some(b"invalid"), &[&one]).unwrap(),
+            1_u64.to_be_bytes()
+        );
+    }
+}
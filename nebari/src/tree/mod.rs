@@ -22,9 +22,19 @@
 //!
 //! Before writing the Root, the file is padded to a multiple of
 //! [`PAGE_SIZE`]. A 3-byte magic code is written, followed by a byte for the
-//! [`PageHeader`].
+//! [`PageHeader`]. The highest bit of this byte is set when the header's chunk
+//! describes whether the tree is encrypted. Files written by older versions do
+//! not set this bit.
 //!
-//! The Root is then serialized and written as a chunk.
+//! The Root is then serialized and written as a chunk. The header chunk is not
+//! processed by the vault. Instead, it starts with a byte that is `0` if the
+//! tree is not encrypted, followed by the serialized Root. If the tree is
+//! encrypted, the byte is `1`, and the remainder of the chunk is an envelope
+//! (described below) containing a known check value followed by the serialized
+//! Root. The byte is `2` instead of `1` if the file also contains chunks that
+//! were written before the tree was encrypted, whose contents are not
+//! encrypted. When the header is read, the check value allows a mismatched
+//! vault to be reported as [`ErrorKind::VaultMismatch`].
 //!
 //! To locate the most recent header, take the file's length and find the
 //! largest multiple of [`PAGE_SIZE`]. Check the first three bytes at that
@@ -46,65 +56,105 @@
 //! - `u32` - Data length, excluding the header.
 //! - `u32` - CRC
 //! - `[u8]` - Contents
+//!
+//! Chunks encrypted by a vault are preceded by an 8-byte marker: a length of
+//! `0` followed by a CRC of `0xFFFFFFFF`, which an empty chunk can never have.
+//! The contents of these chunks are an envelope:
+//!
+//! - `u8` - Envelope version, currently `1`.
+//! - `u8` - `0` if the vault has no [key id](crate::Vault::key_id), or `1`
+//!   followed by the vault's `u32` key id.
+//! - `[u8]` - The vault's encrypted form of the chunk.
+//!
+//! Chunks that are not preceded by the marker are not encrypted, unless they
+//! were written by an older version with a vault, in which case they are
+//! decrypted by the vault without an envelope.
 
+#[cfg(feature = "sampling")]
+use std::collections::BTreeSet;
 use std::{
-    borrow::Cow,
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    cmp::Ordering,
     collections::HashMap,
+    convert::Infallible,
     fmt::{Debug, Display},
     hash::BuildHasher,
     io::SeekFrom,
     marker::PhantomData,
     ops::{Bound, Deref, DerefMut, Range, RangeBounds},
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use crc::{Crc, CRC_32_BZIP2};
+#[cfg(feature = "sampling")]
+use nanorand::{Rng, WyRand};
 use parking_lot::MutexGuard;
 
 use crate::{
     chunk_cache::CacheEntry,
-    error::Error,
+    error::{Error, InternalError},
     io::{
         File, FileManager, FileOp, IntoPathId, ManagedFile, ManagedFileOpener, OpenableFile,
         OperableFile, PathId,
     },
+    keys::PrefixRange,
     roots::AbortError,
     transaction::{ManagedTransaction, TransactionManager},
     tree::btree::{BTreeNode, Indexer, KeyOperation, Reducer, ScanArgs},
-    vault::AnyVault,
-    ArcBytes, ChunkCache, CompareAndSwapError, Context, ErrorKind,
+    vault::{location_binding, open_envelope, seal, unseal, AnyVault},
+    ArcBytes, ChunkCache, CompareAndSwapError, Context, ErrorKind, Metrics,
 };
 
+mod batch;
 /// B+Tree types
 pub mod btree;
 mod by_id;
 mod by_sequence;
+mod comparator;
+mod expiration;
 mod interior;
 mod key_entry;
+mod merge;
 mod modify;
 pub(crate) mod root;
+mod secondary;
 mod serialization;
 pub(crate) mod state;
+mod statistics;
 mod unversioned;
 mod versioned;
 
 pub(crate) const DEFAULT_MAX_ORDER: usize = 1000;
 
 pub use self::{
+    batch::{BatchConflict, WriteBatch},
     by_id::{ByIdIndexer, ByIdStats, UnversionedByIdIndex, VersionedByIdIndex},
     by_sequence::{BySequenceIndex, BySequenceStats, SequenceId},
+    comparator::{AsciiCaseInsensitive, Bytewise, KeyComparator, Reverse},
+    expiration::{EarliestExpiration, Expiration},
     interior::{Interior, Pointer},
     key_entry::{KeyEntry, PositionIndex},
-    modify::{CompareSwap, CompareSwapFn, Modification, Operation, PersistenceMode},
+    merge::{AddU64, Append, MergeOperator},
+    modify::{
+        BatchOperation, CompareSwap, CompareSwapFn, Modification, Operation, PersistenceMode,
+    },
     root::{AnyTreeRoot, Root, TreeRoot},
+    secondary::SecondaryIndexEntry,
     serialization::BinarySerialization,
     state::{ActiveState, State},
+    statistics::{KeyStatistics, RangeEstimate},
     unversioned::{Unversioned, UnversionedTreeRoot},
     versioned::{KeySequence, SequenceEntry, SequenceIndex, Versioned, VersionedTreeRoot},
 };
 
+pub(crate) use self::secondary::{
+    decode_entry_key, deserialize_index_names, entry_range, index_tree_prefix, is_index_tree,
+    serialize_index_names, IndexChanges, SecondaryIndex, INDEX_TREE_SEPARATOR,
+};
+
 /// The number of bytes in each page on-disk.
 // The memory used by PagedWriter is PAGE_SIZE * PAGED_WRITER_BATCH_COUNT. E.g,
 // 4096 * 4 = 16kb
@@ -112,6 +162,22 @@ pub const PAGE_SIZE: usize = 256;
 
 const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_BZIP2);
 
+/// Set in a page's header byte when the header's chunk begins by describing
+/// whether the tree is encrypted.
+const VAULT_DESCRIBED_HEADER: u8 = 0x80;
+const UNENCRYPTED_HEADER: u8 = 0;
+const ENCRYPTED_HEADER: u8 = 1;
+/// An encrypted header of a file that also contains chunks written without a
+/// vault.
+const PARTIALLY_ENCRYPTED_HEADER: u8 = 2;
+/// Written before a chunk whose contents are a [sealed](crate::vault::seal)
+/// envelope. The CRC of an empty chunk is `0`, which prevents any chunk from
+/// starting with this marker.
+const SEALED_CHUNK: [u8; 8] = [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+/// The value encrypted at the start of an encrypted header, used to detect
+/// when a tree is opened with a different vault than it was written with.
+const VAULT_CHECK: &[u8] = b"NbriVault";
+
 /// The header byte for a tree file's page.
 #[derive(Eq, PartialEq)]
 pub enum PageHeader {
@@ -150,6 +216,7 @@ pub struct TreeFile<Root: root::Root, File: ManagedFile> {
     pub vault: Option<Arc<dyn AnyVault>>,
     /// The cache used to cache chunks from the file.
     pub cache: Option<ChunkCache>,
+    metrics: Option<Arc<dyn Metrics>>,
     scratch: Vec<u8>,
 }
 
@@ -182,10 +249,19 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
             state,
             vault,
             cache,
+            metrics: None,
             scratch: Vec::new(),
         })
     }
 
+    /// Reports measurements of this file's writes, synchronizations and
+    /// compactions to `metrics`.
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: Option<Arc<dyn Metrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
     /// Opens a tree file with read-only permissions.
     pub fn read(
         path: impl IntoPathId,
@@ -195,7 +271,9 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
     ) -> Result<Self, Error> {
         let file = context.file_manager.read(path)?;
         Self::initialize_state(&state, file.id(), context, transactions)?;
-        Self::new(file, state, context.vault.clone(), context.cache.clone())
+        let vault = effective_vault(state.read().plaintext_chunks, context);
+        Self::new(file, state, vault, context.cache.clone())
+            .map(|tree| tree.with_metrics(context.metrics.clone()))
     }
 
     /// Opens a tree file with the ability to read and write.
@@ -207,15 +285,45 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
     ) -> Result<Self, Error> {
         let file = context.file_manager.append(path)?;
         Self::initialize_state(&state, file.id(), context, transactions)?;
-        Self::new(file, state, context.vault.clone(), context.cache.clone())
+        let vault = effective_vault(state.read().plaintext_chunks, context);
+        Self::new(file, state, vault, context.cache.clone())
+            .map(|tree| tree.with_metrics(context.metrics.clone()))
     }
 
     /// Attempts to load the last saved state of this tree into `state`.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ErrorKind::VaultMismatch`] if `context` has a vault, the file
+    /// contains data written without a vault, and `context` does not
+    /// [allow unencrypted data](Context::allow_unencrypted_data).
     pub fn initialize_state(
         state: &State<Root>,
         file_path: &PathId,
         context: &Context<File::Manager>,
         transaction_manager: Option<&TransactionManager<File::Manager>>,
+    ) -> Result<(), Error> {
+        Self::load_state(state, file_path, context, transaction_manager)?;
+        if context.vault.is_some()
+            && !context.allow_unencrypted_data
+            && state.read().plaintext_chunks
+        {
+            return Err(Error::from(ErrorKind::VaultMismatch {
+                path: file_path.path().to_path_buf(),
+                reason: String::from(
+                    "the tree contains data written without a vault, and unencrypted data is not allowed",
+                ),
+            }));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn load_state(
+        state: &State<Root>,
+        file_path: &PathId,
+        context: &Context<File::Manager>,
+        transaction_manager: Option<&TransactionManager<File::Manager>>,
     ) -> Result<(), Error> {
         {
             let read_state = state.read();
@@ -231,6 +339,8 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
 
         active_state.file_id = file_path.id();
         let file_length = context.file_manager.file_length(file_path)?;
+        // Chunks are only written unencrypted when there is no vault.
+        active_state.plaintext_chunks = context.vault.is_none();
         if file_length == 0 {
             active_state.root.initialize_default();
             active_state.publish(state);
@@ -251,30 +361,65 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
             tree.seek(SeekFrom::Start(block_start))?;
             tree.read_exact(&mut scratch_buffer)?;
 
+            let vault_described = scratch_buffer[3] & VAULT_DESCRIBED_HEADER != 0;
             #[allow(clippy::match_on_vec_items)]
             match (
                 &scratch_buffer[0..3],
-                PageHeader::try_from(scratch_buffer[3]),
+                PageHeader::try_from(scratch_buffer[3] & !VAULT_DESCRIBED_HEADER),
             ) {
                 (b"Nbr", Ok(header)) => {
                     if header != Root::HEADER {
-                        return Err(Error::data_integrity(format!(
-                            "Tree {:?} contained another header type",
-                            file_path
-                        )));
+                        return Err(Error::data_integrity(ErrorKind::RootTypeMismatch)
+                            .with_path(file_path)
+                            .with_position(block_start));
                     }
                     let contents = match read_chunk(
                         block_start + 4,
                         true,
                         &mut tree,
-                        context.vault(),
-                        context.cache(),
-                    )? {
-                        CacheEntry::ArcBytes(buffer) => buffer,
-                        CacheEntry::Decoded(_) => unreachable!(),
+                        // Headers are decrypted below.
+                        None,
+                        None,
+                    ) {
+                        Ok(CacheEntry::ArcBytes(buffer)) => buffer,
+                        Ok(CacheEntry::Decoded(_)) => unreachable!(),
+                        // The header extends beyond the end of the file, which
+                        // means it was torn by a crash while being written.
+                        Err(Error {
+                            kind: ErrorKind::Io(err),
+                            ..
+                        }) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            if block_start == 0 {
+                                active_state.root.initialize_default();
+                                break;
+                            }
+                            block_start -= PAGE_SIZE as u64;
+                            continue;
+                        }
+                        Err(err) => return Err(err),
                     };
-                    let root = Root::deserialize(contents, active_state.root.reducer().clone())
-                        .map_err(|err| ErrorKind::DataIntegrity(Box::new(err)))?;
+                    let (contents, plaintext_chunks) = decode_header(
+                        contents,
+                        vault_described,
+                        context,
+                        file_path.path(),
+                        block_start + 4,
+                    )?;
+                    active_state.plaintext_chunks = plaintext_chunks;
+                    let root = Root::deserialize(
+                        contents,
+                        active_state.root.reducer().clone(),
+                        active_state.root.comparator().clone(),
+                    )
+                    .map_err(|err| {
+                        if matches!(err.kind, ErrorKind::ComparatorMismatch { .. }) {
+                            err
+                        } else {
+                            Error::data_integrity(err)
+                        }
+                        .with_path(file_path)
+                        .with_position(block_start + 4)
+                    })?;
                     if let Some(transaction_manager) = transaction_manager {
                         if root.transaction_id().valid()
                             && !transaction_manager
@@ -291,7 +436,9 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
                             continue;
                         }
                     }
+                    let merge_operator = active_state.root.merge_operator().cloned();
                     active_state.root = root;
+                    active_state.root.set_merge_operator(merge_operator);
                     break;
                 }
                 (_, Ok(_) | Err(_)) => {
@@ -331,6 +478,7 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
                 state: &self.state,
                 vault: self.vault.as_deref(),
                 cache: self.cache.as_ref(),
+                metrics: self.metrics.as_deref(),
                 modification: Some(Modification {
                     persistence_mode: persistence_mode.into(),
                     keys: vec![key.into()],
@@ -345,6 +493,56 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
             .expect("modification always produces a new index"))
     }
 
+    /// Sets `key` to `value`, which expires once `ttl` has elapsed. Expired
+    /// values are ignored by reads, and are removed by
+    /// [`remove_expired()`](Self::remove_expired) or when the tree is
+    /// compacted. Writing to `key` again without an expiration clears the
+    /// expiration.
+    ///
+    /// Returns the new/updated index for this key.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn set_with_ttl(
+        &mut self,
+        persistence_mode: impl Into<PersistenceMode>,
+        key: impl Into<ArcBytes<'static>>,
+        value: impl Into<Root::Value>,
+        ttl: Duration,
+    ) -> Result<Root::Index, Error> {
+        Ok(self
+            .modify(Modification {
+                persistence_mode: persistence_mode.into(),
+                keys: vec![key.into()],
+                operation: Operation::SetWithExpiration(value.into(), Expiration::after(ttl)),
+            })?
+            .into_iter()
+            .next()
+            .expect("always produces a single result")
+            .index
+            .expect("modification always produces a new index"))
+    }
+
+    /// Merges `operand` into the value of `key` using the tree's
+    /// [`MergeOperator`].
+    ///
+    /// Returns the updated index for this key, or `None` if the merge removed
+    /// the key.
+    pub fn merge(
+        &mut self,
+        persistence_mode: impl Into<PersistenceMode>,
+        key: impl Into<ArcBytes<'static>>,
+        operand: impl Into<Root::Value>,
+    ) -> Result<Option<Root::Index>, Error> {
+        Ok(self
+            .modify(Modification {
+                persistence_mode: persistence_mode.into(),
+                keys: vec![key.into()],
+                operation: Operation::Merge(operand.into()),
+            })?
+            .into_iter()
+            .next()
+            .and_then(|result| result.index))
+    }
+
     /// Executes a modification. Returns a list of modified keys and their
     /// updated indexes, if the keys are still present.
     pub fn modify(
@@ -355,11 +553,48 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
             state: &self.state,
             vault: self.vault.as_deref(),
             cache: self.cache.as_ref(),
+            metrics: self.metrics.as_deref(),
             modification: Some(modification),
             scratch: &mut self.scratch,
         })
     }
 
+    /// Applies each operation in `batch` within a single modification. Returns
+    /// the compare-and-swap operations that were skipped because the stored
+    /// value didn't match the expected value. All other operations in the
+    /// batch are still applied.
+    ///
+    /// Returns [`ErrorKind::MergeOperatorNotConfigured`] if `batch` contains a
+    /// merge and the tree has no [`MergeOperator`].
+    pub fn apply_batch(
+        &mut self,
+        batch: WriteBatch<Root::Value>,
+        persistence_mode: impl Into<PersistenceMode>,
+    ) -> Result<Vec<BatchConflict<Root::Value>>, Error>
+    where
+        Root::Value: PartialEq + AsRef<[u8]> + From<Vec<u8>>,
+    {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let conflicts = RefCell::new(Vec::new());
+        let (comparator, merge_operator) = {
+            let state = self.state.lock();
+            (
+                state.root.comparator().clone(),
+                state.root.merge_operator().cloned(),
+            )
+        };
+        self.modify(batch.into_modification(
+            comparator.as_ref(),
+            merge_operator.as_ref(),
+            persistence_mode.into(),
+            &conflicts,
+        )?)?;
+        Ok(conflicts.into_inner())
+    }
+
     /// Compares the value of `key` against `old`. If the values match, key will
     /// be set to the new value if `new` is `Some` or removed if `new` is
     /// `None`.
@@ -424,6 +659,107 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
         Ok(existing_value)
     }
 
+    /// Removes all keys contained within `range`, returning the number of keys
+    /// removed.
+    ///
+    /// For unversioned trees, subtrees whose keys are entirely contained
+    /// within `range` are removed without reading the keys they contain.
+    /// Versioned trees record each removed key in their sequence history, so
+    /// every removed key is visited.
+    pub fn remove_range<'keys, KeyRangeBounds>(
+        &mut self,
+        range: &'keys KeyRangeBounds,
+        persistence_mode: impl Into<PersistenceMode>,
+    ) -> Result<u64, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + ?Sized,
+    {
+        let original_count = self.state.lock().root.count();
+        self.modify(Modification {
+            persistence_mode: persistence_mode.into(),
+            keys: Vec::new(),
+            operation: Operation::remove_range(range),
+        })?;
+        let count = self.state.lock().root.count();
+        Ok(original_count.saturating_sub(count))
+    }
+
+    /// Removes all keys whose values have expired, returning the number of keys
+    /// removed.
+    ///
+    /// Nodes are only read if their reduced index contains a value that has
+    /// expired, so trees with few expired keys are not scanned in their
+    /// entirety.
+    pub fn remove_expired(
+        &mut self,
+        persistence_mode: impl Into<PersistenceMode>,
+    ) -> Result<usize, Error> {
+        self.remove_expired_keys(persistence_mode)
+            .map(|removed| removed.len())
+    }
+
+    /// Removes all keys whose values have expired, returning the keys that were
+    /// removed.
+    pub(crate) fn remove_expired_keys(
+        &mut self,
+        persistence_mode: impl Into<PersistenceMode>,
+    ) -> Result<Vec<ArcBytes<'static>>, Error> {
+        let now = Expiration::now();
+        let expired = self.expired_keys(now)?;
+        if expired.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // The keys may have been written since they were scanned, so each key
+        // is checked again before it is removed.
+        let mut removed = Vec::new();
+        self.modify(Modification {
+            persistence_mode: persistence_mode.into(),
+            keys: expired,
+            operation: Operation::CompareSwap(CompareSwap::new(
+                &mut |key, index: Option<&Root::Index>, _value| {
+                    if index.map_or(false, |index| {
+                        index.position() > 0 && has_expired(index.expiration(), now)
+                    }) {
+                        removed.push(key.clone().into_owned());
+                        KeyOperation::Remove
+                    } else {
+                        KeyOperation::Skip
+                    }
+                },
+            )),
+        })?;
+        Ok(removed)
+    }
+
+    /// Returns the keys whose values have expired as of `now`, including keys
+    /// modified by the current transaction.
+    fn expired_keys(&mut self, now: Expiration) -> Result<Vec<ArcBytes<'static>>, Error> {
+        let has_expired = |expiration: Option<Expiration>| has_expired(expiration, now);
+        let mut expired = Vec::new();
+        self.scan::<Infallible, _, _, _, _>(
+            &(..),
+            true,
+            true,
+            |_, stats, _| {
+                if has_expired(stats.earliest_expiration()) {
+                    ScanEvaluation::ReadData
+                } else {
+                    ScanEvaluation::Skip
+                }
+            },
+            |key, index| {
+                if index.position() > 0 && has_expired(index.expiration()) {
+                    expired.push(key.clone());
+                }
+                ScanEvaluation::Skip
+            },
+            |_, _, _| unreachable!(),
+        )
+        .map_err(AbortError::infallible)?;
+        Ok(expired)
+    }
+
     /// Sets `key` to `value`. Returns a tuple containing two elements:
     ///
     /// - The previously stored value, if a value was already present.
@@ -755,6 +1091,80 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
         Ok(())
     }
 
+    /// Retrieves all keys and values for keys that begin with `prefix`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
+    pub fn get_prefix(
+        &mut self,
+        prefix: &[u8],
+        in_transaction: bool,
+    ) -> Result<Vec<(ArcBytes<'static>, Root::Value)>, Error> {
+        let mut results = Vec::new();
+        self.scan_prefix(
+            prefix,
+            true,
+            in_transaction,
+            |_, _| ScanEvaluation::ReadData,
+            |key, _index, value| {
+                results.push((key, value));
+                Ok(())
+            },
+        )?;
+        Ok(results)
+    }
+
+    /// Scans the tree for keys that begin with `prefix`.
+    ///
+    /// If `forwards` is true, the tree is scanned in ascending order.
+    /// Otherwise, the tree is scanned in descending order.
+    ///
+    /// `key_evaluator` and `key_reader` are invoked the same way as in
+    /// [`scan()`](Self::scan). If the tree uses the [`Bytewise`] comparator,
+    /// only the nodes that may contain keys beginning with `prefix` are read.
+    /// Other comparators may not store keys that share a prefix next to each
+    /// other, so every key in the tree is checked.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, key_evaluator, key_reader))
+    )]
+    pub fn scan_prefix<CallerError, KeyEvaluator, DataCallback>(
+        &mut self,
+        prefix: &[u8],
+        forwards: bool,
+        in_transaction: bool,
+        mut key_evaluator: KeyEvaluator,
+        key_reader: DataCallback,
+    ) -> Result<(), AbortError<CallerError>>
+    where
+        KeyEvaluator: FnMut(&ArcBytes<'static>, &Root::Index) -> ScanEvaluation,
+        DataCallback: FnMut(
+            ArcBytes<'static>,
+            &Root::Index,
+            Root::Value,
+        ) -> Result<(), AbortError<CallerError>>,
+        CallerError: Display + Debug,
+    {
+        let prefix_range = PrefixRange::new(prefix);
+        let range = if self.state.lock().root.comparator().is_bytewise() {
+            prefix_range.bounds()
+        } else {
+            (Bound::Unbounded, Bound::Unbounded)
+        };
+        self.scan(
+            &range,
+            forwards,
+            in_transaction,
+            |_, _, _| ScanEvaluation::ReadData,
+            |key, index| {
+                if key.starts_with(prefix) {
+                    key_evaluator(key, index)
+                } else {
+                    ScanEvaluation::Skip
+                }
+            },
+            key_reader,
+        )
+    }
+
     /// Returns the reduced index over the provided range. This is an
     /// aggregation function that builds atop the `scan()` operation which calls
     /// [`Reducer::reduce()`] and [`Reducer::rereduce()`] on all matching
@@ -773,9 +1183,12 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
         KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
         Root::Index: Clone,
     {
-        let reducer = {
+        let (reducer, comparator) = {
             let state = self.state.lock();
-            state.root.reducer().clone()
+            (
+                state.root.reducer().clone(),
+                state.root.comparator().clone(),
+            )
         };
         let reduce_state = RefCell::new(ReduceState::new(reducer));
         self.file.execute(TreeScanner {
@@ -790,17 +1203,23 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
                 state.reduce_to_depth(depth);
                 let start_is_after_max = match range.start_bound() {
                     Bound::Unbounded => false,
-                    Bound::Excluded(start) => start >= &max_key.as_slice(),
-                    Bound::Included(start) => start > &max_key.as_slice(),
+                    Bound::Excluded(start) => comparator.compare(start, max_key) != Ordering::Less,
+                    Bound::Included(start) => {
+                        comparator.compare(start, max_key) == Ordering::Greater
+                    }
                 };
                 let start_is_lowest = match range.start_bound() {
                     Bound::Unbounded => true,
-                    Bound::Excluded(start) => start < &state.lowest_key.as_slice(),
-                    Bound::Included(start) => start <= &state.lowest_key.as_slice(),
+                    Bound::Excluded(start) => {
+                        comparator.compare(start, &state.lowest_key) == Ordering::Less
+                    }
+                    Bound::Included(start) => {
+                        comparator.compare(start, &state.lowest_key) != Ordering::Greater
+                    }
                 };
                 let end_included = match range.end_bound() {
-                    Bound::Included(end) => end <= &max_key.as_slice(),
-                    Bound::Excluded(end) => end < &max_key.as_slice(),
+                    Bound::Included(end) => comparator.compare(end, max_key) != Ordering::Less,
+                    Bound::Excluded(end) => comparator.compare(end, max_key) == Ordering::Greater,
                     Bound::Unbounded => true,
                 };
                 if start_is_after_max {
@@ -810,6 +1229,7 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
                     // The node is fully included. Copy the index to the
                     // stack and skip all the children.
                     state.push_reduced(depth, index.clone());
+                    state.lowest_key.clone_from(max_key);
                     ScanEvaluation::Skip
                 } else {
                     // This node is partially contained.
@@ -817,9 +1237,10 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
                 }
             },
             key_evaluator: |key, index| {
-                if range.contains(&key.as_slice()) {
+                if comparator.range_contains(range, key) {
                     let mut state = reduce_state.borrow_mut();
                     state.push_index(index.clone());
+                    state.lowest_key.clone_from(key);
                 }
                 ScanEvaluation::Skip
             },
@@ -830,89 +1251,300 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
         Ok(reduce_state.finish())
     }
 
-    /// Returns the first key of the tree.
-    pub fn first_key(&mut self, in_transaction: bool) -> Result<Option<ArcBytes<'static>>, Error> {
-        let mut result = None;
-        self.scan(
-            &(..),
-            true,
-            in_transaction,
-            |_, _, _| ScanEvaluation::ReadData,
-            |key, _index| {
-                result = Some(key.clone());
-                ScanEvaluation::Stop
-            },
-            |_key, _index, _value| Ok(()),
-        )?;
+    /// Returns the number of keys contained within `range`. The key counts
+    /// stored in the reduced indexes are used for nodes entirely contained
+    /// within `range`, so only the nodes along the edges of `range` are read.
+    ///
+    /// Keys whose values have expired are counted until they are removed.
+    pub fn count_range<'keys, KeyRangeBounds>(
+        &mut self,
+        range: &'keys KeyRangeBounds,
+        in_transaction: bool,
+    ) -> Result<u64, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+        Root::Index: Clone,
+    {
+        Ok(self
+            .reduce(range, in_transaction)?
+            .map_or(0, |stats| stats.alive_keys()))
+    }
 
-        Ok(result)
+    /// Returns the number of keys ordered before `key`, which is the rank of
+    /// `key` if it is present. See [`Self::count_range()`] for more
+    /// information.
+    pub fn rank(&mut self, key: &[u8], in_transaction: bool) -> Result<u64, Error>
+    where
+        Root::Index: Clone,
+    {
+        self.count_range(&(..key), in_transaction)
     }
 
-    /// Returns the first key and value of the tree.
-    pub fn first(
+    /// Returns the key whose rank is `n`, where the first key of the tree has a
+    /// rank of 0. Nodes are skipped using the key counts stored in their
+    /// reduced indexes, so only one node per level of the tree is read.
+    ///
+    /// Keys whose values have expired are counted until they are removed.
+    pub fn nth(
         &mut self,
+        n: u64,
         in_transaction: bool,
-    ) -> Result<Option<(ArcBytes<'static>, Root::Value)>, Error> {
+    ) -> Result<Option<ArcBytes<'static>>, Error> {
+        // The key evaluator and node evaluator both update the remaining rank.
+        let remaining = Cell::new(n);
         let mut result = None;
-        let mut key_requested = false;
-        self.scan(
+        self.scan::<Infallible, _, _, _, _>(
             &(..),
             true,
             in_transaction,
-            |_, _, _| ScanEvaluation::ReadData,
-            |_, _| {
-                if key_requested {
-                    ScanEvaluation::Stop
+            |_, stats, _| {
+                let alive_keys = stats.alive_keys();
+                if alive_keys <= remaining.get() {
+                    remaining.set(remaining.get() - alive_keys);
+                    ScanEvaluation::Skip
                 } else {
-                    key_requested = true;
                     ScanEvaluation::ReadData
                 }
             },
-            |key, _index, value| {
-                result = Some((key, value));
-                Ok(())
+            |key, index| {
+                // Removed keys are stored with a 0 position.
+                if index.position() > 0 {
+                    if remaining.get() == 0 {
+                        result = Some(key.clone());
+                        return ScanEvaluation::Stop;
+                    }
+                    remaining.set(remaining.get() - 1);
+                }
+                ScanEvaluation::Skip
             },
-        )?;
-
+            |_, _, _| unreachable!(),
+        )
+        .map_err(AbortError::infallible)?;
         Ok(result)
     }
 
-    /// Returns the last key of the tree.
-    pub fn last_key(&mut self, in_transaction: bool) -> Result<Option<ArcBytes<'static>>, Error> {
-        let mut result = None;
-        self.scan(
-            &(..),
-            false,
-            in_transaction,
-            |_, _, _| ScanEvaluation::ReadData,
-            |key, _index| {
-                result = Some(key.clone());
-                ScanEvaluation::Stop
-            },
-            |_key, _index, _value| Ok(()),
-        )?;
-
-        Ok(result)
+    /// Returns the approximate number of keys and value bytes contained within
+    /// `range`. Like [`Self::count_range()`], the statistics stored in the
+    /// reduced indexes are used for nodes entirely contained within `range`.
+    ///
+    /// Keys whose values have expired are counted until they are removed, and
+    /// the value sizes are the sizes stored on disk, which include any
+    /// overhead added by the tree's vault.
+    pub fn estimate_range<'keys, KeyRangeBounds>(
+        &mut self,
+        range: &'keys KeyRangeBounds,
+        in_transaction: bool,
+    ) -> Result<RangeEstimate, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+        Root::Index: Clone,
+    {
+        Ok(self
+            .reduce(range, in_transaction)?
+            .map(|stats| RangeEstimate {
+                keys: stats.alive_keys(),
+                value_bytes: stats.alive_bytes(),
+            })
+            .unwrap_or_default())
     }
 
-    /// Returns the last key and value of the tree.
-    pub fn last(
+    /// Returns up to `count` entries chosen uniformly at random from the keys
+    /// contained within `range`, ordered by key. If `range` contains `count`
+    /// keys or fewer, all of its entries are returned.
+    ///
+    /// The ranks of the entries are chosen using [`Self::count_range()`], and
+    /// nodes that contain none of the chosen ranks are skipped without being
+    /// read. Keys whose values have expired can be chosen, but are not
+    /// returned, so fewer than `count` entries may be returned.
+    #[cfg(feature = "sampling")]
+    pub fn sample<'keys, KeyRangeBounds>(
         &mut self,
+        count: usize,
+        range: &'keys KeyRangeBounds,
         in_transaction: bool,
-    ) -> Result<Option<(ArcBytes<'static>, Root::Value)>, Error> {
-        let mut result = None;
-        let mut key_requested = false;
-        self.scan(
+    ) -> Result<Vec<(ArcBytes<'static>, Root::Value)>, Error>
+    where
+        KeyRangeBounds: RangeBounds<&'keys [u8]> + Debug + ?Sized,
+        Root::Index: Clone,
+    {
+        let keys_in_range = self.count_range(range, in_transaction)?;
+        if keys_in_range <= count as u64 {
+            return self.get_range(range, in_transaction);
+        }
+        let keys_before_range = match range.start_bound() {
+            Bound::Included(start) => {
+                self.count_range(&(Bound::Unbounded, Bound::Excluded(*start)), in_transaction)?
+            }
+            Bound::Excluded(start) => {
+                self.count_range(&(Bound::Unbounded, Bound::Included(*start)), in_transaction)?
+            }
+            Bound::Unbounded => 0,
+        };
+
+        // Choose `count` distinct ranks using Robert Floyd's algorithm.
+        let mut rng = WyRand::new();
+        let mut chosen = BTreeSet::new();
+        for upper in keys_in_range - count as u64..keys_in_range {
+            let rank = rng.generate_range(0..=upper);
+            if !chosen.insert(rank) {
+                chosen.insert(upper);
+            }
+        }
+        let ranks = chosen
+            .into_iter()
+            .map(|rank| keys_before_range + rank)
+            .collect::<Vec<_>>();
+
+        // The key evaluator and node evaluator both update the current rank
+        // and the next chosen rank to find.
+        let current_rank = Cell::new(0);
+        let next_rank = Cell::new(0);
+        let comparator = self.state.lock().root.comparator().clone();
+        let mut results = Vec::with_capacity(count);
+        self.scan::<Infallible, _, _, _, _>(
             &(..),
-            false,
+            true,
+            in_transaction,
+            |_, stats, _| match ranks.get(next_rank.get()) {
+                Some(&rank) => {
+                    let alive_keys = stats.alive_keys();
+                    if rank < current_rank.get() + alive_keys {
+                        ScanEvaluation::ReadData
+                    } else {
+                        current_rank.set(current_rank.get() + alive_keys);
+                        ScanEvaluation::Skip
+                    }
+                }
+                None => ScanEvaluation::Stop,
+            },
+            |_, index| {
+                // Removed keys are stored with a 0 position.
+                if index.position() == 0 {
+                    return ScanEvaluation::Skip;
+                }
+                let rank = current_rank.get();
+                current_rank.set(rank + 1);
+                match ranks.get(next_rank.get()) {
+                    Some(&next) if next == rank => {
+                        next_rank.set(next_rank.get() + 1);
+                        ScanEvaluation::ReadData
+                    }
+                    Some(_) => ScanEvaluation::Skip,
+                    None => ScanEvaluation::Stop,
+                }
+            },
+            |key, _index, value| {
+                // The ranks were counted separately from this scan, so a
+                // concurrent write may have shifted keys outside of the range.
+                if comparator.range_contains(range, &key) {
+                    results.push((key, value));
+                }
+                Ok(())
+            },
+        )
+        .map_err(AbortError::infallible)?;
+        Ok(results)
+    }
+
+    /// Returns the first key of the tree.
+    pub fn first_key(&mut self, in_transaction: bool) -> Result<Option<ArcBytes<'static>>, Error> {
+        let mut result = None;
+        let now = Expiration::now();
+        self.scan(
+            &(..),
+            true,
+            in_transaction,
+            |_, _, _| ScanEvaluation::ReadData,
+            |key, index| {
+                if index.has_value(now) {
+                    result = Some(key.clone());
+                    ScanEvaluation::Stop
+                } else {
+                    ScanEvaluation::Skip
+                }
+            },
+            |_key, _index, _value| Ok(()),
+        )?;
+
+        Ok(result)
+    }
+
+    /// Returns the first key and value of the tree.
+    pub fn first(
+        &mut self,
+        in_transaction: bool,
+    ) -> Result<Option<(ArcBytes<'static>, Root::Value)>, Error> {
+        let mut result = None;
+        let mut key_requested = false;
+        let now = Expiration::now();
+        self.scan(
+            &(..),
+            true,
             in_transaction,
             |_, _, _| ScanEvaluation::ReadData,
-            |_, _| {
+            |_, index| {
                 if key_requested {
                     ScanEvaluation::Stop
+                } else if index.has_value(now) {
+                    key_requested = true;
+                    ScanEvaluation::ReadData
                 } else {
+                    ScanEvaluation::Skip
+                }
+            },
+            |key, _index, value| {
+                result = Some((key, value));
+                Ok(())
+            },
+        )?;
+
+        Ok(result)
+    }
+
+    /// Returns the last key of the tree.
+    pub fn last_key(&mut self, in_transaction: bool) -> Result<Option<ArcBytes<'static>>, Error> {
+        let mut result = None;
+        let now = Expiration::now();
+        self.scan(
+            &(..),
+            false,
+            in_transaction,
+            |_, _, _| ScanEvaluation::ReadData,
+            |key, index| {
+                if index.has_value(now) {
+                    result = Some(key.clone());
+                    ScanEvaluation::Stop
+                } else {
+                    ScanEvaluation::Skip
+                }
+            },
+            |_key, _index, _value| Ok(()),
+        )?;
+
+        Ok(result)
+    }
+
+    /// Returns the last key and value of the tree.
+    pub fn last(
+        &mut self,
+        in_transaction: bool,
+    ) -> Result<Option<(ArcBytes<'static>, Root::Value)>, Error> {
+        let mut result = None;
+        let mut key_requested = false;
+        let now = Expiration::now();
+        self.scan(
+            &(..),
+            false,
+            in_transaction,
+            |_, _, _| ScanEvaluation::ReadData,
+            |_, index| {
+                if key_requested {
+                    ScanEvaluation::Stop
+                } else if index.has_value(now) {
                     key_requested = true;
                     ScanEvaluation::ReadData
+                } else {
+                    ScanEvaluation::Skip
                 }
             },
             |key, _index, value| {
@@ -924,6 +1556,47 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
         Ok(result)
     }
 
+    /// Reads the chunks at `positions` into this tree's cache, and loads the
+    /// nodes within `levels` of the root node. If this tree has no cache, this
+    /// function does nothing.
+    ///
+    /// `positions` should be ordered from the most to the least important
+    /// chunk to cache. If the cache is too small to hold every chunk, the
+    /// least important chunks will be evicted first.
+    ///
+    /// Chunks that can no longer be read are skipped. This allows `positions`
+    /// to be a list that was exported using
+    /// [`ChunkCache::cached_chunks()`] before the tree was last modified.
+    pub fn warm_cache(&mut self, positions: &[u64], levels: usize) -> Result<(), Error> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        self.file.execute(ChunkPrefetcher {
+            vault: self.vault.as_deref(),
+            cache,
+            positions,
+        })?;
+        if levels > 0 {
+            self.scan::<Infallible, _, _, _, _>(
+                &(..),
+                true,
+                false,
+                |_, _, depth| {
+                    if depth < levels {
+                        ScanEvaluation::ReadData
+                    } else {
+                        ScanEvaluation::Skip
+                    }
+                },
+                |_, _| ScanEvaluation::Skip,
+                |_, _, _| unreachable!(),
+            )
+            .map_err(AbortError::infallible)?;
+        }
+        Ok(())
+    }
+
     /// Commits the tree. This is only needed if writes were done with a
     /// transaction id. This will fully flush the tree and publish the
     /// transactional state to be available to readers.
@@ -932,6 +1605,7 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
             state: &self.state,
             vault: self.vault.as_deref(),
             cache: self.cache.as_ref(),
+            metrics: self.metrics.as_deref(),
             scratch: &mut self.scratch,
         })
     }
@@ -939,18 +1613,42 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
     /// Rewrites the database, removing all unused data in the process. For a
     /// `VersionedTreeRoot`, this will remove old version information.
     ///
+    /// If `transactions` is `None`, expired keys are removed using
+    /// [`remove_expired()`](Self::remove_expired) before the file is
+    /// rewritten. Otherwise, the caller is responsible for removing expired
+    /// keys within a transaction beforehand.
+    ///
     /// This process is done atomically by creating a new file containing the
     /// active data. Once the new file has all the current file's data, the file
     /// contents are swapped using atomic file operations.
     pub fn compact(
+        self,
+        file_manager: &File::Manager,
+        transactions: Option<TransactableCompaction<'_, File::Manager>>,
+    ) -> Result<Self, Error> {
+        let vault = self.vault.clone();
+        self.compact_with_vault(file_manager, vault, transactions)
+    }
+
+    /// Compacts the tree like [`Self::compact()`], writing the compacted file
+    /// using `vault` instead of the vault this file was opened with. This can
+    /// be used to encrypt a tree that was written without a vault.
+    pub fn compact_with_vault(
         mut self,
         file_manager: &File::Manager,
+        vault: Option<Arc<dyn AnyVault>>,
         transactions: Option<TransactableCompaction<'_, File::Manager>>,
     ) -> Result<Self, Error> {
+        if transactions.is_none() {
+            self.remove_expired(PersistenceMode::Sync)?;
+        }
+        let start = Instant::now();
         let (compacted_file, finisher) = self.file.execute(TreeCompactor {
             state: &self.state,
             manager: file_manager,
-            vault: self.vault.as_deref(),
+            source_vault: self.vault.as_deref(),
+            vault: vault.as_deref(),
+            metrics: self.metrics.as_deref(),
             transactions,
             scratch: &mut self.scratch,
         })?;
@@ -959,6 +1657,10 @@ impl<Root: root::Root, File: ManagedFile> TreeFile<Root, File> {
             .replace_with(compacted_file, file_manager, |file_id| {
                 finisher.finish(file_id.id().expect("id can't be none at this stage"));
             })?;
+        self.vault = vault;
+        if let Some(metrics) = &self.metrics {
+            metrics.tree_compacted(self.file.id().path(), start.elapsed());
+        }
         Ok(self)
     }
 }
@@ -1213,7 +1915,11 @@ pub struct TransactableCompaction<'a, Manager: FileManager> {
 struct TreeCompactor<'a, Root: root::Root, Manager: FileManager> {
     manager: &'a Manager,
     state: &'a State<Root>,
+    /// The vault to read the current file with.
+    source_vault: Option<&'a dyn AnyVault>,
+    /// The vault to write the compacted file with.
     vault: Option<&'a dyn AnyVault>,
+    metrics: Option<&'a dyn Metrics>,
     transactions: Option<TransactableCompaction<'a, Manager>>,
     scratch: &'a mut Vec<u8>,
 }
@@ -1232,17 +1938,15 @@ where
         let current_path = file.id().path();
         let file_name = current_path
             .file_name()
-            .ok_or_else(|| ErrorKind::message("could not retrieve file name"))?;
+            .ok_or_else(|| ErrorKind::InvalidFilePath(current_path.to_path_buf()))?;
         let mut compacted_name = file_name.to_os_string();
         compacted_name.push(".compacting");
         let compacted_path = current_path
             .parent()
-            .ok_or_else(|| ErrorKind::message("couldn't access parent of file"))?
+            .ok_or_else(|| ErrorKind::InvalidFilePath(current_path.to_path_buf()))?
             .join(compacted_name);
 
-        if compacted_path.exists() {
-            std::fs::remove_file(&compacted_path)?;
-        }
+        self.manager.delete(&compacted_path)?;
 
         let transaction = self.transactions.as_ref().map(|transactions| {
             transactions
@@ -1250,28 +1954,47 @@ where
                 .new_transaction([transactions.name.as_bytes()])
         });
         let mut new_file = self.manager.open_for_append(&compacted_path)?;
+        // Compaction visits each chunk once, so neither the chunks read nor the
+        // chunks written are cached. This keeps compaction from evicting
+        // frequently read chunks from the cache.
         let mut writer = PagedWriter::new(None, &mut new_file, self.vault, None, 0)?;
+        // Once compaction finishes, the new file will replace the current
+        // file, so chunks must be bound to the current file's path.
+        writer.bound_path = Some(current_path.to_path_buf());
 
         // Use the read state to list all the currently live chunks
         let mut copied_chunks = HashMap::new();
         let read_state = self.state.read();
         let mut temporary_header = read_state.root.clone();
         drop(read_state);
-        temporary_header.copy_data_to(false, file, &mut copied_chunks, &mut writer, self.vault)?;
+        temporary_header.copy_data_to(
+            false,
+            file,
+            &mut copied_chunks,
+            &mut writer,
+            self.source_vault,
+        )?;
 
         // Now, do the same with the write state, which should be very fast,
         // since only nodes that have changed will need to be visited.
         let mut write_state = self.state.lock();
-        write_state
-            .root
-            .copy_data_to(true, file, &mut copied_chunks, &mut writer, self.vault)?;
+        write_state.root.copy_data_to(
+            true,
+            file,
+            &mut copied_chunks,
+            &mut writer,
+            self.source_vault,
+        )?;
 
+        // Every chunk in the compacted file is written with the new vault.
         save_tree(
             &mut write_state,
             self.vault,
             None,
+            self.metrics,
             writer,
             self.scratch,
+            self.vault.is_none(),
             true,
         )?;
 
@@ -1284,6 +2007,7 @@ where
             TreeCompactionFinisher {
                 write_state,
                 state: self.state,
+                plaintext_chunks: self.vault.is_none(),
                 _transaction: transaction,
             },
         ))
@@ -1293,21 +2017,60 @@ where
 struct TreeCompactionFinisher<'a, Root: root::Root, Manager: FileManager> {
     state: &'a State<Root>,
     write_state: MutexGuard<'a, ActiveState<Root>>,
+    plaintext_chunks: bool,
     _transaction: Option<ManagedTransaction<Manager>>,
 }
 
 impl<'a, Root: root::Root, Manager: FileManager> TreeCompactionFinisher<'a, Root, Manager> {
     fn finish(mut self, new_file_id: u64) {
         self.write_state.file_id = Some(new_file_id);
+        self.write_state.plaintext_chunks = self.plaintext_chunks;
         self.write_state.publish(self.state);
         drop(self);
     }
 }
 
+struct ChunkPrefetcher<'a> {
+    vault: Option<&'a dyn AnyVault>,
+    cache: &'a ChunkCache,
+    positions: &'a [u64],
+}
+
+impl FileOp<Result<(), Error>> for ChunkPrefetcher<'_> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn execute(self, file: &mut dyn File) -> Result<(), Error> {
+        let file_length = file.length()?;
+        let mut header = [0_u8; 8];
+        // Chunks are read from least to most important so that the most
+        // important chunks are the most recently used.
+        for &position in self.positions.iter().rev() {
+            if position.saturating_add(8) > file_length {
+                continue;
+            }
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut header)?;
+            let length = u64::from(BigEndian::read_u32(&header[0..4]));
+            if position + 8 + length > file_length || length > self.cache.max_chunk_size() as u64 {
+                continue;
+            }
+
+            // Validating the CRC ensures that positions that no longer point
+            // to a chunk are not cached.
+            if let Err(err) = read_chunk(position, true, file, self.vault, Some(self.cache)) {
+                if matches!(err.kind, ErrorKind::Io(_)) {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 struct TreeWriter<'a, Root: root::Root> {
     state: &'a State<Root>,
     vault: Option<&'a dyn AnyVault>,
     cache: Option<&'a ChunkCache>,
+    metrics: Option<&'a dyn Metrics>,
     scratch: &'a mut Vec<u8>,
 }
 
@@ -1321,6 +2084,8 @@ where
             return Err(Error::from(ErrorKind::TreeCompacted));
         }
         if active_state.root.dirty() {
+            let file_id = file.id().clone();
+            let starting_position = active_state.current_position;
             let data_block = PagedWriter::new(
                 None,
                 file,
@@ -1330,17 +2095,24 @@ where
             )?;
 
             self.scratch.clear();
+            let plaintext_chunks = active_state.plaintext_chunks;
             save_tree(
                 &mut *active_state,
                 self.vault,
                 self.cache,
+                self.metrics,
                 data_block,
                 self.scratch,
+                plaintext_chunks,
                 true,
-            )
-        } else {
-            Ok(())
+            )?;
+            let bytes_written = active_state.current_position - starting_position;
+            drop(active_state);
+            if let Some(metrics) = self.metrics {
+                metrics.tree_bytes_written(file_id.path(), bytes_written);
+            }
         }
+        Ok(())
     }
 }
 
@@ -1348,6 +2120,7 @@ struct TreeModifier<'a, 'm, Root: root::Root> {
     state: &'a State<Root>,
     vault: Option<&'a dyn AnyVault>,
     cache: Option<&'a ChunkCache>,
+    metrics: Option<&'a dyn Metrics>,
     modification: Option<Modification<'m, Root::Value, Root::Index>>,
     scratch: &'a mut Vec<u8>,
 }
@@ -1366,6 +2139,8 @@ where
             return Err(Error::from(ErrorKind::TreeCompacted));
         }
 
+        let file_id = file.id().clone();
+        let starting_position = active_state.current_position;
         let mut data_block = PagedWriter::new(
             None,
             file,
@@ -1391,32 +2166,52 @@ where
         } else {
             // Save the tree to disk immediately.
             self.scratch.clear();
+            let plaintext_chunks = active_state.plaintext_chunks;
             save_tree(
                 &mut *active_state,
                 self.vault,
                 self.cache,
+                self.metrics,
                 data_block,
                 self.scratch,
+                plaintext_chunks,
                 persistence_mode.should_synchronize(),
             )?;
             active_state.publish(self.state);
         }
 
+        let bytes_written = active_state.current_position - starting_position;
+        drop(active_state);
+        if let Some(metrics) = self.metrics {
+            metrics.tree_bytes_written(file_id.path(), bytes_written);
+        }
+
         Ok(results)
     }
 }
 
+/// Returns true if `expiration` is set and has passed as of `now`.
+fn has_expired(expiration: Option<Expiration>, now: Expiration) -> bool {
+    expiration.map_or(false, |expiration| expiration.has_passed(now))
+}
+
 #[allow(clippy::shadow_unrelated)] // It is related, but clippy can't tell.
+/// Writes the root of `active_state` and a new header. `plaintext_chunks`
+/// records whether the file contains chunks written without a vault.
+#[allow(clippy::too_many_arguments)]
 fn save_tree<Root: root::Root>(
     active_state: &mut ActiveState<Root>,
     vault: Option<&dyn AnyVault>,
     cache: Option<&ChunkCache>,
+    metrics: Option<&dyn Metrics>,
     mut data_block: PagedWriter<'_>,
     scratch: &mut Vec<u8>,
+    plaintext_chunks: bool,
     synchronize: bool,
 ) -> Result<(), Error> {
     scratch.clear();
     active_state.root.serialize(&mut data_block, scratch)?;
+    let bound_path = data_block.bound_path.take();
     let (file, after_data) = data_block.finish()?;
     active_state.current_position = after_data;
 
@@ -1428,13 +2223,18 @@ fn save_tree<Root: root::Root>(
         cache,
         active_state.current_position,
     )?;
-    header_block.write_chunk(scratch)?;
+    header_block.bound_path = bound_path;
+    header_block.write_header_chunk(scratch, plaintext_chunks)?;
 
     let (file, after_header) = header_block.finish()?;
     active_state.current_position = after_header;
 
     if synchronize {
+        let start = Instant::now();
         file.synchronize()?;
+        if let Some(metrics) = metrics {
+            metrics.file_synchronized(file.id().path(), start.elapsed());
+        }
     }
 
     Ok(())
@@ -1675,6 +2475,7 @@ where
                     .keys
                     .into_iter()
                     .map(|sequence| sequence.0.to_be_bytes()),
+                &Bytewise,
                 |key, index| {
                     (self.key_evaluator)(SequenceId::try_from(key.as_slice()).unwrap(), index)
                 },
@@ -1696,6 +2497,7 @@ where
                     .keys
                     .into_iter()
                     .map(|sequence| sequence.0.to_be_bytes()),
+                &Bytewise,
                 |key, index| {
                     (self.key_evaluator)(SequenceId::try_from(key.as_slice()).unwrap(), index)
                 },
@@ -1791,6 +2593,7 @@ where
                 .by_sequence_root
                 .scan(
                     range,
+                    &Bytewise,
                     &mut ScanArgs::new(
                         forwards,
                         |_, _, _| ScanEvaluation::ReadData,
@@ -1814,6 +2617,7 @@ where
                 .by_sequence_root
                 .scan(
                     range,
+                    &Bytewise,
                     &mut ScanArgs::new(
                         forwards,
                         |_, _, _| ScanEvaluation::ReadData,
@@ -1834,6 +2638,9 @@ where
 pub struct PagedWriter<'a> {
     file: &'a mut dyn File,
     vault: Option<&'a dyn AnyVault>,
+    /// The path encrypted chunks are bound to, if it differs from the path of
+    /// `file`.
+    bound_path: Option<PathBuf>,
     cache: Option<&'a ChunkCache>,
     position: u64,
     offset: usize,
@@ -1867,6 +2674,7 @@ impl<'a> PagedWriter<'a> {
         let mut writer = Self {
             file,
             vault,
+            bound_path: None,
             cache,
             position,
             offset: 0,
@@ -1877,7 +2685,7 @@ impl<'a> PagedWriter<'a> {
             #[allow(clippy::cast_possible_truncation)]
             let padding_needed = PAGE_SIZE - (writer.position % PAGE_SIZE as u64) as usize;
             let mut padding_and_header = Vec::new();
-            padding_and_header.resize(padding_needed + 4, header as u8);
+            padding_and_header.resize(padding_needed + 4, header as u8 | VAULT_DESCRIBED_HEADER);
             padding_and_header.splice(
                 padding_and_header.len() - 4..padding_and_header.len() - 1,
                 b"Nbr".iter().copied(),
@@ -1942,32 +2750,79 @@ impl<'a> PagedWriter<'a> {
     /// Returns the position that this chunk can be read from in the file.
     #[allow(clippy::cast_possible_truncation)]
     pub fn write_chunk(&mut self, contents: &[u8]) -> Result<u64, Error> {
-        let possibly_encrypted = self.vault.as_ref().map_or_else(
-            || Ok(Cow::Borrowed(contents)),
-            |vault| vault.encrypt(contents).map(Cow::Owned),
-        )?;
-        let length =
-            u32::try_from(possibly_encrypted.len()).map_err(|_| ErrorKind::ValueTooLarge)?;
-        let crc = CRC32.checksum(&possibly_encrypted);
         let position = self.current_position();
-
-        self.write_u32::<BigEndian>(length)?;
-        self.write_u32::<BigEndian>(crc)?;
-        self.write(&possibly_encrypted)?;
+        match self.vault {
+            Some(vault) => {
+                let sealed = seal(vault, contents, &self.location_binding(position))?;
+                self.write(&SEALED_CHUNK)?;
+                self.write_chunk_bytes(&sealed)?;
+            }
+            None => self.write_chunk_bytes(contents)?,
+        }
 
         if let (Some(cache), Some(file_id)) = (self.cache, self.file.id().id()) {
+            // The cache stores decrypted chunks.
             if cache.max_chunk_size() >= contents.len() {
-                cache.insert(
-                    file_id,
-                    position,
-                    ArcBytes::owned(possibly_encrypted.to_vec()),
-                );
+                cache.insert(file_id, position, ArcBytes::owned(contents.to_vec()));
             }
         }
 
         Ok(position)
     }
 
+    /// Writes a tree header chunk, which describes whether the tree is
+    /// encrypted and whether the file contains `plaintext_chunks`. Returns the
+    /// position that this chunk can be read from in the file.
+    fn write_header_chunk(
+        &mut self,
+        contents: &[u8],
+        plaintext_chunks: bool,
+    ) -> Result<u64, Error> {
+        let position = self.current_position();
+        let mut chunk = Vec::with_capacity(contents.len() + 1);
+        if let Some(vault) = self.vault {
+            let header = if plaintext_chunks {
+                PARTIALLY_ENCRYPTED_HEADER
+            } else {
+                ENCRYPTED_HEADER
+            };
+            let mut plaintext = Vec::with_capacity(VAULT_CHECK.len() + contents.len());
+            plaintext.extend_from_slice(VAULT_CHECK);
+            plaintext.extend_from_slice(contents);
+            // The header byte is authenticated so that chunks can't be
+            // reinterpreted as unencrypted.
+            let mut associated_data = self.location_binding(position);
+            associated_data.push(header);
+            chunk.push(header);
+            chunk.extend_from_slice(&seal(vault, &plaintext, &associated_data)?);
+        } else {
+            chunk.push(UNENCRYPTED_HEADER);
+            chunk.extend_from_slice(contents);
+        }
+        self.write_chunk_bytes(&chunk)?;
+        Ok(position)
+    }
+
+    /// Writes `bytes` as a chunk without processing it with the vault.
+    fn write_chunk_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let length = u32::try_from(bytes.len()).map_err(|_| ErrorKind::ValueTooLarge)?;
+        let crc = CRC32.checksum(bytes);
+
+        self.write_u32::<BigEndian>(length)?;
+        self.write_u32::<BigEndian>(crc)?;
+        self.write(bytes)?;
+        Ok(())
+    }
+
+    /// Returns the associated data for a chunk written at `position`.
+    fn location_binding(&self, position: u64) -> Vec<u8> {
+        let path = self
+            .bound_path
+            .as_deref()
+            .unwrap_or_else(|| self.file.id().path());
+        location_binding(path, position)
+    }
+
     /// Writes a chunk of data to the file, after possibly encrypting it.
     /// Returns the position that this chunk can be read from in the file.
     #[allow(clippy::cast_possible_truncation)]
@@ -2029,6 +2884,164 @@ impl<'a> PagedWriter<'a> {
     }
 }
 
+/// Returns the vault to use for a file whose header records whether it
+/// contains `plaintext_chunks`.
+fn effective_vault<Manager: FileManager>(
+    plaintext_chunks: bool,
+    context: &Context<Manager>,
+) -> Option<Arc<dyn AnyVault>> {
+    let vault = context.vault.clone()?;
+    if plaintext_chunks {
+        Some(Arc::new(PartiallyEncrypted(vault)))
+    } else {
+        Some(vault)
+    }
+}
+
+/// A vault for a file containing chunks written without a vault. Chunks that
+/// are not sealed are returned unchanged rather than being decrypted, and all
+/// other operations use the wrapped vault.
+#[derive(Debug)]
+struct PartiallyEncrypted(Arc<dyn AnyVault>);
+
+impl AnyVault for PartiallyEncrypted {
+    fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.0.encrypt(payload)
+    }
+
+    fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(payload.to_vec())
+    }
+
+    fn encrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        self.0
+            .encrypt_with_associated_data(payload, associated_data)
+    }
+
+    fn decrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        _associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        Ok(payload.to_vec())
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        self.0.key_id()
+    }
+
+    fn encrypt_with_key_id(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<(Option<u32>, Vec<u8>), Error> {
+        self.0.encrypt_with_key_id(payload, associated_data)
+    }
+
+    fn decrypt_with_key_id(
+        &self,
+        key_id: Option<u32>,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        self.0.decrypt_with_key_id(key_id, payload, associated_data)
+    }
+}
+
+/// Decodes a tree header chunk read from `position`, returning the serialized
+/// root and whether the file contains chunks written without a vault.
+fn decode_header<Manager: FileManager>(
+    contents: ArcBytes<'static>,
+    vault_described: bool,
+    context: &Context<Manager>,
+    path: &Path,
+    position: u64,
+) -> Result<(ArcBytes<'static>, bool), Error> {
+    if vault_described {
+        decode_vault_described_header(&contents, context.vault(), path, position)
+    } else {
+        decode_legacy_header(contents, context, path, position)
+    }
+}
+
+/// Decodes a header chunk that describes whether the tree is encrypted,
+/// returning the serialized root and whether the file contains chunks written
+/// without a vault.
+fn decode_vault_described_header(
+    contents: &ArcBytes<'static>,
+    vault: Option<&dyn AnyVault>,
+    path: &Path,
+    position: u64,
+) -> Result<(ArcBytes<'static>, bool), Error> {
+    let (header, encrypted) = match contents.split_first() {
+        Some((&UNENCRYPTED_HEADER, _)) => return Ok((contents.slice(1..), true)),
+        Some((&header, encrypted))
+            if header == ENCRYPTED_HEADER || header == PARTIALLY_ENCRYPTED_HEADER =>
+        {
+            (header, encrypted)
+        }
+        Some(_) | None => return Err(Error::data_integrity(ErrorKind::InvalidTreeHeader)),
+    };
+    let (key_id, _) = open_envelope(encrypted)?;
+    let written_with = key_id.map_or_else(
+        || String::from("a vault"),
+        |key_id| format!("a vault with key id {}", key_id),
+    );
+    let vault = vault.ok_or_else(|| ErrorKind::VaultMismatch {
+        path: path.to_path_buf(),
+        reason: format!(
+            "the tree was encrypted by {}, but no vault was provided",
+            written_with
+        ),
+    })?;
+    let mut associated_data = location_binding(path, position);
+    associated_data.push(header);
+    match unseal(vault, encrypted, &associated_data) {
+        Ok(decrypted) if decrypted.starts_with(VAULT_CHECK) => Ok((
+            ArcBytes::from(decrypted).slice(VAULT_CHECK.len()..),
+            header == PARTIALLY_ENCRYPTED_HEADER,
+        )),
+        _ => Err(Error::from(ErrorKind::VaultMismatch {
+            path: path.to_path_buf(),
+            reason: format!(
+                "the tree was encrypted by {}, which the provided vault cannot decrypt",
+                written_with
+            ),
+        })),
+    }
+}
+
+/// Decodes a header chunk written before headers described whether the tree
+/// was encrypted. Returns the serialized root and whether the file contains
+/// chunks written without a vault.
+///
+/// These headers do not record whether the tree was encrypted. When `context`
+/// has a vault, the tree is assumed to have been written without a vault only
+/// if `context` [allows unencrypted data](Context::allow_unencrypted_data).
+fn decode_legacy_header<Manager: FileManager>(
+    contents: ArcBytes<'static>,
+    context: &Context<Manager>,
+    path: &Path,
+    position: u64,
+) -> Result<(ArcBytes<'static>, bool), Error> {
+    match context.vault() {
+        Some(vault) if !context.allow_unencrypted_data => {
+            match vault.decrypt_with_associated_data(&contents, &location_binding(path, position)) {
+                Ok(decrypted) => Ok((ArcBytes::from(decrypted), false)),
+                Err(err) => Err(Error::from(ErrorKind::VaultMismatch {
+                    path: path.to_path_buf(),
+                    reason: format!("the vault could not decrypt the tree's header: {}", err),
+                })),
+            }
+        }
+        _ => Ok((contents, true)),
+    }
+}
+
 #[allow(clippy::cast_possible_truncation)]
 #[cfg_attr(feature = "tracing", tracing::instrument(skip(file, vault, cache)))]
 fn read_chunk(
@@ -2044,31 +3057,48 @@ fn read_chunk(
         }
     }
 
-    // Read the chunk header
     let mut header = [0_u8; 8];
-    file.seek(SeekFrom::Start(position))?;
-    file.read_exact(&mut header)?;
-    let length = BigEndian::read_u32(&header[0..4]) as usize;
-
-    let mut scratch = Vec::new();
-    scratch.resize(length, 0);
-    file.read_exact(&mut scratch)?;
+    let (contents, sealed) = read_chunk_contents(position, file, &mut header)
+        .map_err(|err| err.with_path(file.id()).with_position(position))?;
 
     if validate_crc {
         let crc = BigEndian::read_u32(&header[4..8]);
-        let computed_crc = CRC32.checksum(&scratch);
+        let computed_crc = CRC32.checksum(&contents);
         if crc != computed_crc {
-            return Err(Error::data_integrity(format!(
-                "crc32 failure on chunk at position {}",
-                position
-            )));
+            return Err(Error::data_integrity(ErrorKind::ChecksumMismatch {
+                stored: crc,
+                computed: computed_crc,
+            })
+            .with_path(file.id())
+            .with_position(position));
         }
     }
 
-    let decrypted = ArcBytes::from(match vault {
-        Some(vault) => vault.decrypt(&scratch)?,
-        None => scratch,
-    });
+    let decrypted = match (vault, sealed) {
+        (Some(vault), true) => ArcBytes::from(
+            unseal(
+                vault,
+                &contents,
+                &location_binding(file.id().path(), position),
+            )
+            .map_err(|err| err.with_path(file.id()).with_position(position))?,
+        ),
+        // Written before chunks were sealed.
+        (Some(vault), false) => ArcBytes::from(
+            vault
+                .decrypt_with_associated_data(
+                    &contents,
+                    &location_binding(file.id().path(), position),
+                )
+                .map_err(|err| err.with_path(file.id()).with_position(position))?,
+        ),
+        (None, true) => {
+            return Err(Error::from(ErrorKind::VaultNotConfigured)
+                .with_path(file.id())
+                .with_position(position))
+        }
+        (None, false) => contents,
+    };
 
     if let (Some(cache), Some(file_id)) = (cache, file.id().id()) {
         cache.insert(file_id, position, decrypted.clone());
@@ -2077,6 +3107,61 @@ fn read_chunk(
     Ok(CacheEntry::ArcBytes(decrypted))
 }
 
+/// Reads the header and contents of the chunk at `position`, returning the
+/// contents and whether they are a sealed envelope. `header` is filled with the
+/// header of the chunk's contents.
+fn read_chunk_contents(
+    position: u64,
+    file: &mut dyn File,
+    header: &mut [u8; 8],
+) -> Result<(ArcBytes<'static>, bool), Error> {
+    read_chunk_header(position, file, header)?;
+    let (contents_position, sealed) = if *header == SEALED_CHUNK {
+        read_chunk_header(position + 8, file, header)?;
+        (position + 16, true)
+    } else {
+        (position + 8, false)
+    };
+    let length = BigEndian::read_u32(&header[0..4]) as usize;
+    let contents = if let Some(contents) = file.read_bytes_at(contents_position, length) {
+        contents?
+    } else {
+        file.seek(SeekFrom::Start(contents_position))?;
+        let mut scratch = Vec::new();
+        scratch.resize(length, 0);
+        file.read_exact(&mut scratch)?;
+        ArcBytes::from(scratch)
+    };
+    if contents.len() == length {
+        Ok((contents, sealed))
+    } else {
+        Err(Error::data_integrity(ErrorKind::Internal(
+            InternalError::IncorrectByteLength,
+        )))
+    }
+}
+
+/// Reads the 8-byte header of a chunk at `position` into `header`.
+fn read_chunk_header(
+    position: u64,
+    file: &mut dyn File,
+    header: &mut [u8; 8],
+) -> Result<(), Error> {
+    if let Some(header_bytes) = file.read_bytes_at(position, header.len()) {
+        let header_bytes = header_bytes?;
+        if header_bytes.len() != header.len() {
+            return Err(Error::data_integrity(ErrorKind::Internal(
+                InternalError::IncorrectByteLength,
+            )));
+        }
+        header.copy_from_slice(&header_bytes);
+    } else {
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(header)?;
+    }
+    Ok(())
+}
+
 /// Returns a value for the "order" (maximum children per node) value for the
 /// database. This function is meant to keep the tree shallow while still
 /// keeping the nodes smaller along the way. This is an approximation that
@@ -2330,8 +3415,9 @@ pub enum ChangeResult {
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::{BTreeMap, HashSet},
+        collections::{BTreeMap, BTreeSet, HashSet},
         convert::Infallible,
+        io::Write,
         path::Path,
     };
 
@@ -2343,7 +3429,7 @@ mod tests {
         any::AnyFileManager,
         fs::{StdFile, StdFileManager},
         memory::{MemoryFile, MemoryFileManager, MemoryFileOpener},
-        ManagedFileOpener,
+        ManagedFileOpener, PositionedFile,
     };
 
     fn test_paged_write(offset: usize, length: usize) -> Result<(), Error> {
@@ -2396,6 +3482,223 @@ mod tests {
         }
     }
 
+    #[test]
+    fn memory_file_shares_reads() {
+        let mut file = MemoryFileOpener
+            .open_for_append("memory-file-shares-reads")
+            .unwrap();
+        let contents = (0..u8::MAX).cycle().take(200_000).collect::<Vec<_>>();
+        file.write_all(&contents).unwrap();
+
+        // Reads within a segment share it, while reads spanning segments copy.
+        let first = file.read_bytes_at(10, 100).unwrap().unwrap();
+        let second = file.read_bytes_at(10, 100).unwrap().unwrap();
+        assert_eq!(first, &contents[10..110]);
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        let spanning = file.read_bytes_at(65_000, 2_000).unwrap().unwrap();
+        assert_eq!(spanning, &contents[65_000..67_000]);
+        assert!(file.read_bytes_at(199_999, 2).unwrap().is_err());
+
+        // Overwriting a shared segment leaves previous reads unchanged.
+        file.write_at(&[0; 100], 10).unwrap();
+        assert_eq!(first, &contents[10..110]);
+        assert_eq!(file.read_bytes_at(10, 100).unwrap().unwrap(), &[0; 100][..]);
+        file.write_at(b"gap", 300_000).unwrap();
+        assert_eq!(file.length().unwrap(), 300_003);
+        let mut gap = [1; 4];
+        file.read_at(&mut gap, 199_999).unwrap();
+        assert_eq!(gap, [contents[199_999], 0, 0, 0]);
+    }
+
+    /// A file that returns one byte fewer than requested from
+    /// `read_bytes_at()`.
+    #[derive(Debug)]
+    struct ShortReads(MemoryFile);
+
+    impl File for ShortReads {
+        fn id(&self) -> &PathId {
+            self.0.id()
+        }
+
+        fn length(&self) -> Result<u64, Error> {
+            self.0.length()
+        }
+
+        fn synchronize(&mut self) -> Result<(), Error> {
+            self.0.synchronize()
+        }
+
+        fn close(self) -> Result<(), Error> {
+            self.0.close()
+        }
+
+        fn read_bytes_at(
+            &mut self,
+            position: u64,
+            length: usize,
+        ) -> Option<Result<ArcBytes<'static>, Error>> {
+            self.0
+                .read_bytes_at(position, length)
+                .map(|bytes| bytes.map(|bytes| bytes.slice(1..)))
+        }
+    }
+
+    impl std::io::Seek for ShortReads {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl std::io::Read for ShortReads {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl std::io::Write for ShortReads {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn read_chunk_checks_lengths() {
+        let mut file = MemoryFileOpener
+            .open_for_append("read-chunk-checks-lengths")
+            .unwrap();
+        let mut paged_writer =
+            PagedWriter::new(Some(PageHeader::VersionedHeader), &mut file, None, None, 0).unwrap();
+        let position = paged_writer.write_chunk(b"chunk").unwrap();
+        drop(paged_writer.finish());
+
+        match read_chunk(position, true, &mut file, None, None).unwrap() {
+            CacheEntry::ArcBytes(data) => assert_eq!(data, b"chunk"),
+            CacheEntry::Decoded(_) => unreachable!(),
+        }
+        let err = read_chunk(position, true, &mut ShortReads(file), None, None).unwrap_err();
+        assert!(matches!(
+            &err.kind,
+            ErrorKind::DataIntegrity(err)
+                if matches!(err.kind, ErrorKind::Internal(InternalError::IncorrectByteLength))
+        ));
+        assert_eq!(err.position(), Some(position));
+    }
+
+    #[test]
+    fn sealed_chunks() {
+        // An empty chunk's CRC is 0, so the marker can't be a chunk header.
+        assert_ne!(CRC32.checksum(&[]), 0xFFFF_FFFF);
+
+        let vault = crate::test_util::RotatorVault::new(13).with_key_id(7);
+        let mut file = MemoryFileOpener.open_for_append("sealed-chunks").unwrap();
+        let mut paged_writer = PagedWriter::new(
+            Some(PageHeader::VersionedHeader),
+            &mut file,
+            Some(&vault),
+            None,
+            0,
+        )
+        .unwrap();
+        let position = paged_writer.write_chunk(b"chunk").unwrap();
+        drop(paged_writer.finish());
+
+        match read_chunk(position, true, &mut file, Some(&vault), None).unwrap() {
+            CacheEntry::ArcBytes(data) => assert_eq!(data, b"chunk"),
+            CacheEntry::Decoded(_) => unreachable!(),
+        }
+        // A vault with another key id can't decrypt the chunk, and sealed
+        // chunks are never returned without a vault.
+        let other_vault = crate::test_util::RotatorVault::new(13).with_key_id(8);
+        assert!(read_chunk(position, true, &mut file, Some(&other_vault), None).is_err());
+        assert!(matches!(
+            read_chunk(position, true, &mut file, None, None)
+                .unwrap_err()
+                .kind,
+            ErrorKind::VaultNotConfigured
+        ));
+    }
+
+    #[test]
+    fn written_chunks_are_cached_decrypted() {
+        let vault = crate::test_util::RotatorVault::new(13);
+        let cache = ChunkCache::with_byte_capacity(1 << 20, 1024);
+        let path = MemoryFileManager::default()
+            .resolve_path("written-chunks-are-cached-decrypted", true)
+            .unwrap();
+        let file_id = path.id().unwrap();
+        let mut file = MemoryFileOpener.open_for_append(path).unwrap();
+        let mut paged_writer = PagedWriter::new(
+            Some(PageHeader::VersionedHeader),
+            &mut file,
+            Some(&vault),
+            Some(&cache),
+            0,
+        )
+        .unwrap();
+        let position = paged_writer.write_chunk(b"chunk").unwrap();
+        drop(paged_writer.finish());
+
+        // The cache holds the decrypted contents, matching what is read from
+        // the file.
+        match cache.get(file_id, position) {
+            Some(CacheEntry::ArcBytes(cached)) => assert_eq!(cached, b"chunk"),
+            other => unreachable!("chunk not cached: {:?}", other),
+        }
+        match read_chunk(position, true, &mut file, Some(&vault), None).unwrap() {
+            CacheEntry::ArcBytes(data) => assert_eq!(data, b"chunk"),
+            CacheEntry::Decoded(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn legacy_headers() {
+        let vault = crate::test_util::RotatorVault::new(13);
+        let path = Path::new("legacy.nebari");
+        let binding = location_binding(path, 4);
+        let encrypted = ArcBytes::from(
+            vault
+                .encrypt_with_associated_data(b"root", &binding)
+                .unwrap(),
+        );
+        let plaintext = ArcBytes::from(b"root".to_vec());
+        let mut context = Context {
+            file_manager: MemoryFileManager::default(),
+            vault: None,
+            cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
+        };
+
+        // Without a vault, legacy headers are never encrypted.
+        let (root, plaintext_chunks) =
+            decode_header(plaintext.clone(), false, &context, path, 4).unwrap();
+        assert_eq!(root, b"root");
+        assert!(plaintext_chunks);
+
+        // With a vault, legacy headers are always decrypted unless unencrypted
+        // data is allowed.
+        context = context.with_vault(vault);
+        let (root, plaintext_chunks) =
+            decode_header(encrypted.clone(), false, &context, path, 4).unwrap();
+        assert_eq!(root, b"root");
+        assert!(!plaintext_chunks);
+        assert!(matches!(
+            decode_header(plaintext.clone(), false, &context, path, 4)
+                .unwrap_err()
+                .kind,
+            ErrorKind::VaultMismatch { .. }
+        ));
+
+        context = context.allow_unencrypted_data();
+        let (root, plaintext_chunks) = decode_header(plaintext, false, &context, path, 4).unwrap();
+        assert_eq!(root, b"root");
+        assert!(plaintext_chunks);
+    }
+
     fn insert_one_record<R: Root<Value = ArcBytes<'static>> + Default, F: ManagedFile>(
         context: &Context<F::Manager>,
         file_path: &Path,
@@ -2489,6 +3792,8 @@ mod tests {
             file_manager: StdFileManager::default(),
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new("btree-tests");
         std::fs::create_dir(&temp_dir).unwrap();
@@ -2546,6 +3851,8 @@ mod tests {
             file_manager: StdFileManager::default(),
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new(format!("btree-removals-{}", label));
         std::fs::create_dir(&temp_dir).unwrap();
@@ -2584,6 +3891,564 @@ mod tests {
         remove::<Unversioned>("unversioned");
     }
 
+    /// A temporary directory containing a single tree file.
+    struct TestTree {
+        context: Context<StdFileManager>,
+        file_path: PathBuf,
+        _directory: crate::test_util::TestDirectory,
+    }
+
+    impl TestTree {
+        fn new(label: &str) -> Self {
+            let directory = crate::test_util::TestDirectory::new(format!("btree-{}", label));
+            std::fs::create_dir(&directory).unwrap();
+            Self {
+                context: Context {
+                    file_manager: StdFileManager::default(),
+                    vault: None,
+                    cache: None,
+                    metrics: None,
+                    allow_unencrypted_data: false,
+                },
+                file_path: directory.join("tree"),
+                _directory: directory,
+            }
+        }
+
+        fn open<R: Root<Value = ArcBytes<'static>>>(
+            &self,
+            root: R,
+            order: Option<usize>,
+        ) -> TreeFile<R, StdFile> {
+            TreeFile::write(
+                &self.file_path,
+                State::new(None, order, root),
+                &self.context,
+                None,
+            )
+            .unwrap()
+        }
+    }
+
+    /// Opens a new tree using the default root in a temporary directory
+    /// identified by `label`.
+    fn open_test_tree<R: Root<Value = ArcBytes<'static>> + Default>(
+        label: &str,
+    ) -> (TestTree, TreeFile<R, StdFile>) {
+        let test_tree = TestTree::new(label);
+        let tree = test_tree.open(R::default(), None);
+        (test_tree, tree)
+    }
+
+    fn random_bound(rng: &mut Pcg64, key: u16) -> Bound<u16> {
+        match rng.generate_range(0_u8..3) {
+            0 => Bound::Included(key),
+            1 => Bound::Excluded(key),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    fn byte_bound(bound: Bound<u16>, bytes: &[u8; 2]) -> Bound<&[u8]> {
+        match bound {
+            Bound::Included(_) => Bound::Included(bytes),
+            Bound::Excluded(_) => Bound::Excluded(bytes),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+    }
+
+    fn remove_range<R: Root<Value = ArcBytes<'static>> + Default>(label: &str) {
+        const ORDER: usize = 4;
+
+        let mut seed_rng = Pcg64::new();
+        let seed = seed_rng.generate();
+        println!("Seeding range removal {} with {}", label, seed);
+        let mut rng = Pcg64::new_seed(seed);
+        let test_tree = TestTree::new(&format!("range-removals-{}", label));
+        let mut tree = test_tree.open(R::default(), Some(ORDER));
+
+        let mut expected = BTreeSet::new();
+        for _ in 0..20 {
+            // Insert a batch of keys, then remove a random range of keys.
+            let new_keys = (0..200)
+                .map(|_| rng.generate_range(0_u16..1000))
+                .collect::<BTreeSet<_>>();
+            tree.modify(Modification {
+                persistence_mode: PersistenceMode::Sync,
+                keys: new_keys
+                    .iter()
+                    .map(|key| ArcBytes::from(key.to_be_bytes().to_vec()))
+                    .collect(),
+                operation: Operation::Set(ArcBytes::from(b"value")),
+            })
+            .unwrap();
+            expected.extend(new_keys);
+
+            let start = rng.generate_range(0_u16..1000);
+            let end = rng.generate_range(start..1000);
+            let range = (random_bound(&mut rng, start), random_bound(&mut rng, end));
+            let removed_keys = expected
+                .iter()
+                .copied()
+                .filter(|key| range.contains(key))
+                .collect::<Vec<_>>();
+            for key in &removed_keys {
+                expected.remove(key);
+            }
+
+            let (start, end) = (start.to_be_bytes(), end.to_be_bytes());
+            let range = (byte_bound(range.0, &start), byte_bound(range.1, &end));
+            assert_eq!(
+                tree.remove_range(&range, PersistenceMode::Sync).unwrap(),
+                removed_keys.len() as u64
+            );
+            let stored = tree
+                .get_range(&(..), false)
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| u16::from_be_bytes(key.as_slice().try_into().unwrap()))
+                .collect::<Vec<_>>();
+            assert_eq!(stored, expected.iter().copied().collect::<Vec<_>>());
+        }
+
+        // Reopen the tree to verify the removals were saved.
+        drop(tree);
+        let mut tree = test_tree.open(R::default(), Some(ORDER));
+        assert_eq!(tree.get_range(&(..), false).unwrap().len(), expected.len());
+        assert_eq!(
+            tree.remove_range(&(..), None).unwrap(),
+            expected.len() as u64
+        );
+        assert!(tree.first(false).unwrap().is_none());
+
+        assert!(matches!(
+            tree.modify(Modification {
+                persistence_mode: PersistenceMode::Sync,
+                keys: vec![ArcBytes::from(b"a")],
+                operation: Operation::RemoveRange(Bound::Unbounded, Bound::Unbounded),
+            }),
+            Err(Error {
+                kind: ErrorKind::RemoveRangeWithKeys,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn remove_range_versioned() {
+        remove_range::<Versioned>("versioned");
+    }
+
+    #[test]
+    fn remove_range_unversioned() {
+        remove_range::<Unversioned>("unversioned");
+    }
+
+    fn write_batch<R: Root<Value = ArcBytes<'static>> + Default>(label: &str) {
+        let (test_tree, mut tree) = open_test_tree::<R>(&format!("batch-{}", label));
+        for key in [b"a", b"b", b"c"] {
+            tree.set(PersistenceMode::Sync, &key[..], &key[..]).unwrap();
+        }
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set(b"z", b"z")
+            .remove(b"a")
+            .remove(b"missing")
+            .compare_and_swap(b"b", Some(ArcBytes::from(b"b")), Some(ArcBytes::from(b"B")))
+            .compare_and_swap(b"c", Some(ArcBytes::from(b"wrong")), None)
+            .compare_and_swap(b"d", None, Some(ArcBytes::from(b"d")))
+            .set(b"e", b"first")
+            .set(b"e", b"last");
+        assert_eq!(batch.len(), 8);
+        let conflicts = tree.apply_batch(batch, PersistenceMode::Sync).unwrap();
+        assert_eq!(
+            conflicts,
+            vec![BatchConflict {
+                key: ArcBytes::from(b"c"),
+                value: Some(ArcBytes::from(b"c")),
+            }]
+        );
+
+        let expected = vec![
+            (ArcBytes::from(b"b"), ArcBytes::from(b"B")),
+            (ArcBytes::from(b"c"), ArcBytes::from(b"c")),
+            (ArcBytes::from(b"d"), ArcBytes::from(b"d")),
+            (ArcBytes::from(b"e"), ArcBytes::from(b"last")),
+            (ArcBytes::from(b"z"), ArcBytes::from(b"z")),
+        ];
+        assert_eq!(tree.get_range(&(..), false).unwrap(), expected);
+
+        // Reopen the tree to verify the batch was saved.
+        drop(tree);
+        let mut tree = test_tree.open(R::default(), None);
+        assert_eq!(tree.get_range(&(..), false).unwrap(), expected);
+    }
+
+    #[test]
+    fn write_batch_versioned() {
+        write_batch::<Versioned>("versioned");
+    }
+
+    #[test]
+    fn write_batch_unversioned() {
+        write_batch::<Unversioned>("unversioned");
+    }
+
+    fn merges<R: Root<Value = ArcBytes<'static>> + Default>(label: &str) {
+        let test_tree = TestTree::new(&format!("merges-{}", label));
+        let mut root = R::default();
+        root.set_merge_operator(Some(Arc::new(Append)));
+        let mut tree = test_tree.open(root, None);
+
+        tree.set(PersistenceMode::Sync, b"a", b"a").unwrap();
+        tree.modify(Modification {
+            persistence_mode: PersistenceMode::Sync,
+            keys: vec![ArcBytes::from(b"a"), ArcBytes::from(b"b")],
+            operation: Operation::Merge(ArcBytes::from(b"+")),
+        })
+        .unwrap();
+        assert!(tree
+            .merge(PersistenceMode::Sync, b"b", b"+")
+            .unwrap()
+            .is_some());
+        assert_eq!(tree.get(b"a", false).unwrap().unwrap(), b"a+");
+        assert_eq!(tree.get(b"b", false).unwrap().unwrap(), b"++");
+
+        let mut batch = WriteBatch::new();
+        batch
+            .merge(b"a", b"1")
+            .merge(b"a", b"2")
+            .set(b"b", b"b")
+            .merge(b"b", b"1")
+            .remove(b"c")
+            .merge(b"c", b"c")
+            .compare_and_swap(b"d", None, Some(ArcBytes::from(b"d")))
+            .merge(b"d", b"1");
+        assert!(tree
+            .apply_batch(batch, PersistenceMode::Sync)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            tree.get_range(&(..), false).unwrap(),
+            vec![
+                (ArcBytes::from(b"a"), ArcBytes::from(b"a+12")),
+                (ArcBytes::from(b"b"), ArcBytes::from(b"b1")),
+                (ArcBytes::from(b"c"), ArcBytes::from(b"c")),
+                (ArcBytes::from(b"d"), ArcBytes::from(b"d1")),
+            ]
+        );
+
+        // Without a merge operator, merges are rejected.
+        drop(tree);
+        let mut tree = test_tree.open(R::default(), None);
+        assert!(matches!(
+            tree.merge(PersistenceMode::Sync, b"a", b"+"),
+            Err(Error {
+                kind: ErrorKind::MergeOperatorNotConfigured,
+                ..
+            })
+        ));
+        let mut batch = WriteBatch::new();
+        batch.set(b"e", b"e").merge(b"a", b"+");
+        assert!(matches!(
+            tree.apply_batch(batch, PersistenceMode::Sync),
+            Err(Error {
+                kind: ErrorKind::MergeOperatorNotConfigured,
+                ..
+            })
+        ));
+        assert_eq!(tree.get(b"a", false).unwrap().unwrap(), b"a+12");
+        assert!(tree.get(b"e", false).unwrap().is_none());
+    }
+
+    #[test]
+    fn merges_versioned() {
+        merges::<Versioned>("versioned");
+    }
+
+    #[test]
+    fn merges_unversioned() {
+        merges::<Unversioned>("unversioned");
+    }
+
+    fn expirations<R: Root<Value = ArcBytes<'static>> + Default>(label: &str) {
+        const HOUR: Duration = Duration::from_secs(60 * 60);
+        let (test_tree, mut tree) = open_test_tree::<R>(&format!("expirations-{}", label));
+
+        let expired = tree
+            .set_with_ttl(PersistenceMode::Sync, b"a", b"a", Duration::ZERO)
+            .unwrap();
+        assert!(expired.expiration().is_some());
+        let alive = tree
+            .set_with_ttl(PersistenceMode::Sync, b"b", b"b", HOUR)
+            .unwrap();
+        let b_expiration = alive.expiration().unwrap();
+        assert!(!b_expiration.has_passed(Expiration::now()));
+        tree.set(PersistenceMode::Sync, b"c", b"c").unwrap();
+
+        // Expired values are ignored by reads and compare-and-swaps.
+        assert!(tree.get(b"a", false).unwrap().is_none());
+        assert_eq!(
+            tree.get_multiple([&b"a"[..], &b"b"[..]], false).unwrap(),
+            vec![(ArcBytes::from(b"b"), ArcBytes::from(b"b"))]
+        );
+        assert_eq!(
+            tree.get_range(&(..), false).unwrap(),
+            vec![
+                (ArcBytes::from(b"b"), ArcBytes::from(b"b")),
+                (ArcBytes::from(b"c"), ArcBytes::from(b"c")),
+            ]
+        );
+        assert_eq!(tree.first_key(false).unwrap().unwrap(), b"b");
+        assert_eq!(
+            tree.first(false).unwrap(),
+            Some((ArcBytes::from(b"b"), ArcBytes::from(b"b")))
+        );
+        assert!(tree
+            .replace(b"a", b"new-a", PersistenceMode::Sync)
+            .unwrap()
+            .0
+            .is_none());
+        // Writing without an expiration clears the expiration.
+        assert_eq!(tree.get(b"a", false).unwrap().unwrap(), b"new-a");
+        assert!(tree
+            .get_index(b"a", false)
+            .unwrap()
+            .unwrap()
+            .expiration()
+            .is_none());
+
+        // Expirations are persisted.
+        tree.set_with_ttl(PersistenceMode::Sync, b"d", b"d", Duration::ZERO)
+            .unwrap();
+        drop(tree);
+        let mut tree = test_tree.open(R::default(), None);
+        assert_eq!(
+            tree.get_index(b"b", false).unwrap().unwrap().expiration(),
+            Some(b_expiration)
+        );
+        assert!(tree.get(b"d", false).unwrap().is_none());
+        assert_eq!(tree.last_key(false).unwrap().unwrap(), b"c");
+        assert_eq!(
+            tree.last(false).unwrap(),
+            Some((ArcBytes::from(b"c"), ArcBytes::from(b"c")))
+        );
+        assert!(tree
+            .reduce(&(..), false)
+            .unwrap()
+            .unwrap()
+            .earliest_expiration()
+            .unwrap()
+            .has_passed(Expiration::now()));
+
+        assert_eq!(tree.remove_expired(PersistenceMode::Sync).unwrap(), 1);
+        assert_eq!(tree.remove_expired(PersistenceMode::Sync).unwrap(), 0);
+        assert_eq!(
+            tree.reduce(&(..), false)
+                .unwrap()
+                .unwrap()
+                .earliest_expiration(),
+            Some(b_expiration)
+        );
+
+        // Compaction removes expired keys before rewriting the file.
+        tree.set_with_ttl(PersistenceMode::Sync, b"e", b"e", Duration::ZERO)
+            .unwrap();
+        let mut tree = tree.compact(&test_tree.context.file_manager, None).unwrap();
+        assert!(tree
+            .get_index(b"e", false)
+            .unwrap()
+            .map_or(true, |index| index.position() == 0));
+        assert_eq!(
+            tree.get_range(&(..), false).unwrap(),
+            vec![
+                (ArcBytes::from(b"a"), ArcBytes::from(b"new-a")),
+                (ArcBytes::from(b"b"), ArcBytes::from(b"b")),
+                (ArcBytes::from(b"c"), ArcBytes::from(b"c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn expirations_versioned() {
+        expirations::<Versioned>("versioned");
+    }
+
+    #[test]
+    fn expirations_unversioned() {
+        expirations::<Unversioned>("unversioned");
+    }
+
+    /// Returns the key used for `id` by [`write_statistics_keys()`].
+    fn statistics_key(id: u32) -> ArcBytes<'static> {
+        ArcBytes::from(id.to_be_bytes().to_vec())
+    }
+
+    /// Writes enough keys to require interior nodes, and removes every seventh
+    /// key. Returns the ids of the keys that remain, in order.
+    fn write_statistics_keys<R: Root<Value = ArcBytes<'static>>>(
+        tree: &mut TreeFile<R, StdFile>,
+    ) -> Vec<u32> {
+        tree.modify(Modification {
+            persistence_mode: PersistenceMode::Sync,
+            keys: (0..5_000).map(statistics_key).collect(),
+            operation: Operation::Set(ArcBytes::from(b"value")),
+        })
+        .unwrap();
+        tree.modify(Modification {
+            persistence_mode: PersistenceMode::Sync,
+            keys: (0..5_000).step_by(7).map(statistics_key).collect(),
+            operation: Operation::Remove,
+        })
+        .unwrap();
+        (0..5_000_u32).filter(|id| id % 7 != 0).collect()
+    }
+
+    fn order_statistics<R: Root<Value = ArcBytes<'static>> + Default>(label: &str) {
+        let (_test_tree, mut tree) = open_test_tree::<R>(&format!("order-statistics-{}", label));
+        let alive = write_statistics_keys(&mut tree);
+        let key = statistics_key;
+
+        assert_eq!(tree.count_range(&(..), false).unwrap(), 4_285);
+        for (start, end) in [
+            (0, 5_000),
+            (1, 4_999),
+            (700, 701),
+            (1_234, 3_456),
+            (4_000, 10_000),
+        ] {
+            let expected = alive.iter().filter(|&&id| id >= start && id < end).count();
+            assert_eq!(
+                tree.count_range(&(&key(start)[..]..&key(end)[..]), false)
+                    .unwrap(),
+                expected as u64
+            );
+            let expected = alive.iter().filter(|&&id| id > start && id <= end).count();
+            assert_eq!(
+                tree.count_range(
+                    &(
+                        Bound::Excluded(&key(start)[..]),
+                        Bound::Included(&key(end)[..])
+                    ),
+                    false
+                )
+                .unwrap(),
+                expected as u64
+            );
+        }
+
+        for rank in [0, 1, 6, 999, 2_500, 4_284] {
+            let id = alive[rank];
+            assert_eq!(tree.nth(rank as u64, false).unwrap().unwrap(), key(id));
+            assert_eq!(tree.rank(&key(id), false).unwrap(), rank as u64);
+        }
+        assert!(tree.nth(4_285, false).unwrap().is_none());
+        // Removed keys have the rank they would have if they were present.
+        assert_eq!(tree.rank(&key(7), false).unwrap(), 6);
+        assert_eq!(tree.rank(&key(10_000), false).unwrap(), 4_285);
+
+        let estimate = tree.estimate_range(&(..), false).unwrap();
+        assert_eq!(estimate.keys, 4_285);
+        assert_eq!(estimate.value_bytes, 4_285 * 5);
+        let estimate = tree
+            .estimate_range(&(&key(1_000)[..]..&key(2_000)[..]), false)
+            .unwrap();
+        assert_eq!(estimate.keys, 857);
+        assert_eq!(estimate.value_bytes, 857 * 5);
+    }
+
+    #[test]
+    fn order_statistics_versioned() {
+        order_statistics::<Versioned>("versioned");
+    }
+
+    #[test]
+    fn order_statistics_unversioned() {
+        order_statistics::<Unversioned>("unversioned");
+    }
+
+    #[cfg(feature = "sampling")]
+    fn sampling<R: Root<Value = ArcBytes<'static>> + Default>(label: &str) {
+        let (_test_tree, mut tree) = open_test_tree::<R>(&format!("sampling-{}", label));
+        let alive = write_statistics_keys(&mut tree);
+        let key = statistics_key;
+        let id = |key: &[u8]| u32::from_be_bytes(key.try_into().unwrap());
+
+        // Samples are distinct, alive, ordered, and within the range.
+        let range = (
+            Bound::Excluded(&key(1_000)[..]),
+            Bound::Included(&key(2_000)[..]),
+        );
+        let in_range = alive
+            .iter()
+            .copied()
+            .filter(|&id| id > 1_000 && id <= 2_000)
+            .collect::<Vec<_>>();
+        let mut times_chosen = HashMap::<u32, usize>::new();
+        for _ in 0..1_000 {
+            let sample = tree.sample(20, &range, false).unwrap();
+            assert_eq!(sample.len(), 20);
+            let mut previous = 1_000;
+            for (key, value) in sample {
+                let id = id(&key);
+                assert!(id > previous && id <= 2_000 && id % 7 != 0);
+                assert_eq!(value, b"value");
+                *times_chosen.entry(id).or_default() += 1;
+                previous = id;
+            }
+        }
+        // Each key is expected to be chosen 20,000 / 857, or about 23 times, so
+        // every key should have been chosen at least once.
+        assert_eq!(times_chosen.len(), in_range.len());
+        // Each tenth of the range contains a tenth of its keys, so each should
+        // contain about 2,000 of the 20,000 sampled keys. The standard
+        // deviation is about 42, so these bounds are never exceeded in
+        // practice.
+        for bucket in in_range.chunks(in_range.len() / 10 + 1) {
+            let expected = 20_000 * bucket.len() / in_range.len();
+            let chosen = bucket.iter().map(|id| times_chosen[id]).sum::<usize>();
+            assert!(
+                chosen > expected * 85 / 100 && chosen < expected * 115 / 100,
+                "{} of {} expected",
+                chosen,
+                expected
+            );
+        }
+
+        // Choosing many of the keys in a range often chooses ranks that have
+        // already been chosen, but still produces distinct entries.
+        for count in [4_284, 4_000, 2_143] {
+            let sample = tree.sample(count, &(..), false).unwrap();
+            let ids = sample.iter().map(|(key, _)| id(key)).collect::<Vec<_>>();
+            assert_eq!(ids.len(), count);
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(ids.iter().all(|id| id % 7 != 0 && *id < 5_000));
+        }
+
+        // Ranges containing too few keys return all of their entries.
+        let all = tree
+            .sample(1_000, &(&key(4_000)[..]..), false)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| id(&key))
+            .collect::<Vec<_>>();
+        let expected = (4_000..5_000).filter(|id| id % 7 != 0).collect::<Vec<_>>();
+        assert_eq!(all, expected);
+        assert!(tree.sample(0, &(..), false).unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "sampling")]
+    fn sampling_versioned() {
+        sampling::<Versioned>("versioned");
+    }
+
+    #[test]
+    #[cfg(feature = "sampling")]
+    fn sampling_unversioned() {
+        sampling::<Unversioned>("unversioned");
+    }
+
     #[test]
     fn spam_insert_std_versioned() {
         spam_insert::<Versioned, StdFile>("std-versioned");
@@ -2601,7 +4466,9 @@ mod tests {
         let context = Context {
             file_manager: F::Manager::default(),
             vault: None,
-            cache: Some(ChunkCache::new(100, 160_384)),
+            cache: Some(ChunkCache::with_byte_capacity(16 * 1024 * 1024, 160_384)),
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new(format!("spam-inserts-{}", name));
         std::fs::create_dir(&temp_dir).unwrap();
@@ -2657,7 +4524,9 @@ mod tests {
         let context = Context {
             file_manager,
             vault: None,
-            cache: Some(ChunkCache::new(100, 160_384)),
+            cache: Some(ChunkCache::with_byte_capacity(16 * 1024 * 1024, 160_384)),
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new(format!("bulk-inserts-{}", name));
         std::fs::create_dir(&temp_dir).unwrap();
@@ -2695,6 +4564,8 @@ mod tests {
             file_manager: MemoryFileManager::default(),
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let state = State::default();
         // let file = context.file_manager.append("test").unwrap();
@@ -2755,6 +4626,8 @@ mod tests {
             file_manager,
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new(format!("btree-compact-{}", label));
         std::fs::create_dir(&temp_dir).unwrap();
@@ -2821,6 +4694,8 @@ mod tests {
             file_manager: StdFileManager::default(),
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let state = State::default();
         let tempfile = NamedTempFile::new().unwrap();
@@ -2877,6 +4752,8 @@ mod tests {
             file_manager: MemoryFileManager::default(),
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new("header_incompatible");
         std::fs::create_dir(&temp_dir).unwrap();
@@ -2913,6 +4790,8 @@ mod tests {
             file_manager: MemoryFileManager::default(),
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new("page-header-edge-cases");
         std::fs::create_dir(&temp_dir).unwrap();
@@ -2949,6 +4828,8 @@ mod tests {
             file_manager,
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new(format!("edit-keys-{}", label));
         std::fs::create_dir(&temp_dir).unwrap();
@@ -3035,6 +4916,8 @@ mod tests {
             file_manager: StdFileManager::default(),
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new("reduce");
         std::fs::create_dir(&temp_dir).unwrap();
@@ -3096,6 +4979,8 @@ mod tests {
             file_manager,
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new(format!("first-last-{}", label));
         std::fs::create_dir(&temp_dir).unwrap();
@@ -3160,6 +5045,8 @@ mod tests {
             file_manager,
             vault: None,
             cache: None,
+            metrics: None,
+            allow_unencrypted_data: false,
         };
         let temp_dir = crate::test_util::TestDirectory::new(format!("bulk-swap-{}", label));
         std::fs::create_dir(&temp_dir).unwrap();
@@ -1,9 +1,10 @@
 use std::{
+    cmp::Ordering,
     fmt::Debug,
-    ops::{Deref, DerefMut},
+    ops::{Bound, Deref, DerefMut, RangeBounds},
 };
 
-use super::btree::KeyOperation;
+use super::{btree::KeyOperation, Expiration, KeyComparator};
 use crate::{error::Error, transaction::TransactionId, ArcBytes, ErrorKind};
 
 /// A tree modification.
@@ -19,15 +20,32 @@ pub struct Modification<'a, T, Index> {
 
 impl<'a, T, Index> Modification<'a, T, Index> {
     /// Prepares this modification for efficient operation, and ensures that the
-    /// keys are properly ordered.
+    /// keys are properly ordered according to `comparator`.
     ///
-    /// After calling this function, the keys and values (if applicable) are
-    /// reversed so that keys and values can be removed by calling [`Vec::pop`].
-    pub fn prepare(&mut self) -> Result<(), Error> {
-        if self.keys.windows(2).all(|w| w[0] < w[1]) {
+    /// After calling this function, the keys and values or operations (if
+    /// applicable) are reversed so that they can be removed by calling
+    /// [`Vec::pop`].
+    ///
+    /// [`Operation::RemoveRange`] modifications must not contain any keys.
+    pub fn prepare(&mut self, comparator: &dyn KeyComparator) -> Result<(), Error> {
+        if matches!(self.operation, Operation::RemoveRange(..)) {
+            return if self.keys.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::from(ErrorKind::RemoveRangeWithKeys))
+            };
+        }
+
+        if self
+            .keys
+            .windows(2)
+            .all(|w| comparator.compare(&w[0], &w[1]) == Ordering::Less)
+        {
             self.keys.reverse();
-            if let Operation::SetEach(values) = &mut self.operation {
-                values.reverse();
+            match &mut self.operation {
+                Operation::SetEach(values) => values.reverse(),
+                Operation::Batch(operations) => operations.reverse(),
+                _ => {}
             }
             Ok(())
         } else {
@@ -92,6 +110,11 @@ impl From<Option<TransactionId>> for PersistenceMode {
 pub enum Operation<'a, T, Index> {
     /// Sets all keys to the value.
     Set(T),
+    /// Sets all keys to the value, which expires at the [`Expiration`].
+    /// Expired values are ignored by reads until they are removed.
+    ///
+    /// All other operations that store a value clear the key's expiration.
+    SetWithExpiration(T, Expiration),
     /// Sets each key to the corresponding entry in this value. The number of
     /// keys must match the number of values.
     SetEach(Vec<T>),
@@ -100,15 +123,112 @@ pub enum Operation<'a, T, Index> {
     /// Executes the `CompareSwap`. The original value (or `None` if not
     /// present) is the only argument.
     CompareSwap(CompareSwap<'a, T, Index>),
+    /// Removes all keys contained within the start and end bounds. The
+    /// modification's keys must be empty.
+    ///
+    /// Subtrees whose keys are entirely contained within the range are removed
+    /// without reading their keys, which means the removed keys are not
+    /// included in the modification's results.
+    RemoveRange(Bound<ArcBytes<'a>>, Bound<ArcBytes<'a>>),
+    /// Performs each operation on the corresponding key. The number of keys
+    /// must match the number of operations.
+    Batch(Vec<BatchOperation<'a, T, Index>>),
+    /// Merges the operand into each key's value using the tree's
+    /// [`MergeOperator`](super::MergeOperator).
+    ///
+    /// Merges must be replaced using [`Modification::resolve_merges()`] before
+    /// the modification is applied to a [`BTreeEntry`](super::btree::BTreeEntry).
+    Merge(T),
+}
+
+impl<'a, T, Index> Operation<'a, T, Index> {
+    /// Returns an [`Operation::RemoveRange`] that removes all keys contained
+    /// within `range`.
+    pub fn remove_range<KeyRangeBounds: RangeBounds<&'a [u8]> + ?Sized>(
+        range: &KeyRangeBounds,
+    ) -> Self {
+        Self::RemoveRange(
+            owned_bound(range.start_bound()),
+            owned_bound(range.end_bound()),
+        )
+    }
+
+    /// Returns the expiration of an [`Operation::SetWithExpiration`].
+    pub(crate) const fn expiration(&self) -> Option<Expiration> {
+        if let Self::SetWithExpiration(_, expiration) = self {
+            Some(*expiration)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the range of an [`Operation::RemoveRange`].
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn removed_range(&self) -> Option<(Bound<&[u8]>, Bound<&[u8]>)> {
+        if let Self::RemoveRange(start, end) = self {
+            Some((borrow_bound(start), borrow_bound(end)))
+        } else {
+            None
+        }
+    }
+}
+
+fn owned_bound<'a>(bound: Bound<&&'a [u8]>) -> Bound<ArcBytes<'a>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(ArcBytes::from(*key)),
+        Bound::Excluded(key) => Bound::Excluded(ArcBytes::from(*key)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn borrow_bound<'b>(bound: &'b Bound<ArcBytes<'_>>) -> Bound<&'b [u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
 }
 
 impl<'a, T: Debug, Index> Debug for Operation<'a, T, Index> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Set(arg0) => f.debug_tuple("Set").field(arg0).finish(),
+            Self::SetWithExpiration(arg0, arg1) => f
+                .debug_tuple("SetWithExpiration")
+                .field(arg0)
+                .field(arg1)
+                .finish(),
             Self::SetEach(arg0) => f.debug_tuple("SetEach").field(arg0).finish(),
             Self::Remove => write!(f, "Remove"),
             Self::CompareSwap(_) => f.debug_tuple("CompareSwap").finish(),
+            Self::RemoveRange(start, end) => f
+                .debug_tuple("RemoveRange")
+                .field(start)
+                .field(end)
+                .finish(),
+            Self::Batch(arg0) => f.debug_tuple("Batch").field(arg0).finish(),
+            Self::Merge(arg0) => f.debug_tuple("Merge").field(arg0).finish(),
+        }
+    }
+}
+
+/// An operation performed on a single key of an [`Operation::Batch`].
+pub enum BatchOperation<'a, T, Index> {
+    /// Sets the key to the value.
+    Set(T),
+    /// Removes the key.
+    Remove,
+    /// Executes the callback. The original value (or `None` if not present) is
+    /// the only argument.
+    CompareSwap(Box<CompareSwapFn<'a, T, Index>>),
+}
+
+impl<T: Debug, Index> Debug for BatchOperation<'_, T, Index> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Set(arg0) => f.debug_tuple("Set").field(arg0).finish(),
+            Self::Remove => write!(f, "Remove"),
+            Self::CompareSwap(_) => f.debug_tuple("CompareSwap").finish(),
         }
     }
 }
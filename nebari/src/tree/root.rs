@@ -14,11 +14,13 @@ use crate::{
     roots::AnyTransactionTree,
     transaction::{TransactionId, TransactionManager},
     tree::{
-        btree::ScanArgs, state::AnyTreeState, Modification, ModificationResult, PageHeader,
-        PagedWriter, Reducer, ScanEvaluation, State, TreeFile,
+        btree::ScanArgs, state::AnyTreeState, Bytewise, EarliestExpiration, KeyComparator,
+        KeyStatistics, MergeOperator, Modification, ModificationResult, PageHeader, PagedWriter,
+        PositionIndex, Reducer, ScanEvaluation, SecondaryIndex, State, TransactableCompaction,
+        TreeFile,
     },
     vault::AnyVault,
-    AbortError, ArcBytes, ChunkCache, Context, TransactionTree, Vault,
+    AbortError, ArcBytes, ChunkCache, Context, ErrorKind, TransactionTree, Vault,
 };
 
 /// A B-Tree root implementation.
@@ -27,9 +29,9 @@ pub trait Root: Debug + Send + Sync + Clone + 'static {
     const HEADER: PageHeader;
 
     /// The primary index type contained within this root.
-    type Index: Clone + Debug + 'static;
+    type Index: PositionIndex + Clone + Debug + 'static;
     /// The primary index type contained within this root.
-    type ReducedIndex: Clone + Debug + 'static;
+    type ReducedIndex: EarliestExpiration + KeyStatistics + Clone + Debug + 'static;
     /// The reducer that reduces `Index`es and re-reduces `ReducedIndex`es.
     type Reducer: Reducer<Self::Index, Self::ReducedIndex> + 'static;
     /// The value type stored by this root.
@@ -41,6 +43,33 @@ pub trait Root: Debug + Send + Sync + Clone + 'static {
     /// Returns the instance's reducer.
     fn reducer(&self) -> &Self::Reducer;
 
+    /// Returns the [`KeyComparator`] that orders this root's keys.
+    fn comparator(&self) -> &Arc<dyn KeyComparator>;
+
+    /// Replaces the [`KeyComparator`] that orders this root's keys. This must
+    /// be called before any keys are stored.
+    fn set_comparator(&mut self, comparator: Arc<dyn KeyComparator>);
+
+    /// Returns the [`MergeOperator`] used to apply [`Operation::Merge`]
+    /// modifications, if one has been set.
+    ///
+    /// [`Operation::Merge`]: crate::tree::Operation::Merge
+    fn merge_operator(&self) -> Option<&Arc<dyn MergeOperator>>;
+
+    /// Replaces the [`MergeOperator`] used to apply [`Operation::Merge`]
+    /// modifications.
+    ///
+    /// [`Operation::Merge`]: crate::tree::Operation::Merge
+    fn set_merge_operator(&mut self, merge_operator: Option<Arc<dyn MergeOperator>>);
+
+    /// Returns the names of the secondary indexes maintained for this root's
+    /// keys, in sorted order. The names are stored in the tree's file.
+    fn secondary_indexes(&self) -> &[String];
+
+    /// Replaces the names of the secondary indexes maintained for this root's
+    /// keys. `names` must be sorted.
+    fn set_secondary_indexes(&mut self, names: Vec<String>);
+
     /// Returns the number of values contained in this tree, not including
     /// deleted records.
     fn count(&self) -> u64;
@@ -54,6 +83,9 @@ pub trait Root: Debug + Send + Sync + Clone + 'static {
             name: name.into(),
             vault: None,
             reducer: Arc::new(<Self::Reducer as Default>::default()),
+            comparator: Arc::new(Bytewise),
+            merge_operator: None,
+            indexes: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -67,6 +99,9 @@ pub trait Root: Debug + Send + Sync + Clone + 'static {
             name: name.into(),
             vault: None,
             reducer: Arc::new(reducer),
+            comparator: Arc::new(Bytewise),
+            merge_operator: None,
+            indexes: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -89,8 +124,13 @@ pub trait Root: Debug + Send + Sync + Clone + 'static {
         output: &mut Vec<u8>,
     ) -> Result<(), Error>;
 
-    /// Deserializes the root from `bytes`.
-    fn deserialize(bytes: ArcBytes<'_>, reducer: Self::Reducer) -> Result<Self, Error>;
+    /// Deserializes the root from `bytes`. Returns an error if the root was
+    /// serialized using a different comparator than `comparator`.
+    fn deserialize(
+        bytes: ArcBytes<'_>,
+        reducer: Self::Reducer,
+        comparator: Arc<dyn KeyComparator>,
+    ) -> Result<Self, Error>;
 
     /// Returns the current transaction id.
     fn transaction_id(&self) -> TransactionId;
@@ -182,6 +222,12 @@ pub struct TreeRoot<R: Root, File: ManagedFile> {
     pub vault: Option<Arc<dyn AnyVault>>,
     /// The [`Reducer`] for this tree.
     pub(crate) reducer: Arc<dyn AnyReducer>,
+    /// The [`KeyComparator`] for this tree.
+    pub(crate) comparator: Arc<dyn KeyComparator>,
+    /// The [`MergeOperator`] for this tree.
+    pub(crate) merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// The secondary indexes maintained for this tree.
+    pub(crate) indexes: Vec<SecondaryIndex<R::Value>>,
     _phantom: PhantomData<(R, File)>,
 }
 
@@ -204,6 +250,106 @@ impl<R: Root, File: ManagedFile> TreeRoot<R, File> {
         self.vault = Some(Arc::new(vault));
         self
     }
+
+    /// Orders this tree's keys using `comparator` instead of [`Bytewise`].
+    ///
+    /// The comparator's name is stored in the tree's file, and the tree must
+    /// always be opened with the same comparator.
+    pub fn with_comparator<C: KeyComparator>(mut self, comparator: C) -> Self {
+        self.comparator = Arc::new(comparator);
+        self
+    }
+
+    /// Applies [`Operation::Merge`] modifications to this tree using
+    /// `merge_operator`.
+    ///
+    /// The merge operator is not stored in the tree's file. Once registered,
+    /// it remains in use by the tree's [`Roots`](crate::Roots) until it is
+    /// replaced by opening the tree with another merge operator.
+    ///
+    /// [`Operation::Merge`]: crate::tree::Operation::Merge
+    pub fn with_merge_operator<M: MergeOperator>(mut self, merge_operator: M) -> Self {
+        self.merge_operator = Some(Arc::new(merge_operator));
+        self
+    }
+
+    /// Maintains a secondary index named `name` for this tree. `extractor`
+    /// returns the index keys of a value, and each key is stored under every
+    /// index key its value produces.
+    ///
+    /// The index is stored in a companion tree named `{tree}~{name}`, which is
+    /// updated in the same transaction as the changes to this tree. Because
+    /// `~` isn't valid in tree names, the companion tree never shares a name
+    /// with another tree. `name` is limited to the characters that are valid
+    /// in tree names, and opening the tree fails with
+    /// [`ErrorKind::InvalidTreeName`] otherwise.
+    ///
+    /// Index keys are ordered bytewise, regardless of this tree's
+    /// [`KeyComparator`]. The extractor is not stored in the tree's file, so
+    /// the index must be declared each time the tree is opened. Keys stored
+    /// before the index was declared are not indexed until
+    /// [`Tree::rebuild_indexes()`](crate::Tree::rebuild_indexes) is called.
+    ///
+    /// The names of the tree's indexes are stored in the tree's file once it
+    /// is modified. Transactions that include the tree without declaring each
+    /// of its stored indexes fail with
+    /// [`ErrorKind::SecondaryIndexNotDeclared`], which prevents the index from
+    /// becoming out of date.
+    ///
+    /// The index can be queried using
+    /// [`Tree::get_by_index()`](crate::Tree::get_by_index) and
+    /// [`Tree::get_range_by_index()`](crate::Tree::get_range_by_index).
+    pub fn with_index<F>(mut self, name: impl Into<Cow<'static, str>>, extractor: F) -> Self
+    where
+        F: Fn(&[u8]) -> Vec<ArcBytes<'static>> + Send + Sync + 'static,
+        R::Value: AsRef<[u8]>,
+    {
+        let name = name.into();
+        self.indexes.retain(|index| index.name != name);
+        self.indexes.push(SecondaryIndex::new(
+            &self.name,
+            name,
+            move |value: &R::Value| extractor(value.as_ref()),
+        ));
+        self
+    }
+
+    /// Ensures every secondary index stored in `state`'s file has been
+    /// declared, and records any newly declared indexes so that they are
+    /// stored the next time the tree is saved.
+    fn record_secondary_indexes(&self, state: &State<R>) -> Result<(), Error> {
+        let mut active_state = state.lock();
+        let stored = active_state.root.secondary_indexes();
+        if let Some(undeclared) = stored
+            .iter()
+            .find(|name| !self.indexes.iter().any(|index| index.name == name.as_str()))
+        {
+            return Err(Error::from(ErrorKind::SecondaryIndexNotDeclared(
+                undeclared.clone(),
+            )));
+        }
+        // Every stored index is declared, so the names only differ if an index
+        // has been added.
+        if stored.len() < self.indexes.len() {
+            let mut names = self
+                .indexes
+                .iter()
+                .map(|index| index.name.to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            active_state.root.set_secondary_indexes(names);
+        }
+        drop(active_state);
+        Ok(())
+    }
+
+    /// Returns `context` using this tree's vault, if it has one.
+    fn context<'c>(&self, context: &'c Context<File::Manager>) -> Cow<'c, Context<File::Manager>> {
+        self.vault.as_ref().map_or_else(
+            || Cow::Borrowed(context),
+            |vault| Cow::Owned(context.clone().with_any_vault(vault.clone())),
+        )
+    }
 }
 
 impl<R: Root, File: ManagedFile> Clone for TreeRoot<R, File> {
@@ -212,6 +358,9 @@ impl<R: Root, File: ManagedFile> Clone for TreeRoot<R, File> {
             name: self.name.clone(),
             vault: self.vault.clone(),
             reducer: self.reducer.clone(),
+            comparator: self.comparator.clone(),
+            merge_operator: self.merge_operator.clone(),
+            indexes: self.indexes.clone(),
             _phantom: PhantomData,
         }
     }
@@ -232,6 +381,36 @@ pub trait AnyTreeRoot<File: ManagedFile> {
         context: &Context<File::Manager>,
         transactions: Option<&TransactionManager<File::Manager>>,
     ) -> Result<Box<dyn AnyTransactionTree<File>>, Error>;
+    /// Compacts this tree, rewriting all of its live data into a new file
+    /// using the vault in `context`. Trees that were written without a vault
+    /// are encrypted by this operation if `context` has a vault.
+    fn compact(
+        &self,
+        file_path: &PathId,
+        state: &dyn AnyTreeState,
+        context: &Context<File::Manager>,
+        transactions: Option<&TransactionManager<File::Manager>>,
+    ) -> Result<(), Error>;
+    /// Reads the chunks at `positions` into the cache in `context`, and loads
+    /// the nodes within `levels` of the tree's root node. See
+    /// [`TreeFile::warm_cache()`] for more information.
+    fn warm_cache(
+        &self,
+        file_path: &PathId,
+        state: &dyn AnyTreeState,
+        context: &Context<File::Manager>,
+        transactions: Option<&TransactionManager<File::Manager>>,
+        positions: &[u64],
+        levels: usize,
+    ) -> Result<(), Error>;
+    /// Returns the companion trees that store this tree's secondary indexes,
+    /// which are included in transactions that include this tree.
+    fn secondary_index_trees(&self) -> Vec<Box<dyn AnyTreeRoot<File>>> {
+        Vec::new()
+    }
+    /// Returns a boxed copy of this root, which [`Roots`](crate::Roots) keeps
+    /// to be able to compact this tree when re-encrypting its database.
+    fn boxed_root(&self) -> Box<dyn AnyTreeRoot<File> + Send + Sync>;
 }
 
 impl<R: Root, File: ManagedFile> AnyTreeRoot<File> for TreeRoot<R, File> {
@@ -240,18 +419,17 @@ impl<R: Root, File: ManagedFile> AnyTreeRoot<File> for TreeRoot<R, File> {
     }
 
     fn default_state(&self) -> Box<dyn AnyTreeState> {
-        Box::new(State::<R>::new(
-            None,
-            None,
-            R::default_with(
-                self.reducer
-                    .as_ref()
-                    .as_any()
-                    .downcast_ref::<R::Reducer>()
-                    .unwrap()
-                    .clone(),
-            ),
-        ))
+        let mut root = R::default_with(
+            self.reducer
+                .as_ref()
+                .as_any()
+                .downcast_ref::<R::Reducer>()
+                .unwrap()
+                .clone(),
+        );
+        root.set_comparator(self.comparator.clone());
+        root.set_merge_operator(self.merge_operator.clone());
+        Box::new(State::<R>::new(None, None, root))
     }
 
     fn begin_transaction(
@@ -262,20 +440,86 @@ impl<R: Root, File: ManagedFile> AnyTreeRoot<File> for TreeRoot<R, File> {
         context: &Context<File::Manager>,
         transactions: Option<&TransactionManager<File::Manager>>,
     ) -> Result<Box<dyn AnyTransactionTree<File>>, Error> {
-        let context = self.vault.as_ref().map_or_else(
-            || Cow::Borrowed(context),
-            |vault| Cow::Owned(context.clone().with_any_vault(vault.clone())),
-        );
-        let tree = TreeFile::write(
+        let context = self.context(context);
+        let state = state.as_any().downcast_ref::<State<R>>().unwrap();
+        {
+            let mut active_state = state.lock();
+            if !active_state.initialized() {
+                active_state.root.set_comparator(self.comparator.clone());
+            }
+            if self.merge_operator.is_some() {
+                active_state
+                    .root
+                    .set_merge_operator(self.merge_operator.clone());
+            }
+        }
+        let tree = TreeFile::write(file_path, state.clone(), &context, transactions)?;
+        self.comparator
+            .check_matches(state.lock().root.comparator().as_ref())?;
+        self.record_secondary_indexes(state)?;
+
+        Ok(Box::new(TransactionTree {
+            transaction_id,
+            tree,
+            indexes: self.indexes.clone(),
+            changed_keys: Vec::new(),
+        }))
+    }
+
+    fn compact(
+        &self,
+        file_path: &PathId,
+        state: &dyn AnyTreeState,
+        context: &Context<File::Manager>,
+        transactions: Option<&TransactionManager<File::Manager>>,
+    ) -> Result<(), Error> {
+        let context = self.context(context);
+        let tree = TreeFile::<R, File>::read(
+            file_path,
+            state.as_any().downcast_ref::<State<R>>().unwrap().clone(),
+            &context,
+            transactions,
+        )?;
+        tree.compact_with_vault(
+            &context.file_manager,
+            context.vault.clone(),
+            transactions.map(|manager| TransactableCompaction {
+                name: &self.name,
+                manager,
+            }),
+        )?;
+        Ok(())
+    }
+
+    fn warm_cache(
+        &self,
+        file_path: &PathId,
+        state: &dyn AnyTreeState,
+        context: &Context<File::Manager>,
+        transactions: Option<&TransactionManager<File::Manager>>,
+        positions: &[u64],
+        levels: usize,
+    ) -> Result<(), Error> {
+        let context = self.context(context);
+        let mut tree = TreeFile::<R, File>::read(
             file_path,
             state.as_any().downcast_ref::<State<R>>().unwrap().clone(),
             &context,
             transactions,
         )?;
+        tree.warm_cache(positions, levels)
+    }
 
-        Ok(Box::new(TransactionTree {
-            transaction_id,
-            tree,
-        }))
+    fn secondary_index_trees(&self) -> Vec<Box<dyn AnyTreeRoot<File>>> {
+        self.indexes
+            .iter()
+            .map(|index| {
+                Box::new(index.tree::<File>(self.vault.clone())) as Box<dyn AnyTreeRoot<File>>
+            })
+            .collect()
+    }
+
+    fn boxed_root(&self) -> Box<dyn AnyTreeRoot<File> + Send + Sync> {
+        Box::new(self.clone())
     }
 }
@@ -0,0 +1,306 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fmt::Debug,
+    ops::{Bound, Range, RangeBounds},
+    sync::Arc,
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    error::Error,
+    io::ManagedFile,
+    keys::{DecodeKey, EncodeKey, PrefixRange},
+    tree::{Root, TreeRoot, Unversioned, WriteBatch},
+    vault::AnyVault,
+    ArcBytes, ErrorKind, TransactionTree,
+};
+
+/// The first byte of keys in a companion tree that map an index key and a
+/// primary key to an empty value.
+const ENTRY: u8 = 0;
+/// The first byte of keys in a companion tree that map a primary key to the
+/// index keys it is currently stored under.
+const REVERSE_ENTRY: u8 = 1;
+
+/// Extracts the index keys of a value.
+type Extractor<Value> = dyn Fn(&Value) -> Vec<ArcBytes<'static>> + Send + Sync;
+
+/// A secondary index declared using
+/// [`TreeRoot::with_index()`](crate::tree::TreeRoot::with_index).
+///
+/// Each index is stored in an [`Unversioned`] companion tree named
+/// `{tree}~{index}`, which contains two kinds of entries:
+///
+/// - `[ENTRY] + index_key.encode_key() + key`, with an empty value. Because
+///   the encoded index key is self-delimiting and order-preserving, the entries
+///   are ordered by index key, then by primary key.
+/// - `[REVERSE_ENTRY] + key`, whose value contains the concatenated encodings
+///   of the key's index keys. This allows the previous entries of a key to be
+///   removed without knowing the key's previous value.
+pub struct SecondaryIndex<Value> {
+    pub name: Cow<'static, str>,
+    pub tree_name: String,
+    extractor: Arc<Extractor<Value>>,
+}
+
+impl<Value> SecondaryIndex<Value> {
+    pub fn new<F>(tree: &str, name: Cow<'static, str>, extractor: F) -> Self
+    where
+        F: Fn(&Value) -> Vec<ArcBytes<'static>> + Send + Sync + 'static,
+    {
+        Self {
+            tree_name: format!("{}{}", index_tree_prefix(tree), name),
+            name,
+            extractor: Arc::new(extractor),
+        }
+    }
+
+    /// Returns the unique index keys of `value`.
+    pub fn index_keys(&self, value: &Value) -> BTreeSet<ArcBytes<'static>> {
+        (self.extractor)(value).into_iter().collect()
+    }
+
+    /// Returns the companion tree that stores this index.
+    pub fn tree<File: ManagedFile>(
+        &self,
+        vault: Option<Arc<dyn AnyVault>>,
+    ) -> TreeRoot<Unversioned, File> {
+        let mut root = Unversioned::tree(self.tree_name.clone());
+        root.vault = vault;
+        root
+    }
+}
+
+impl<Value> Clone for SecondaryIndex<Value> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            tree_name: self.tree_name.clone(),
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<Value> Debug for SecondaryIndex<Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("name", &self.name)
+            .field("tree_name", &self.tree_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Separates the name of a tree from the name of one of its secondary indexes
+/// in the name of the index's companion tree. Tree names can't contain this
+/// character, so companion trees never share a name with another tree.
+pub const INDEX_TREE_SEPARATOR: char = '~';
+
+/// Returns the prefix of the names of the companion trees that store the
+/// secondary indexes of `tree`.
+pub fn index_tree_prefix(tree: &str) -> String {
+    format!("{}{}", tree, INDEX_TREE_SEPARATOR)
+}
+
+/// Returns true if `name` is the name of a companion tree storing a secondary
+/// index.
+pub fn is_index_tree(name: &str) -> bool {
+    name.contains(INDEX_TREE_SEPARATOR)
+}
+
+/// Writes the names of a tree's secondary indexes after the identity of its
+/// [`KeyComparator`](crate::tree::KeyComparator).
+pub fn serialize_index_names(names: &[String], output: &mut Vec<u8>) -> Result<(), Error> {
+    for name in names {
+        let length = u16::try_from(name.len()).map_err(|_| ErrorKind::InvalidTreeName)?;
+        output.write_u16::<BigEndian>(length)?;
+        output.extend_from_slice(name.as_bytes());
+    }
+    Ok(())
+}
+
+/// Reads the index names written by [`serialize_index_names()`], which are
+/// the last bytes of a serialized root.
+pub fn deserialize_index_names(mut bytes: ArcBytes<'_>) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+    while !bytes.is_empty() {
+        let length = usize::from(bytes.read_u16::<BigEndian>()?);
+        let name = bytes.read_bytes(length)?;
+        names.push(
+            String::from_utf8(name.to_vec())
+                .map_err(|_| Error::data_integrity(ErrorKind::InvalidTreeHeader))?,
+        );
+    }
+    Ok(names)
+}
+
+/// The index keys of the keys changed within a transaction, which are applied
+/// to the companion tree named `tree_name`.
+pub struct IndexChanges {
+    pub tree_name: String,
+    pub keys: Vec<(ArcBytes<'static>, BTreeSet<ArcBytes<'static>>)>,
+}
+
+impl IndexChanges {
+    /// Replaces the entries of each changed key in `tree`.
+    pub fn apply<File: ManagedFile>(
+        self,
+        tree: &mut TransactionTree<Unversioned, File>,
+    ) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        for (key, index_keys) in self.keys {
+            let reverse_key = reverse_entry_key(&key);
+            let previous = match tree.get(&reverse_key)? {
+                Some(encoded) => decode_index_keys(&encoded)?,
+                None => BTreeSet::new(),
+            };
+            if previous == index_keys {
+                continue;
+            }
+
+            for removed in previous.difference(&index_keys) {
+                batch.remove(entry_key(removed, &key));
+            }
+            for added in index_keys.difference(&previous) {
+                batch.set(entry_key(added, &key), ArcBytes::default());
+            }
+            if index_keys.is_empty() {
+                batch.remove(reverse_key);
+            } else {
+                batch.set(reverse_key, encode_index_keys(&index_keys));
+            }
+        }
+
+        if !batch.is_empty() {
+            tree.apply_batch(batch)?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry found using a secondary index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecondaryIndexEntry<Value> {
+    /// The index key the entry was found under.
+    pub index_key: ArcBytes<'static>,
+    /// The entry's key.
+    pub key: ArcBytes<'static>,
+    /// The entry's value.
+    pub value: Value,
+}
+
+fn entry_key(index_key: &[u8], key: &[u8]) -> ArcBytes<'static> {
+    let mut entry = vec![ENTRY];
+    index_key.encode_key_into(&mut entry);
+    entry.extend_from_slice(key);
+    ArcBytes::from(entry)
+}
+
+fn reverse_entry_key(key: &[u8]) -> ArcBytes<'static> {
+    let mut entry = Vec::with_capacity(key.len() + 1);
+    entry.push(REVERSE_ENTRY);
+    entry.extend_from_slice(key);
+    ArcBytes::from(entry)
+}
+
+fn encode_index_keys(index_keys: &BTreeSet<ArcBytes<'static>>) -> ArcBytes<'static> {
+    let mut encoded = Vec::new();
+    for index_key in index_keys {
+        index_key.encode_key_into(&mut encoded);
+    }
+    ArcBytes::from(encoded)
+}
+
+fn decode_index_keys(mut encoded: &[u8]) -> Result<BTreeSet<ArcBytes<'static>>, Error> {
+    let mut index_keys = BTreeSet::new();
+    while !encoded.is_empty() {
+        index_keys.insert(ArcBytes::decode_key_from(&mut encoded)?);
+    }
+    Ok(index_keys)
+}
+
+/// Splits a companion tree entry key into its index key and primary key.
+pub fn decode_entry_key(entry: &[u8]) -> Result<(ArcBytes<'static>, ArcBytes<'static>), Error> {
+    let mut remaining = entry.get(1..).unwrap_or_default();
+    let index_key = ArcBytes::decode_key_from(&mut remaining)?;
+    Ok((index_key, ArcBytes::from(remaining.to_vec())))
+}
+
+/// Returns the range of companion tree entries whose index keys are contained
+/// within `range`.
+pub fn entry_range<'keys, KeyRangeBounds>(range: &KeyRangeBounds) -> Range<Vec<u8>>
+where
+    KeyRangeBounds: RangeBounds<&'keys [u8]> + ?Sized,
+{
+    // The entries of an index key all begin with the same prefix, so bounds
+    // that exclude an index key must exclude every key beginning with it.
+    let prefix = |index_key: &[u8]| PrefixRange::new(entry_key(index_key, b"").to_vec());
+    let after = |index_key: &[u8]| match prefix(index_key).bounds().1 {
+        Bound::Excluded(end) => end.to_vec(),
+        // Entry prefixes begin with ENTRY, which can always be incremented.
+        _ => unreachable!("entry prefixes always have an end"),
+    };
+    let start = match range.start_bound() {
+        Bound::Included(index_key) => prefix(index_key).prefix().to_vec(),
+        Bound::Excluded(index_key) => after(index_key),
+        Bound::Unbounded => vec![ENTRY],
+    };
+    let end = match range.end_bound() {
+        Bound::Included(index_key) => after(index_key),
+        Bound::Excluded(index_key) => prefix(index_key).prefix().to_vec(),
+        Bound::Unbounded => vec![REVERSE_ENTRY],
+    };
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_keys() {
+        let entry = entry_key(b"in\0dex", b"key");
+        assert_eq!(
+            decode_entry_key(&entry).unwrap(),
+            (ArcBytes::from(b"in\0dex"), ArcBytes::from(b"key"))
+        );
+
+        let index_keys = [ArcBytes::from(b"a"), ArcBytes::from(b"b\0")]
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            decode_index_keys(&encode_index_keys(&index_keys)).unwrap(),
+            index_keys
+        );
+
+        // An index key's entries are contained within its own range, but not
+        // within the range of an index key it is a prefix of.
+        let range = entry_range(&(&b"a"[..]..=&b"a"[..]));
+        let contains = |key: &[u8]| range.contains(&key.to_vec());
+        assert!(contains(&entry_key(b"a", b"key")));
+        assert!(contains(&entry_key(b"a", b"\xFF\xFF")));
+        assert!(!contains(&entry_key(b"ab", b"key")));
+        assert!(!contains(&entry_key(b"", b"key")));
+        assert!(!contains(&reverse_entry_key(b"key")));
+    }
+
+    #[test]
+    fn index_names() {
+        let names = vec![String::from("city"), String::from("name")];
+        let mut output = Vec::new();
+        serialize_index_names(&names, &mut output).unwrap();
+        assert_eq!(
+            deserialize_index_names(ArcBytes::from(output.as_slice())).unwrap(),
+            names
+        );
+        assert!(deserialize_index_names(ArcBytes::default())
+            .unwrap()
+            .is_empty());
+
+        let err = deserialize_index_names(ArcBytes::from(b"\0\x01\xFF")).unwrap_err();
+        assert!(
+            matches!(&err.kind, ErrorKind::DataIntegrity(err) if matches!(err.kind, ErrorKind::InvalidTreeHeader))
+        );
+    }
+}
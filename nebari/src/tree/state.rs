@@ -24,6 +24,7 @@ where
             max_order,
             current_position: 0,
             root,
+            plaintext_chunks: false,
         };
 
         Self {
@@ -40,6 +41,7 @@ where
             max_order,
             current_position: 0,
             root,
+            plaintext_chunks: false,
         };
 
         Self {
@@ -100,6 +102,9 @@ pub struct ActiveState<Root: super::Root> {
     /// children any node in the tree may contain. Nebari will automatically
     /// scale up to this number as the database grows.
     pub max_order: Option<usize>,
+    /// True if chunks in the file that are not sealed by a vault contain
+    /// unencrypted data, which is recorded in the file's header.
+    pub(crate) plaintext_chunks: bool,
 }
 
 impl<Root> ActiveState<Root>
@@ -117,7 +122,10 @@ where
     }
 
     pub(crate) fn rollback(&mut self, state: &State<Root>) {
-        let reader = state.reader.read();
-        self.root = reader.root.clone();
+        // The merge operator isn't part of the tree's data, and may have been
+        // registered after the reader was published.
+        let merge_operator = self.root.merge_operator().cloned();
+        self.root = state.reader.read().root.clone();
+        self.root.set_merge_operator(merge_operator);
     }
 }
@@ -0,0 +1,22 @@
+/// A reduced index that tracks the number of keys it contains, which allows
+/// order-statistic queries such as
+/// [`TreeFile::nth()`](crate::tree::TreeFile::nth) to skip nodes without
+/// reading their keys.
+pub trait KeyStatistics {
+    /// Returns the number of keys with values contained in this reduced index.
+    fn alive_keys(&self) -> u64;
+    /// Returns the total number of bytes the values of the alive keys occupy
+    /// on disk.
+    fn alive_bytes(&self) -> u64;
+}
+
+/// The approximate size of a range of keys, returned by
+/// [`TreeFile::estimate_range()`](crate::tree::TreeFile::estimate_range).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RangeEstimate {
+    /// The number of keys with values contained within the range.
+    pub keys: u64,
+    /// The number of bytes the values of the keys occupy on disk. This does
+    /// not include the keys themselves or the overhead of the tree's nodes.
+    pub value_bytes: u64,
+}
@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     fmt::{Debug, Display},
     ops::RangeBounds,
+    sync::Arc,
 };
 
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
@@ -9,7 +10,7 @@ use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use super::{
     btree::BTreeEntry,
     by_id::{ByIdStats, UnversionedByIdIndex},
-    modify::Modification,
+    modify::{Modification, Operation},
     serialization::BinarySerialization,
     PagedWriter, ScanEvaluation,
 };
@@ -22,7 +23,9 @@ use crate::{
     tree::{
         btree::{Indexer, KeyOperation, ModificationContext, NodeInclusion, ScanArgs},
         by_id::ByIdIndexer,
-        dynamic_order, BTreeNode, ChangeResult, ModificationResult, PageHeader, Root,
+        deserialize_index_names, dynamic_order, serialize_index_names, BTreeNode, Bytewise,
+        ChangeResult, Expiration, KeyComparator, MergeOperator, ModificationResult, PageHeader,
+        PositionIndex, Root,
     },
     vault::AnyVault,
     ArcBytes, ChunkCache, ErrorKind,
@@ -47,6 +50,9 @@ where
         BTreeEntry<UnversionedByIdIndex<Index, ArcBytes<'static>>, ByIdStats<Index::Reduced>>,
 
     reducer: <Self as Root>::Reducer,
+    comparator: Arc<dyn KeyComparator>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    secondary_indexes: Vec<String>,
 }
 
 impl<Index> Default for UnversionedTreeRoot<Index>
@@ -59,6 +65,9 @@ where
             transaction_id: None,
             by_id_root: BTreeEntry::default(),
             reducer: <<Self as Root>::Reducer as Default>::default(),
+            comparator: Arc::new(Bytewise),
+            merge_operator: None,
+            secondary_indexes: Vec::new(),
         }
     }
 }
@@ -78,7 +87,7 @@ where
         max_order: Option<usize>,
     ) -> Result<Vec<ModificationResult<UnversionedByIdIndex<Index, ArcBytes<'static>>>>, Error>
     {
-        modification.prepare()?;
+        modification.prepare(self.comparator.as_ref())?;
 
         let total_keys =
             self.by_id_root.stats(self.reducer()).total_keys() + modification.keys.len() as u64;
@@ -88,6 +97,8 @@ where
             minimum_children.min(usize::try_from(total_keys).unwrap_or(usize::MAX));
 
         let reducer = self.reducer.clone();
+        let expiration = modification.operation.expiration();
+        let now = Expiration::now();
 
         let mut results = Vec::with_capacity(modification.keys.len());
 
@@ -110,7 +121,8 @@ where
                                 value_length,
                                 position,
                                 reducer.0.index(key, Some(value)),
-                            );
+                            )
+                            .with_expiration(expiration);
                             results.push(ModificationResult {
                                 key: key.to_owned(),
                                 index: Some(new_index.clone()),
@@ -124,12 +136,19 @@ where
                             Ok(KeyOperation::Remove)
                         }
                     },
-                    |index, writer| match writer.read_chunk(index.position)? {
-                        CacheEntry::ArcBytes(buffer) => Ok(Some(buffer.clone())),
-                        CacheEntry::Decoded(_) => unreachable!(),
+                    |index, writer| {
+                        if index.has_value(now) {
+                            match writer.read_chunk(index.position)? {
+                                CacheEntry::ArcBytes(buffer) => Ok(Some(buffer.clone())),
+                                CacheEntry::Decoded(_) => unreachable!(),
+                            }
+                        } else {
+                            Ok(None)
+                        }
                     },
                     self.reducer().clone(),
-                ),
+                )
+                .with_comparator(self.comparator.clone()),
                 None,
                 writer,
             )? {
@@ -146,6 +165,42 @@ where
 
         Ok(results)
     }
+
+    fn remove_id_range<'k, KeyRangeBounds: RangeBounds<&'k [u8]> + ?Sized>(
+        &mut self,
+        range: &KeyRangeBounds,
+        writer: &mut PagedWriter<'_>,
+        max_order: Option<usize>,
+    ) -> Result<(), Error> {
+        let total_keys = self.by_id_root.stats(self.reducer()).total_keys();
+        let by_id_order = dynamic_order(total_keys, max_order);
+        let minimum_children = by_id_order / 2 - 1;
+        let minimum_children =
+            minimum_children.min(usize::try_from(total_keys).unwrap_or(usize::MAX));
+
+        let mut context = ModificationContext::new(
+            by_id_order,
+            minimum_children,
+            |_key: &ArcBytes<'_>,
+             _value: Option<&ArcBytes<'static>>,
+             _existing_index: Option<&UnversionedByIdIndex<Index, ArcBytes<'static>>>,
+             _writer: &mut PagedWriter<'_>| Ok(KeyOperation::Remove),
+            |_index: &UnversionedByIdIndex<Index, ArcBytes<'static>>,
+             _writer: &mut PagedWriter<'_>| Ok(None),
+            self.reducer().clone(),
+        )
+        .with_comparator(self.comparator.clone());
+        if self
+            .by_id_root
+            .remove_range(range, None, &mut context, writer)?
+            == ChangeResult::Remove
+        {
+            self.by_id_root.node = BTreeNode::Leaf(vec![]);
+            self.by_id_root.dirty = true;
+        }
+
+        Ok(())
+    }
 }
 
 impl<EmbeddedIndex> Root for UnversionedTreeRoot<EmbeddedIndex>
@@ -163,6 +218,9 @@ where
             transaction_id: None,
             by_id_root: BTreeEntry::default(),
             reducer,
+            comparator: Arc::new(Bytewise),
+            merge_operator: None,
+            secondary_indexes: Vec::new(),
         }
     }
 
@@ -170,6 +228,34 @@ where
         &self.reducer
     }
 
+    fn comparator(&self) -> &Arc<dyn KeyComparator> {
+        &self.comparator
+    }
+
+    fn set_comparator(&mut self, comparator: Arc<dyn KeyComparator>) {
+        self.comparator = comparator;
+    }
+
+    fn merge_operator(&self) -> Option<&Arc<dyn MergeOperator>> {
+        self.merge_operator.as_ref()
+    }
+
+    fn set_merge_operator(&mut self, merge_operator: Option<Arc<dyn MergeOperator>>) {
+        self.merge_operator = merge_operator;
+    }
+
+    fn secondary_indexes(&self) -> &[String] {
+        &self.secondary_indexes
+    }
+
+    fn set_secondary_indexes(&mut self, names: Vec<String>) {
+        if self.secondary_indexes != names {
+            self.secondary_indexes = names;
+            // The names are stored in the header, so it must be rewritten.
+            self.by_id_root.dirty = true;
+        }
+    }
+
     fn count(&self) -> u64 {
         self.by_id_root.stats(self.reducer()).alive_keys
     }
@@ -205,13 +291,21 @@ where
             .ok_or(ErrorKind::Internal(InternalError::HeaderTooLarge))?;
         BigEndian::write_u32(&mut output[8..12], by_id_size);
 
+        self.comparator
+            .serialize_to(output, !self.secondary_indexes.is_empty())?;
+        serialize_index_names(&self.secondary_indexes, output)?;
+
         Ok(())
     }
 
-    fn deserialize(mut bytes: ArcBytes<'_>, reducer: Self::Reducer) -> Result<Self, Error> {
+    fn deserialize(
+        mut bytes: ArcBytes<'_>,
+        reducer: Self::Reducer,
+        comparator: Arc<dyn KeyComparator>,
+    ) -> Result<Self, Error> {
         let transaction_id = Some(TransactionId(bytes.read_u64::<BigEndian>()?));
         let by_id_size = bytes.read_u32::<BigEndian>()? as usize;
-        if by_id_size != bytes.len() {
+        if by_id_size > bytes.len() {
             return Err(Error::data_integrity(format!(
                 "Header reported index size {}, but data has {} remaining",
                 by_id_size,
@@ -222,11 +316,16 @@ where
         let mut by_id_bytes = bytes.read_bytes(by_id_size)?.to_owned();
 
         let by_id_root = BTreeEntry::deserialize_from(&mut by_id_bytes, None)?;
+        comparator.check_serialized(&mut bytes)?;
+        let secondary_indexes = deserialize_index_names(bytes)?;
 
         Ok(Self {
             transaction_id,
             by_id_root,
             reducer,
+            comparator,
+            merge_operator: None,
+            secondary_indexes,
         })
     }
 
@@ -236,13 +335,22 @@ where
 
     fn modify(
         &mut self,
-        modification: Modification<'_, ArcBytes<'static>, Self::Index>,
+        mut modification: Modification<'_, ArcBytes<'static>, Self::Index>,
         writer: &mut PagedWriter<'_>,
         max_order: Option<usize>,
     ) -> Result<Vec<ModificationResult<Self::Index>>, Error> {
         let transaction_id = modification.persistence_mode.transaction_id();
-
-        let results = self.modify_id_root(modification, writer, max_order)?;
+        modification.resolve_merges(self.merge_operator.as_ref())?;
+
+        let results = if matches!(modification.operation, Operation::RemoveRange(..)) {
+            // Ensures no keys were provided.
+            modification.prepare(self.comparator.as_ref())?;
+            let range = modification.operation.removed_range().unwrap();
+            self.remove_id_range(&range, writer, max_order)?;
+            Vec::new()
+        } else {
+            self.modify_id_root(modification, writer, max_order)?
+        };
 
         // Only update the transaction id if a new one was specified.
         if let Some(transaction_id) = transaction_id {
@@ -266,8 +374,15 @@ where
         KeyReader: FnMut(ArcBytes<'static>, ArcBytes<'static>, Self::Index) -> Result<(), Error>,
         Keys: Iterator<Item = &'keys [u8]>,
     {
-        self.by_id_root
-            .get_multiple(keys, key_evaluator, key_reader, file, vault, cache)
+        self.by_id_root.get_multiple(
+            keys,
+            self.comparator.as_ref(),
+            key_evaluator,
+            key_reader,
+            file,
+            vault,
+            cache,
+        )
     }
 
     fn scan<
@@ -303,8 +418,15 @@ where
             ArcBytes<'static>,
         ) -> Result<(), AbortError<CallerError>>,
     {
-        self.by_id_root
-            .scan(range, &mut args, file, vault, cache, 0)
+        self.by_id_root.scan(
+            range,
+            self.comparator.as_ref(),
+            &mut args,
+            file,
+            vault,
+            cache,
+            0,
+        )
     }
 
     fn copy_data_to(
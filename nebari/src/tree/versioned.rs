@@ -1,8 +1,10 @@
 use std::{
     array::TryFromSliceError,
     collections::HashMap,
+    convert::Infallible,
     fmt::{Debug, Display},
-    ops::RangeBounds,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
 };
 
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
@@ -25,10 +27,12 @@ use crate::{
         btree::{Indexer, KeyOperation, ModificationContext, NodeInclusion, ScanArgs},
         by_id::ByIdIndexer,
         by_sequence::{BySequenceReducer, SequenceId},
-        dynamic_order,
+        deserialize_index_names, dynamic_order,
         key_entry::KeyEntry,
         modify::Operation,
-        BTreeNode, Interior, ModificationResult, PageHeader, PersistenceMode, Reducer, Root,
+        serialize_index_names, BTreeNode, Bytewise, Expiration, Interior, KeyComparator,
+        MergeOperator, ModificationResult, PageHeader, PersistenceMode, PositionIndex, Reducer,
+        Root,
     },
     vault::AnyVault,
     ArcBytes, ChunkCache, ErrorKind,
@@ -59,6 +63,9 @@ where
     >,
 
     reducer: ByIdIndexer<EmbeddedIndex::Indexer>,
+    comparator: Arc<dyn KeyComparator>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    secondary_indexes: Vec<String>,
 }
 impl<EmbeddedIndex> Default for VersionedTreeRoot<EmbeddedIndex>
 where
@@ -72,6 +79,9 @@ where
             by_sequence_root: BTreeEntry::default(),
             by_id_root: BTreeEntry::default(),
             reducer: ByIdIndexer(<EmbeddedIndex::Indexer as Default>::default()),
+            comparator: Arc::new(Bytewise),
+            merge_operator: None,
+            secondary_indexes: Vec::new(),
         }
     }
 }
@@ -100,8 +110,9 @@ where
         writer: &mut PagedWriter<'_>,
         max_order: Option<usize>,
     ) -> Result<(), Error> {
-        // Reverse so that pop is efficient.
-        modification.prepare()?;
+        // Reverse so that pop is efficient. Sequence IDs are always ordered
+        // bytewise, regardless of the comparator used for keys.
+        modification.prepare(&Bytewise)?;
 
         let total_sequence_records = self
             .by_sequence_root
@@ -146,6 +157,37 @@ where
         Ok(())
     }
 
+    fn live_keys_in_range(
+        &self,
+        range: &(Bound<&[u8]>, Bound<&[u8]>),
+        writer: &mut PagedWriter<'_>,
+    ) -> Result<Vec<ArcBytes<'static>>, Error> {
+        let mut keys = Vec::new();
+        self.by_id_root
+            .scan::<Infallible, _, _, _, _>(
+                range,
+                self.comparator.as_ref(),
+                &mut ScanArgs::new(
+                    true,
+                    |_, _, _| ScanEvaluation::ReadData,
+                    |key, index: &VersionedByIdIndex<EmbeddedIndex, ArcBytes<'static>>| {
+                        // Removed keys are stored with a 0 position.
+                        if index.position > 0 {
+                            keys.push(key.clone());
+                        }
+                        ScanEvaluation::Skip
+                    },
+                    |_, _, _| unreachable!(),
+                ),
+                writer.file,
+                writer.vault,
+                writer.cache,
+                0,
+            )
+            .map_err(AbortError::infallible)?;
+        Ok(keys)
+    }
+
     fn modify_id_root(
         &mut self,
         mut modification: Modification<
@@ -158,7 +200,7 @@ where
         max_order: Option<usize>,
     ) -> Result<Vec<ModificationResult<VersionedByIdIndex<EmbeddedIndex, ArcBytes<'static>>>>, Error>
     {
-        modification.prepare()?;
+        modification.prepare(self.comparator.as_ref())?;
 
         let total_id_records =
             self.by_id_root.stats(self.reducer()).total_keys() + modification.keys.len() as u64;
@@ -169,6 +211,8 @@ where
             by_id_minimum_children.min(usize::try_from(total_id_records).unwrap_or(usize::MAX));
 
         let mut results = Vec::with_capacity(modification.keys.len());
+        let expiration = modification.operation.expiration();
+        let now = Expiration::now();
 
         while !modification.keys.is_empty() {
             let reducer = self.reducer.clone();
@@ -213,7 +257,8 @@ where
                             value_size,
                             position,
                             embedded,
-                        );
+                        )
+                        .with_expiration(value.and(expiration));
                         results.push(ModificationResult {
                             key,
                             index: Some(new_index.clone()),
@@ -221,7 +266,7 @@ where
                         Ok(KeyOperation::Set(new_index))
                     },
                     |index, writer| {
-                        if index.position > 0 {
+                        if index.has_value(now) {
                             match writer.read_chunk(index.position) {
                                 Ok(CacheEntry::ArcBytes(buffer)) => Ok(Some(buffer)),
                                 Ok(CacheEntry::Decoded(_)) => unreachable!(),
@@ -232,7 +277,8 @@ where
                         }
                     },
                     self.reducer().clone(),
-                ),
+                )
+                .with_comparator(self.comparator.clone()),
                 None,
                 writer,
             )? {
@@ -270,6 +316,9 @@ where
             by_sequence_root: BTreeEntry::default(),
             by_id_root: BTreeEntry::default(),
             reducer,
+            comparator: Arc::new(Bytewise),
+            merge_operator: None,
+            secondary_indexes: Vec::new(),
         }
     }
 
@@ -277,6 +326,34 @@ where
         &self.reducer
     }
 
+    fn comparator(&self) -> &Arc<dyn KeyComparator> {
+        &self.comparator
+    }
+
+    fn set_comparator(&mut self, comparator: Arc<dyn KeyComparator>) {
+        self.comparator = comparator;
+    }
+
+    fn merge_operator(&self) -> Option<&Arc<dyn MergeOperator>> {
+        self.merge_operator.as_ref()
+    }
+
+    fn set_merge_operator(&mut self, merge_operator: Option<Arc<dyn MergeOperator>>) {
+        self.merge_operator = merge_operator;
+    }
+
+    fn secondary_indexes(&self) -> &[String] {
+        &self.secondary_indexes
+    }
+
+    fn set_secondary_indexes(&mut self, names: Vec<String>) {
+        if self.secondary_indexes != names {
+            self.secondary_indexes = names;
+            // The names are stored in the header, so it must be rewritten.
+            self.by_id_root.dirty = true;
+        }
+    }
+
     fn count(&self) -> u64 {
         self.by_id_root.stats(self.reducer()).alive_keys
     }
@@ -317,15 +394,23 @@ where
             .ok_or(ErrorKind::Internal(InternalError::HeaderTooLarge))?;
         BigEndian::write_u32(&mut output[20..24], by_id_size);
 
+        self.comparator
+            .serialize_to(output, !self.secondary_indexes.is_empty())?;
+        serialize_index_names(&self.secondary_indexes, output)?;
+
         Ok(())
     }
 
-    fn deserialize(mut bytes: ArcBytes<'_>, reducer: Self::Reducer) -> Result<Self, Error> {
+    fn deserialize(
+        mut bytes: ArcBytes<'_>,
+        reducer: Self::Reducer,
+        comparator: Arc<dyn KeyComparator>,
+    ) -> Result<Self, Error> {
         let transaction_id = TransactionId(bytes.read_u64::<BigEndian>()?);
         let sequence = SequenceId(bytes.read_u64::<BigEndian>()?);
         let by_sequence_size = bytes.read_u32::<BigEndian>()? as usize;
         let by_id_size = bytes.read_u32::<BigEndian>()? as usize;
-        if by_sequence_size + by_id_size != bytes.len() {
+        if by_sequence_size + by_id_size > bytes.len() {
             return Err(Error::data_integrity(format!(
                 "Header reported index sizes {} and {}, but data has {} remaining",
                 by_sequence_size,
@@ -339,6 +424,8 @@ where
 
         let by_sequence_root = BTreeEntry::deserialize_from(&mut by_sequence_bytes, None)?;
         let by_id_root = BTreeEntry::deserialize_from(&mut by_id_bytes, None)?;
+        comparator.check_serialized(&mut bytes)?;
+        let secondary_indexes = deserialize_index_names(bytes)?;
 
         Ok(Self {
             transaction_id,
@@ -346,6 +433,9 @@ where
             by_sequence_root,
             by_id_root,
             reducer,
+            comparator,
+            merge_operator: None,
+            secondary_indexes,
         })
     }
 
@@ -355,11 +445,24 @@ where
 
     fn modify(
         &mut self,
-        modification: Modification<'_, ArcBytes<'static>, Self::Index>,
+        mut modification: Modification<'_, ArcBytes<'static>, Self::Index>,
         writer: &mut PagedWriter<'_>,
         max_order: Option<usize>,
     ) -> Result<Vec<ModificationResult<Self::Index>>, Error> {
         let persistence_mode = modification.persistence_mode;
+        modification.resolve_merges(self.merge_operator.as_ref())?;
+        if matches!(modification.operation, Operation::RemoveRange(..)) {
+            // Ensures no keys were provided.
+            modification.prepare(self.comparator.as_ref())?;
+            // Each removed key must be recorded in the by-sequence tree, so
+            // subtrees cannot be dropped without visiting their keys.
+            let range = modification.operation.removed_range().unwrap();
+            modification = Modification {
+                persistence_mode,
+                keys: self.live_keys_in_range(&range, writer)?,
+                operation: Operation::Remove,
+            };
+        }
 
         // Insert into both trees
         let mut changes = EntryChanges {
@@ -414,8 +517,15 @@ where
         KeyReader: FnMut(ArcBytes<'static>, ArcBytes<'static>, Self::Index) -> Result<(), Error>,
         Keys: Iterator<Item = &'keys [u8]>,
     {
-        self.by_id_root
-            .get_multiple(keys, key_evaluator, key_reader, file, vault, cache)
+        self.by_id_root.get_multiple(
+            keys,
+            self.comparator.as_ref(),
+            key_evaluator,
+            key_reader,
+            file,
+            vault,
+            cache,
+        )
     }
 
     fn scan<
@@ -451,8 +561,15 @@ where
             ArcBytes<'static>,
         ) -> Result<(), AbortError<CallerError>>,
     {
-        self.by_id_root
-            .scan(range, &mut args, file, vault, cache, 0)
+        self.by_id_root.scan(
+            range,
+            self.comparator.as_ref(),
+            &mut args,
+            file,
+            vault,
+            cache,
+            0,
+        )
     }
 
     fn copy_data_to(
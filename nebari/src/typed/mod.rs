@@ -0,0 +1,469 @@
+use std::{
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use self::ordered::OrderedError;
+use crate::{
+    error::Error,
+    io::ManagedFile,
+    roots::{CompareAndSwapError, TransactionTree, Tree},
+    tree::Root,
+    ArcBytes, ErrorKind,
+};
+
+mod ordered;
+
+/// Encodes and decodes keys of type `K`.
+///
+/// Trees are ordered by comparing keys byte-by-byte. For ranges over a
+/// [`TypedTree`] to return the expected results, encoded keys must compare in
+/// the same order as the keys they were encoded from: if `a < b`, the encoding
+/// of `a` must be less than the encoding of `b`.
+pub trait KeyCodec<K>: Debug + Send + Sync + 'static {
+    /// Encodes `key`.
+    fn encode_key(&self, key: &K) -> Result<Vec<u8>, Error>;
+    /// Decodes a key from `bytes`.
+    fn decode_key(&self, bytes: &[u8]) -> Result<K, Error>;
+}
+
+/// Encodes and decodes values of type `V`.
+pub trait ValueCodec<V>: Debug + Send + Sync + 'static {
+    /// Encodes `value`.
+    fn encode_value(&self, value: &V) -> Result<Vec<u8>, Error>;
+    /// Decodes a value from `bytes`.
+    fn decode_value(&self, bytes: &[u8]) -> Result<V, Error>;
+}
+
+/// A codec using an order-preserving binary format for any type implementing
+/// [`Serialize`] and [`DeserializeOwned`].
+///
+/// Integers, floats, strings, byte sequences, options, sequences, tuples,
+/// structs and enums all encode to bytes that sort in the same order as their
+/// [`Ord`] implementations (or their derived implementations, for structs and
+/// enums). The format is not self-describing, so types that require
+/// `deserialize_any()`, such as `serde_json::Value`, are not supported.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ordered;
+
+impl<K: Serialize + DeserializeOwned> KeyCodec<K> for Ordered {
+    fn encode_key(&self, key: &K) -> Result<Vec<u8>, Error> {
+        ordered::to_vec(key).map_err(codec_error)
+    }
+
+    fn decode_key(&self, bytes: &[u8]) -> Result<K, Error> {
+        ordered::from_slice(bytes).map_err(codec_error)
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> ValueCodec<V> for Ordered {
+    fn encode_value(&self, value: &V) -> Result<Vec<u8>, Error> {
+        ordered::to_vec(value).map_err(codec_error)
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> Result<V, Error> {
+        ordered::from_slice(bytes).map_err(codec_error)
+    }
+}
+
+fn codec_error(error: OrderedError) -> Error {
+    Error::from(ErrorKind::Codec(Box::new(error)))
+}
+
+/// A [`Tree`] whose keys and values are encoded from and decoded into `K` and
+/// `V` using a [`KeyCodec`] and a [`ValueCodec`].
+///
+/// By default, both keys and values are encoded using [`Ordered`].
+pub struct TypedTree<K, V, R: Root, File: ManagedFile, KC = Ordered, VC = Ordered> {
+    tree: Tree<R, File>,
+    key_codec: KC,
+    value_codec: VC,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, R, File, KC, VC> Clone for TypedTree<K, V, R, File, KC, VC>
+where
+    R: Root,
+    File: ManagedFile,
+    KC: Clone,
+    VC: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            key_codec: self.key_codec.clone(),
+            value_codec: self.value_codec.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K, V, R, File> TypedTree<K, V, R, File>
+where
+    R: Root<Value = ArcBytes<'static>>,
+    File: ManagedFile,
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Returns a typed wrapper of `tree` that encodes keys and values using
+    /// [`Ordered`].
+    #[must_use]
+    pub fn new(tree: Tree<R, File>) -> Self {
+        Self::with_codecs(tree, Ordered, Ordered)
+    }
+}
+
+impl<K, V, R, File, KC, VC> TypedTree<K, V, R, File, KC, VC>
+where
+    R: Root<Value = ArcBytes<'static>>,
+    File: ManagedFile,
+    KC: KeyCodec<K>,
+    VC: ValueCodec<V>,
+{
+    /// Returns a typed wrapper of `tree` that encodes keys using `key_codec`
+    /// and values using `value_codec`.
+    #[must_use]
+    pub fn with_codecs(tree: Tree<R, File>, key_codec: KC, value_codec: VC) -> Self {
+        Self {
+            tree,
+            key_codec,
+            value_codec,
+            _types: PhantomData,
+        }
+    }
+
+    /// Returns the underlying tree.
+    #[must_use]
+    pub const fn tree(&self) -> &Tree<R, File> {
+        &self.tree
+    }
+
+    /// Returns the underlying tree, consuming this wrapper.
+    #[must_use]
+    #[allow(clippy::missing_const_for_fn)] // false positive, destructors
+    pub fn into_inner(self) -> Tree<R, File> {
+        self.tree
+    }
+
+    /// Returns a typed wrapper of `tree`, a tree locked by a transaction, that
+    /// uses this tree's codecs.
+    pub fn transaction_tree<'a>(
+        &'a self,
+        tree: &'a mut TransactionTree<R, File>,
+    ) -> TypedTransactionTree<'a, K, V, R, File, KC, VC> {
+        TypedTransactionTree {
+            tree,
+            key_codec: &self.key_codec,
+            value_codec: &self.value_codec,
+            _types: PhantomData,
+        }
+    }
+
+    /// Retrieves the current value of `key`, if present.
+    pub fn get(&self, key: &K) -> Result<Option<V>, Error> {
+        let key = self.key_codec.encode_key(key)?;
+        self.tree
+            .get(&key)?
+            .map(|value| self.value_codec.decode_value(&value))
+            .transpose()
+    }
+
+    /// Sets `key` to `value`. This is executed within its own transaction.
+    pub fn set(&self, key: &K, value: &V) -> Result<(), Error> {
+        let key = self.key_codec.encode_key(key)?;
+        let value = self.value_codec.encode_value(value)?;
+        self.tree.set(key, value)
+    }
+
+    /// Removes `key` and returns the existing value, if present. This is
+    /// executed within its own transaction.
+    pub fn remove(&self, key: &K) -> Result<Option<V>, Error> {
+        let key = self.key_codec.encode_key(key)?;
+        self.tree
+            .remove(&key)?
+            .map(|entry| self.value_codec.decode_value(&entry.value))
+            .transpose()
+    }
+
+    /// Compares the value of `key` against `old`. If the values match, key will
+    /// be set to the new value if `new` is `Some` or removed if `new` is
+    /// `None`. This is executed within its own transaction.
+    ///
+    /// Values are compared using their encoded bytes.
+    pub fn compare_and_swap(
+        &self,
+        key: &K,
+        old: Option<&V>,
+        new: Option<&V>,
+    ) -> Result<(), CompareAndSwapError<V>>
+    where
+        V: Debug,
+    {
+        let (key, old, new) = encode_swap(&self.key_codec, &self.value_codec, key, old, new)?;
+        let result = self.tree.compare_and_swap(&key, old.as_deref(), new);
+        decode_conflict(&self.value_codec, result)
+    }
+
+    /// Retrieves all of the keys and values within `range`.
+    pub fn get_range<B: RangeBounds<K>>(&self, range: &B) -> Result<Vec<(K, V)>, Error> {
+        let (start, end) = encode_bounds(&self.key_codec, range)?;
+        let results = self
+            .tree
+            .get_range(&(borrow_bound(&start), borrow_bound(&end)))?;
+        decode_entries(&self.key_codec, &self.value_codec, results)
+    }
+}
+
+impl<K, V, R, File, KC, VC> Debug for TypedTree<K, V, R, File, KC, VC>
+where
+    R: Root,
+    File: ManagedFile,
+    KC: Debug,
+    VC: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TypedTree")
+            .field("tree", &self.tree.name())
+            .field("key_codec", &self.key_codec)
+            .field("value_codec", &self.value_codec)
+            .finish()
+    }
+}
+
+/// A typed wrapper of a [`TransactionTree`], returned from
+/// [`TypedTree::transaction_tree()`].
+pub struct TypedTransactionTree<'a, K, V, R: Root, File: ManagedFile, KC = Ordered, VC = Ordered> {
+    tree: &'a mut TransactionTree<R, File>,
+    key_codec: &'a KC,
+    value_codec: &'a VC,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, R, File, KC, VC> TypedTransactionTree<'_, K, V, R, File, KC, VC>
+where
+    R: Root<Value = ArcBytes<'static>>,
+    File: ManagedFile,
+    KC: KeyCodec<K>,
+    VC: ValueCodec<V>,
+{
+    /// Returns the underlying transaction tree.
+    pub fn tree(&mut self) -> &mut TransactionTree<R, File> {
+        self.tree
+    }
+
+    /// Returns the current value of `key`. This will return updated information
+    /// if it has been previously updated within this transaction.
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, Error> {
+        let key = self.key_codec.encode_key(key)?;
+        self.tree
+            .get(&key)?
+            .map(|value| self.value_codec.decode_value(&value))
+            .transpose()
+    }
+
+    /// Sets `key` to `value`.
+    pub fn set(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        let key = self.key_codec.encode_key(key)?;
+        let value = self.value_codec.encode_value(value)?;
+        self.tree.set(key, value).map(|_| ())
+    }
+
+    /// Removes `key` and returns the existing value, if present.
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>, Error> {
+        let key = self.key_codec.encode_key(key)?;
+        self.tree
+            .remove(&key)?
+            .map(|entry| self.value_codec.decode_value(&entry.value))
+            .transpose()
+    }
+
+    /// Compares the value of `key` against `old`. If the values match, key will
+    /// be set to the new value if `new` is `Some` or removed if `new` is
+    /// `None`.
+    ///
+    /// Values are compared using their encoded bytes.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &K,
+        old: Option<&V>,
+        new: Option<&V>,
+    ) -> Result<(), CompareAndSwapError<V>>
+    where
+        V: Debug,
+    {
+        let (key, old, new) = encode_swap(self.key_codec, self.value_codec, key, old, new)?;
+        let result = self.tree.compare_and_swap(&key, old.as_deref(), new);
+        decode_conflict(self.value_codec, result)
+    }
+
+    /// Retrieves all of the keys and values within `range`. This will return
+    /// updated information if keys have been previously updated within this
+    /// transaction.
+    pub fn get_range<B: RangeBounds<K>>(&mut self, range: &B) -> Result<Vec<(K, V)>, Error> {
+        let (start, end) = encode_bounds(self.key_codec, range)?;
+        let results = self
+            .tree
+            .get_range(&(borrow_bound(&start), borrow_bound(&end)))?;
+        decode_entries(self.key_codec, self.value_codec, results)
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn encode_swap<K, V, KC: KeyCodec<K>, VC: ValueCodec<V>>(
+    key_codec: &KC,
+    value_codec: &VC,
+    key: &K,
+    old: Option<&V>,
+    new: Option<&V>,
+) -> Result<(Vec<u8>, Option<Vec<u8>>, Option<ArcBytes<'static>>), Error> {
+    let key = key_codec.encode_key(key)?;
+    let old = old.map(|old| value_codec.encode_value(old)).transpose()?;
+    let new = new
+        .map(|new| value_codec.encode_value(new).map(ArcBytes::from))
+        .transpose()?;
+    Ok((key, old, new))
+}
+
+fn decode_conflict<V: Debug, VC: ValueCodec<V>>(
+    value_codec: &VC,
+    result: Result<(), CompareAndSwapError<ArcBytes<'static>>>,
+) -> Result<(), CompareAndSwapError<V>> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(CompareAndSwapError::Conflict(value)) => Err(CompareAndSwapError::Conflict(
+            value
+                .map(|value| value_codec.decode_value(&value))
+                .transpose()?,
+        )),
+        Err(CompareAndSwapError::Error(err)) => Err(CompareAndSwapError::Error(err)),
+    }
+}
+
+type EncodedBounds = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+fn encode_bounds<K, KC: KeyCodec<K>, B: RangeBounds<K>>(
+    key_codec: &KC,
+    range: &B,
+) -> Result<EncodedBounds, Error> {
+    let encode = |bound: Bound<&K>| -> Result<Bound<Vec<u8>>, Error> {
+        Ok(match bound {
+            Bound::Included(key) => Bound::Included(key_codec.encode_key(key)?),
+            Bound::Excluded(key) => Bound::Excluded(key_codec.encode_key(key)?),
+            Bound::Unbounded => Bound::Unbounded,
+        })
+    };
+    Ok((encode(range.start_bound())?, encode(range.end_bound())?))
+}
+
+fn borrow_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn decode_entries<K, V, KC: KeyCodec<K>, VC: ValueCodec<V>>(
+    key_codec: &KC,
+    value_codec: &VC,
+    entries: Vec<(ArcBytes<'static>, ArcBytes<'static>)>,
+) -> Result<Vec<(K, V)>, Error> {
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            Ok((
+                key_codec.decode_key(&key)?,
+                value_codec.decode_value(&value)?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{io::fs::StdFile, tree::Unversioned, Config};
+
+    #[test]
+    fn typed_tree() {
+        let temp = tempdir().unwrap();
+        let roots = Config::default_for(temp.path()).open().unwrap();
+        let tree = TypedTree::<(String, i32), u64, _, StdFile>::new(
+            roots.tree(Unversioned::tree("typed")).unwrap(),
+        );
+
+        for (index, name) in ["b", "a", "c"].iter().enumerate() {
+            for number in [-10, 5, -1, 100] {
+                tree.set(&(name.to_string(), number), &(index as u64))
+                    .unwrap();
+            }
+        }
+        assert_eq!(tree.get(&(String::from("a"), -1)).unwrap(), Some(1));
+        assert_eq!(tree.get(&(String::from("a"), 0)).unwrap(), None);
+
+        // Ranges are compared against the typed keys, including negative
+        // numbers.
+        let range = tree
+            .get_range(&((String::from("a"), -5)..(String::from("b"), 0)))
+            .unwrap();
+        assert_eq!(
+            range,
+            vec![
+                ((String::from("a"), -1), 1),
+                ((String::from("a"), 5), 1),
+                ((String::from("a"), 100), 1),
+                ((String::from("b"), -10), 0),
+                ((String::from("b"), -1), 0),
+            ]
+        );
+        assert_eq!(tree.get_range(&(..)).unwrap().len(), 12);
+
+        tree.compare_and_swap(&(String::from("c"), 5), Some(&2), Some(&3))
+            .unwrap();
+        assert!(matches!(
+            tree.compare_and_swap(&(String::from("c"), 5), Some(&2), None),
+            Err(CompareAndSwapError::Conflict(Some(3)))
+        ));
+        assert_eq!(tree.remove(&(String::from("c"), 5)).unwrap(), Some(3));
+        assert_eq!(tree.get(&(String::from("c"), 5)).unwrap(), None);
+    }
+
+    #[test]
+    fn typed_transaction_tree() {
+        let temp = tempdir().unwrap();
+        let roots = Config::default_for(temp.path()).open().unwrap();
+        let tree = TypedTree::<u32, String, _, StdFile>::new(
+            roots.tree(Unversioned::tree("typed")).unwrap(),
+        );
+        tree.set(&1, &String::from("one")).unwrap();
+
+        let transaction = roots.transaction(&[Unversioned::tree("typed")]).unwrap();
+        {
+            let mut locked = transaction.tree::<Unversioned>(0).unwrap();
+            let mut typed = tree.transaction_tree(&mut locked);
+            typed.set(&2, &String::from("two")).unwrap();
+            typed
+                .compare_and_swap(&1, Some(&String::from("one")), Some(&String::from("uno")))
+                .unwrap();
+            assert_eq!(typed.get(&2).unwrap(), Some(String::from("two")));
+            assert_eq!(
+                typed.get_range(&(1..)).unwrap(),
+                vec![(1, String::from("uno")), (2, String::from("two"))]
+            );
+        }
+        transaction.commit().unwrap();
+
+        assert_eq!(tree.get(&1).unwrap(), Some(String::from("uno")));
+        assert_eq!(tree.get(&2).unwrap(), Some(String::from("two")));
+
+        // Values that cannot be decoded as the tree's type report a codec error.
+        tree.tree().set(b"bad".to_vec(), b"value".to_vec()).unwrap();
+        let err = tree.get_range(&(..)).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Codec(_)));
+    }
+}
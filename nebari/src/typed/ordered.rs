@@ -0,0 +1,778 @@
+//! An order-preserving binary format for serde.
+//!
+//! The format is designed so that comparing two encoded values byte-by-byte
+//! produces the same ordering as comparing the values they were encoded from:
+//!
+//! - Integers are encoded big-endian. Signed integers have their sign bit
+//!   flipped so that negative numbers sort before positive numbers.
+//! - Floats have their sign bit flipped when positive, and all bits flipped when
+//!   negative.
+//! - Strings and byte sequences are terminated by `[0, 0]`, with each `0`
+//!   within the sequence escaped as `[0, 255]`. This allows a sequence to sort
+//!   before every sequence it is a prefix of.
+//! - Each element of a sequence or map is preceded by a `1`, and the sequence
+//!   is terminated by a `0`.
+//! - Options are encoded as `0` for `None`, or `1` followed by the value.
+//! - Enum variants are encoded as their index as a big-endian `u32`, followed
+//!   by the variant's contents.
+//! - Tuples and structs are encoded as their fields in order.
+//!
+//! The format is not self-describing, so types that require
+//! `deserialize_any()` are not supported.
+
+use std::{borrow::Cow, fmt::Display};
+
+use serde::{
+    de::{self, DeserializeSeed, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+};
+
+use crate::keys::{self, DecodeKey, DecodeKeyError, EncodeKey};
+
+/// An error from the ordered format.
+#[derive(Debug, thiserror::Error)]
+pub enum OrderedError {
+    /// An error reported by a type being serialized or deserialized.
+    #[error("{0}")]
+    Message(String),
+    /// The encoded value could not be decoded.
+    #[error(transparent)]
+    Key(#[from] DecodeKeyError),
+    /// The operation is not supported by the format.
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
+}
+
+impl ser::Error for OrderedError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::Message(msg.to_string())
+    }
+}
+
+impl de::Error for OrderedError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::Message(msg.to_string())
+    }
+}
+
+/// Encodes `value` using the ordered format.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, OrderedError> {
+    let mut serializer = Serializer { output: Vec::new() };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+/// Decodes a `T` from `bytes`, which must have been encoded using the ordered
+/// format.
+pub fn from_slice<'de, T: de::Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, OrderedError> {
+    let mut deserializer = Deserializer { input: bytes };
+    let value = T::deserialize(&mut deserializer)?;
+    if deserializer.input.is_empty() {
+        Ok(value)
+    } else {
+        Err(OrderedError::Key(DecodeKeyError::TrailingBytes))
+    }
+}
+
+struct Serializer {
+    output: Vec<u8>,
+}
+
+impl ser::Serializer for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<(), OrderedError> {
+        self.output.push(u8::from(v));
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), OrderedError> {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), OrderedError> {
+        v.encode_key_into(&mut self.output);
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), OrderedError> {
+        keys::encode_escaped(v.as_bytes(), &mut self.output);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), OrderedError> {
+        keys::encode_escaped(v, &mut self.output);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), OrderedError> {
+        self.output.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), OrderedError> {
+        self.output.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), OrderedError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), OrderedError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), OrderedError> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), OrderedError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), OrderedError> {
+        variant_index.encode_key_into(&mut self.output);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, OrderedError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, OrderedError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self, OrderedError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, OrderedError> {
+        variant_index.encode_key_into(&mut self.output);
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self, OrderedError> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, OrderedError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, OrderedError> {
+        variant_index.encode_key_into(&mut self.output);
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), OrderedError> {
+        self.output.push(1);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), OrderedError> {
+        self.output.push(0);
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), OrderedError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), OrderedError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), OrderedError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), OrderedError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), OrderedError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), OrderedError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), OrderedError> {
+        self.output.push(1);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), OrderedError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), OrderedError> {
+        self.output.push(0);
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), OrderedError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), OrderedError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Serializer {
+    type Ok = ();
+    type Error = OrderedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), OrderedError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), OrderedError> {
+        Ok(())
+    }
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+}
+
+impl Deserializer<'_> {
+    fn take_marker(&mut self) -> Result<bool, OrderedError> {
+        match u8::decode_key_from(&mut self.input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(OrderedError::Key(DecodeKeyError::Invalid("marker"))),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = OrderedError;
+
+    serde::forward_to_deserialize_any! {
+        identifier ignored_any
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, OrderedError> {
+        Err(OrderedError::Unsupported(
+            "deserializing self-describing types",
+        ))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_bool(bool::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_i8(i8::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_i16(i16::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_i32(i32::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_i64(i64::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_i128(i128::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_u8(u8::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_u16(u16::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_u32(u32::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_u64(u64::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_u128(u128::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_f32(f32::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_f64(f64::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_char(char::decode_key_from(&mut self.input)?)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        let invalid = || OrderedError::Key(DecodeKeyError::Invalid("string"));
+        match keys::decode_escaped(&mut self.input)? {
+            Cow::Borrowed(bytes) => {
+                visitor.visit_borrowed_str(std::str::from_utf8(bytes).map_err(|_| invalid())?)
+            }
+            Cow::Owned(bytes) => {
+                visitor.visit_string(String::from_utf8(bytes).map_err(|_| invalid())?)
+            }
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        match keys::decode_escaped(&mut self.input)? {
+            Cow::Borrowed(bytes) => visitor.visit_borrowed_bytes(bytes),
+            Cow::Owned(bytes) => visitor.visit_byte_buf(bytes),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        if self.take_marker()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_seq(Elements { de: self })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        visitor.visit_seq(Fields {
+            de: self,
+            remaining: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, OrderedError> {
+        visitor.visit_map(Elements { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        visitor.visit_enum(self)
+    }
+}
+
+/// Accesses the elements of a sequence or map, each of which is preceded by a
+/// marker.
+struct Elements<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = OrderedError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, OrderedError> {
+        if self.de.take_marker()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = OrderedError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, OrderedError> {
+        if self.de.take_marker()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, OrderedError> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// Accesses a fixed number of fields.
+struct Fields<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    remaining: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Fields<'_, 'de> {
+    type Error = OrderedError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, OrderedError> {
+        if self.remaining == 0 {
+            Ok(None)
+        } else {
+            self.remaining -= 1;
+            seed.deserialize(&mut *self.de).map(Some)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = OrderedError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), OrderedError> {
+        let index = u32::decode_key_from(&mut self.input)?;
+        let deserializer: de::value::U32Deserializer<OrderedError> = index.into_deserializer();
+        let variant = seed.deserialize(deserializer)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = OrderedError;
+
+    fn unit_variant(self) -> Result<(), OrderedError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, OrderedError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, OrderedError> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fmt::Debug};
+
+    use super::*;
+
+    fn assert_ordered<T: Serialize + de::DeserializeOwned + Ord + Debug>(mut values: Vec<T>) {
+        values.sort();
+        let encoded = values
+            .iter()
+            .map(|value| to_vec(value).unwrap())
+            .collect::<Vec<_>>();
+        for (value, encoded) in values.iter().zip(&encoded) {
+            assert_eq!(&from_slice::<T>(encoded).unwrap(), value);
+        }
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_ordered(vec![0_u64, 1, 255, 256, u64::MAX]);
+        assert_ordered(vec![i32::MIN, -256, -1, 0, 1, 255, i32::MAX]);
+        assert_ordered(vec![i128::MIN, -1, 0, i128::MAX]);
+    }
+
+    #[test]
+    fn floats() {
+        let values = [f64::NEG_INFINITY, -1.5, -0.0, 0.0, 1e-9, 1.5, f64::INFINITY];
+        let encoded = values
+            .iter()
+            .map(|value| to_vec(value).unwrap())
+            .collect::<Vec<_>>();
+        for (value, encoded) in values.iter().zip(&encoded) {
+            assert_eq!(
+                from_slice::<f64>(encoded).unwrap().to_bits(),
+                value.to_bits()
+            );
+        }
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+    }
+
+    #[test]
+    fn strings() {
+        assert_ordered(vec![
+            String::new(),
+            String::from("\0"),
+            String::from("\0\0"),
+            String::from("\0a"),
+            String::from("a"),
+            String::from("a\0"),
+            String::from("a\0b"),
+            String::from("ab"),
+            String::from("b"),
+        ]);
+        let encoded = to_vec("abc").unwrap();
+        let borrowed: &str = from_slice(&encoded).unwrap();
+        assert_eq!(borrowed, "abc");
+    }
+
+    #[test]
+    fn compound() {
+        assert_ordered(vec![
+            (String::from("a"), 2_u16),
+            (String::from("a"), 10_u16),
+            (String::from("ab"), 0_u16),
+        ]);
+        assert_ordered(vec![vec![], vec![0_u8], vec![0, 0], vec![1]]);
+        assert_ordered(vec![None, Some(0_i8), Some(1)]);
+        assert_ordered(vec![Ok::<u8, String>(5), Err(String::from("a"))]);
+
+        let mut map = BTreeMap::new();
+        map.insert(String::from("a"), vec![1_u32, 2]);
+        map.insert(String::from("b"), Vec::new());
+        assert_eq!(
+            from_slice::<BTreeMap<String, Vec<u32>>>(&to_vec(&map).unwrap()).unwrap(),
+            map
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            from_slice::<u32>(&[0, 0]),
+            Err(OrderedError::Key(DecodeKeyError::UnexpectedEnd))
+        ));
+        assert!(matches!(
+            from_slice::<u8>(&[0, 0]),
+            Err(OrderedError::Key(DecodeKeyError::TrailingBytes))
+        ));
+        assert!(matches!(
+            from_slice::<String>(&[b'a', 0, 1]),
+            Err(OrderedError::Key(DecodeKeyError::Invalid(_)))
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt::Debug;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use super::Vault;
+
+/// The length of the nonce stored at the start of each encrypted payload.
+const NONCE_LENGTH: usize = 24;
+
+/// A [`Vault`] that encrypts using `XChaCha20-Poly1305`.
+///
+/// Each payload is encrypted with a newly generated random nonce, which is
+/// stored alongside the encrypted payload. The 192-bit nonces used by
+/// `XChaCha20` are large enough that randomly generated nonces will not repeat
+/// in practice.
+///
+/// Payloads are authenticated along with the location they were written to,
+/// which prevents an encrypted payload from being copied or moved to another
+/// location within a database without being detected.
+pub struct ChaChaVault {
+    cipher: XChaCha20Poly1305,
+    key_id: Option<u32>,
+}
+
+impl ChaChaVault {
+    /// Returns a vault that encrypts using `key`.
+    #[must_use]
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            key_id: None,
+        }
+    }
+
+    /// Sets the [key id](Vault::key_id) of this vault.
+    #[must_use]
+    pub const fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = Some(key_id);
+        self
+    }
+
+    /// Returns a new randomly generated key.
+    #[must_use]
+    pub fn generate_key() -> [u8; 32] {
+        XChaCha20Poly1305::generate_key(&mut OsRng).into()
+    }
+}
+
+impl Debug for ChaChaVault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The key is intentionally omitted.
+        f.debug_struct("ChaChaVault")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Vault for ChaChaVault {
+    type Error = ChaChaVaultError;
+
+    fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        self.encrypt_with_associated_data(payload, &[])
+    }
+
+    fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        self.decrypt_with_associated_data(payload, &[])
+    }
+
+    fn encrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Self::Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| ChaChaVaultError::Encryption)?;
+        let mut output = Vec::with_capacity(NONCE_LENGTH + encrypted.len());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&encrypted);
+        Ok(output)
+    }
+
+    fn decrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Self::Error> {
+        if payload.len() < NONCE_LENGTH {
+            return Err(ChaChaVaultError::Decryption);
+        }
+        let (nonce, encrypted) = payload.split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: encrypted,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| ChaChaVaultError::Decryption)
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        self.key_id
+    }
+}
+
+/// An error from a [`ChaChaVault`].
+#[derive(thiserror::Error, Debug)]
+pub enum ChaChaVaultError {
+    /// A payload could not be encrypted.
+    #[error("payload could not be encrypted")]
+    Encryption,
+    /// A payload could not be decrypted. The payload was either encrypted with
+    /// a different key, has been modified, or was moved from the location it
+    /// was written to.
+    #[error("payload could not be decrypted")]
+    Decryption,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let vault = ChaChaVault::new(&ChaChaVault::generate_key());
+        let encrypted = vault
+            .encrypt_with_associated_data(b"hello", b"location")
+            .unwrap();
+        assert_ne!(&encrypted[NONCE_LENGTH..], b"hello");
+        let decrypted = vault
+            .decrypt_with_associated_data(&encrypted, b"location")
+            .unwrap();
+        assert_eq!(decrypted, b"hello");
+
+        // Each encryption uses a new nonce.
+        assert_ne!(
+            encrypted,
+            vault
+                .encrypt_with_associated_data(b"hello", b"location")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_moved_or_modified_payloads() {
+        let vault = ChaChaVault::new(&ChaChaVault::generate_key());
+        let mut encrypted = vault
+            .encrypt_with_associated_data(b"hello", b"location")
+            .unwrap();
+        assert!(vault
+            .decrypt_with_associated_data(&encrypted, b"elsewhere")
+            .is_err());
+
+        let other_vault = ChaChaVault::new(&ChaChaVault::generate_key());
+        assert!(other_vault
+            .decrypt_with_associated_data(&encrypted, b"location")
+            .is_err());
+
+        *encrypted.last_mut().unwrap() ^= 1;
+        assert!(vault
+            .decrypt_with_associated_data(&encrypted, b"location")
+            .is_err());
+        assert!(vault
+            .decrypt_with_associated_data(&[], b"location")
+            .is_err());
+    }
+}
@@ -0,0 +1,387 @@
+use std::{path::Path, sync::Arc};
+
+use parking_lot::{Mutex, MutexGuard, RwLock};
+
+use crate::{
+    error::{InternalError, SendSyncError},
+    ErrorKind,
+};
+
+#[cfg(feature = "encryption")]
+mod chacha;
+
+#[cfg(feature = "encryption")]
+pub use self::chacha::{ChaChaVault, ChaChaVaultError};
+
+/// A provider of encryption for blocks of data.
+pub trait Vault: std::fmt::Debug + Send + Sync + 'static {
+    /// The error type that the vault can produce.
+    type Error: SendSyncError;
+
+    /// Encrypts `payload`, returning a new buffer that contains all information
+    /// necessary to decrypt it in the future.
+    fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Decrypts a previously encrypted `payload`, returning the decrypted
+    /// information.
+    fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Encrypts `payload`, authenticating `associated_data` alongside it.
+    ///
+    /// Nebari passes the location a payload is being written to as
+    /// `associated_data`: the name of the file and the position within it.
+    /// Vaults that implement authenticated encryption can use this to detect
+    /// payloads that have been moved or swapped. The same `associated_data`
+    /// will be provided to
+    /// [`decrypt_with_associated_data()`](Self::decrypt_with_associated_data).
+    ///
+    /// The default implementation ignores `associated_data` and calls
+    /// [`encrypt()`](Self::encrypt).
+    fn encrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Self::Error> {
+        let _ = associated_data;
+        self.encrypt(payload)
+    }
+
+    /// Decrypts a previously encrypted `payload`, verifying that it was
+    /// encrypted with `associated_data`.
+    ///
+    /// The default implementation ignores `associated_data` and calls
+    /// [`decrypt()`](Self::decrypt).
+    fn decrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Self::Error> {
+        let _ = associated_data;
+        self.decrypt(payload)
+    }
+
+    /// Returns the identifier of the key this vault encrypts with.
+    ///
+    /// When a database's vault returns an identifier, it is stored alongside
+    /// each encrypted payload. This allows payloads written with an older key
+    /// to be decrypted by the matching vault while a database is being moved
+    /// to a new key with [`Roots::rotate_vault()`](crate::Roots::rotate_vault).
+    /// Each key used by a database must have a unique identifier.
+    fn key_id(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// A [`Vault`] that can be boxed.
+pub trait AnyVault: std::fmt::Debug + Send + Sync + 'static {
+    /// Encrypts `payload`, returning a new buffer that contains all information
+    /// necessary to decrypt it in the future.
+    fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, crate::Error>;
+
+    /// Decrypts a previously encrypted `payload`, returning the decrypted
+    /// information.
+    fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, crate::Error>;
+
+    /// Encrypts `payload`, authenticating `associated_data` alongside it. See
+    /// [`Vault::encrypt_with_associated_data()`] for more information.
+    fn encrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error>;
+
+    /// Decrypts a previously encrypted `payload`, verifying that it was
+    /// encrypted with `associated_data`. See
+    /// [`Vault::decrypt_with_associated_data()`] for more information.
+    fn decrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error>;
+
+    /// Returns the identifier of the key this vault encrypts with. See
+    /// [`Vault::key_id()`] for more information.
+    fn key_id(&self) -> Option<u32>;
+
+    /// Encrypts `payload` as [`encrypt_with_associated_data()`] does,
+    /// returning the [key id](Self::key_id) of the key used alongside the
+    /// encrypted payload.
+    ///
+    /// Vaults that hold more than one key must override this function so that
+    /// the key id returned is always the id of the key that was used.
+    ///
+    /// [`encrypt_with_associated_data()`]: Self::encrypt_with_associated_data
+    fn encrypt_with_key_id(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<(Option<u32>, Vec<u8>), crate::Error> {
+        let encrypted = self.encrypt_with_associated_data(payload, associated_data)?;
+        Ok((self.key_id(), encrypted))
+    }
+
+    /// Decrypts a `payload` that was encrypted by the key identified by
+    /// `key_id`, verifying that it was encrypted with `associated_data`.
+    ///
+    /// The default implementation decrypts `payload` if `key_id` matches
+    /// [`Self::key_id()`], and returns [`ErrorKind::VaultKeyNotFound`]
+    /// otherwise. Vaults that hold more than one key must override this
+    /// function to decrypt using the matching key.
+    fn decrypt_with_key_id(
+        &self,
+        key_id: Option<u32>,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error> {
+        if key_id == self.key_id() {
+            self.decrypt_with_associated_data(payload, associated_data)
+        } else {
+            Err(crate::Error::from(ErrorKind::VaultKeyNotFound))
+        }
+    }
+}
+
+impl<T, E> AnyVault for T
+where
+    T: Vault<Error = E> + std::fmt::Debug,
+    E: SendSyncError,
+{
+    fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, crate::Error> {
+        self.encrypt(payload)
+            .map_err(|err| crate::Error::from(ErrorKind::Vault(Box::new(err))))
+    }
+
+    fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, crate::Error> {
+        Vault::decrypt(self, payload)
+            .map_err(|err| crate::Error::from(ErrorKind::Vault(Box::new(err))))
+    }
+
+    fn encrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error> {
+        Vault::encrypt_with_associated_data(self, payload, associated_data)
+            .map_err(|err| crate::Error::from(ErrorKind::Vault(Box::new(err))))
+    }
+
+    fn decrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error> {
+        Vault::decrypt_with_associated_data(self, payload, associated_data)
+            .map_err(|err| crate::Error::from(ErrorKind::Vault(Box::new(err))))
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        Vault::key_id(self)
+    }
+}
+
+/// Returns the associated data that binds an encrypted payload to `position`
+/// within the file at `path`. Only the file's name is used, allowing a database
+/// directory to be moved or renamed.
+#[allow(clippy::redundant_pub_crate)]
+pub(crate) fn location_binding(path: &Path, position: u64) -> Vec<u8> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let mut binding = Vec::with_capacity(file_name.len() + 8);
+    binding.extend_from_slice(file_name.as_bytes());
+    binding.extend_from_slice(&position.to_be_bytes());
+    binding
+}
+
+/// The version of the envelope written by [`seal()`].
+const ENVELOPE_VERSION: u8 = 1;
+/// Marks an envelope whose payload was encrypted by a vault without a key id.
+const WITHOUT_KEY_ID: u8 = 0;
+/// Marks an envelope whose payload was encrypted by a vault with a key id,
+/// which follows as a big-endian `u32`.
+const WITH_KEY_ID: u8 = 1;
+
+/// Encrypts `payload` using `vault`, and wraps the result in an envelope that
+/// records the envelope's version and the [key id](Vault::key_id) of the key
+/// that encrypted it.
+///
+/// Envelopes are not self-describing: whoever stores the envelope must record
+/// that it is one, so that it is never confused with a payload written before
+/// envelopes existed.
+#[allow(clippy::redundant_pub_crate)]
+pub(crate) fn seal(
+    vault: &dyn AnyVault,
+    payload: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, crate::Error> {
+    let (key_id, encrypted) = vault.encrypt_with_key_id(payload, associated_data)?;
+    let mut envelope = Vec::with_capacity(6 + encrypted.len());
+    envelope.push(ENVELOPE_VERSION);
+    match key_id {
+        Some(key_id) => {
+            envelope.push(WITH_KEY_ID);
+            envelope.extend_from_slice(&key_id.to_be_bytes());
+        }
+        None => envelope.push(WITHOUT_KEY_ID),
+    }
+    envelope.extend_from_slice(&encrypted);
+    Ok(envelope)
+}
+
+/// Decrypts an envelope written by [`seal()`] using the key of `vault` that
+/// encrypted it.
+#[allow(clippy::redundant_pub_crate)]
+pub(crate) fn unseal(
+    vault: &dyn AnyVault,
+    envelope: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, crate::Error> {
+    let (key_id, encrypted) = open_envelope(envelope)?;
+    vault.decrypt_with_key_id(key_id, encrypted, associated_data)
+}
+
+/// Returns the key id recorded in an envelope written by [`seal()`], and the
+/// encrypted payload it contains.
+#[allow(clippy::redundant_pub_crate)]
+pub(crate) fn open_envelope(envelope: &[u8]) -> Result<(Option<u32>, &[u8]), crate::Error> {
+    match envelope {
+        [ENVELOPE_VERSION, WITHOUT_KEY_ID, encrypted @ ..] => Ok((None, encrypted)),
+        [ENVELOPE_VERSION, WITH_KEY_ID, a, b, c, d, encrypted @ ..] => {
+            Ok((Some(u32::from_be_bytes([*a, *b, *c, *d])), encrypted))
+        }
+        [ENVELOPE_VERSION, ..] | [] => Err(crate::Error::data_integrity(ErrorKind::Internal(
+            InternalError::IncorrectByteLength,
+        ))),
+        [version, ..] => Err(crate::Error::from(ErrorKind::UnsupportedEnvelope(*version))),
+    }
+}
+
+/// The set of vaults a database can decrypt with, one of which is used to
+/// encrypt.
+///
+/// Payloads are [sealed](seal) along with the [key id](Vault::key_id) of the
+/// vault that encrypted them, and are decrypted by the vault with the matching
+/// id. Payloads written before envelopes existed are decrypted by the
+/// keyring's vault that has no key id.
+#[derive(Debug)]
+#[allow(clippy::redundant_pub_crate)]
+pub(crate) struct VaultKeyring {
+    keys: RwLock<Keys>,
+    rotation: Mutex<()>,
+}
+
+#[derive(Debug)]
+struct Keys {
+    current: Arc<dyn AnyVault>,
+    previous: Vec<Arc<dyn AnyVault>>,
+}
+
+impl Keys {
+    fn all(&self) -> impl Iterator<Item = &Arc<dyn AnyVault>> {
+        std::iter::once(&self.current).chain(self.previous.iter())
+    }
+}
+
+impl VaultKeyring {
+    pub fn new(current: Arc<dyn AnyVault>, previous: Vec<Arc<dyn AnyVault>>) -> Self {
+        Self {
+            keys: RwLock::new(Keys { current, previous }),
+            rotation: Mutex::default(),
+        }
+    }
+
+    /// Locks the keyring for a rotation, preventing another rotation from
+    /// retiring keys that are still in use.
+    pub fn lock_rotation(&self) -> MutexGuard<'_, ()> {
+        self.rotation.lock()
+    }
+
+    /// Encrypts with `vault` from now on, keeping the current vault available
+    /// for decryption. If `vault` has the same key id as the current vault,
+    /// the keyring is unchanged.
+    pub fn rotate_to(&self, vault: Arc<dyn AnyVault>) -> Result<(), crate::Error> {
+        let key_id = vault.key_id().ok_or(ErrorKind::VaultKeyIdRequired)?;
+        let mut keys = self.keys.write();
+        if keys.current.key_id() == Some(key_id) {
+            // Resuming a rotation to the current key.
+            return Ok(());
+        } else if keys
+            .previous
+            .iter()
+            .any(|existing| existing.key_id() == Some(key_id))
+        {
+            return Err(crate::Error::from(ErrorKind::VaultKeyIdInUse(key_id)));
+        }
+        let previous = std::mem::replace(&mut keys.current, vault);
+        keys.previous.push(previous);
+        Ok(())
+    }
+
+    /// Forgets every vault except the current one.
+    pub fn retire_previous(&self) {
+        self.keys.write().previous.clear();
+    }
+}
+
+impl AnyVault for VaultKeyring {
+    fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, crate::Error> {
+        self.encrypt_with_associated_data(payload, &[])
+    }
+
+    fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, crate::Error> {
+        self.decrypt_with_associated_data(payload, &[])
+    }
+
+    fn encrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error> {
+        self.keys
+            .read()
+            .current
+            .encrypt_with_associated_data(payload, associated_data)
+    }
+
+    /// Decrypts a payload written before envelopes existed.
+    fn decrypt_with_associated_data(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error> {
+        self.decrypt_with_key_id(None, payload, associated_data)
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        self.keys.read().current.key_id()
+    }
+
+    fn encrypt_with_key_id(
+        &self,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<(Option<u32>, Vec<u8>), crate::Error> {
+        let keys = self.keys.read();
+        let encrypted = keys
+            .current
+            .encrypt_with_associated_data(payload, associated_data)?;
+        Ok((keys.current.key_id(), encrypted))
+    }
+
+    fn decrypt_with_key_id(
+        &self,
+        key_id: Option<u32>,
+        payload: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, crate::Error> {
+        let vault = self
+            .keys
+            .read()
+            .all()
+            .find(|vault| vault.key_id() == key_id)
+            .cloned()
+            .ok_or(ErrorKind::VaultKeyNotFound)?;
+        vault.decrypt_with_associated_data(payload, associated_data)
+    }
+}